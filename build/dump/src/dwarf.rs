@@ -0,0 +1,787 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Just enough DWARF to decode Rust statics out of a memory dump.
+//!
+//! We walk every unit in an ELF file once, recording the types and variables
+//! we understand into a `DebugInfo`, and then throw the DWARF itself away.
+//! Types are identified by their offset in `.debug_info`.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+use gimli::{AttributeValue, Reader};
+
+pub type TypeId = usize;
+
+#[derive(Debug)]
+pub enum Type {
+    Base {
+        name: String,
+        size: usize,
+        encoding: gimli::DwAte,
+    },
+    Struct(Struct),
+    /// A C-like enum, with no data in any variant.
+    Enum {
+        name: String,
+        size: usize,
+        variants: Vec<(String, u64)>,
+    },
+    Pointer {
+        size: usize,
+        target: Option<TypeId>,
+    },
+    Array {
+        element: TypeId,
+        count: usize,
+    },
+    /// A typedef, or a `const` or `volatile` qualifier.
+    Alias(TypeId),
+}
+
+/// A struct, union, or Rust enum with data. Rust enums are structs with a
+/// variant part and (usually) no members of their own.
+#[derive(Debug)]
+pub struct Struct {
+    pub name: String,
+    pub size: usize,
+    pub members: Vec<Member>,
+    pub variant_part: Option<VariantPart>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Member {
+    pub name: String,
+    pub offset: usize,
+    pub ty: TypeId,
+}
+
+#[derive(Debug)]
+pub struct VariantPart {
+    /// The member holding the discriminant. Enums with a single variant have
+    /// none.
+    pub discr: Option<Member>,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug)]
+pub struct Variant {
+    /// Discriminant value selecting this variant, or `None` for the variant
+    /// taken when no other one matches (the dataful variant of a niche-encoded
+    /// enum).
+    pub value: Option<u64>,
+    pub member: Member,
+}
+
+/// Types and variables extracted from the DWARF of a single ELF file.
+#[derive(Debug, Default)]
+pub struct DebugInfo {
+    types: HashMap<TypeId, Type>,
+    /// Type of each variable, by linkage name where there is one, and by
+    /// plain name otherwise.
+    variables: HashMap<String, TypeId>,
+}
+
+impl DebugInfo {
+    pub fn from_elf(elf: &goblin::elf::Elf, bytes: &[u8]) -> Result<Self> {
+        let endian = if elf.little_endian {
+            gimli::RunTimeEndian::Little
+        } else {
+            gimli::RunTimeEndian::Big
+        };
+
+        let load = |id: gimli::SectionId| -> Result<_> {
+            let data =
+                match elf.section_headers.iter().find(|s| {
+                    elf.shdr_strtab.get_at(s.sh_name) == Some(id.name())
+                }) {
+                    Some(s) => s
+                        .file_range()
+                        .and_then(|range| bytes.get(range))
+                        .ok_or_else(|| anyhow!("{} is truncated", id.name()))?,
+                    None => &[],
+                };
+            Ok(gimli::EndianSlice::new(data, endian))
+        };
+        let dwarf = gimli::Dwarf::load(load)?;
+
+        let mut info = DebugInfo::default();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let mut tree = unit.entries_tree(None)?;
+            info.walk(&dwarf, &unit, tree.root()?)?;
+        }
+        Ok(info)
+    }
+
+    pub fn variable(&self, name: &str) -> Option<TypeId> {
+        self.variables.get(name).copied()
+    }
+
+    pub fn size_of(&self, ty: TypeId) -> Option<usize> {
+        match self.types.get(&ty)? {
+            Type::Base { size, .. }
+            | Type::Enum { size, .. }
+            | Type::Pointer { size, .. } => Some(*size),
+            Type::Struct(s) => Some(s.size),
+            Type::Array { element, count } => {
+                self.size_of(*element)?.checked_mul(*count)
+            }
+            Type::Alias(t) => self.size_of(*t),
+        }
+    }
+
+    /// Finds the first pointer reachable from `ty` -- through aliases,
+    /// members, and enum variants -- and returns the type it points to. This
+    /// is how we get from something like `Option<NonNull<Task>>` to `Task`.
+    pub fn pointee(&self, ty: TypeId) -> Option<TypeId> {
+        match self.types.get(&ty)? {
+            Type::Pointer { target, .. } => *target,
+            Type::Alias(t) => self.pointee(*t),
+            Type::Struct(s) => {
+                let variants = s
+                    .variant_part
+                    .iter()
+                    .flat_map(|vp| vp.variants.iter().map(|v| &v.member));
+                s.members
+                    .iter()
+                    .chain(variants)
+                    .find_map(|m| self.pointee(m.ty))
+            }
+            _ => None,
+        }
+    }
+
+    /// Decodes a value of type `ty` from the start of `bytes`, which are
+    /// assumed to be little-endian target memory.
+    pub fn decode(&self, ty: TypeId, bytes: &[u8]) -> Result<Value> {
+        let t = self
+            .types
+            .get(&ty)
+            .ok_or_else(|| anyhow!("unknown type at {:#x}", ty))?;
+
+        if let Some(size) = self.size_of(ty) {
+            if bytes.len() < size {
+                bail!("need {} bytes to decode, have {}", size, bytes.len());
+            }
+        }
+
+        Ok(match t {
+            Type::Base {
+                name,
+                size,
+                encoding,
+            } => {
+                let raw = read_uint(&bytes[..*size]);
+                match *encoding {
+                    _ if *size == 0 => Value::Unit,
+                    gimli::DW_ATE_boolean => Value::Bool(raw != 0),
+                    gimli::DW_ATE_signed | gimli::DW_ATE_signed_char => {
+                        let shift = 128 - 8 * *size as u32;
+                        Value::Signed(((raw << shift) as i128) >> shift)
+                    }
+                    gimli::DW_ATE_unsigned | gimli::DW_ATE_unsigned_char => {
+                        Value::Unsigned(raw)
+                    }
+                    gimli::DW_ATE_UTF => std::char::from_u32(raw as u32)
+                        .map(Value::Char)
+                        .unwrap_or(Value::Unsigned(raw)),
+                    gimli::DW_ATE_float if *size == 4 => {
+                        Value::Float(f32::from_bits(raw as u32) as f64)
+                    }
+                    gimli::DW_ATE_float if *size == 8 => {
+                        Value::Float(f64::from_bits(raw as u64))
+                    }
+                    _ => Value::Opaque(name.clone()),
+                }
+            }
+            Type::Enum {
+                name,
+                size,
+                variants,
+            } => {
+                let raw = read_uint(&bytes[..*size]) as u64;
+                match variants.iter().find(|(_, v)| *v == raw) {
+                    Some((variant, _)) => Value::Enum(variant.clone()),
+                    None => Value::Opaque(format!("{}::<{:#x}>", name, raw)),
+                }
+            }
+            Type::Pointer { size, .. } => {
+                Value::Pointer(read_uint(&bytes[..*size]) as u64)
+            }
+            Type::Array { element, count } => {
+                let stride = self.size_of(*element).unwrap_or(0);
+                if stride.checked_mul(*count).is_none() {
+                    bail!("array of {} elements is too big", count);
+                }
+                // Not `with_capacity`, since a corrupt count of elements of
+                // unknown size can be anything.
+                let mut elements = vec![];
+                for i in 0..*count {
+                    let element_bytes = at(bytes, i * stride)?;
+                    elements.push(self.decode(*element, element_bytes)?);
+                }
+                Value::Array(elements)
+            }
+            Type::Alias(t) => self.decode(*t, bytes)?,
+            Type::Struct(s) => {
+                if let Some(vp) = &s.variant_part {
+                    let variant = match &vp.discr {
+                        None => vp.variants.first(),
+                        Some(d) => {
+                            let size = self.size_of(d.ty).unwrap_or(0);
+                            let discr =
+                                at(bytes, d.offset)?.get(..size).ok_or_else(
+                                    || anyhow!("{} is truncated", s.name),
+                                )?;
+                            let raw = read_uint(discr);
+                            let mask = if size >= 8 {
+                                u64::MAX
+                            } else {
+                                (1u64 << (8 * size)) - 1
+                            };
+                            vp.variants
+                                .iter()
+                                .find(|v| {
+                                    v.value.map(|x| x & mask)
+                                        == Some(raw as u64 & mask)
+                                })
+                                .or_else(|| {
+                                    vp.variants
+                                        .iter()
+                                        .find(|v| v.value.is_none())
+                                })
+                        }
+                    };
+                    match variant {
+                        Some(v) => self
+                            .decode(v.member.ty, at(bytes, v.member.offset)?)?,
+                        None => Value::Opaque(format!("{}::<invalid>", s.name)),
+                    }
+                } else {
+                    let mut fields = Vec::with_capacity(s.members.len());
+                    for m in &s.members {
+                        fields.push((
+                            m.name.clone(),
+                            self.decode(m.ty, at(bytes, m.offset)?)?,
+                        ));
+                    }
+                    Value::Struct {
+                        name: s.name.clone(),
+                        fields,
+                    }
+                }
+            }
+        })
+    }
+
+    /// Builds debug info by hand, for tests that have no ELF file.
+    #[cfg(test)]
+    pub fn from_parts(
+        types: Vec<(TypeId, Type)>,
+        variables: &[(&str, TypeId)],
+    ) -> Self {
+        Self {
+            types: types.into_iter().collect(),
+            variables: variables
+                .iter()
+                .map(|(name, ty)| (name.to_string(), *ty))
+                .collect(),
+        }
+    }
+
+    fn walk<R: Reader<Offset = usize>>(
+        &mut self,
+        dwarf: &gimli::Dwarf<R>,
+        unit: &gimli::Unit<R>,
+        node: gimli::EntriesTreeNode<R>,
+    ) -> Result<()> {
+        let entry = node.entry();
+        let id = global_offset(unit, entry.offset());
+        let name = name_of(dwarf, unit, entry)?;
+        let size = entry
+            .attr_value(gimli::DW_AT_byte_size)?
+            .and_then(|v| v.udata_value())
+            .map(|v| v as usize);
+        let ty = type_of(unit, entry)?;
+
+        match entry.tag() {
+            gimli::DW_TAG_base_type => {
+                let encoding = match entry.attr_value(gimli::DW_AT_encoding)? {
+                    Some(AttributeValue::Encoding(e)) => e,
+                    _ => gimli::DW_ATE_unsigned,
+                };
+                self.types.insert(
+                    id,
+                    Type::Base {
+                        name,
+                        size: size.unwrap_or(0),
+                        encoding,
+                    },
+                );
+            }
+            gimli::DW_TAG_pointer_type
+            | gimli::DW_TAG_reference_type
+            | gimli::DW_TAG_rvalue_reference_type => {
+                let size =
+                    size.unwrap_or_else(|| unit.header.address_size() as usize);
+                self.types.insert(id, Type::Pointer { size, target: ty });
+            }
+            gimli::DW_TAG_typedef
+            | gimli::DW_TAG_const_type
+            | gimli::DW_TAG_volatile_type => {
+                if let Some(ty) = ty {
+                    self.types.insert(id, Type::Alias(ty));
+                }
+            }
+            gimli::DW_TAG_variable => {
+                if let Some(ty) = ty {
+                    let linkage_name =
+                        match entry.attr_value(gimli::DW_AT_linkage_name)? {
+                            Some(v) => Some(
+                                dwarf
+                                    .attr_string(unit, v)?
+                                    .to_string_lossy()?
+                                    .into_owned(),
+                            ),
+                            None => None,
+                        };
+                    if let Some(key) = linkage_name.or(Some(name)) {
+                        self.variables.entry(key).or_insert(ty);
+                    }
+                }
+            }
+            gimli::DW_TAG_array_type => {
+                let element = match ty {
+                    Some(ty) => ty,
+                    None => return Ok(()),
+                };
+                let mut count = 0;
+                let mut children = node.children();
+                while let Some(child) = children.next()? {
+                    let e = child.entry();
+                    if e.tag() != gimli::DW_TAG_subrange_type {
+                        continue;
+                    }
+                    if let Some(n) = e
+                        .attr_value(gimli::DW_AT_count)?
+                        .and_then(|v| v.udata_value())
+                    {
+                        count = n as usize;
+                    } else if let Some(n) = e
+                        .attr_value(gimli::DW_AT_upper_bound)?
+                        .and_then(|v| v.udata_value())
+                    {
+                        count = n as usize + 1;
+                    }
+                }
+                self.types.insert(id, Type::Array { element, count });
+                return Ok(());
+            }
+            gimli::DW_TAG_enumeration_type => {
+                let mut variants = vec![];
+                let mut children = node.children();
+                while let Some(child) = children.next()? {
+                    let e = child.entry();
+                    if e.tag() != gimli::DW_TAG_enumerator {
+                        continue;
+                    }
+                    let value = match e.attr_value(gimli::DW_AT_const_value)? {
+                        Some(v) => v
+                            .udata_value()
+                            .or_else(|| v.sdata_value().map(|v| v as u64)),
+                        None => None,
+                    };
+                    if let Some(value) = value {
+                        variants.push((name_of(dwarf, unit, e)?, value));
+                    }
+                }
+                self.types.insert(
+                    id,
+                    Type::Enum {
+                        name,
+                        size: size.unwrap_or(0),
+                        variants,
+                    },
+                );
+                return Ok(());
+            }
+            gimli::DW_TAG_structure_type | gimli::DW_TAG_union_type => {
+                let mut s = Struct {
+                    name,
+                    size: size.unwrap_or(0),
+                    members: vec![],
+                    variant_part: None,
+                };
+                let mut children = node.children();
+                while let Some(child) = children.next()? {
+                    match child.entry().tag() {
+                        gimli::DW_TAG_member => {
+                            if let Some(m) = member(dwarf, unit, child.entry())?
+                            {
+                                s.members.push(m);
+                            }
+                        }
+                        gimli::DW_TAG_variant_part => {
+                            s.variant_part =
+                                Some(self.variant_part(dwarf, unit, child)?);
+                        }
+                        _ => self.walk(dwarf, unit, child)?,
+                    }
+                }
+                self.types.insert(id, Type::Struct(s));
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        let mut children = node.children();
+        while let Some(child) = children.next()? {
+            self.walk(dwarf, unit, child)?;
+        }
+        Ok(())
+    }
+
+    fn variant_part<R: Reader<Offset = usize>>(
+        &mut self,
+        dwarf: &gimli::Dwarf<R>,
+        unit: &gimli::Unit<R>,
+        node: gimli::EntriesTreeNode<R>,
+    ) -> Result<VariantPart> {
+        let discr = match node.entry().attr_value(gimli::DW_AT_discr)? {
+            Some(AttributeValue::UnitRef(o)) => Some(o),
+            _ => None,
+        };
+
+        let mut part = VariantPart {
+            discr: None,
+            variants: vec![],
+        };
+        let mut children = node.children();
+        while let Some(child) = children.next()? {
+            let entry = child.entry();
+            match entry.tag() {
+                gimli::DW_TAG_member if Some(entry.offset()) == discr => {
+                    part.discr = member(dwarf, unit, entry)?;
+                }
+                gimli::DW_TAG_variant => {
+                    let value =
+                        match entry.attr_value(gimli::DW_AT_discr_value)? {
+                            Some(v) => v
+                                .udata_value()
+                                .or_else(|| v.sdata_value().map(|v| v as u64)),
+                            None => None,
+                        };
+                    let mut grandchildren = child.children();
+                    while let Some(gc) = grandchildren.next()? {
+                        if gc.entry().tag() == gimli::DW_TAG_member {
+                            if let Some(member) =
+                                member(dwarf, unit, gc.entry())?
+                            {
+                                part.variants.push(Variant { value, member });
+                            }
+                        } else {
+                            self.walk(dwarf, unit, gc)?;
+                        }
+                    }
+                }
+                _ => self.walk(dwarf, unit, child)?,
+            }
+        }
+        Ok(part)
+    }
+}
+
+fn global_offset<R: Reader<Offset = usize>>(
+    unit: &gimli::Unit<R>,
+    offset: gimli::UnitOffset,
+) -> TypeId {
+    offset
+        .to_debug_info_offset(&unit.header)
+        .map(|o| o.0)
+        .unwrap_or(offset.0)
+}
+
+fn name_of<R: Reader<Offset = usize>>(
+    dwarf: &gimli::Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    entry: &gimli::DebuggingInformationEntry<R>,
+) -> Result<String> {
+    Ok(match entry.attr_value(gimli::DW_AT_name)? {
+        Some(v) => dwarf.attr_string(unit, v)?.to_string_lossy()?.into_owned(),
+        None => String::new(),
+    })
+}
+
+fn type_of<R: Reader<Offset = usize>>(
+    unit: &gimli::Unit<R>,
+    entry: &gimli::DebuggingInformationEntry<R>,
+) -> Result<Option<TypeId>> {
+    Ok(match entry.attr_value(gimli::DW_AT_type)? {
+        Some(AttributeValue::UnitRef(o)) => Some(global_offset(unit, o)),
+        Some(AttributeValue::DebugInfoRef(o)) => Some(o.0),
+        _ => None,
+    })
+}
+
+fn member<R: Reader<Offset = usize>>(
+    dwarf: &gimli::Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    entry: &gimli::DebuggingInformationEntry<R>,
+) -> Result<Option<Member>> {
+    let ty = match type_of(unit, entry)? {
+        Some(ty) => ty,
+        None => return Ok(None),
+    };
+    let offset = entry
+        .attr_value(gimli::DW_AT_data_member_location)?
+        .and_then(|v| v.udata_value())
+        .unwrap_or(0) as usize;
+    Ok(Some(Member {
+        name: name_of(dwarf, unit, entry)?,
+        offset,
+        ty,
+    }))
+}
+
+/// Returns `bytes` from `offset` on, which corrupt debug info can put past
+/// the end.
+fn at(bytes: &[u8], offset: usize) -> Result<&[u8]> {
+    bytes.get(offset..).ok_or_else(|| {
+        anyhow!(
+            "offset {:#x} is past the end of {:#x} bytes",
+            offset,
+            bytes.len()
+        )
+    })
+}
+
+fn read_uint(bytes: &[u8]) -> u128 {
+    bytes
+        .iter()
+        .take(16)
+        .rev()
+        .fold(0, |acc, &b| (acc << 8) | b as u128)
+}
+
+/// A decoded value, which prints much like Rust's `{:?}` would.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Unit,
+    Bool(bool),
+    Unsigned(u128),
+    Signed(i128),
+    Float(f64),
+    Char(char),
+    /// A C-like enum variant.
+    Enum(String),
+    Pointer(u64),
+    Array(Vec<Value>),
+    /// A struct, or the selected variant of a Rust enum.
+    Struct {
+        name: String,
+        fields: Vec<(String, Value)>,
+    },
+    /// Something we found but don't know how to decode.
+    Opaque(String),
+}
+
+impl Value {
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Struct { fields, .. } => {
+                fields.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Unsigned(v) => Some(*v as u64),
+            Value::Signed(v) => Some(*v as u64),
+            Value::Pointer(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// Interprets `self` as an `Option`, returning `None` if it's not one.
+    pub fn as_option(&self) -> Option<Option<&Value>> {
+        match self {
+            Value::Struct { name, fields }
+                if name == "None" && fields.is_empty() =>
+            {
+                Some(None)
+            }
+            Value::Struct { name, .. } if name == "Some" => {
+                Some(self.field("__0"))
+            }
+            _ => None,
+        }
+    }
+
+    /// Finds the first value, depth first, for which `pred` is true.
+    pub fn find(&self, pred: &dyn Fn(&Value) -> bool) -> Option<&Value> {
+        if pred(self) {
+            return Some(self);
+        }
+        match self {
+            Value::Struct { fields, .. } => {
+                fields.iter().find_map(|(_, v)| v.find(pred))
+            }
+            Value::Array(elements) => {
+                elements.iter().find_map(|v| v.find(pred))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Unit => write!(f, "()"),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Unsigned(v) => write!(f, "{}", v),
+            Value::Signed(v) => write!(f, "{}", v),
+            Value::Float(v) => write!(f, "{}", v),
+            Value::Char(v) => write!(f, "{:?}", v),
+            Value::Enum(v) | Value::Opaque(v) => write!(f, "{}", v),
+            Value::Pointer(v) => write!(f, "{:#x}", v),
+            Value::Array(elements) => {
+                write!(f, "[")?;
+                for (i, v) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Value::Struct { name, fields } => {
+                // Tuple structs and tuple variants have fields named __0,
+                // __1, and so on.
+                let tuple = fields
+                    .iter()
+                    .enumerate()
+                    .all(|(i, (n, _))| *n == format!("__{}", i));
+
+                write!(f, "{}", name)?;
+                if fields.is_empty() {
+                    return Ok(());
+                }
+                write!(f, "{}", if tuple { "(" } else { " { " })?;
+                for (i, (n, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    if !tuple {
+                        write!(f, "{}: ", n)?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "{}", if tuple { ")" } else { " }" })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const U32: TypeId = 1;
+    const PAIR: TypeId = 2;
+    const PAIRS: TypeId = 3;
+
+    fn member(name: &str, offset: usize, ty: TypeId) -> Member {
+        Member {
+            name: name.to_string(),
+            offset,
+            ty,
+        }
+    }
+
+    fn info(extra: Vec<(TypeId, Type)>) -> DebugInfo {
+        let mut types = vec![
+            (
+                U32,
+                Type::Base {
+                    name: "u32".to_string(),
+                    size: 4,
+                    encoding: gimli::DW_ATE_unsigned,
+                },
+            ),
+            (
+                PAIR,
+                Type::Struct(Struct {
+                    name: "Pair".to_string(),
+                    size: 8,
+                    members: vec![member("a", 0, U32), member("b", 4, U32)],
+                    variant_part: None,
+                }),
+            ),
+            (
+                PAIRS,
+                Type::Array {
+                    element: PAIR,
+                    count: 2,
+                },
+            ),
+        ];
+        types.extend(extra);
+        DebugInfo::from_parts(types, &[])
+    }
+
+    const BYTES: [u8; 16] = [1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0];
+
+    #[test]
+    fn structs_and_arrays() {
+        let info = info(vec![]);
+        assert_eq!(info.size_of(PAIRS), Some(16));
+        let value = info.decode(PAIRS, &BYTES).unwrap();
+        assert_eq!(
+            value.to_string(),
+            "[Pair { a: 1, b: 2 }, Pair { a: 3, b: 4 }]"
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let info = info(vec![]);
+        let err = info.decode(PAIRS, &BYTES[..12]).unwrap_err();
+        assert_eq!(err.to_string(), "need 16 bytes to decode, have 12");
+    }
+
+    #[test]
+    fn member_past_the_end() {
+        let info = info(vec![(
+            4,
+            Type::Struct(Struct {
+                name: "Bad".to_string(),
+                size: 4,
+                members: vec![member("a", 8, U32)],
+                variant_part: None,
+            }),
+        )]);
+        let err = info.decode(4, &BYTES[..4]).unwrap_err();
+        assert_eq!(err.to_string(), "offset 0x8 is past the end of 0x4 bytes");
+    }
+
+    #[test]
+    fn huge_array() {
+        let info = info(vec![(
+            4,
+            Type::Array {
+                element: PAIR,
+                count: usize::MAX / 4,
+            },
+        )]);
+        assert_eq!(info.size_of(4), None);
+        assert!(info.decode(4, &BYTES).is_err());
+    }
+}
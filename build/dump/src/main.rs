@@ -0,0 +1,196 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Offline decoder for Hubris memory dumps.
+//!
+//! Given the build archive produced by `xtask dist` and one or more raw RAM
+//! dumps from the same image, this reports the state of each task from the
+//! kernel's task table, and the contents of every ring buffer in the kernel
+//! and in each task -- without needing the board.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+mod dwarf;
+mod memory;
+mod ringbuf;
+mod task;
+
+use memory::{Memory, RegionArg};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    max_term_width = 80,
+    about = "decode task state and ring buffers from a Hubris RAM dump"
+)]
+struct Args {
+    /// Only report task states
+    #[structopt(long, conflicts_with = "ringbufs-only")]
+    tasks_only: bool,
+
+    /// Only report ring buffers
+    #[structopt(long)]
+    ringbufs_only: bool,
+
+    /// Path to the build archive (build-<name>.zip) from `xtask dist`
+    archive: PathBuf,
+
+    /// RAM dumps, each given as ADDRESS=FILE, where ADDRESS is where the
+    /// first byte of FILE was read from (e.g. 0x24000000=axisram.bin)
+    #[structopt(required = true)]
+    dumps: Vec<RegionArg>,
+}
+
+/// The parts of `app.toml` we care about: just the task names, in order.
+#[derive(Debug, Deserialize)]
+struct AppToml {
+    tasks: IndexMap<String, toml::Value>,
+}
+
+/// An ELF file from the build archive.
+struct Component {
+    name: String,
+    bytes: Vec<u8>,
+}
+
+fn read_file(
+    archive: &mut zip::ZipArchive<std::fs::File>,
+    name: &str,
+) -> Result<Vec<u8>> {
+    let mut file = archive
+        .by_name(name)
+        .with_context(|| format!("archive has no {}", name))?;
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn load_archive(path: &Path) -> Result<(Component, Vec<Component>)> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    let mut archive = zip::ZipArchive::new(file)?;
+
+    let app: AppToml = toml::from_slice(&read_file(&mut archive, "app.toml")?)?;
+    let kernel = Component {
+        name: "kernel".to_string(),
+        bytes: read_file(&mut archive, "elf/kernel")?,
+    };
+    let mut tasks = vec![];
+    for name in app.tasks.keys() {
+        tasks.push(Component {
+            name: name.clone(),
+            bytes: read_file(&mut archive, &format!("elf/task/{}", name))?,
+        });
+    }
+    Ok((kernel, tasks))
+}
+
+fn print_tasks(
+    kernel: &Component,
+    tasks: &[Component],
+    memory: &Memory,
+) -> Result<()> {
+    let elf = goblin::elf::Elf::parse(&kernel.bytes)?;
+    let info = dwarf::DebugInfo::from_elf(&elf, &kernel.bytes)?;
+    let table = task::read_table(&elf, &info, memory)?;
+
+    println!(
+        "{:>2} {:<16} {:>5} {:<12} STATE",
+        "ID", "TASK", "GEN", "PRI"
+    );
+    for (i, t) in table.iter().enumerate() {
+        let name = tasks.get(i).map(|c| c.name.as_str()).unwrap_or("?");
+        println!(
+            "{:>2} {:<16} {:>5} {:<12} {}",
+            i,
+            name,
+            t.generation
+                .map(|g| g.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            t.priority
+                .as_ref()
+                .map(|p| p.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            t.state
+        );
+    }
+    Ok(())
+}
+
+fn print_ringbufs(component: &Component, memory: &Memory) -> Result<()> {
+    let elf = goblin::elf::Elf::parse(&component.bytes)?;
+    let syms = ringbuf::find(&elf);
+    if syms.is_empty() {
+        return Ok(());
+    }
+    let info = dwarf::DebugInfo::from_elf(&elf, &component.bytes)?;
+
+    for sym in &syms {
+        println!();
+        println!("{}: {} at {:#x}:", component.name, sym.name, sym.address);
+        let entries = match ringbuf::decode(&info, memory, sym) {
+            Ok(entries) => entries,
+            Err(e) => {
+                // One unreadable ring buffer shouldn't stop us reporting the
+                // rest.
+                println!("    can't decode: {:#}", e);
+                continue;
+            }
+        };
+        if entries.is_empty() {
+            println!("    (empty)");
+            continue;
+        }
+
+        let timestamped = entries.iter().any(|e| e.timestamps.is_some());
+        if timestamped {
+            println!(
+                "{:>4} {:>5} {:>5} {:>6} {:>12} {:>12} PAYLOAD",
+                "NDX", "LINE", "GEN", "COUNT", "FIRST", "LAST"
+            );
+        } else {
+            println!(
+                "{:>4} {:>5} {:>5} {:>6} PAYLOAD",
+                "NDX", "LINE", "GEN", "COUNT"
+            );
+        }
+        for e in &entries {
+            print!(
+                "{:>4} {:>5} {:>5} {:>6}",
+                e.index, e.line, e.generation, e.count
+            );
+            if let Some((first, last)) = e.timestamps {
+                print!(" {:>12} {:>12}", first, last);
+            }
+            println!(" {}", e.payload);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::from_args();
+
+    let (kernel, tasks) = load_archive(&args.archive)?;
+    let memory = Memory::load(&args.dumps)?;
+
+    if !args.ringbufs_only {
+        print_tasks(&kernel, &tasks, &memory)?;
+    }
+
+    if !args.tasks_only {
+        for component in std::iter::once(&kernel).chain(tasks.iter()) {
+            print_ringbufs(component, &memory).with_context(|| {
+                format!("decoding ring buffers in {}", component.name)
+            })?;
+        }
+    }
+
+    Ok(())
+}
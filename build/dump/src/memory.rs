@@ -0,0 +1,154 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// A raw RAM dump file, and the address its first byte was read from, as
+/// given on the command line in the form `ADDRESS=FILE`.
+#[derive(Clone, Debug)]
+pub struct RegionArg {
+    pub address: u64,
+    pub path: PathBuf,
+}
+
+impl FromStr for RegionArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, path) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected ADDRESS=FILE, got {:?}", s))?;
+        let address = match address.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => address.parse(),
+        }
+        .with_context(|| format!("bad address {:?}", address))?;
+        Ok(Self {
+            address,
+            path: path.into(),
+        })
+    }
+}
+
+/// Target memory, as recovered from one or more dump files.
+#[derive(Debug, Default)]
+pub struct Memory {
+    regions: Vec<(u64, Vec<u8>)>,
+}
+
+impl Memory {
+    pub fn load(args: &[RegionArg]) -> Result<Self> {
+        let mut memory = Memory::default();
+        for arg in args {
+            let data = std::fs::read(&arg.path).with_context(|| {
+                format!("reading dump {}", arg.path.display())
+            })?;
+            let end = match arg.address.checked_add(data.len() as u64) {
+                Some(end) => end,
+                None => bail!(
+                    "dump {} at {:#x} runs off the end of memory",
+                    arg.path.display(),
+                    arg.address
+                ),
+            };
+            for (base, other) in &memory.regions {
+                if arg.address < base + other.len() as u64 && *base < end {
+                    bail!(
+                        "dump {} at {:#x} overlaps another at {:#x}",
+                        arg.path.display(),
+                        arg.address,
+                        base
+                    );
+                }
+            }
+            memory.regions.push((arg.address, data));
+        }
+        Ok(memory)
+    }
+
+    /// Returns the `len` bytes at `addr`, which must all come from a single
+    /// dump file.
+    pub fn read(&self, addr: u64, len: usize) -> Result<&[u8]> {
+        let end = addr.checked_add(len as u64).ok_or_else(|| {
+            anyhow!("{:#x} bytes at {:#x} run off the end of memory", len, addr)
+        })?;
+        for (base, data) in &self.regions {
+            // Regions were checked not to wrap when they were loaded.
+            if addr >= *base && end <= base + data.len() as u64 {
+                let start = (addr - base) as usize;
+                return Ok(&data[start..start + len]);
+            }
+        }
+        bail!("{:#x}..{:#x} is not in the dump", addr, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `bytes` to a dump file, returning the argument naming it.
+    fn dump(name: &str, address: u64, bytes: &[u8]) -> RegionArg {
+        let path = std::env::temp_dir().join(format!(
+            "dump-{}-{}.bin",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, bytes).unwrap();
+        RegionArg { address, path }
+    }
+
+    #[test]
+    fn region_args() {
+        let arg: RegionArg = "0x24000000=axisram.bin".parse().unwrap();
+        assert_eq!(arg.address, 0x2400_0000);
+        assert_eq!(arg.path, PathBuf::from("axisram.bin"));
+        assert!("axisram.bin".parse::<RegionArg>().is_err());
+        assert!("0xzz=axisram.bin".parse::<RegionArg>().is_err());
+    }
+
+    #[test]
+    fn reads() {
+        let bytes: Vec<u8> = (0..16).collect();
+        let memory = Memory::load(&[
+            dump("reads-a", 0x1000, &bytes),
+            dump("reads-b", 0x2000, &bytes),
+        ])
+        .unwrap();
+        assert_eq!(memory.read(0x1004, 4).unwrap(), [4, 5, 6, 7]);
+        assert_eq!(memory.read(0x2000, 16).unwrap(), &bytes[..]);
+
+        // Reads may not run past the end of a dump, or between dumps.
+        let err = memory.read(0x100c, 8).unwrap_err();
+        assert_eq!(err.to_string(), "0x100c..0x1014 is not in the dump");
+        assert!(memory.read(0x1ffc, 8).is_err());
+
+        let err = memory.read(u64::MAX - 2, 4).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "0x4 bytes at 0xfffffffffffffffd run off the end of memory"
+        );
+    }
+
+    #[test]
+    fn overlapping_dumps() {
+        let bytes = [0; 16];
+        let err = Memory::load(&[
+            dump("overlap-a", 0x1000, &bytes),
+            dump("overlap-b", 0x1008, &bytes),
+        ])
+        .unwrap_err();
+        assert!(err.to_string().contains("overlaps another at 0x1000"));
+    }
+
+    #[test]
+    fn wrapping_dump() {
+        let err = Memory::load(&[dump("wrapping", u64::MAX - 2, &[0; 16])])
+            .unwrap_err();
+        assert!(err.to_string().ends_with("runs off the end of memory"));
+    }
+}
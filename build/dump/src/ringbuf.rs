@@ -0,0 +1,299 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{anyhow, Result};
+
+use crate::dwarf::{DebugInfo, Value};
+use crate::memory::Memory;
+
+/// A `ringbuf!` (or `timestamped_ringbuf!`) static found in an ELF file.
+#[derive(Debug)]
+pub struct RingbufSym {
+    /// Demangled path, without the hash.
+    pub name: String,
+    pub linkage_name: String,
+    pub address: u64,
+    pub size: usize,
+}
+
+/// Finds every ring buffer in `elf`. By convention, and as Humility
+/// requires, their names all end in `RINGBUF`.
+pub fn find(elf: &goblin::elf::Elf) -> Vec<RingbufSym> {
+    let mut found = vec![];
+    for sym in elf.syms.iter() {
+        if sym.st_type() != goblin::elf::sym::STT_OBJECT {
+            continue;
+        }
+        let linkage_name = match elf.strtab.get_at(sym.st_name) {
+            Some(n) => n,
+            None => continue,
+        };
+        let name = format!("{:#}", rustc_demangle::demangle(linkage_name));
+        if !name.ends_with("RINGBUF") {
+            continue;
+        }
+        found.push(RingbufSym {
+            name,
+            linkage_name: linkage_name.to_string(),
+            address: sym.st_value,
+            size: sym.st_size as usize,
+        });
+    }
+    found.sort_by_key(|r| r.address);
+    found
+}
+
+/// A single decoded entry.
+#[derive(Debug)]
+pub struct Entry {
+    pub index: usize,
+    pub line: u64,
+    pub generation: u64,
+    pub count: u64,
+    /// First and last occurrence, for timestamped ring buffers.
+    pub timestamps: Option<(u64, u64)>,
+    pub payload: Value,
+}
+
+/// Decodes the ring buffer `sym` from `memory`, returning its entries from
+/// oldest to newest.
+pub fn decode(
+    info: &DebugInfo,
+    memory: &Memory,
+    sym: &RingbufSym,
+) -> Result<Vec<Entry>> {
+    let ty = info
+        .variable(&sym.linkage_name)
+        .ok_or_else(|| anyhow!("no debug info for {}", sym.name))?;
+    let size = info.size_of(ty).unwrap_or(sym.size);
+    let value = info.decode(ty, memory.read(sym.address, size)?)?;
+
+    // The ring buffer itself is wrapped in a StaticCell; dig it out.
+    let ringbuf = value
+        .find(&|v| v.field("last").is_some() && v.field("buffer").is_some())
+        .ok_or_else(|| {
+            anyhow!("{} doesn't look like a ring buffer", sym.name)
+        })?;
+
+    let last = match ringbuf.field("last").and_then(Value::as_option) {
+        Some(Some(last)) => last
+            .as_u64()
+            .ok_or_else(|| anyhow!("bad last index in {}", sym.name))?
+            as usize,
+        // Nothing has ever been recorded.
+        Some(None) => return Ok(vec![]),
+        None => return Err(anyhow!("bad last index in {}", sym.name)),
+    };
+    let buffer = match ringbuf.field("buffer") {
+        Some(Value::Array(buffer)) => buffer,
+        _ => return Err(anyhow!("bad buffer in {}", sym.name)),
+    };
+
+    let field = |ent: &Value, name: &str| -> Result<u64> {
+        ent.field(name)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| anyhow!("entry in {} is missing {}", sym.name, name))
+    };
+
+    let mut entries = vec![];
+    for i in 0..buffer.len() {
+        let index = (last + 1 + i) % buffer.len();
+        let ent = &buffer[index];
+        let generation = field(ent, "generation")?;
+        if generation == 0 {
+            // Never written.
+            continue;
+        }
+        let timestamps = match (ent.field("first"), ent.field("last")) {
+            (Some(first), Some(last)) => first.as_u64().zip(last.as_u64()),
+            _ => None,
+        };
+        entries.push(Entry {
+            index,
+            line: field(ent, "line")?,
+            generation,
+            count: field(ent, "count")?,
+            timestamps,
+            payload: ent.field("payload").cloned().ok_or_else(|| {
+                anyhow!("entry in {} has no payload", sym.name)
+            })?,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dwarf::{Member, Struct, Type, TypeId, Variant, VariantPart};
+    use crate::memory::RegionArg;
+
+    const ADDRESS: u64 = 0x2000_0000;
+
+    fn member(name: &str, offset: usize, ty: TypeId) -> Member {
+        Member {
+            name: name.to_string(),
+            offset,
+            ty,
+        }
+    }
+
+    fn structure(name: &str, size: usize, members: Vec<Member>) -> Type {
+        Type::Struct(Struct {
+            name: name.to_string(),
+            size,
+            members,
+            variant_part: None,
+        })
+    }
+
+    /// Debug info for a `StaticCell<Ringbuf<u32, 2>>`, laid out as
+    /// `last: Option<u32>` (a 4-byte discriminant and the index) followed
+    /// by two 16-byte entries of line, generation, count and payload.
+    fn info() -> DebugInfo {
+        let u32 = Type::Base {
+            name: "u32".to_string(),
+            size: 4,
+            encoding: gimli::DW_ATE_unsigned,
+        };
+        let option = Type::Struct(Struct {
+            name: "Option<u32>".to_string(),
+            size: 8,
+            members: vec![],
+            variant_part: Some(VariantPart {
+                discr: Some(member("<discr>", 0, 1)),
+                variants: vec![
+                    Variant {
+                        value: Some(0),
+                        member: member("None", 0, 3),
+                    },
+                    Variant {
+                        value: Some(1),
+                        member: member("Some", 0, 4),
+                    },
+                ],
+            }),
+        });
+        let entry = vec![
+            member("line", 0, 1),
+            member("generation", 4, 1),
+            member("count", 8, 1),
+            member("payload", 12, 1),
+        ];
+        DebugInfo::from_parts(
+            vec![
+                (1, u32),
+                (2, option),
+                (3, structure("None", 8, vec![])),
+                (4, structure("Some", 8, vec![member("__0", 4, 1)])),
+                (5, structure("RingbufEntry<u32>", 16, entry)),
+                (
+                    6,
+                    Type::Array {
+                        element: 5,
+                        count: 2,
+                    },
+                ),
+                (
+                    7,
+                    structure(
+                        "Ringbuf<u32, 2>",
+                        40,
+                        vec![member("last", 0, 2), member("buffer", 8, 6)],
+                    ),
+                ),
+                (8, structure("StaticCell", 40, vec![member("cell", 0, 7)])),
+            ],
+            &[("TEST_RINGBUF", 8)],
+        )
+    }
+
+    fn sym() -> RingbufSym {
+        RingbufSym {
+            name: "test::TEST_RINGBUF".to_string(),
+            linkage_name: "TEST_RINGBUF".to_string(),
+            address: ADDRESS,
+            size: 40,
+        }
+    }
+
+    /// Makes a dump of the ring buffer, from `last` and the entries given
+    /// as (line, generation, count, payload).
+    fn dump(name: &str, last: Option<u32>, entries: [[u32; 4]; 2]) -> Memory {
+        let mut words = match last {
+            Some(last) => vec![1, last],
+            None => vec![0, 0],
+        };
+        words.extend(entries.iter().flatten());
+        let bytes: Vec<u8> =
+            words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        load(name, &bytes)
+    }
+
+    fn load(name: &str, bytes: &[u8]) -> Memory {
+        let path = std::env::temp_dir().join(format!(
+            "ringbuf-{}-{}.bin",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, bytes).unwrap();
+        Memory::load(&[RegionArg {
+            address: ADDRESS,
+            path,
+        }])
+        .unwrap()
+    }
+
+    fn decoded(memory: &Memory) -> Vec<(usize, u64, u64, u64, String)> {
+        decode(&info(), memory, &sym())
+            .unwrap()
+            .into_iter()
+            .map(|e| {
+                let payload = e.payload.to_string();
+                (e.index, e.line, e.generation, e.count, payload)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty() {
+        let memory = dump("empty", None, [[0; 4]; 2]);
+        assert!(decoded(&memory).is_empty());
+    }
+
+    #[test]
+    fn oldest_first() {
+        let memory = dump("partial", Some(0), [[10, 1, 2, 7], [0; 4]]);
+        assert_eq!(decoded(&memory), [(0, 10, 1, 2, "7".to_string())]);
+
+        let memory = dump("full", Some(0), [[10, 2, 1, 7], [20, 1, 3, 8]]);
+        assert_eq!(
+            decoded(&memory),
+            [
+                (1, 20, 1, 3, "8".to_string()),
+                (0, 10, 2, 1, "7".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let memory = load("truncated", &[0; 30]);
+        let err = decode(&info(), &memory, &sym()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "0x20000000..0x20000028 is not in the dump"
+        );
+    }
+
+    #[test]
+    fn corrupt() {
+        // A discriminant that's neither `None` nor `Some`.
+        let mut bytes = vec![5, 0, 0, 0];
+        bytes.resize(40, 0);
+        let memory = load("corrupt", &bytes);
+        let err = decode(&info(), &memory, &sym()).unwrap_err();
+        assert_eq!(err.to_string(), "bad last index in test::TEST_RINGBUF");
+    }
+}
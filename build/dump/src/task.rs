@@ -0,0 +1,88 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::dwarf::{DebugInfo, Value};
+use crate::memory::Memory;
+
+/// The state of one task, as recorded in the kernel's task table.
+#[derive(Debug)]
+pub struct TaskInfo {
+    pub generation: Option<u64>,
+    pub priority: Option<Value>,
+    pub state: Value,
+}
+
+fn symbol_address(elf: &goblin::elf::Elf, name: &str) -> Result<u64> {
+    elf.syms
+        .iter()
+        .find(|s| elf.strtab.get_at(s.st_name) == Some(name))
+        .map(|s| s.st_value)
+        .ok_or_else(|| anyhow!("kernel has no {} symbol", name))
+}
+
+fn read_variable(
+    elf: &goblin::elf::Elf,
+    info: &DebugInfo,
+    memory: &Memory,
+    name: &str,
+) -> Result<Value> {
+    let addr = symbol_address(elf, name)?;
+    let ty = info
+        .variable(name)
+        .ok_or_else(|| anyhow!("no debug info for {}", name))?;
+    let size = info
+        .size_of(ty)
+        .ok_or_else(|| anyhow!("unknown size for {}", name))?;
+    info.decode(ty, memory.read(addr, size)?)
+}
+
+/// Reads the kernel's task table, which the kernel records in
+/// `TASK_TABLE_BASE` and `TASK_TABLE_SIZE` at startup. Tasks appear in the
+/// same order as in `app.toml`.
+pub fn read_table(
+    kernel: &goblin::elf::Elf,
+    info: &DebugInfo,
+    memory: &Memory,
+) -> Result<Vec<TaskInfo>> {
+    let base = read_variable(kernel, info, memory, "TASK_TABLE_BASE")?;
+    let base = match base.as_option() {
+        Some(Some(ptr)) => ptr
+            .find(&|v| matches!(v, Value::Pointer(_)))
+            .and_then(Value::as_u64)
+            .ok_or_else(|| anyhow!("can't find pointer in TASK_TABLE_BASE"))?,
+        Some(None) => bail!("kernel was not started at the time of the dump"),
+        None => bail!("TASK_TABLE_BASE is not an Option"),
+    };
+    let count = read_variable(kernel, info, memory, "TASK_TABLE_SIZE")?
+        .as_u64()
+        .ok_or_else(|| anyhow!("TASK_TABLE_SIZE is not an integer"))?;
+
+    let task_ty = info
+        .variable("TASK_TABLE_BASE")
+        .and_then(|ty| info.pointee(ty))
+        .ok_or_else(|| anyhow!("can't find the type of a task"))?;
+    let size = info
+        .size_of(task_ty)
+        .ok_or_else(|| anyhow!("unknown size for Task"))?;
+
+    let mut tasks = vec![];
+    for i in 0..count {
+        let addr = i
+            .checked_mul(size as u64)
+            .and_then(|offset| base.checked_add(offset))
+            .ok_or_else(|| anyhow!("task {} runs off the end of memory", i))?;
+        let task = info.decode(task_ty, memory.read(addr, size)?)?;
+        tasks.push(TaskInfo {
+            generation: task.field("generation").and_then(Value::as_u64),
+            priority: task.field("priority").cloned(),
+            state: task
+                .field("state")
+                .cloned()
+                .ok_or_else(|| anyhow!("task {} has no state", i))?,
+        });
+    }
+    Ok(tasks)
+}
@@ -0,0 +1,946 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Code generation for the GPIO pins that tasks own, from `config.gpio`.
+//!
+//! Each table in `config.gpio` is named after the task that owns the pins in
+//! it, and names each pin, e.g.:
+//!
+//! ```toml
+//! [config.gpio.user_leds.led0]
+//! description = "green LED"
+//! port = "B"
+//! pin = 0
+//! mode = "output"
+//! initial = "low"
+//! ```
+//!
+//! The owning task gets a `gpio_config` module with a `PinConfig` constant per
+//! pin (named after the pin, in upper case), a `PINS` slice of them all, and a
+//! `configure` function that configures them all.  Pins are checked across the
+//! whole application: a pin may be owned by only one task, and may not also be
+//! used by `config.i2c` or `config.spi`.
+//!
+
+use anyhow::{bail, Result};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::fs::File;
+use std::path::Path;
+
+//
+// Our definition of the `Config` type.  We share this type with all other
+// build-specific types; we must not set `deny_unknown_fields` here.  We only
+// look at as much of `config.i2c` and `config.spi` as names pins.
+//
+#[derive(Clone, Debug, Deserialize)]
+struct Config {
+    #[serde(default)]
+    gpio: IndexMap<String, IndexMap<String, GpioPin>>,
+    i2c: Option<I2cConfig>,
+    #[serde(default)]
+    spi: IndexMap<String, SpiController>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GpioPin {
+    /// what the pin is for
+    description: Option<String>,
+
+    /// port: `A` through `K` on STM32H7; `0` or `1` on LPC55
+    port: String,
+
+    /// pin within the port
+    pin: u8,
+
+    mode: Mode,
+
+    #[serde(default)]
+    pull: Pull,
+
+    /// slew rate; defaults to `high` on STM32H7 and `standard` on LPC55
+    speed: Option<Speed>,
+
+    #[serde(default)]
+    output_type: OutputType,
+
+    /// alternate function, for pins in `alternate` mode
+    af: Option<u8>,
+
+    /// level driven before the pin becomes an output, for pins in `output`
+    /// mode; if absent, the output is left at whatever level it already has
+    initial: Option<Level>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    Input,
+    Output,
+    Alternate,
+    Analog,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Input
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Pull {
+    None,
+    Up,
+    Down,
+}
+
+impl Default for Pull {
+    fn default() -> Self {
+        Pull::None
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Speed {
+    // STM32H7
+    Low,
+    Medium,
+    High,
+    VeryHigh,
+
+    // LPC55
+    Standard,
+    Fast,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum OutputType {
+    PushPull,
+    OpenDrain,
+}
+
+impl Default for OutputType {
+    fn default() -> Self {
+        OutputType::PushPull
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Level {
+    Low,
+    High,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct I2cConfig {
+    controllers: Vec<I2cController>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct I2cController {
+    controller: u8,
+    ports: IndexMap<String, I2cPort>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct I2cPort {
+    pins: Vec<I2cPinSet>,
+    #[serde(default)]
+    muxes: Vec<I2cMux>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct I2cPinSet {
+    gpio_port: Option<String>,
+    pins: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct I2cMux {
+    enable: Option<I2cPinSet>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SpiController {
+    mux_options: IndexMap<String, SpiMuxOption>,
+    devices: IndexMap<String, SpiDevice>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SpiMuxOption {
+    outputs: Vec<SpiPinSet>,
+    input: SpiPin,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SpiPinSet {
+    port: String,
+    pins: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SpiPin {
+    port: String,
+    pin: u8,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SpiDevice {
+    cs: SpiPin,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Artifact {
+    /// part of a complete distribution of an application
+    Dist,
+
+    /// standalone build of a single task
+    Standalone,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Family {
+    /// clients of `drv_stm32h7_gpio_api`
+    Stm32h7,
+
+    /// clients of `drv_lpc55_gpio_api`
+    Lpc55,
+}
+
+///
+/// The pins that a task refers to by name, beyond the `PINS` slice.  A
+/// required pin becomes a `PinConfig`, and is an error for the owning task
+/// not to have; an optional pin becomes an `Option<PinConfig>`, for pins
+/// that only some boards have.  (In a standalone build, required pins are
+/// placeholders and optional pins are absent.)
+///
+#[derive(Clone, Default)]
+pub struct Pins<'a> {
+    pub required: &'a [&'a str],
+    pub optional: &'a [&'a str],
+}
+
+/// A GPIO pin, as its port and number.
+type Pin = (String, u8);
+
+fn pin_name(family: Family, pin: &Pin) -> String {
+    match family {
+        Family::Stm32h7 => format!("P{}{}", pin.0, pin.1),
+        Family::Lpc55 => format!("PIO{}_{}", pin.0, pin.1),
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+///
+/// Checks one pin for settings that `family` doesn't have, or that don't
+/// make sense together.
+///
+fn check_pin(
+    errors: &mut Vec<String>,
+    family: Family,
+    what: &str,
+    pin: &GpioPin,
+) {
+    let (ports, afs, speeds): (&[&str], _, &[Speed]) = match family {
+        Family::Stm32h7 => (
+            &["A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K"],
+            0..=15,
+            &[Speed::Low, Speed::Medium, Speed::High, Speed::VeryHigh],
+        ),
+        //
+        // AF0 is the GPIO function on LPC55, so a pin in alternate mode
+        // must have some other one.
+        //
+        Family::Lpc55 => (&["0", "1"], 1..=9, &[Speed::Standard, Speed::Fast]),
+    };
+
+    let max = match family {
+        Family::Stm32h7 => 15,
+        Family::Lpc55 => 31,
+    };
+
+    if !ports.contains(&pin.port.as_str()) || pin.pin > max {
+        errors.push(format!(
+            "{}: port {} pin {} is not a GPIO pin",
+            what, pin.port, pin.pin
+        ));
+    }
+
+    match (pin.mode, pin.af) {
+        (Mode::Alternate, None) => {
+            errors.push(format!("{}: alternate mode requires an af", what));
+        }
+        (Mode::Alternate, Some(af)) if !afs.contains(&af) => {
+            errors.push(format!(
+                "{}: {} is not an alternate function; expected {} to {}",
+                what,
+                af,
+                afs.start(),
+                afs.end()
+            ));
+        }
+        (Mode::Alternate, _) => {}
+        (_, Some(_)) => {
+            errors.push(format!("{}: af is only for alternate mode", what));
+        }
+        (_, None) => {}
+    }
+
+    if pin.initial.is_some() && pin.mode != Mode::Output {
+        errors.push(format!("{}: initial level is only for output mode", what));
+    }
+
+    if let Some(speed) = pin.speed {
+        if !speeds.contains(&speed) {
+            errors.push(format!(
+                "{}: speed {:?} is not one of {:?}",
+                what, speed, speeds
+            ));
+        }
+    }
+}
+
+///
+/// Checks `config.gpio` for pins that can't work: pins that don't exist,
+/// settings the family lacks, owners that aren't tasks, and pins claimed
+/// twice -- whether by two tasks (or two names in one task), or by a task
+/// and the pins of `config.i2c` or `config.spi`.  All problems are reported
+/// at once.
+///
+fn check(
+    family: Family,
+    config: &Config,
+    tasks: Option<&[String]>,
+) -> Result<()> {
+    let mut errors = vec![];
+
+    // what each pin is used for
+    let mut used: HashMap<Pin, String> = HashMap::new();
+
+    if let Some(i2c) = &config.i2c {
+        for c in &i2c.controllers {
+            for (port, p) in &c.ports {
+                let what =
+                    format!("i2c controller {} port {}", c.controller, port);
+                let enables = p.muxes.iter().filter_map(|m| m.enable.as_ref());
+
+                for set in p.pins.iter().chain(enables) {
+                    let gpio_port = set.gpio_port.as_ref().unwrap_or(port);
+
+                    for &pin in &set.pins {
+                        used.insert((gpio_port.clone(), pin), what.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    for (name, c) in &config.spi {
+        for (m, opt) in &c.mux_options {
+            let what = format!("{} mux option {}", name, m);
+
+            for set in &opt.outputs {
+                for &pin in &set.pins {
+                    used.insert((set.port.clone(), pin), what.clone());
+                }
+            }
+
+            used.insert((opt.input.port.clone(), opt.input.pin), what);
+        }
+
+        for (d, dev) in &c.devices {
+            used.insert(
+                (dev.cs.port.clone(), dev.cs.pin),
+                format!("{} device {} CS", name, d),
+            );
+        }
+    }
+
+    for (task, pins) in &config.gpio {
+        if let Some(tasks) = tasks {
+            if !tasks.contains(task) {
+                errors
+                    .push(format!("config.gpio.{} does not name a task", task));
+            }
+        }
+
+        for (name, pin) in pins {
+            let what = format!("{} pin {}", task, name);
+
+            if !valid_name(name) || name == "pins" {
+                errors.push(format!(
+                    "{}: name must be lowercase letters, digits and \
+                    underscores, and not \"pins\"",
+                    what
+                ));
+            }
+
+            check_pin(&mut errors, family, &what, pin);
+
+            let p = (pin.port.clone(), pin.pin);
+
+            if let Some(other) = used.get(&p) {
+                errors.push(format!(
+                    "{} is used by both {} and {}",
+                    pin_name(family, &p),
+                    other,
+                    what
+                ));
+            } else {
+                used.insert(p, what);
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "config.gpio has {} problem(s):\n    {}",
+            errors.len(),
+            errors.join("\n    ")
+        );
+    }
+
+    Ok(())
+}
+
+struct ConfigGenerator {
+    /// output that we're building
+    output: String,
+
+    /// family whose API we're generating for
+    family: Family,
+
+    /// pins owned by the task being built, by name
+    pins: IndexMap<String, GpioPin>,
+
+    /// pins that the task refers to by name
+    uses: Pins<'static>,
+}
+
+impl ConfigGenerator {
+    fn new(
+        family: Family,
+        artifact: Artifact,
+        uses: Pins<'static>,
+    ) -> Result<Self> {
+        let pins = match artifact {
+            //
+            // For the standalone build, we make up a pin for every pin that
+            // is required.
+            //
+            Artifact::Standalone => uses
+                .required
+                .iter()
+                .map(|name| {
+                    let port = match family {
+                        Family::Stm32h7 => "A",
+                        Family::Lpc55 => "0",
+                    };
+
+                    let pin = GpioPin {
+                        port: port.to_string(),
+                        ..Default::default()
+                    };
+
+                    (name.to_string(), pin)
+                })
+                .collect(),
+
+            Artifact::Dist => {
+                let config = match build_util::config::<Config>() {
+                    Ok(config) => config,
+                    Err(err) => {
+                        panic!("malformed config.gpio: {:?}", err);
+                    }
+                };
+
+                println!("cargo:rerun-if-env-changed=HUBRIS_TASKS");
+                let tasks = env::var("HUBRIS_TASKS").ok().map(|tasks| {
+                    tasks.split(',').map(String::from).collect::<Vec<_>>()
+                });
+
+                check(family, &config, tasks.as_deref())?;
+
+                let task = build_util::task_name()?;
+                let pins = config.gpio.get(&task).cloned().unwrap_or_default();
+
+                for name in uses.required {
+                    if !pins.contains_key(*name) {
+                        bail!(
+                            "config.gpio.{} has no pin {}, which the task \
+                            requires",
+                            task,
+                            name
+                        );
+                    }
+                }
+
+                pins
+            }
+        };
+
+        Ok(Self {
+            output: String::new(),
+            family,
+            pins,
+            uses,
+        })
+    }
+
+    pub fn generate_header(&mut self) -> Result<()> {
+        let api = match self.family {
+            Family::Stm32h7 => "drv_stm32h7_gpio_api",
+            Family::Lpc55 => "drv_lpc55_gpio_api",
+        };
+
+        writeln!(
+            &mut self.output,
+            r##"mod gpio_config {{
+    #[allow(unused_imports)]
+    use {}::*;"##,
+            api
+        )?;
+        Ok(())
+    }
+
+    pub fn generate_footer(&mut self) -> Result<()> {
+        writeln!(&mut self.output, "}}")?;
+        Ok(())
+    }
+
+    ///
+    /// Generates a `PinConfig` constant per pin, and an `Option<PinConfig>`
+    /// per optional pin.
+    ///
+    pub fn generate_pins(&mut self) -> Result<()> {
+        for (name, pin) in &self.pins {
+            let p = (pin.port.clone(), pin.pin);
+            let description = match &pin.description {
+                Some(description) => {
+                    format!("{}: {}", pin_name(self.family, &p), description)
+                }
+                None => pin_name(self.family, &p),
+            };
+
+            let config = self.pin_config(pin);
+
+            if self.uses.optional.contains(&name.as_str()) {
+                writeln!(
+                    &mut self.output,
+                    r##"
+    /// {description}
+    #[allow(dead_code)]
+    pub const {name}: Option<PinConfig> = Some({config});"##,
+                    description = description,
+                    name = name.to_uppercase(),
+                    config = config,
+                )?;
+            } else {
+                writeln!(
+                    &mut self.output,
+                    r##"
+    /// {description}
+    #[allow(dead_code)]
+    pub const {name}: PinConfig = {config};"##,
+                    description = description,
+                    name = name.to_uppercase(),
+                    config = config,
+                )?;
+            }
+        }
+
+        for name in self.uses.optional {
+            if !self.pins.contains_key(*name) {
+                writeln!(
+                    &mut self.output,
+                    r##"
+    /// (not present on this board)
+    #[allow(dead_code)]
+    pub const {}: Option<PinConfig> = None;"##,
+                    name.to_uppercase(),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Generates `PINS` and the `configure` function that applies them.
+    ///
+    pub fn generate_configure(&mut self) -> Result<()> {
+        let pins = self
+            .pins
+            .iter()
+            .map(|(name, pin)| {
+                if self.uses.optional.contains(&name.as_str()) {
+                    format!("\n        {},", self.pin_config(pin))
+                } else {
+                    format!("\n        {},", name.to_uppercase())
+                }
+            })
+            .collect::<String>();
+
+        writeln!(
+            &mut self.output,
+            r##"
+    /// All of the task's pins, in the order they are declared
+    #[allow(dead_code)]
+    pub const PINS: &[PinConfig] = &[{}
+    ];
+
+    /// Configures all of the task's pins, in the order they are declared
+    #[allow(dead_code)]
+    pub fn configure(gpio: &Gpio) -> Result<(), GpioError> {{
+        for pin in PINS {{
+            gpio.configure_pin(pin)?;
+        }}
+
+        Ok(())
+    }}"##,
+            pins
+        )?;
+
+        Ok(())
+    }
+
+    ///
+    /// Returns a `PinConfig` expression (as defined by the family's API) for
+    /// `pin`.
+    ///
+    fn pin_config(&self, pin: &GpioPin) -> String {
+        match self.family {
+            Family::Stm32h7 => {
+                let mode = match pin.mode {
+                    Mode::Input => "Input",
+                    Mode::Output => "Output",
+                    Mode::Alternate => "Alternate",
+                    Mode::Analog => "Analog",
+                };
+                let output_type = match pin.output_type {
+                    OutputType::PushPull => "PushPull",
+                    OutputType::OpenDrain => "OpenDrain",
+                };
+                let speed = match pin.speed {
+                    Some(Speed::Low) => "Low",
+                    Some(Speed::Medium) => "Medium",
+                    Some(Speed::VeryHigh) => "VeryHigh",
+                    _ => "High",
+                };
+                let pull = match pin.pull {
+                    Pull::None => "None",
+                    Pull::Up => "Up",
+                    Pull::Down => "Down",
+                };
+                let initial = match pin.initial {
+                    Some(Level::Low) => "Some(false)",
+                    Some(Level::High) => "Some(true)",
+                    None => "None",
+                };
+
+                format!(
+                    r##"PinConfig {{
+        pin: Port::{port}.pin({pin}),
+        mode: Mode::{mode},
+        output_type: OutputType::{output_type},
+        speed: Speed::{speed},
+        pull: Pull::{pull},
+        af: Alternate::AF{af},
+        initial: {initial},
+    }}"##,
+                    port = pin.port,
+                    pin = pin.pin,
+                    mode = mode,
+                    output_type = output_type,
+                    speed = speed,
+                    pull = pull,
+                    af = pin.af.unwrap_or(0),
+                    initial = initial,
+                )
+            }
+
+            Family::Lpc55 => {
+                let direction = match pin.mode {
+                    Mode::Input => "Some(Direction::Input)",
+                    Mode::Output => "Some(Direction::Output)",
+                    Mode::Alternate | Mode::Analog => "None",
+                };
+                let digimode = match pin.mode {
+                    Mode::Analog => "Analog",
+                    _ => "Digital",
+                };
+                let slew = match pin.speed {
+                    Some(Speed::Fast) => "Fast",
+                    _ => "Standard",
+                };
+                let mode = match pin.pull {
+                    Pull::None => "NoPull",
+                    Pull::Up => "PullUp",
+                    Pull::Down => "PullDown",
+                };
+                let od = match pin.output_type {
+                    OutputType::PushPull => "Normal",
+                    OutputType::OpenDrain => "Opendrain",
+                };
+                let initial = match pin.initial {
+                    Some(Level::Low) => "Some(Value::Zero)",
+                    Some(Level::High) => "Some(Value::One)",
+                    None => "None",
+                };
+
+                format!(
+                    r##"PinConfig {{
+        pin: Pin::PIO{port}_{pin},
+        alt: AltFn::Alt{af},
+        mode: Mode::{mode},
+        slew: Slew::{slew},
+        invert: Invert::Disable,
+        digimode: Digimode::{digimode},
+        od: Opendrain::{od},
+        direction: {direction},
+        initial: {initial},
+    }}"##,
+                    port = pin.port,
+                    pin = pin.pin,
+                    af = pin.af.unwrap_or(0),
+                    mode = mode,
+                    slew = slew,
+                    digimode = digimode,
+                    od = od,
+                    direction = direction,
+                    initial = initial,
+                )
+            }
+        }
+    }
+}
+
+pub fn codegen(
+    family: Family,
+    artifact: Artifact,
+    uses: Pins<'static>,
+) -> Result<()> {
+    use std::io::Write;
+
+    let out_dir = env::var("OUT_DIR")?;
+    let dest_path = Path::new(&out_dir).join("gpio_config.rs");
+    let mut file = File::create(&dest_path)?;
+
+    let mut g = ConfigGenerator::new(family, artifact, uses)?;
+
+    g.generate_header()?;
+    g.generate_pins()?;
+    g.generate_configure()?;
+    g.generate_footer()?;
+
+    file.write_all(g.output.as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(family: Family, text: &str, tasks: &[&str]) -> String {
+        let value: toml::Value = toml::from_str(text).unwrap();
+        let config = value["config"].clone().try_into().unwrap();
+        let tasks = tasks.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+
+        match check(family, &config, Some(&tasks)) {
+            Ok(_) => panic!("expected problems"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    ///
+    /// Every app's `config.gpio` must be valid, and must agree with its
+    /// `config.i2c` and `config.spi`.  (Apps only inherit configuration from
+    /// bases without any `config`, but may inherit tasks.)
+    ///
+    #[test]
+    fn apps() {
+        let app = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../app");
+        let mut checked = 0;
+
+        for entry in app.read_dir().unwrap() {
+            let dir = entry.unwrap().path();
+            if !dir.is_dir() {
+                continue;
+            }
+
+            for file in dir.read_dir().unwrap() {
+                let path = file.unwrap().path();
+                if path.extension().map_or(true, |e| e != "toml") {
+                    continue;
+                }
+
+                let read = |path: &Path| -> toml::Value {
+                    let text = std::fs::read_to_string(path).unwrap();
+                    toml::from_str(&text).unwrap()
+                };
+
+                let value = read(&path);
+
+                let config = match value.get("config") {
+                    Some(config) if config.get("gpio").is_some() => {
+                        config.clone()
+                    }
+                    _ => continue,
+                };
+
+                let mut tasks: Vec<String> = vec![];
+                let mut target = value.get("target").cloned();
+
+                if let Some(base) = value.get("inherit") {
+                    let base = read(&dir.join(base.as_str().unwrap()));
+                    tasks.extend(
+                        base["tasks"].as_table().unwrap().keys().cloned(),
+                    );
+                    target = target.or_else(|| base.get("target").cloned());
+                }
+
+                tasks
+                    .extend(value["tasks"].as_table().unwrap().keys().cloned());
+
+                let family = match target.unwrap().as_str().unwrap() {
+                    t if t.starts_with("thumbv8m") => Family::Lpc55,
+                    _ => Family::Stm32h7,
+                };
+
+                if let Err(err) = config
+                    .try_into()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|config| check(family, &config, Some(&tasks)))
+                {
+                    panic!("{}: {}", path.display(), err);
+                }
+                checked += 1;
+            }
+        }
+
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn conflicts() {
+        let text = r#"
+            [[config.i2c.controllers]]
+            controller = 2
+
+            [[config.i2c.controllers.ports.F.pins]]
+            pins = [ 0, 1 ]
+            af = 4
+
+            [config.spi.spi4.mux_options.port_e]
+            outputs = [ { port = "E", pins = [ 12, 13 ], af = 5 } ]
+            input = { port = "E", pin = 14, af = 5 }
+
+            [config.spi.spi4.devices.ice40]
+            description = "iCE40"
+            mux = "port_e"
+            cs = { port = "E", pin = 11 }
+
+            [config.gpio.seq.creset]
+            port = "E"
+            pin = 11
+            mode = "output"
+
+            [config.gpio.seq.cdone]
+            port = "G"
+            pin = 2
+            mode = "input"
+            initial = "high"
+
+            [config.gpio.seq.mux]
+            port = "L"
+            pin = 3
+            mode = "alternate"
+            af = 16
+
+            [config.gpio.user_leds.led0]
+            port = "G"
+            pin = 2
+            mode = "output"
+            initial = "low"
+
+            [config.gpio.user_leds.led1]
+            port = "F"
+            pin = 1
+            mode = "output"
+
+            [config.gpio.nobody.pins]
+            port = "A"
+            pin = 0
+            mode = "analog"
+            speed = "fast"
+        "#;
+
+        let problems = problems(Family::Stm32h7, text, &["user_leds", "seq"]);
+
+        for problem in &[
+            "PF1 is used by both i2c controller 2 port F and user_leds pin \
+            led1",
+            "PE11 is used by both spi4 device ice40 CS and seq pin creset",
+            "PG2 is used by both seq pin cdone and user_leds pin led0",
+            "seq pin cdone: initial level is only for output mode",
+            "seq pin mux: port L pin 3 is not a GPIO pin",
+            "seq pin mux: 16 is not an alternate function",
+            "config.gpio.nobody does not name a task",
+            "nobody pin pins: name must be",
+            "nobody pin pins: speed Fast",
+        ] {
+            assert!(problems.contains(problem), "{}", problems);
+        }
+
+        assert!(
+            problems.contains("config.gpio has 9 problem(s)"),
+            "{}",
+            problems
+        );
+    }
+
+    #[test]
+    fn lpc55() {
+        let text = r#"
+            [config.gpio.user_leds.led0]
+            port = "1"
+            pin = 6
+            mode = "output"
+            speed = "high"
+
+            [config.gpio.user_leds.led1]
+            port = "2"
+            pin = 4
+            mode = "alternate"
+            af = 0
+        "#;
+
+        let problems = problems(Family::Lpc55, text, &["user_leds"]);
+
+        for problem in &[
+            "user_leds pin led0: speed High is not one of [Standard, Fast]",
+            "user_leds pin led1: port 2 pin 4 is not a GPIO pin",
+            "user_leds pin led1: 0 is not an alternate function; expected 1 \
+            to 9",
+        ] {
+            assert!(problems.contains(problem), "{}", problems);
+        }
+    }
+}
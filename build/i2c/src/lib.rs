@@ -13,6 +13,10 @@ use std::fmt::Write;
 use std::fs::File;
 use std::path::Path;
 
+mod topology;
+
+use topology::Topology;
+
 //
 // Our definition of the `Config` type.  We share this type with all other
 // build-specific types; we must not set `deny_unknown_fields` here.
@@ -45,8 +49,8 @@ struct I2cController {
 // (i.e., [`controller`]/[`port`] vs. [`bus`]) and device class parameters
 // (i.e., [`pmbus`]) into optional fields in [`I2cDevice`].  This makes it
 // easier to accidentally create invalid entries (e.g., a device that has both
-// a controller *and* a named bus), so the validation code should go to
-// additional lengths to assure that these mistakes are caught in compilation.
+// a controller *and* a named bus), so [`Topology`] goes to additional lengths
+// to assure that these mistakes are caught in compilation.
 //
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -161,18 +165,12 @@ struct ConfigGenerator {
     /// all devices
     devices: Vec<I2cDevice>,
 
-    /// hash bus name to controller/port index pair
-    buses: HashMap<String, (u8, usize)>,
-
-    /// hash controller/port pair to port index
-    ports: IndexMap<(u8, String), usize>,
-
-    /// hash of controllers to single port indices
-    singletons: HashMap<u8, usize>,
+    /// where all controllers, ports and devices are
+    topology: Topology,
 }
 
 impl ConfigGenerator {
-    fn new(disposition: Disposition, artifact: Artifact) -> Self {
+    fn new(disposition: Disposition, artifact: Artifact) -> Result<Self> {
         let i2c = match artifact {
             Artifact::Standalone => I2cConfig {
                 controllers: vec![],
@@ -186,80 +184,35 @@ impl ConfigGenerator {
             },
         };
 
-        let mut controllers = vec![];
-        let mut buses = HashMap::new();
-        let mut ports = IndexMap::new();
-        let mut singletons = HashMap::new();
-
-        for c in i2c.controllers {
-            //
-            // We always insert our buses (even for controllers that don't
-            // match our dispostion) to assure that devices can always find
-            // their bus.
-            //
-            for (index, (p, port)) in c.ports.iter().enumerate() {
-                if let Some(name) = &port.name {
-                    match buses.insert(name.clone(), (c.controller, index)) {
-                        Some(_) => {
-                            panic!("i2c bus {} appears twice", name);
-                        }
-                        None => {}
-                    }
-                }
-
-                if c.ports.len() == 1 {
-                    singletons.insert(c.controller, index);
-                }
-
-                ports.insert((c.controller, p.clone()), index);
-            }
+        Self::from_config(i2c, disposition, artifact)
+    }
 
-            if c.target != (disposition == Disposition::Target) {
-                continue;
-            }
+    fn from_config(
+        i2c: I2cConfig,
+        disposition: Disposition,
+        artifact: Artifact,
+    ) -> Result<Self> {
+        //
+        // We always build our topology from all controllers (even those that
+        // don't match our dispostion) to assure that devices can always find
+        // their bus.
+        //
+        let topology = Topology::new(&i2c)?;
 
-            controllers.push(c);
-        }
+        let controllers = i2c
+            .controllers
+            .into_iter()
+            .filter(|c| c.target == (disposition == Disposition::Target))
+            .collect();
 
-        if let Some(devices) = &i2c.devices {
-            for d in devices {
-                match (d.controller, d.bus.as_ref()) {
-                    (None, None) => {
-                        panic!(
-                            "device {} at address 0x{:x} must have \
-                            a bus or controller",
-                            d.device, d.address
-                        );
-                    }
-                    (Some(_), Some(_)) => {
-                        panic!(
-                            "device {} at address 0x{:x} has both \
-                            a bus and a controller",
-                            d.device, d.address
-                        );
-                    }
-                    (_, Some(bus)) if buses.get(bus).is_none() => {
-                        panic!(
-                            "device {} at address 0x{:x} specifies \
-                            unknown bus \"{}\"",
-                            d.device, d.address, bus
-                        );
-                    }
-                    (_, _) => {}
-                }
-            }
-        }
-
-        Self {
+        Ok(Self {
             output: String::new(),
             disposition: disposition,
             artifact: artifact,
             controllers: controllers,
-            buses: buses,
-            ports: ports,
-            singletons: singletons,
             devices: i2c.devices.unwrap_or(Vec::new()),
-        }
+            topology: topology,
+        })
     }
 
     pub fn ncontrollers(&self) -> usize {
@@ -314,6 +267,12 @@ impl ConfigGenerator {
         ["##
         )?;
 
+        //
+        // Each controller's event and error interrupts get their own
+        // notification bits: I2C1 event is bit 0 and I2C1 error is bit 8,
+        // I2C2 event is bit 1 and I2C2 error is bit 9, and so on.  The
+        // app.toml must route them accordingly.
+        //
         for c in &self.controllers {
             write!(
                 &mut s,
@@ -321,10 +280,12 @@ impl ConfigGenerator {
             I2cController {{
                 controller: Controller::I2C{controller},
                 peripheral: Peripheral::I2c{controller},
-                notification: (1 << ({controller} - 1)),
+                notification: (1 << ({event})) | (1 << ({error})),
                 registers: unsafe {{ &*device::I2C{controller}::ptr() }},
             }},"##,
-                controller = c.controller
+                controller = c.controller,
+                event = c.controller - 1,
+                error = c.controller + 7,
             )?;
         }
 
@@ -528,46 +489,13 @@ impl ConfigGenerator {
         Ok(())
     }
 
-    fn generate_device(&self, d: &I2cDevice) -> String {
-        let controller = match &d.bus {
-            Some(bus) => self.buses.get(bus).unwrap().0,
-            None => d.controller.unwrap(),
+    fn generate_device(&self, d: &I2cDevice) -> Result<String> {
+        let location = match self.topology.locate(d) {
+            Ok(location) => location,
+            Err(err) => bail!(err),
         };
 
-        let port = match (&d.bus, &d.port) {
-            (Some(_), Some(_)) => {
-                panic!("device {} has both port and bus", d.device);
-            }
-
-            (Some(bus), None) => match self.buses.get(bus) {
-                Some((_, port)) => port,
-                None => {
-                    panic!("device {} has invalid bus", d.device);
-                }
-            },
-
-            (None, Some(port)) => {
-                match self.ports.get(&(controller, port.to_string())) {
-                    None => {
-                        panic!("device {} has invalid port", d.device);
-                    }
-                    Some(port) => port,
-                }
-            }
-
-            //
-            // We allow ports to be unspecified if the specified
-            // controller has only a single port; check the singletons.
-            //
-            (None, None) => match self.singletons.get(&controller) {
-                Some(port) => port,
-                None => {
-                    panic!("device {} has ambiguous port", d.device)
-                }
-            },
-        };
-
-        format!(
+        Ok(format!(
             r##"
             // {description}
             I2cDevice::new(task,
@@ -577,11 +505,11 @@ impl ConfigGenerator {
                 0x{address:x}
             )"##,
             description = d.description,
-            controller = controller,
-            port = port,
-            segment = "None",
+            controller = location.controller,
+            port = location.port,
+            segment = generate_segment(location.segment),
             address = d.address,
-        )
+        ))
     }
 
     pub fn generate_devices(&mut self) -> Result<()> {
@@ -637,7 +565,8 @@ impl ConfigGenerator {
             &mut self.output,
             r##"
     pub mod devices {{
-        use drv_i2c_api::{{I2cDevice, Controller, PortIndex}};
+        #[allow(unused_imports)]
+        use drv_i2c_api::{{I2cDevice, Controller, PortIndex, Mux, Segment}};
         use userlib::TaskId;
 "##
         )?;
@@ -654,7 +583,7 @@ impl ConfigGenerator {
             )?;
 
             for d in devices {
-                let out = self.generate_device(d);
+                let out = self.generate_device(d)?;
                 write!(&mut self.output, "{},", out)?;
             }
 
@@ -679,7 +608,7 @@ impl ConfigGenerator {
             )?;
 
             for d in devices {
-                let out = self.generate_device(d);
+                let out = self.generate_device(d)?;
                 write!(&mut self.output, "{},", out)?;
             }
             writeln!(
@@ -699,7 +628,7 @@ impl ConfigGenerator {
                 device, bus, name
             )?;
 
-            let out = self.generate_device(d);
+            let out = self.generate_device(d)?;
             write!(&mut self.output, "{}", out)?;
 
             writeln!(
@@ -740,7 +669,8 @@ impl ConfigGenerator {
             &mut self.output,
             r##"
     pub mod pmbus {{
-        use drv_i2c_api::{{I2cDevice, Controller, PortIndex}};
+        #[allow(unused_imports)]
+        use drv_i2c_api::{{I2cDevice, Controller, PortIndex, Mux, Segment}};
         use userlib::TaskId;
 "##
         )?;
@@ -754,7 +684,7 @@ impl ConfigGenerator {
                 rail.to_lowercase(),
             )?;
 
-            let out = self.generate_device(device);
+            let out = self.generate_device(device)?;
             writeln!(&mut self.output, "({}, {})\n        }}", out, index)?;
         }
 
@@ -762,6 +692,91 @@ impl ConfigGenerator {
         Ok(())
     }
 
+    pub fn generate_inventory(&mut self) -> Result<()> {
+        fn option(s: &Option<String>) -> String {
+            match s {
+                Some(s) => format!("Some({:?})", s),
+                None => "None".to_string(),
+            }
+        }
+
+        write!(
+            &mut self.output,
+            r##"
+    pub mod inventory {{
+        #[allow(unused_imports)]
+        use drv_i2c_api::{{I2cDeviceInfo, Controller, PortIndex, Mux, Segment}};
+
+        #[allow(dead_code)]
+        pub static DEVICES: [I2cDeviceInfo; {}] = ["##,
+            self.devices.len()
+        )?;
+
+        for d in &self.devices {
+            let location = match self.topology.locate(d) {
+                Ok(location) => location,
+                Err(err) => bail!(err),
+            };
+
+            let rails = match d.pmbus.as_ref().and_then(|p| p.rails.as_ref()) {
+                Some(rails) => rails
+                    .iter()
+                    .map(|rail| format!("{:?}", rail))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                None => String::new(),
+            };
+
+            write!(
+                &mut self.output,
+                r##"
+            I2cDeviceInfo {{
+                device: {device:?},
+                name: {name},
+                bus: {bus},
+                description: {description:?},
+                refdes: {refdes},
+                removable: {removable},
+                rails: &[{rails}],
+                controller: Controller::I2C{controller},
+                port: PortIndex({port}),
+                segment: {segment},
+                address: 0x{address:x},
+            }},"##,
+                device = d.device,
+                name = option(&d.name),
+                bus = option(&d.bus),
+                description = d.description,
+                refdes = option(&d.refdes),
+                removable = d.removable,
+                rails = rails,
+                controller = location.controller,
+                port = location.port,
+                segment = generate_segment(location.segment),
+                address = d.address,
+            )?;
+        }
+
+        writeln!(
+            &mut self.output,
+            r##"
+        ];
+
+        ///
+        /// Returns the devices that are the part `device`, e.g. `tmp117`.
+        ///
+        #[allow(dead_code)]
+        pub fn by_device(
+            device: &'static str,
+        ) -> impl Iterator<Item = &'static I2cDeviceInfo> {{
+            DEVICES.iter().filter(move |d| d.device == device)
+        }}
+    }}"##
+        )?;
+
+        Ok(())
+    }
+
     pub fn generate_ports(&mut self) -> Result<()> {
         writeln!(
             &mut self.output,
@@ -783,7 +798,7 @@ impl ConfigGenerator {
             )?;
         }
 
-        for ((controller, port), index) in &self.ports {
+        for ((controller, port), index) in &self.topology.ports {
             writeln!(
                 &mut self.output,
                 r##"
@@ -802,6 +817,105 @@ impl ConfigGenerator {
     }
 }
 
+fn generate_segment(segment: Option<(u8, u8)>) -> String {
+    match segment {
+        Some((mux, segment)) => {
+            format!("Some((Mux::M{}, Segment::S{}))", mux, segment)
+        }
+        None => "None".to_string(),
+    }
+}
+
+///
+/// A device in `config.i2c.devices`, and where it is.  This is for tools
+/// outside of the build that describe an application's devices, so that they
+/// find each one exactly where the generated code does.
+///
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    /// device part name
+    pub device: String,
+
+    /// device name, if any
+    pub name: Option<String>,
+
+    /// name of the bus the device is on, if the bus is named
+    pub bus: Option<String>,
+
+    pub description: String,
+
+    /// reference designator, if any
+    pub refdes: Option<String>,
+
+    /// device can be removed (and so may be absent)
+    pub removable: bool,
+
+    /// PMBus rails, by rail index
+    pub rails: Vec<String>,
+
+    pub controller: u8,
+
+    /// name of the port on the controller
+    pub port: String,
+
+    /// mux and segment, if any, each numbered from 1
+    pub segment: Option<(u8, u8)>,
+
+    pub address: u8,
+}
+
+///
+/// Locates every device in `i2c`, an application's `config.i2c`, failing
+/// with every problem in it just as the build would.
+///
+pub fn devices<'de, D: serde::Deserializer<'de>>(
+    i2c: D,
+) -> Result<Vec<DeviceInfo>> {
+    let i2c = match I2cConfig::deserialize(i2c) {
+        Ok(i2c) => i2c,
+        Err(err) => bail!("malformed config.i2c: {}", err),
+    };
+    let topology = Topology::new(&i2c)?;
+
+    let mut devices = vec![];
+
+    for d in i2c.devices.iter().flatten() {
+        let location = match topology.locate(d) {
+            Ok(location) => location,
+            Err(err) => bail!(err),
+        };
+
+        // The topology found the port, so its controller has it.
+        let port = i2c
+            .controllers
+            .iter()
+            .find(|c| c.controller == location.controller)
+            .and_then(|c| c.ports.get_index(location.port))
+            .map(|(port, _)| port.clone())
+            .unwrap();
+
+        devices.push(DeviceInfo {
+            device: d.device.clone(),
+            name: d.name.clone(),
+            bus: d.bus.clone(),
+            description: d.description.clone(),
+            refdes: d.refdes.clone(),
+            removable: d.removable,
+            rails: d
+                .pmbus
+                .as_ref()
+                .and_then(|p| p.rails.clone())
+                .unwrap_or_default(),
+            controller: location.controller,
+            port,
+            segment: location.segment,
+            address: d.address,
+        });
+    }
+
+    Ok(devices)
+}
+
 pub fn codegen(disposition: Disposition, artifact: Artifact) -> Result<()> {
     use std::io::Write;
 
@@ -809,7 +923,7 @@ pub fn codegen(disposition: Disposition, artifact: Artifact) -> Result<()> {
     let dest_path = Path::new(&out_dir).join("i2c_config.rs");
     let mut file = File::create(&dest_path)?;
 
-    let mut g = ConfigGenerator::new(disposition, artifact);
+    let mut g = ConfigGenerator::new(disposition, artifact)?;
 
     g.generate_header()?;
 
@@ -841,6 +955,7 @@ pub fn codegen(disposition: Disposition, artifact: Artifact) -> Result<()> {
         Disposition::Devices => {
             g.generate_devices()?;
             g.generate_pmbus()?;
+            g.generate_inventory()?;
         }
     }
 
@@ -850,3 +965,88 @@ pub fn codegen(disposition: Disposition, artifact: Artifact) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(text: &str) -> ConfigGenerator {
+        let value: toml::Value = toml::from_str(text).unwrap();
+        let i2c = value["config"]["i2c"].clone().try_into().unwrap();
+        ConfigGenerator::from_config(i2c, Disposition::Devices, Artifact::Dist)
+            .unwrap()
+    }
+
+    /// Squeezes runs of whitespace, so that generated code can be compared
+    /// without regard to its indentation.
+    fn squeeze(text: &str) -> String {
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn inventory() {
+        let mut g = generator(
+            r#"
+            [[config.i2c.controllers]]
+            controller = 4
+            [config.i2c.controllers.ports.F]
+            name = "rear"
+            pins = [ { pins = [ 14, 15 ], af = 4 } ]
+            muxes = [ { driver = "pca9548", address = 0x70 } ]
+
+            [[config.i2c.devices]]
+            device = "tmp117"
+            name = "Rear"
+            bus = "rear"
+            address = 0x48
+            description = "Rear temperature sensor"
+            refdes = "U1"
+            removable = true
+
+            [[config.i2c.devices]]
+            device = "isl68224"
+            controller = 4
+            port = "F"
+            mux = 1
+            segment = 3
+            address = 0x60
+            description = "ISL68224 evaluation board"
+            pmbus = { rails = [ "VOUT0", "", "VOUT2" ] }
+            "#,
+        );
+        g.generate_inventory().unwrap();
+        let output = squeeze(&g.output);
+
+        assert!(output.contains("pub static DEVICES: [I2cDeviceInfo; 2]"));
+        for device in &[
+            r#"I2cDeviceInfo {
+                device: "tmp117",
+                name: Some("Rear"),
+                bus: Some("rear"),
+                description: "Rear temperature sensor",
+                refdes: Some("U1"),
+                removable: true,
+                rails: &[],
+                controller: Controller::I2C4,
+                port: PortIndex(0),
+                segment: None,
+                address: 0x48,
+            },"#,
+            r#"I2cDeviceInfo {
+                device: "isl68224",
+                name: None,
+                bus: None,
+                description: "ISL68224 evaluation board",
+                refdes: None,
+                removable: false,
+                rails: &["VOUT0", "", "VOUT2"],
+                controller: Controller::I2C4,
+                port: PortIndex(0),
+                segment: Some((Mux::M1, Segment::S3)),
+                address: 0x60,
+            },"#,
+        ] {
+            assert!(output.contains(&squeeze(device)), "{}", output);
+        }
+    }
+}
@@ -0,0 +1,635 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! The I2C topology described by `config.i2c`, validated.
+//!
+//! `config.i2c` is deserialized into flat structures (see the comment on
+//! [`I2cDevice`] for why), which can express entries that make no sense: a
+//! device with both a bus and a controller, a mux without a segment, two
+//! devices at the same address.  [`Topology`] works out where each device
+//! actually sits -- a port on a controller, and possibly a segment on one of
+//! that port's muxes -- and reports every such problem at once, before any
+//! code is generated.
+//!
+
+use anyhow::{bail, Result};
+use indexmap::IndexMap;
+use std::collections::{HashMap, HashSet};
+
+use crate::{I2cConfig, I2cDevice};
+
+/// Muxes per port, as in `drv_i2c_api::Mux`.
+const MAX_MUXES: usize = 4;
+
+/// Segments per mux, as in `drv_i2c_api::Segment`.
+const MAX_SEGMENTS: u8 = 8;
+
+///
+/// Where a device sits in the topology.  Two devices can only share an
+/// address if they are on different ports, or on different segments of the
+/// same port.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub controller: u8,
+
+    /// index of the port within the controller's `ports`
+    pub port: usize,
+
+    /// mux and segment, if any, each numbered from 1
+    pub segment: Option<(u8, u8)>,
+}
+
+pub struct Topology {
+    /// controllers that have been configured
+    controllers: HashSet<u8>,
+
+    /// hash bus name to controller/port index pair
+    buses: HashMap<String, (u8, usize)>,
+
+    /// hash controller/port pair to port index
+    pub ports: IndexMap<(u8, String), usize>,
+
+    /// hash of controllers to single port indices
+    singletons: HashMap<u8, usize>,
+
+    /// the addresses of the muxes on each controller/port index pair
+    muxes: HashMap<(u8, usize), Vec<u8>>,
+}
+
+///
+/// Returns true if `address` is reserved by the I2C specification, as in
+/// `drv_i2c_api::ReservedAddress`.
+///
+fn is_reserved(address: u8) -> bool {
+    address <= 0x07 || (0x7c..=0x7f).contains(&address)
+}
+
+fn describe(d: &I2cDevice) -> String {
+    match &d.name {
+        Some(name) => {
+            format!("device {} ({}) at 0x{:x}", d.device, name, d.address)
+        }
+        None => format!("device {} at 0x{:x}", d.device, d.address),
+    }
+}
+
+impl Topology {
+    ///
+    /// Builds the topology of `i2c`, failing with every problem found if its
+    /// controllers or devices are ambiguous or conflict with one another.
+    ///
+    pub(crate) fn new(i2c: &I2cConfig) -> Result<Self> {
+        let mut errors = vec![];
+
+        let mut topology = Self {
+            controllers: HashSet::new(),
+            buses: HashMap::new(),
+            ports: IndexMap::new(),
+            singletons: HashMap::new(),
+            muxes: HashMap::new(),
+        };
+
+        for c in &i2c.controllers {
+            if !topology.controllers.insert(c.controller) {
+                errors.push(format!("I2C{} appears twice", c.controller));
+            }
+
+            for (index, (p, port)) in c.ports.iter().enumerate() {
+                if let Some(name) = &port.name {
+                    let bus = (c.controller, index);
+                    if topology.buses.insert(name.clone(), bus).is_some() {
+                        errors.push(format!("i2c bus {} appears twice", name));
+                    }
+                }
+
+                if c.ports.len() == 1 {
+                    topology.singletons.insert(c.controller, index);
+                }
+
+                topology.ports.insert((c.controller, p.clone()), index);
+
+                if port.muxes.len() > MAX_MUXES {
+                    errors.push(format!(
+                        "I2C{} port {} has {} muxes; at most {} are allowed",
+                        c.controller,
+                        p,
+                        port.muxes.len(),
+                        MAX_MUXES
+                    ));
+                }
+
+                for (i, mux) in port.muxes.iter().enumerate() {
+                    if is_reserved(mux.address) || mux.address > 0x7f {
+                        errors.push(format!(
+                            "I2C{} port {}: mux {} has invalid address 0x{:x}",
+                            c.controller,
+                            p,
+                            i + 1,
+                            mux.address
+                        ));
+                    }
+
+                    for (j, other) in port.muxes[..i].iter().enumerate() {
+                        if other.address == mux.address {
+                            errors.push(format!(
+                                "I2C{} port {}: muxes {} and {} are both \
+                                at 0x{:x}",
+                                c.controller,
+                                p,
+                                j + 1,
+                                i + 1,
+                                mux.address
+                            ));
+                        }
+                    }
+                }
+
+                topology.muxes.insert(
+                    (c.controller, index),
+                    port.muxes.iter().map(|m| m.address).collect(),
+                );
+            }
+        }
+
+        let mut located: Vec<(&I2cDevice, Location)> = vec![];
+
+        for d in i2c.devices.iter().flatten() {
+            if d.address > 0x7f {
+                errors.push(format!(
+                    "{} has an address beyond 7 bits",
+                    describe(d)
+                ));
+            } else if is_reserved(d.address) {
+                errors.push(format!("{} has a reserved address", describe(d)));
+            }
+
+            let location = match topology.locate(d) {
+                Ok(location) => location,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+
+            //
+            // A device collides with any other at its address that is
+            // visible at the same time: on the same segment, or on the port
+            // itself (which is visible from every segment).
+            //
+            for (other, at) in &located {
+                if other.address != d.address
+                    || (at.controller, at.port)
+                        != (location.controller, location.port)
+                {
+                    continue;
+                }
+
+                match (at.segment, location.segment) {
+                    (Some(a), Some(b)) if a != b => continue,
+                    _ => {}
+                }
+
+                errors.push(format!(
+                    "{} and {} collide on {}",
+                    describe(other),
+                    describe(d),
+                    topology.describe_location(&location)
+                ));
+            }
+
+            //
+            // Muxes sit on the port itself, so their addresses are taken on
+            // every segment.  (A device on the port itself at a mux's address
+            // is the mux, listed so that it can be talked to directly.)
+            //
+            let muxes = &topology.muxes[&(location.controller, location.port)];
+
+            for (i, &address) in muxes.iter().enumerate() {
+                if address == d.address && location.segment.is_some() {
+                    errors.push(format!(
+                        "{} collides with mux {} on {}",
+                        describe(d),
+                        i + 1,
+                        topology.describe_location(&location)
+                    ));
+                }
+            }
+
+            located.push((d, location));
+        }
+
+        if !errors.is_empty() {
+            bail!(
+                "config.i2c has {} problem(s):\n    {}",
+                errors.len(),
+                errors.join("\n    ")
+            );
+        }
+
+        Ok(topology)
+    }
+
+    ///
+    /// Works out where `d` is.  This is only as thorough as it needs to be to
+    /// find the device; [`Topology::new`] checks the rest.
+    ///
+    pub(crate) fn locate(&self, d: &I2cDevice) -> Result<Location, String> {
+        let (controller, port) = match (d.controller, &d.bus, &d.port) {
+            (None, None, _) => {
+                return Err(format!(
+                    "{} must have a bus or controller",
+                    describe(d)
+                ));
+            }
+
+            (Some(_), Some(_), _) => {
+                return Err(format!(
+                    "{} has both a bus and a controller",
+                    describe(d)
+                ));
+            }
+
+            (None, Some(_), Some(_)) => {
+                return Err(format!(
+                    "{} has both a bus and a port",
+                    describe(d)
+                ));
+            }
+
+            (None, Some(bus), None) => match self.buses.get(bus) {
+                Some(&location) => location,
+                None => {
+                    return Err(format!(
+                        "{} specifies unknown bus \"{}\"",
+                        describe(d),
+                        bus
+                    ));
+                }
+            },
+
+            (Some(c), None, _) if !self.controllers.contains(&c) => {
+                return Err(format!(
+                    "{} specifies unknown controller I2C{}",
+                    describe(d),
+                    c
+                ));
+            }
+
+            (Some(c), None, Some(port)) => {
+                match self.ports.get(&(c, port.to_string())) {
+                    Some(&index) => (c, index),
+                    None => {
+                        return Err(format!(
+                            "{} specifies port {}, which I2C{} doesn't have",
+                            describe(d),
+                            port,
+                            c
+                        ));
+                    }
+                }
+            }
+
+            //
+            // We allow ports to be unspecified if the specified
+            // controller has only a single port; check the singletons.
+            //
+            (Some(c), None, None) => match self.singletons.get(&c) {
+                Some(&index) => (c, index),
+                None => {
+                    return Err(format!(
+                        "{} has ambiguous port: I2C{} has more than one",
+                        describe(d),
+                        c
+                    ));
+                }
+            },
+        };
+
+        let segment = match (d.mux, d.segment) {
+            (None, None) => None,
+
+            (Some(mux), Some(segment)) => {
+                let nmuxes = self.muxes[&(controller, port)].len();
+
+                if mux == 0 || mux as usize > nmuxes {
+                    return Err(format!(
+                        "{} specifies mux {}, but its port has {} mux(es)",
+                        describe(d),
+                        mux,
+                        nmuxes
+                    ));
+                }
+
+                if segment == 0 || segment > MAX_SEGMENTS {
+                    return Err(format!(
+                        "{} specifies segment {}; segments are 1 to {}",
+                        describe(d),
+                        segment,
+                        MAX_SEGMENTS
+                    ));
+                }
+
+                Some((mux, segment))
+            }
+
+            _ => {
+                return Err(format!(
+                    "{} must have both a mux and a segment, or neither",
+                    describe(d)
+                ));
+            }
+        };
+
+        Ok(Location {
+            controller,
+            port,
+            segment,
+        })
+    }
+
+    fn describe_location(&self, location: &Location) -> String {
+        let port = self
+            .ports
+            .iter()
+            .find(|(&(c, _), &index)| {
+                c == location.controller && index == location.port
+            })
+            .map(|((_, p), _)| p.as_str())
+            .unwrap_or("?");
+
+        match location.segment {
+            Some((mux, segment)) => format!(
+                "I2C{} port {}, mux {} segment {}",
+                location.controller, port, mux, segment
+            ),
+            None => format!("I2C{} port {}", location.controller, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn config(text: &str) -> Result<I2cConfig> {
+        let value: toml::Value = toml::from_str(text)?;
+        Ok(value["config"]["i2c"].clone().try_into()?)
+    }
+
+    fn topology(text: &str) -> Result<Topology> {
+        Topology::new(&config(text)?)
+    }
+
+    fn problems(text: &str) -> String {
+        match topology(text) {
+            Ok(_) => panic!("expected problems"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    const CONTROLLERS: &str = r#"
+        [[config.i2c.controllers]]
+        controller = 2
+        [config.i2c.controllers.ports.B]
+        name = "front"
+        pins = [ { pins = [ 10, 11 ], af = 4 } ]
+        [config.i2c.controllers.ports.F]
+        pins = [ { pins = [ 0, 1 ], af = 4 } ]
+        muxes = [ { driver = "pca9548", address = 0x70 } ]
+
+        [[config.i2c.controllers]]
+        controller = 4
+        [config.i2c.controllers.ports.D]
+        pins = [ { pins = [ 12, 13 ], af = 4 } ]
+    "#;
+
+    ///
+    /// Every app's `config.i2c` must be valid.  (Apps only inherit from
+    /// bases without any `config.i2c`, so reading the app.toml itself is
+    /// enough.)
+    ///
+    #[test]
+    fn apps() {
+        let app = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../app");
+        let mut checked = 0;
+
+        for entry in app.read_dir().unwrap() {
+            let dir = entry.unwrap().path();
+            if !dir.is_dir() {
+                continue;
+            }
+
+            for file in dir.read_dir().unwrap() {
+                let path = file.unwrap().path();
+                if path.extension().map_or(true, |e| e != "toml") {
+                    continue;
+                }
+
+                let text = std::fs::read_to_string(&path).unwrap();
+                if !text.contains("[config.i2c") {
+                    continue;
+                }
+
+                if let Err(err) = topology(&text) {
+                    panic!("{}: {}", path.display(), err);
+                }
+                checked += 1;
+            }
+        }
+
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn locates() -> Result<()> {
+        let text = format!(
+            r#"{}
+            [[config.i2c.devices]]
+            device = "tmp117"
+            bus = "front"
+            address = 0x48
+            description = "sensor"
+
+            [[config.i2c.devices]]
+            device = "tmp117"
+            controller = 2
+            port = "F"
+            mux = 1
+            segment = 3
+            address = 0x48
+            description = "sensor"
+
+            [[config.i2c.devices]]
+            device = "max31790"
+            controller = 4
+            address = 0x20
+            description = "fans"
+            "#,
+            CONTROLLERS
+        );
+        let t = topology(&text)?;
+        let located: Vec<_> = config(&text)?
+            .devices
+            .unwrap()
+            .iter()
+            .map(|d| t.locate(d))
+            .collect();
+
+        let at = |controller, port, segment| {
+            Ok(Location {
+                controller,
+                port,
+                segment,
+            })
+        };
+        assert_eq!(
+            located,
+            vec![at(2, 0, None), at(2, 1, Some((1, 3))), at(4, 0, None)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ambiguous() {
+        let p = problems(&format!(
+            r#"{}
+            [[config.i2c.devices]]
+            device = "a"
+            controller = 2
+            bus = "front"
+            address = 0x10
+            description = "both"
+
+            [[config.i2c.devices]]
+            device = "b"
+            controller = 2
+            address = 0x11
+            description = "which port?"
+
+            [[config.i2c.devices]]
+            device = "c"
+            controller = 2
+            port = "F"
+            mux = 1
+            address = 0x12
+            description = "which segment?"
+
+            [[config.i2c.devices]]
+            device = "d"
+            controller = 2
+            port = "F"
+            mux = 2
+            segment = 1
+            address = 0x13
+            description = "which mux?"
+            "#,
+            CONTROLLERS
+        ));
+
+        assert!(p.contains("has 4 problem(s)"), "{}", p);
+        assert!(p.contains("both a bus and a controller"), "{}", p);
+        assert!(p.contains("ambiguous port"), "{}", p);
+        assert!(p.contains("both a mux and a segment"), "{}", p);
+        assert!(p.contains("specifies mux 2"), "{}", p);
+    }
+
+    #[test]
+    fn collisions() {
+        let p = problems(&format!(
+            r#"{}
+            [[config.i2c.devices]]
+            device = "a"
+            bus = "front"
+            address = 0x48
+            description = "first"
+
+            [[config.i2c.devices]]
+            device = "b"
+            controller = 2
+            port = "B"
+            address = 0x48
+            description = "same address, same port"
+
+            [[config.i2c.devices]]
+            device = "c"
+            controller = 2
+            port = "F"
+            address = 0x50
+            description = "on the port"
+
+            [[config.i2c.devices]]
+            device = "d"
+            controller = 2
+            port = "F"
+            mux = 1
+            segment = 2
+            address = 0x50
+            description = "behind the mux, but still visible"
+
+            [[config.i2c.devices]]
+            device = "e"
+            controller = 2
+            port = "F"
+            mux = 1
+            segment = 4
+            address = 0x70
+            description = "at the mux's address"
+
+            [[config.i2c.devices]]
+            device = "f"
+            controller = 4
+            address = 0x7e
+            description = "reserved"
+            "#,
+            CONTROLLERS
+        ));
+
+        assert!(p.contains("has 4 problem(s)"), "{}", p);
+        assert!(p.contains("collide on I2C2 port B"), "{}", p);
+        assert!(
+            p.contains("collide on I2C2 port F, mux 1 segment 2"),
+            "{}",
+            p
+        );
+        assert!(p.contains("collides with mux 1"), "{}", p);
+        assert!(p.contains("reserved address"), "{}", p);
+    }
+
+    #[test]
+    fn mux_is_not_a_collision() -> Result<()> {
+        topology(&format!(
+            r#"{}
+            [[config.i2c.devices]]
+            device = "pca9548"
+            controller = 2
+            port = "F"
+            address = 0x70
+            description = "the mux itself"
+
+            [[config.i2c.devices]]
+            device = "a"
+            controller = 2
+            port = "F"
+            mux = 1
+            segment = 1
+            address = 0x50
+            description = "one segment"
+
+            [[config.i2c.devices]]
+            device = "b"
+            controller = 2
+            port = "F"
+            mux = 1
+            segment = 2
+            address = 0x50
+            description = "another segment"
+            "#,
+            CONTROLLERS
+        ))?;
+        Ok(())
+    }
+}
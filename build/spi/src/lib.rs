@@ -0,0 +1,760 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Code generation for SPI controllers and the devices on them, from
+//! `config.spi`.
+//!
+//! Each table in `config.spi` is named after the SPI peripheral it describes
+//! (e.g., `spi2`), and holds the ways that controller can be muxed onto pins
+//! and the devices hanging off of it.  The server for a controller gets the
+//! `spi_config::CONFIG` it runs from; clients of the servers get a
+//! `spi_config::devices` module with a function per device.
+//!
+
+use anyhow::{bail, Result};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::fs::File;
+use std::path::Path;
+
+//
+// Our definition of the `Config` type.  We share this type with all other
+// build-specific types; we must not set `deny_unknown_fields` here.
+//
+#[derive(Clone, Debug, Deserialize)]
+struct Config {
+    spi: IndexMap<String, SpiController>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpiController {
+    /// ways of routing the controller onto pins, by name
+    mux_options: IndexMap<String, SpiMuxOption>,
+
+    /// devices on the controller, by name, in device index order
+    devices: IndexMap<String, SpiDevice>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpiMuxOption {
+    /// COPI and SCK pins
+    outputs: Vec<SpiPinSet>,
+
+    /// CIPO pin
+    input: SpiPin,
+
+    /// swap COPI and CIPO
+    #[serde(default)]
+    swap_data: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpiPinSet {
+    port: String,
+    pins: Vec<u8>,
+    af: u8,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpiPin {
+    port: String,
+    pin: u8,
+    af: u8,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GpioPin {
+    port: String,
+    pin: u8,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpiDevice {
+    /// description of device
+    description: String,
+
+    /// reference designator, if any
+    refdes: Option<String>,
+
+    /// name of the mux option that reaches the device
+    mux: String,
+
+    /// chip select, which is active low
+    cs: GpioPin,
+
+    /// SPI mode, 0 through 3
+    #[serde(default)]
+    mode: u8,
+
+    /// order in which bits are shifted
+    #[serde(default)]
+    bit_order: BitOrder,
+
+    /// divider of the controller's kernel clock, a power of two from 2 to 256
+    #[serde(default = "default_clock_divider")]
+    clock_divider: u32,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum BitOrder {
+    Msb,
+    Lsb,
+}
+
+impl Default for BitOrder {
+    fn default() -> Self {
+        BitOrder::Msb
+    }
+}
+
+///
+/// The divider the server used before it could be configured per device.
+///
+fn default_clock_divider() -> u32 {
+    64
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Artifact {
+    /// part of a complete distribution of an application
+    Dist,
+
+    /// standalone build of a single task
+    Standalone,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Disposition {
+    /// server for the given controller (e.g., 2 for SPI2)
+    Server(u8),
+
+    /// only devices are used (i.e., the task is a client of SPI servers)
+    Devices,
+}
+
+/// A GPIO pin, as its port and number.
+type Pin = (String, u8);
+
+fn pin_name(pin: &Pin) -> String {
+    format!("P{}{}", pin.0, pin.1)
+}
+
+///
+/// Returns the number of the SPI controller named `name`, if it names one.
+///
+fn controller_number(name: &str) -> Option<u8> {
+    match name.strip_prefix("spi")?.parse() {
+        Ok(n) if (1..=6).contains(&n) => Some(n),
+        _ => None,
+    }
+}
+
+fn check_pin(errors: &mut Vec<String>, what: &str, port: &str, pin: u8) {
+    let valid = port.len() == 1 && ("A"..="K").contains(&port);
+
+    if !valid || pin > 15 {
+        errors.push(format!("{}: P{}{} is not a GPIO pin", what, port, pin));
+    }
+}
+
+fn check_af(errors: &mut Vec<String>, what: &str, af: u8) {
+    if af > 15 {
+        errors.push(format!("{}: AF{} is not an alternate function", what, af));
+    }
+}
+
+///
+/// Checks `config.spi` for entries that can't work: pins that don't exist,
+/// mux options that aren't exactly COPI, SCK and CIPO, devices with settings
+/// the controller lacks, and pins claimed twice.  A pin may carry the
+/// signals of only one mux option, and may not also be a chip select; a chip
+/// select may be shared by devices on the same controller (e.g., two
+/// interfaces to one part), but not across controllers, whose servers would
+/// fight over it.  All problems are reported at once.
+///
+fn check(spi: &IndexMap<String, SpiController>) -> Result<()> {
+    let mut errors = vec![];
+
+    // what each pin carrying SPI signals is used for
+    let mut signals: HashMap<Pin, String> = HashMap::new();
+
+    // the controller using each chip select, and where it was first seen
+    let mut selects: IndexMap<Pin, (&str, String)> = IndexMap::new();
+
+    // the controller of each device
+    let mut names: HashMap<&str, &str> = HashMap::new();
+
+    for (name, c) in spi {
+        if controller_number(name).is_none() {
+            errors.push(format!(
+                "{} is not an SPI controller; expected spi1 through spi6",
+                name
+            ));
+        }
+
+        if c.mux_options.is_empty() {
+            errors.push(format!("{} has no mux options", name));
+        }
+
+        if c.devices.is_empty() {
+            errors.push(format!("{} has no devices", name));
+        }
+
+        for (m, opt) in &c.mux_options {
+            let what = format!("{} mux option {}", name, m);
+            let mut pins = vec![];
+
+            for set in &opt.outputs {
+                check_af(&mut errors, &what, set.af);
+
+                for &pin in &set.pins {
+                    check_pin(&mut errors, &what, &set.port, pin);
+                    pins.push((set.port.clone(), pin));
+                }
+            }
+
+            if pins.len() != 2 {
+                errors.push(format!(
+                    "{} has {} output pins; expected 2 (COPI and SCK)",
+                    what,
+                    pins.len()
+                ));
+            }
+
+            check_af(&mut errors, &what, opt.input.af);
+            check_pin(&mut errors, &what, &opt.input.port, opt.input.pin);
+            pins.push((opt.input.port.clone(), opt.input.pin));
+
+            for pin in pins {
+                if let Some(other) = signals.get(&pin) {
+                    errors.push(format!(
+                        "{} is used by both {} and {}",
+                        pin_name(&pin),
+                        other,
+                        what
+                    ));
+                } else {
+                    signals.insert(pin, what.clone());
+                }
+            }
+        }
+
+        for (d, dev) in &c.devices {
+            let what = format!("{} device {}", name, d);
+
+            let valid = d.starts_with(|c: char| c.is_ascii_lowercase())
+                && d.chars().all(|c| {
+                    c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'
+                });
+
+            if !valid {
+                errors.push(format!(
+                    "{}: name must be lowercase letters, digits and \
+                    underscores",
+                    what
+                ));
+            }
+
+            if let Some(other) = names.insert(d, name) {
+                errors.push(format!(
+                    "device {} appears on both {} and {}",
+                    d, other, name
+                ));
+            }
+
+            if !c.mux_options.contains_key(&dev.mux) {
+                errors.push(format!(
+                    "{}: {} has no mux option {}",
+                    what, name, dev.mux
+                ));
+            }
+
+            if dev.mode > 3 {
+                errors.push(format!(
+                    "{}: mode {} is not an SPI mode",
+                    what, dev.mode
+                ));
+            } else if dev.mode >= 2 && c.mux_options.len() > 1 {
+                //
+                // The server drives the outputs of idle mux options low,
+                // which would clock a device whose SCK idles high.
+                //
+                errors.push(format!(
+                    "{}: mode {} idles SCK high, which {} can't do with more \
+                    than one mux option",
+                    what, dev.mode, name
+                ));
+            }
+
+            if !dev.clock_divider.is_power_of_two()
+                || !(2..=256).contains(&dev.clock_divider)
+            {
+                errors.push(format!(
+                    "{}: clock divider {} is not a power of two from 2 to 256",
+                    what, dev.clock_divider
+                ));
+            }
+
+            check_pin(&mut errors, &what, &dev.cs.port, dev.cs.pin);
+            let pin = (dev.cs.port.clone(), dev.cs.pin);
+
+            match selects.get(&pin) {
+                Some((controller, first)) if *controller != name.as_str() => {
+                    errors.push(format!(
+                        "{}: CS {} is already used by {}",
+                        what,
+                        pin_name(&pin),
+                        first
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    selects.insert(pin, (name, what.clone()));
+                }
+            }
+        }
+    }
+
+    for (pin, (_, what)) in &selects {
+        if let Some(other) = signals.get(pin) {
+            errors.push(format!(
+                "{}: CS {} is also used by {}",
+                what,
+                pin_name(pin),
+                other
+            ));
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "config.spi has {} problem(s):\n    {}",
+            errors.len(),
+            errors.join("\n    ")
+        );
+    }
+
+    Ok(())
+}
+
+struct ConfigGenerator {
+    /// output that we're building
+    output: String,
+
+    /// artifact that we're creating: standalone v. dist
+    artifact: Artifact,
+
+    /// all controllers, by name
+    controllers: IndexMap<String, SpiController>,
+}
+
+impl ConfigGenerator {
+    fn new(artifact: Artifact) -> Result<Self> {
+        let controllers = match artifact {
+            Artifact::Standalone => IndexMap::new(),
+            Artifact::Dist => match build_util::config::<Config>() {
+                Ok(config) => config.spi,
+                Err(err) => {
+                    panic!("malformed config.spi: {:?}", err);
+                }
+            },
+        };
+
+        check(&controllers)?;
+
+        Ok(Self {
+            output: String::new(),
+            artifact,
+            controllers,
+        })
+    }
+
+    pub fn generate_header(&mut self) -> Result<()> {
+        writeln!(&mut self.output, "mod spi_config {{")?;
+        Ok(())
+    }
+
+    pub fn generate_footer(&mut self) -> Result<()> {
+        writeln!(&mut self.output, "}}")?;
+        Ok(())
+    }
+
+    ///
+    /// Generates the server's `CONFIG`, in terms of the server's own types.
+    ///
+    pub fn generate_server(&mut self, controller: u8) -> Result<()> {
+        let mut s = &mut self.output;
+
+        writeln!(
+            &mut s,
+            r##"
+    use super::*;
+"##
+        )?;
+
+        if self.artifact == Artifact::Standalone {
+            //
+            // For the standalone build, nobody is going to run the server.
+            //
+            writeln!(
+                &mut s,
+                r##"    pub(super) const CONFIG: ServerConfig = ServerConfig {{
+        registers: device::SPI{controller}::ptr(),
+        peripheral: rcc_api::Peripheral::Spi{controller},
+        mux_options: &[],
+        devices: &[],
+    }};"##,
+                controller = controller
+            )?;
+
+            return Ok(());
+        }
+
+        let name = format!("spi{}", controller);
+
+        let c = match self.controllers.get(&name) {
+            Some(c) => c,
+            None => bail!("config.spi has no {} for this server", name),
+        };
+
+        write!(
+            &mut s,
+            r##"    pub(super) const CONFIG: ServerConfig = ServerConfig {{
+        registers: device::SPI{controller}::ptr(),
+        peripheral: rcc_api::Peripheral::Spi{controller},
+        mux_options: &["##,
+            controller = controller
+        )?;
+
+        for (m, opt) in &c.mux_options {
+            write!(
+                &mut s,
+                r##"
+            // {}
+            SpiMuxOption {{
+                outputs: &["##,
+                m
+            )?;
+
+            for set in &opt.outputs {
+                write!(
+                    &mut s,
+                    r##"
+                    (
+                        {},
+                        gpio_api::Alternate::AF{},
+                    ),"##,
+                    pin_set(&set.port, &set.pins),
+                    set.af
+                )?;
+            }
+
+            write!(
+                &mut s,
+                r##"
+                ],
+                input: (
+                    {},
+                    gpio_api::Alternate::AF{},
+                ),
+                swap_data: {},
+            }},"##,
+                pin_set(&opt.input.port, &[opt.input.pin]),
+                opt.input.af,
+                opt.swap_data
+            )?;
+        }
+
+        write!(
+            &mut s,
+            r##"
+        ],
+        devices: &["##
+        )?;
+
+        for (d, dev) in &c.devices {
+            let cpol = if dev.mode >= 2 { "IDLEHIGH" } else { "IDLELOW" };
+            let cpha = if dev.mode % 2 == 1 {
+                "SECONDEDGE"
+            } else {
+                "FIRSTEDGE"
+            };
+            let lsbfrst = match dev.bit_order {
+                BitOrder::Msb => "MSBFIRST",
+                BitOrder::Lsb => "LSBFIRST",
+            };
+
+            write!(
+                &mut s,
+                r##"
+            // {name}: {description}
+            DeviceDescriptor {{
+                mux_index: {mux_index},
+                cs: {cs},
+                clock_divider: device::spi1::cfg1::MBR_A::DIV{divider},
+                cpol: device::spi1::cfg2::CPOL_A::{cpol},
+                cpha: device::spi1::cfg2::CPHA_A::{cpha},
+                lsbfrst: device::spi1::cfg2::LSBFRST_A::{lsbfrst},
+            }},"##,
+                name = d,
+                description = dev.description,
+                mux_index = c.mux_options.get_index_of(&dev.mux).unwrap(),
+                cs = pin_set(&dev.cs.port, &[dev.cs.pin]),
+                divider = dev.clock_divider,
+                cpol = cpol,
+                cpha = cpha,
+                lsbfrst = lsbfrst,
+            )?;
+        }
+
+        writeln!(
+            &mut s,
+            r##"
+        ],
+    }};"##
+        )?;
+
+        Ok(())
+    }
+
+    ///
+    /// Generates a function per device that returns the `SpiDevice` for it,
+    /// given the task ID of the server for its controller.
+    ///
+    pub fn generate_devices(&mut self) -> Result<()> {
+        let mut s = &mut self.output;
+
+        writeln!(
+            &mut s,
+            r##"
+    pub mod devices {{
+        #[allow(unused_imports)]
+        use drv_spi_api::{{Spi, SpiDevice}};
+        #[allow(unused_imports)]
+        use userlib::TaskId;"##
+        )?;
+
+        if self.artifact == Artifact::Standalone {
+            //
+            // For the standalone build, we generate a mock device.
+            //
+            writeln!(
+                &mut s,
+                r##"
+        #[allow(dead_code)]
+        pub fn mock(task: TaskId) -> SpiDevice {{
+            SpiDevice::new(Spi::from(task), 0)
+        }}"##
+            )?;
+        }
+
+        for (name, c) in &self.controllers {
+            for (index, (d, dev)) in c.devices.iter().enumerate() {
+                let refdes = match &dev.refdes {
+                    Some(refdes) => format!("{}, ", refdes),
+                    None => String::new(),
+                };
+
+                writeln!(
+                    &mut s,
+                    r##"
+        /// {description} ({refdes}device {index} on {controller})
+        #[allow(dead_code)]
+        pub fn {name}(task: TaskId) -> SpiDevice {{
+            SpiDevice::new(Spi::from(task), {index})
+        }}"##,
+                    description = dev.description,
+                    refdes = refdes,
+                    index = index,
+                    controller = name.to_uppercase(),
+                    name = d,
+                )?;
+            }
+        }
+
+        writeln!(&mut s, "    }}")?;
+        Ok(())
+    }
+}
+
+///
+/// Returns a `PinSet` (as defined by the server) for `pins` on `port`.
+///
+fn pin_set(port: &str, pins: &[u8]) -> String {
+    let mask = pins
+        .iter()
+        .map(|pin| format!("(1 << {})", pin))
+        .collect::<Vec<_>>()
+        .join(" | ");
+
+    format!(
+        "PinSet {{ port: gpio_api::Port::{}, pin_mask: {} }}",
+        port, mask
+    )
+}
+
+pub fn codegen(disposition: Disposition, artifact: Artifact) -> Result<()> {
+    use std::io::Write;
+
+    let out_dir = env::var("OUT_DIR")?;
+    let dest_path = Path::new(&out_dir).join("spi_config.rs");
+    let mut file = File::create(&dest_path)?;
+
+    let mut g = ConfigGenerator::new(artifact)?;
+
+    g.generate_header()?;
+
+    match disposition {
+        Disposition::Server(controller) => {
+            g.generate_server(controller)?;
+        }
+
+        Disposition::Devices => {
+            g.generate_devices()?;
+        }
+    }
+
+    g.generate_footer()?;
+
+    file.write_all(g.output.as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(text: &str) -> String {
+        let value: toml::Value = toml::from_str(text).unwrap();
+        let spi = value["config"]["spi"].clone().try_into().unwrap();
+
+        match check(&spi) {
+            Ok(_) => panic!("expected problems"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    const SPI2: &str = r#"
+        [config.spi.spi2.mux_options.port_i]
+        outputs = [ { port = "I", pins = [ 1, 3 ], af = 5 } ]
+        input = { port = "I", pin = 2, af = 5 }
+
+        [config.spi.spi2.mux_options.port_b]
+        outputs = [ { port = "B", pins = [ 13, 14 ], af = 5 } ]
+        input = { port = "B", pin = 15, af = 5 }
+
+        [config.spi.spi2.devices.flash]
+        description = "flash"
+        mux = "port_b"
+        cs = { port = "B", pin = 12 }
+    "#;
+
+    ///
+    /// Every app's `config.spi` must be valid.  (Apps only inherit from
+    /// bases without any `config.spi`, so reading the app.toml itself is
+    /// enough.)
+    ///
+    #[test]
+    fn apps() {
+        let app = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../app");
+        let mut checked = 0;
+
+        for entry in app.read_dir().unwrap() {
+            let dir = entry.unwrap().path();
+            if !dir.is_dir() {
+                continue;
+            }
+
+            for file in dir.read_dir().unwrap() {
+                let path = file.unwrap().path();
+                if path.extension().map_or(true, |e| e != "toml") {
+                    continue;
+                }
+
+                let text = std::fs::read_to_string(&path).unwrap();
+                let value: toml::Value = toml::from_str(&text).unwrap();
+
+                let spi = match value.get("config") {
+                    Some(config) if config.get("spi").is_some() => {
+                        config["spi"].clone()
+                    }
+                    _ => continue,
+                };
+
+                if let Err(err) = spi
+                    .try_into()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|spi| check(&spi))
+                {
+                    panic!("{}: {}", path.display(), err);
+                }
+                checked += 1;
+            }
+        }
+
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn conflicts() {
+        let text = format!(
+            r#"{}
+            [config.spi.spi2.devices.sequencer]
+            description = "sequencer"
+            mux = "port_b"
+            cs = {{ port = "B", pin = 12 }}
+
+            [config.spi.spi2.devices.switch]
+            description = "switch"
+            mux = "port_i"
+            cs = {{ port = "I", pin = 3 }}
+            mode = 3
+
+            [config.spi.spi4.mux_options.port_e]
+            outputs = [ {{ port = "E", pins = [ 2, 6 ], af = 5 }} ]
+            input = {{ port = "I", pin = 2, af = 5 }}
+
+            [config.spi.spi4.devices.flash]
+            description = "another flash"
+            mux = "port_e"
+            cs = {{ port = "B", pin = 12 }}
+            clock_divider = 3
+            "#,
+            SPI2
+        );
+
+        let problems = problems(&text);
+
+        for problem in &[
+            "PI2 is used by both spi2 mux option port_i and spi4 mux option \
+            port_e",
+            "spi2 device switch: mode 3 idles SCK high",
+            "spi2 device switch: CS PI3 is also used by spi2 mux option port_i",
+            "device flash appears on both spi2 and spi4",
+            "spi4 device flash: clock divider 3",
+            "spi4 device flash: CS PB12 is already used by spi2 device flash",
+        ] {
+            assert!(problems.contains(problem), "{}", problems);
+        }
+
+        // the sequencer sharing the flash's CS on the same controller is fine
+        assert!(
+            problems.contains("config.spi has 6 problem(s)"),
+            "{}",
+            problems
+        );
+    }
+}
@@ -31,7 +31,8 @@ SECTIONS
 
   /*
    * Table of entry points for Hubris to get into the bootloader.
-   * table.ld containing the actual bytes is generated at runtime.
+   * table.ld containing the actual bytes is generated at runtime. It also
+   * defines __stage0_handoff, the address of the bootloader's DICE handoff.
    * Note the ALIGN requirement comes from TrustZone requirements.
    */
   .addr_table __erodata : ALIGN(32) {
@@ -94,6 +95,13 @@ SECTIONS
     KEEP(*(.task_slot_table));
   }
 
+  /* ## .stack_sizes */
+  /* Frame size of each function, emitted by rustc with -Z emit-stack-sizes.
+     Used to check task stack sizes during packaging. */
+  .stack_sizes (INFO) : {
+    KEEP(*(.stack_sizes));
+  }
+
   /* ## Discarded sections */
   /DISCARD/ :
   {
@@ -50,3 +50,13 @@ pub fn config<T: DeserializeOwned>() -> Result<T> {
     println!("cargo:rerun-if-env-changed=HUBRIS_APP_CONFIG");
     Ok(rval)
 }
+
+///
+/// Returns the name of the task being built, from the `HUBRIS_TASK_NAME`
+/// envvar.  This is set for each task in an application, but not for the
+/// kernel -- nor, of course, for standalone builds.
+///
+pub fn task_name() -> Result<String> {
+    println!("cargo:rerun-if-env-changed=HUBRIS_TASK_NAME");
+    Ok(env::var("HUBRIS_TASK_NAME")?)
+}
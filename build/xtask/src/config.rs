@@ -0,0 +1,554 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reading app.toml files, and what they inherit.
+//!
+//! An app.toml can build on others by naming them in `inherit`, as one path
+//! or a list of them, relative to the file doing the inheriting. The bases
+//! are read (along with whatever they inherit in turn) and merged in order,
+//! and then the file's own settings are merged on top:
+//!
+//! - Tables, like `tasks`, a task's `interrupts` or `config.i2c`, are merged
+//!   key by key, so a file can add a task, or change one setting of a task,
+//!   without repeating the rest.
+//! - Arrays, like `features`, `uses` or `config.i2c.devices`, are appended
+//!   to, leaving out anything that's already there.
+//! - Anything else replaces what the bases had.
+//!
+//! Tables keep the order in which their keys first appear, bases first; this
+//! matters for `tasks`, since a task's index is its place there.
+//!
+//! To take something out of what's inherited, list its dotted path in
+//! `remove`: `"tasks.hiffy"` drops a task, `"tasks.jefe.features"` drops all
+//! of a task's features so that the file can give them afresh, and
+//! `"tasks.jefe.features.itm"` drops just the one. Removals apply to the
+//! merged bases, before the file's own settings are merged in.
+//!
+//! Paths in a base, like a task's `path`, are taken relative to the app.toml
+//! being built, as if they'd been written there; keep bases in a directory
+//! beside the apps, like `app/common`, and they'll read the same either way.
+//!
+//! Everything in `xtask` reads app.toml files with `load`, and build scripts
+//! only see the merged result, through `HUBRIS_APP_CONFIG` and
+//! `HUBRIS_TASK_CONFIG`. To see it, run `cargo xtask dist --print-config`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use toml::value::Table;
+use toml::Value;
+
+use crate::Config;
+
+/// Reads and parses the app.toml at `cfg`, merged with what it inherits.
+pub(crate) fn load(cfg: &Path) -> Result<Config> {
+    let contents = std::fs::read(cfg)?;
+    let raw: Value = toml::from_slice(&contents)
+        .with_context(|| format!("failed to parse {}", cfg.display()))?;
+
+    // Parse files that don't inherit anything from their text, so that
+    // errors point at a line.
+    if raw.get("inherit").is_none() && raw.get("remove").is_none() {
+        return toml::from_slice(&contents)
+            .with_context(|| format!("failed to parse {}", cfg.display()));
+    }
+
+    read(cfg)?.try_into().with_context(|| {
+        format!(
+            "failed to parse {}, merged with what it inherits",
+            cfg.display()
+        )
+    })
+}
+
+/// Reads the app.toml at `cfg`, merged with what it inherits, without
+/// interpreting it further; `to_string` writes the result back out.
+pub fn read(cfg: &Path) -> Result<Value> {
+    read_merged(cfg, &mut vec![])
+}
+
+/// Writes out `value`, a whole app.toml, as TOML.
+///
+/// The `toml` crate can't write arrays that mix tables with other things,
+/// like a `task-slots` that renames one of its slots, so this writes any
+/// array that isn't all tables inline.
+pub fn to_string(value: &Value) -> String {
+    let mut out = String::new();
+    if let Value::Table(table) = value {
+        write_table(&mut out, &[], table);
+    }
+    out
+}
+
+fn write_table(out: &mut String, path: &[&str], table: &Table) {
+    // Plain values have to come before any of the table's subtables.
+    for (key, value) in table {
+        if is_plain(value) {
+            *out += &format!("{} = {}\n", key_str(key), inline(value));
+        }
+    }
+
+    for (key, value) in table {
+        let mut path = path.to_vec();
+        path.push(key);
+        let header = path.iter().map(|k| key_str(k)).collect::<Vec<_>>();
+        match value {
+            Value::Table(t) => {
+                // Leave out the headers of tables that only hold tables.
+                if t.is_empty() || t.values().any(is_plain) {
+                    *out += &format!("\n[{}]\n", header.join("."));
+                }
+                write_table(out, &path, t);
+            }
+            Value::Array(a) if !is_plain(value) => {
+                for t in a.iter().filter_map(Value::as_table) {
+                    *out += &format!("\n[[{}]]\n", header.join("."));
+                    write_table(out, &path, t);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Returns whether `value` is written as `key = value`, rather than under a
+/// header of its own: anything but a table or an array of tables.
+fn is_plain(value: &Value) -> bool {
+    match value {
+        Value::Table(_) => false,
+        Value::Array(a) => a.is_empty() || !a.iter().all(Value::is_table),
+        _ => true,
+    }
+}
+
+/// Writes `value` as it would appear after `key = `.
+fn inline(value: &Value) -> String {
+    match value {
+        Value::Array(a) => {
+            format!("[{}]", a.iter().map(inline).collect::<Vec<_>>().join(", "))
+        }
+        Value::Table(t) => format!(
+            "{{ {} }}",
+            t.iter()
+                .map(|(k, v)| format!("{} = {}", key_str(k), inline(v)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        v => v.to_string(),
+    }
+}
+
+/// Quotes `key`, unless it can be written bare.
+fn key_str(key: &str) -> String {
+    let bare = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if !key.is_empty() && key.chars().all(bare) {
+        key.to_string()
+    } else {
+        Value::String(key.to_string()).to_string()
+    }
+}
+
+/// Reads the file at `path`, given the files that are inheriting it, to
+/// catch cycles.
+fn read_merged(path: &Path, inheriting: &mut Vec<PathBuf>) -> Result<Value> {
+    let canonical = std::fs::canonicalize(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    if inheriting.contains(&canonical) {
+        bail!("{} inherits itself", path.display());
+    }
+
+    let contents = std::fs::read(path)?;
+    let value: Value = toml::from_slice(&contents)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    let mut table = match value {
+        Value::Table(table) => table,
+        _ => unreachable!("toml documents are tables"),
+    };
+
+    let bases = paths(path, take(&mut table, "inherit"), "inherit")?;
+    let removals = paths(path, take(&mut table, "remove"), "remove")?;
+    if bases.is_empty() {
+        if !removals.is_empty() {
+            bail!("{} has `remove` but doesn't inherit", path.display());
+        }
+        return Ok(Value::Table(table));
+    }
+
+    inheriting.push(canonical);
+    let mut merged = Table::new();
+    for base in &bases {
+        let base = path.parent().unwrap_or_else(|| Path::new(".")).join(base);
+        let value = read_merged(&base, inheriting)?;
+        if let Value::Table(base) = value {
+            merge(&mut merged, base);
+        }
+    }
+    inheriting.pop();
+
+    for removal in &removals {
+        if !remove(&mut merged, removal) {
+            bail!(
+                "{}: nothing to remove at `{}` in what it inherits",
+                path.display(),
+                removal
+            );
+        }
+    }
+
+    merge(&mut merged, table);
+    Ok(Value::Table(merged))
+}
+
+/// Removes `key` from `table`, keeping the order of the rest.
+fn take(table: &mut Table, key: &str) -> Option<Value> {
+    let value = table.get(key).cloned();
+    if value.is_some() {
+        *table = std::mem::take(table)
+            .into_iter()
+            .filter(|(k, _)| k != key)
+            .collect();
+    }
+    value
+}
+
+/// Makes a list of strings from `value`, which should be a string or an
+/// array of them, for the error messages about `path`'s `key`.
+fn paths(path: &Path, value: Option<Value>, key: &str) -> Result<Vec<String>> {
+    let strings = match value {
+        None => Some(vec![]),
+        Some(Value::String(s)) => Some(vec![s]),
+        Some(Value::Array(values)) => values
+            .into_iter()
+            .map(|v| match v {
+                Value::String(s) => Some(s),
+                _ => None,
+            })
+            .collect(),
+        Some(_) => None,
+    };
+    match strings {
+        Some(strings) => Ok(strings),
+        None => bail!(
+            "{}: `{}` should be a string or a list of strings",
+            path.display(),
+            key
+        ),
+    }
+}
+
+/// Merges `overlay` into `base`, by the rules in the module docs.
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(b)), Value::Table(o)) => merge(b, o),
+            (Some(Value::Array(b)), Value::Array(o)) => {
+                for item in o {
+                    if !b.contains(&item) {
+                        b.push(item);
+                    }
+                }
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Removes whatever is at the dotted `path` in `table`, returning whether
+/// there was anything there.
+fn remove(table: &mut Table, path: &str) -> bool {
+    let (parents, last) = match path.rsplit_once('.') {
+        Some((parents, last)) => (Some(parents), last),
+        None => (None, path),
+    };
+
+    let mut parent = Some(table);
+    let mut array = None;
+    for key in parents.into_iter().flat_map(|p| p.split('.')) {
+        match parent.and_then(|t| t.get_mut(key)) {
+            Some(Value::Table(t)) => parent = Some(t),
+            Some(Value::Array(a)) if array.is_none() => {
+                // Only the last part of the path can name something in an
+                // array.
+                array = Some(a);
+                parent = None;
+            }
+            _ => return false,
+        }
+    }
+
+    match (parent, array) {
+        (Some(table), None) => take(table, last).is_some(),
+        (None, Some(array)) => {
+            let before = array.len();
+            array.retain(|v| v.as_str() != Some(last));
+            array.len() != before
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `files` to a fresh directory, returning its path.
+    fn fixture(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "xtask-config-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, text) in files {
+            std::fs::write(dir.join(file), text).unwrap();
+        }
+        dir
+    }
+
+    fn parse(text: &str) -> Value {
+        toml::from_str(text).unwrap()
+    }
+
+    fn keys(value: &Value, path: &str) -> Vec<String> {
+        let mut value = value;
+        for key in path.split('.') {
+            value = &value[key];
+        }
+        value.as_table().unwrap().keys().cloned().collect()
+    }
+
+    fn error(dir: &Path, file: &str) -> String {
+        format!("{:#}", read(&dir.join(file)).unwrap_err())
+    }
+
+    #[test]
+    fn merges() {
+        let dir = fixture(
+            "merges",
+            &[
+                (
+                    "base.toml",
+                    r#"
+                    name = "base"
+                    stacksize = 1024
+
+                    [kernel]
+                    requires = {flash = 1, ram = 2}
+                    features = ["itm"]
+
+                    [tasks.a]
+                    features = ["x", "y"]
+                    priority = 0
+
+                    [tasks.b]
+                    priority = 1
+                    "#,
+                ),
+                (
+                    "app.toml",
+                    r#"
+                    inherit = "base.toml"
+                    stacksize = 2048
+
+                    [kernel]
+                    name = "app"
+                    features = ["y", "itm", "z"]
+
+                    [tasks.c]
+                    priority = 2
+
+                    [tasks.a]
+                    priority = 3
+                    "#,
+                ),
+            ],
+        );
+
+        let merged = read(&dir.join("app.toml")).unwrap();
+        assert_eq!(
+            merged,
+            parse(
+                r#"
+                name = "base"
+                stacksize = 2048
+
+                [kernel]
+                requires = {flash = 1, ram = 2}
+                features = ["itm", "y", "z"]
+                name = "app"
+
+                [tasks.a]
+                features = ["x", "y"]
+                priority = 3
+
+                [tasks.b]
+                priority = 1
+
+                [tasks.c]
+                priority = 2
+                "#
+            )
+        );
+        // Tasks are numbered in order, so it has to be the bases' first.
+        assert_eq!(keys(&merged, "tasks"), ["a", "b", "c"]);
+
+        // And what's written out reads back the same.
+        assert_eq!(parse(&to_string(&merged)), merged);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merges_bases_in_order() {
+        let dir = fixture(
+            "order",
+            &[
+                ("one.toml", "name = \"one\"\n[tasks.a]\n[tasks.b]\n"),
+                ("two.toml", "name = \"two\"\n[tasks.c]\n[tasks.a]\n"),
+                ("app.toml", "inherit = [\"one.toml\", \"two.toml\"]\n"),
+                ("sub.toml", "inherit = \"app.toml\"\n[tasks.d]\n"),
+            ],
+        );
+
+        let merged = read(&dir.join("app.toml")).unwrap();
+        assert_eq!(merged["name"].as_str(), Some("two"));
+        assert_eq!(keys(&merged, "tasks"), ["a", "b", "c"]);
+
+        // Bases can inherit in turn.
+        let merged = read(&dir.join("sub.toml")).unwrap();
+        assert_eq!(keys(&merged, "tasks"), ["a", "b", "c", "d"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removes() {
+        let dir = fixture(
+            "removes",
+            &[
+                (
+                    "base.toml",
+                    r#"
+                    [tasks.a]
+                    features = ["x", "y"]
+                    [tasks.b]
+                    features = ["x"]
+                    [tasks.c]
+                    features = ["x", "y"]
+                    "#,
+                ),
+                (
+                    "app.toml",
+                    r#"
+                    inherit = "base.toml"
+                    remove = [
+                        "tasks.b",
+                        "tasks.a.features.x",
+                        "tasks.c.features",
+                    ]
+
+                    # Removals happen before this is merged in, so this
+                    # replaces the features rather than adding to them.
+                    [tasks.c]
+                    features = ["z"]
+                    "#,
+                ),
+            ],
+        );
+
+        assert_eq!(
+            read(&dir.join("app.toml")).unwrap(),
+            parse(
+                r#"
+                [tasks.a]
+                features = ["y"]
+                [tasks.c]
+                features = ["z"]
+                "#
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        let dir = fixture(
+            "errors",
+            &[
+                ("base.toml", "[tasks.a]\nfeatures = [\"x\"]\n"),
+                ("gone.toml", "inherit = \"base.toml\"\nremove = \"tasks.b\"\n"),
+                (
+                    "element.toml",
+                    "inherit = \"base.toml\"\nremove = \"tasks.a.features.y\"\n",
+                ),
+                ("orphan.toml", "remove = \"tasks.a\"\n"),
+                ("loop.toml", "inherit = \"loop2.toml\"\n"),
+                ("loop2.toml", "inherit = \"loop.toml\"\n"),
+                ("number.toml", "inherit = 1\n"),
+            ],
+        );
+
+        assert!(error(&dir, "gone.toml")
+            .contains("nothing to remove at `tasks.b` in what it inherits"));
+        assert!(error(&dir, "element.toml")
+            .contains("nothing to remove at `tasks.a.features.y`"));
+        assert!(error(&dir, "orphan.toml")
+            .contains("has `remove` but doesn't inherit"));
+        assert!(error(&dir, "loop.toml").contains("inherits itself"));
+        assert!(error(&dir, "number.toml")
+            .contains("`inherit` should be a string or a list of strings"));
+        assert!(error(&dir, "missing.toml").contains("failed to read"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Checks the apps that inherit our common STM32H7 settings.
+    #[test]
+    fn apps() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("../..");
+        let base = parse(
+            &std::fs::read_to_string(root.join("app/common/stm32h7.toml"))
+                .unwrap(),
+        );
+
+        for app in ["gemini-bu", "gimlet", "gimletlet", "sidecar"] {
+            let path = root.join("app").join(app).join("app.toml");
+            let own = parse(&std::fs::read_to_string(&path).unwrap());
+            let merged = read(&path).unwrap();
+
+            // The base's tasks come first, then the app's own.
+            let tasks = keys(&merged, "tasks");
+            let base_tasks = keys(&base, "tasks");
+            assert_eq!(tasks[..base_tasks.len()], base_tasks[..], "{}", app);
+            assert_eq!(
+                tasks.len(),
+                base_tasks.len() + keys(&own, "tasks").len()
+            );
+
+            // Each of the base's settings survives, next to the app's own.
+            assert_eq!(merged["target"], base["target"], "{}", app);
+            assert_eq!(merged["outputs"], base["outputs"], "{}", app);
+            assert_eq!(
+                merged["tasks"]["jefe"], base["tasks"]["jefe"],
+                "{}",
+                app
+            );
+            assert_eq!(
+                merged["kernel"]["requires"], base["kernel"]["requires"],
+                "{}",
+                app
+            );
+            assert_eq!(
+                merged["kernel"]["name"], own["kernel"]["name"],
+                "{}",
+                app
+            );
+            assert!(merged.get("inherit").is_none());
+
+            assert_eq!(parse(&to_string(&merged)), merged, "{}", app);
+            let config = load(&path).unwrap();
+            assert_eq!(config.tasks.len(), tasks.len());
+        }
+    }
+}
@@ -0,0 +1,523 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A machine-readable description of the image `xtask dist` built.
+//!
+//! This is the app descriptor the kernel reads, with names attached: every
+//! region, each task's regions, interrupts and resolved task slots, where the
+//! kernel and tasks landed, plus the peripherals and the i2c device inventory.
+//! It's written to `info/app.json` in the build archive, so that tools outside
+//! xtask needn't re-parse the app.toml and work out how `make_descriptors`
+//! laid things out.
+//!
+//! Fields may be added at any time; `version` is bumped when one changes
+//! meaning or goes away.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use anyhow::Result;
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::dist::AppTable;
+use crate::Config;
+
+/// Version of the format, bumped when a field changes meaning or goes away.
+const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct AppDescriptor {
+    pub version: u32,
+    pub name: String,
+    pub board: String,
+    pub target: String,
+    pub kernel: Kernel,
+    /// Notification the supervisor gets when a task faults.
+    pub fault_notification: Option<u32>,
+    /// The descriptor's region table, by index.
+    pub regions: Vec<Region>,
+    pub peripherals: IndexMap<String, Peripheral>,
+    /// Each task, by index.
+    pub tasks: Vec<Task>,
+    pub i2c_devices: Vec<I2cDevice>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Kernel {
+    pub entry_point: u32,
+    /// Memory allocated to the kernel, by output.
+    pub memory: BTreeMap<String, Span>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Span {
+    pub base: u32,
+    pub size: u32,
+}
+
+impl From<&Range<u32>> for Span {
+    fn from(range: &Range<u32>) -> Self {
+        Self {
+            base: range.start,
+            size: range.end - range.start,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Region {
+    pub index: usize,
+    pub base: u32,
+    pub size: u32,
+    /// Any of `read`, `write`, `execute`, `device` and `dma`.
+    pub attributes: Vec<&'static str>,
+    /// The peripheral or task the region belongs to; none for region 0,
+    /// which gives no access to anything.
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Peripheral {
+    pub address: u32,
+    pub size: u32,
+    /// Index of the peripheral's region.
+    pub region: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Task {
+    pub index: usize,
+    pub name: String,
+    /// The crate the task was built from.
+    pub package: String,
+    pub priority: u32,
+    pub start: bool,
+    pub entry_point: u32,
+    pub initial_stack: u32,
+    /// Indices of the task's regions, leaving out region 0.
+    pub regions: Vec<usize>,
+    /// Memory allocated to the task, by output.
+    pub memory: BTreeMap<String, Span>,
+    /// Peripherals the task uses, by name.
+    pub uses: Vec<String>,
+    /// Notification mask posted to the task for each IRQ number.
+    pub interrupts: BTreeMap<u32, u32>,
+    /// The task each of its task slots names.
+    pub task_slots: IndexMap<String, TaskSlot>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskSlot {
+    pub task: String,
+    pub index: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct I2cDevice {
+    pub device: String,
+    pub name: Option<String>,
+    /// The bus the device is on, if it's named.
+    pub bus: Option<String>,
+    pub description: String,
+    pub refdes: Option<String>,
+    /// The controller the device hangs off, resolved from its bus if need be.
+    pub controller: u8,
+    pub port: String,
+    pub address: u8,
+    pub mux: Option<u8>,
+    pub segment: Option<u8>,
+    pub removable: bool,
+    /// PMBus rails, if the device has any.
+    pub rails: Vec<String>,
+}
+
+impl AppDescriptor {
+    pub(crate) fn new(
+        toml: &Config,
+        table: &AppTable,
+        kernel_entry: u32,
+        kernel_memory: &BTreeMap<String, Range<u32>>,
+        task_memory: &BTreeMap<String, BTreeMap<String, Range<u32>>>,
+    ) -> Result<Self> {
+        // Regions are laid out as the null region, then peripherals, then
+        // extra text, then each task's memory in turn.
+        let mut owners = vec![None];
+        owners.extend(
+            toml.peripherals
+                .keys()
+                .chain(toml.extratext.keys())
+                .map(|name| Some(name.clone())),
+        );
+        for name in toml.tasks.keys() {
+            for _ in &task_memory[name] {
+                owners.push(Some(name.clone()));
+            }
+        }
+
+        let regions = table
+            .regions
+            .iter()
+            .zip(owners)
+            .enumerate()
+            .map(|(index, (region, owner))| Region {
+                index,
+                base: region.base,
+                size: region.size,
+                attributes: attributes(region.attributes),
+                owner,
+            })
+            .collect();
+
+        let peripherals = toml
+            .peripherals
+            .iter()
+            .enumerate()
+            .map(|(i, (name, p))| {
+                let peripheral = Peripheral {
+                    address: p.address,
+                    size: p.size,
+                    region: i + 1,
+                };
+                (name.clone(), peripheral)
+            })
+            .collect();
+
+        let tasks = toml
+            .tasks
+            .iter()
+            .zip(&table.tasks)
+            .enumerate()
+            .map(|(index, ((name, task), desc))| Task {
+                index,
+                name: name.clone(),
+                package: task.name.clone(),
+                priority: desc.priority,
+                start: desc.flags.contains(abi::TaskFlags::START_AT_BOOT),
+                entry_point: desc.entry_point,
+                initial_stack: desc.initial_stack,
+                regions: desc
+                    .regions
+                    .iter()
+                    .filter(|&&r| r != 0)
+                    .map(|&r| usize::from(r))
+                    .collect(),
+                memory: task_memory[name]
+                    .iter()
+                    .map(|(mem, range)| (mem.clone(), range.into()))
+                    .collect(),
+                uses: task.uses.clone(),
+                interrupts: table
+                    .irqs
+                    .iter()
+                    .filter(|irq| irq.task as usize == index)
+                    .map(|irq| (irq.irq, irq.notification))
+                    .collect(),
+                task_slots: task
+                    .task_slots
+                    .iter()
+                    .filter_map(|(slot, target)| {
+                        let index = toml.tasks.get_index_of(target)?;
+                        let slot_target = TaskSlot {
+                            task: target.clone(),
+                            index,
+                        };
+                        Some((slot.clone(), slot_target))
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Ok(Self {
+            version: VERSION,
+            name: toml.name.clone(),
+            board: toml.board.clone(),
+            target: toml.target.clone(),
+            kernel: Kernel {
+                entry_point: kernel_entry,
+                memory: kernel_memory
+                    .iter()
+                    .map(|(mem, range)| (mem.clone(), range.into()))
+                    .collect(),
+            },
+            fault_notification: table.fault_notification,
+            regions,
+            peripherals,
+            tasks,
+            i2c_devices: i2c_devices(toml)?,
+        })
+    }
+}
+
+fn attributes(attributes: abi::RegionAttributes) -> Vec<&'static str> {
+    use abi::RegionAttributes as A;
+
+    [
+        (A::READ, "read"),
+        (A::WRITE, "write"),
+        (A::EXECUTE, "execute"),
+        (A::DEVICE, "device"),
+        (A::DMA, "dma"),
+    ]
+    .iter()
+    .filter(|(flag, _)| attributes.contains(*flag))
+    .map(|&(_, name)| name)
+    .collect()
+}
+
+/// Lists the devices in `config.i2c`, finding each one's controller and port
+/// with `build-i2c`, so that we agree with the code it generates.
+fn i2c_devices(toml: &Config) -> Result<Vec<I2cDevice>> {
+    let i2c = match toml.config.as_ref().and_then(|c| c.get("i2c")) {
+        Some(i2c) => i2c.clone(),
+        None => return Ok(vec![]),
+    };
+
+    Ok(build_i2c::devices(i2c)?
+        .into_iter()
+        .map(|d| I2cDevice {
+            device: d.device,
+            name: d.name,
+            bus: d.bus,
+            description: d.description,
+            refdes: d.refdes,
+            controller: d.controller,
+            port: d.port,
+            address: d.address,
+            mux: d.segment.map(|(mux, _)| mux),
+            segment: d.segment.map(|(_, segment)| segment),
+            removable: d.removable,
+            rails: d.rails,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP: &str = r#"
+        name = "demo"
+        target = "thumbv7em-none-eabihf"
+        board = "demo-board"
+
+        [kernel]
+        path = "."
+        name = "kernel"
+        requires = { flash = 1024, ram = 1024 }
+
+        [outputs.flash]
+        address = 0x08000000
+        size = 0x10000
+        read = true
+        execute = true
+
+        [outputs.ram]
+        address = 0x24000000
+        size = 0x10000
+        read = true
+        write = true
+
+        [supervisor]
+        notification = 1
+
+        [peripherals.i2c2]
+        address = 0x40005800
+        size = 1024
+
+        [tasks.jefe]
+        path = "."
+        name = "task-jefe"
+        priority = 0
+
+        [tasks.i2c_driver]
+        path = "."
+        name = "drv-stm32h7-i2c-server"
+        priority = 1
+        uses = ["i2c2"]
+        task-slots = ["jefe"]
+        interrupts = { 33 = 0b10 }
+
+        [[config.i2c.controllers]]
+        controller = 2
+        [config.i2c.controllers.ports.F]
+        name = "front"
+        pins = [ { pins = [ 0, 1 ], af = 4 } ]
+        muxes = [ { driver = "pca9548", address = 0x70 } ]
+
+        [[config.i2c.devices]]
+        device = "tmp117"
+        bus = "front"
+        mux = 1
+        segment = 2
+        address = 0x48
+        description = "Front temperature sensor"
+        refdes = "U1"
+    "#;
+
+    fn region(base: u32, size: u32, attributes: u32) -> abi::RegionDesc {
+        abi::RegionDesc {
+            base,
+            size,
+            attributes: abi::RegionAttributes::from_bits_truncate(attributes),
+            reserved_zero: 0,
+        }
+    }
+
+    fn task(
+        regions: [u8; 2],
+        entry_point: u32,
+        priority: u32,
+    ) -> abi::TaskDesc {
+        abi::TaskDesc {
+            regions: [regions[0], regions[1], 0, 0, 0, 0, 0, 0],
+            entry_point,
+            initial_stack: entry_point + 0x0100_0000,
+            priority,
+            flags: abi::TaskFlags::empty(),
+        }
+    }
+
+    #[test]
+    fn json() -> Result<()> {
+        let toml: Config = toml::from_str(APP)?;
+        let table = AppTable {
+            regions: vec![
+                region(0, 32, 0),
+                region(0x4000_5800, 1024, 0b1011),
+                region(0x0800_0400, 0x400, 0b101),
+                region(0x0800_0800, 0x400, 0b101),
+            ],
+            tasks: vec![
+                task([2, 0], 0x0800_0401, 0),
+                task([3, 1], 0x0800_0801, 1),
+            ],
+            irqs: vec![abi::Interrupt {
+                irq: 33,
+                task: 1,
+                notification: 0b10,
+            }],
+            fault_notification: Some(1),
+        };
+        let span = |mem: &str, start: u32| {
+            let memory = [(mem.to_string(), start..start + 0x400)];
+            memory.iter().cloned().collect::<BTreeMap<_, _>>()
+        };
+        let mut tasks = BTreeMap::new();
+        tasks.insert("jefe".to_string(), span("flash", 0x0800_0400));
+        tasks.insert("i2c_driver".to_string(), span("flash", 0x0800_0800));
+
+        let app = AppDescriptor::new(
+            &toml,
+            &table,
+            0x0800_0001,
+            &span("flash", 0x0800_0000),
+            &tasks,
+        )?;
+
+        assert_eq!(
+            serde_json::to_value(&app)?,
+            serde_json::json!({
+                "version": 1,
+                "name": "demo",
+                "board": "demo-board",
+                "target": "thumbv7em-none-eabihf",
+                "kernel": {
+                    "entry_point": 0x0800_0001,
+                    "memory": {
+                        "flash": { "base": 0x0800_0000, "size": 0x400 },
+                    },
+                },
+                "fault_notification": 1,
+                "regions": [
+                    {
+                        "index": 0, "base": 0, "size": 32,
+                        "attributes": [], "owner": null,
+                    },
+                    {
+                        "index": 1, "base": 0x4000_5800, "size": 1024,
+                        "attributes": ["read", "write", "device"],
+                        "owner": "i2c2",
+                    },
+                    {
+                        "index": 2, "base": 0x0800_0400, "size": 0x400,
+                        "attributes": ["read", "execute"],
+                        "owner": "jefe",
+                    },
+                    {
+                        "index": 3, "base": 0x0800_0800, "size": 0x400,
+                        "attributes": ["read", "execute"],
+                        "owner": "i2c_driver",
+                    },
+                ],
+                "peripherals": {
+                    "i2c2": {
+                        "address": 0x4000_5800, "size": 1024, "region": 1,
+                    },
+                },
+                "tasks": [
+                    {
+                        "index": 0,
+                        "name": "jefe",
+                        "package": "task-jefe",
+                        "priority": 0,
+                        "start": false,
+                        "entry_point": 0x0800_0401,
+                        "initial_stack": 0x0900_0401,
+                        "regions": [2],
+                        "memory": {
+                            "flash": { "base": 0x0800_0400, "size": 0x400 },
+                        },
+                        "uses": [],
+                        "interrupts": {},
+                        "task_slots": {},
+                    },
+                    {
+                        "index": 1,
+                        "name": "i2c_driver",
+                        "package": "drv-stm32h7-i2c-server",
+                        "priority": 1,
+                        "start": false,
+                        "entry_point": 0x0800_0801,
+                        "initial_stack": 0x0900_0801,
+                        "regions": [3, 1],
+                        "memory": {
+                            "flash": { "base": 0x0800_0800, "size": 0x400 },
+                        },
+                        "uses": ["i2c2"],
+                        "interrupts": { "33": 2 },
+                        "task_slots": {
+                            "jefe": { "task": "jefe", "index": 0 },
+                        },
+                    },
+                ],
+                "i2c_devices": [
+                    {
+                        "device": "tmp117",
+                        "name": null,
+                        "bus": "front",
+                        "description": "Front temperature sensor",
+                        "refdes": "U1",
+                        "controller": 2,
+                        "port": "F",
+                        "address": 0x48,
+                        "mux": 1,
+                        "segment": 2,
+                        "removable": false,
+                        "rails": [],
+                    },
+                ],
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bad_i2c() {
+        let app = APP.replace("bus = \"front\"", "bus = \"back\"");
+        let toml: Config = toml::from_str(&app).unwrap();
+        let err = i2c_devices(&toml).unwrap_err();
+        assert!(err.to_string().contains("unknown bus \"back\""), "{}", err);
+    }
+}
@@ -0,0 +1,525 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Comparing two build archives.
+//!
+//! `xtask diff` reads two archives made by `xtask dist` and reports what a
+//! change did to the image: how much of each region each component uses,
+//! where its regions are, the size of every symbol in each component, which
+//! tasks interrupts go to, what each task slot names, the app descriptor the
+//! kernel was built with, and any other setting in the app.toml. Everything
+//! comes from the archives, so neither needs to match the current tree.
+//!
+//! The comparison is printed, or with `--json`, written out in a form meant
+//! for CI to turn into comments.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use goblin::elf::sym::{STT_FUNC, STT_OBJECT};
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::dist::AppTable;
+use crate::sizes::SizeReport;
+use crate::{elf, stack, Config};
+
+/// How many symbols to show per component, when printing.
+const SYMBOLS_SHOWN: usize = 10;
+
+#[derive(Debug, Default, Serialize)]
+pub struct Diff {
+    /// Bytes used, by component and memory.
+    pub sizes: Vec<Change<u32>>,
+    /// Each component's region, by component and memory.
+    pub regions: Vec<Change<Region>>,
+    /// Size of each symbol, by component, biggest changes first.
+    pub symbols: IndexMap<String, Vec<Change<u64>>>,
+    /// The task and notification bits for each interrupt.
+    pub interrupts: Vec<Change<String>>,
+    /// The task each task slot names, by task and slot.
+    pub task_slots: Vec<Change<String>>,
+    /// Records in the app descriptor, as the kernel will see them.
+    pub descriptor: Vec<Change<String>>,
+    /// Every other app.toml setting, by dotted path.
+    pub config: Vec<Change<String>>,
+}
+
+/// Something that differs between the builds; `None` means it's not there.
+#[derive(Debug, Serialize)]
+pub struct Change<T> {
+    pub what: String,
+    pub old: Option<T>,
+    pub new: Option<T>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Region {
+    pub base: u32,
+    pub size: u32,
+}
+
+/// The parts of a build archive we compare.
+struct Build {
+    config: Config,
+    /// The app.toml as written, for comparing settings we don't interpret.
+    raw_config: toml::Value,
+    sizes: Option<SizeReport>,
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl Build {
+    fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let mut zip = zip::ZipArchive::new(file)?;
+
+        let mut files = BTreeMap::new();
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i)?;
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            files.insert(entry.name().to_string(), data);
+        }
+
+        let app_toml = files
+            .get("app.toml")
+            .ok_or_else(|| anyhow!("{} has no app.toml", path.display()))?;
+        let config = toml::from_slice(app_toml).with_context(|| {
+            format!("failed to parse the app.toml in {}", path.display())
+        })?;
+        let raw_config = toml::from_slice(app_toml)?;
+
+        // Archives from before we measured sizes don't have this.
+        let sizes = match files.get("info/sizes.json") {
+            Some(json) => Some(serde_json::from_slice(json)?),
+            None => {
+                eprintln!(
+                    "warning: {} has no info/sizes.json, so sizes and \
+                     regions can't be compared",
+                    path.display()
+                );
+                None
+            }
+        };
+
+        Ok(Self {
+            config,
+            raw_config,
+            sizes,
+            files,
+        })
+    }
+
+    /// Returns the ELF file for `component`, which is a task or `kernel`.
+    fn elf(&self, component: &str) -> Option<&[u8]> {
+        let path = if component == "kernel" {
+            "elf/kernel".to_string()
+        } else {
+            format!("elf/task/{}", component)
+        };
+        self.files.get(&path).map(|f| &f[..])
+    }
+
+    /// Returns each component, starting with the kernel.
+    fn components(&self) -> Vec<String> {
+        std::iter::once("kernel".to_string())
+            .chain(self.config.tasks.keys().cloned())
+            .collect()
+    }
+
+    fn usage(&self) -> (BTreeMap<String, u32>, BTreeMap<String, Region>) {
+        let mut used = BTreeMap::new();
+        let mut regions = BTreeMap::new();
+        for c in self.sizes.iter().flat_map(|s| &s.components) {
+            for (mem, r) in &c.regions {
+                let what = format!("{} {}", c.name, mem);
+                used.insert(what.clone(), r.used);
+                regions.insert(
+                    what,
+                    Region {
+                        base: r.base,
+                        size: r.size,
+                    },
+                );
+            }
+        }
+        (used, regions)
+    }
+
+    fn interrupts(&self) -> BTreeMap<String, String> {
+        let mut irqs = BTreeMap::new();
+        for (name, task) in &self.config.tasks {
+            for (irq, bits) in &task.interrupts {
+                irqs.insert(
+                    format!("irq {}", irq),
+                    format!("{} (notification {:#x})", name, bits),
+                );
+            }
+        }
+        irqs
+    }
+
+    fn task_slots(&self) -> BTreeMap<String, String> {
+        let mut slots = BTreeMap::new();
+        for (name, task) in &self.config.tasks {
+            for (slot, target) in &task.task_slots {
+                slots.insert(format!("{}.{}", name, slot), target.clone());
+            }
+        }
+        slots
+    }
+
+    /// Decodes the app descriptor from the kernel's `.hubris_app_table`.
+    fn descriptor(&self) -> Result<BTreeMap<String, String>> {
+        let kernel = match self.elf("kernel") {
+            Some(kernel) => kernel,
+            None => return Ok(BTreeMap::new()),
+        };
+        let elf = goblin::elf::Elf::parse(kernel)?;
+        let section = elf::get_section_by_name(&elf, ".hubris_app_table")
+            .ok_or_else(|| anyhow!("kernel has no .hubris_app_table"))?;
+        let bytes = section
+            .file_range()
+            .and_then(|range| kernel.get(range))
+            .ok_or_else(|| {
+                anyhow!("kernel's .hubris_app_table is truncated")
+            })?;
+        let words = bytes
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect::<Vec<_>>();
+        let table = AppTable::from_words(&words)
+            .context("failed to read the kernel's .hubris_app_table")?;
+
+        let names = self.config.tasks.keys().collect::<Vec<_>>();
+        Ok(records(&table, &names))
+    }
+
+    /// Returns every setting in the app.toml by dotted path, except for the
+    /// ones compared on their own.
+    fn settings(&self) -> BTreeMap<String, String> {
+        fn flatten(
+            prefix: &str,
+            value: &toml::Value,
+            out: &mut BTreeMap<String, String>,
+        ) {
+            match value {
+                toml::Value::Table(table) => {
+                    for (key, value) in table {
+                        let path = if prefix.is_empty() {
+                            key.clone()
+                        } else {
+                            format!("{}.{}", prefix, key)
+                        };
+                        flatten(&path, value, out);
+                    }
+                }
+                _ => {
+                    out.insert(prefix.to_string(), value.to_string());
+                }
+            }
+        }
+
+        let mut settings = BTreeMap::new();
+        flatten("", &self.raw_config, &mut settings);
+        settings.retain(|path, _| {
+            let mut parts = path.split('.');
+            !(parts.next() == Some("tasks")
+                && matches!(
+                    parts.nth(1),
+                    Some("interrupts") | Some("task-slots")
+                ))
+        });
+        settings
+    }
+}
+
+/// Describes each record in `table`, naming tasks by `names`.
+fn records(table: &AppTable, names: &[&String]) -> BTreeMap<String, String> {
+    let mut records = BTreeMap::new();
+    records.insert(
+        "header".to_string(),
+        format!(
+            "fault notification {:#x}",
+            table.fault_notification.unwrap_or(0)
+        ),
+    );
+    for (i, r) in table.regions.iter().enumerate() {
+        records.insert(
+            format!("region {}", i),
+            format!(
+                "{:#010x}+{:#x} attributes {:#x}",
+                r.base,
+                r.size,
+                r.attributes.bits()
+            ),
+        );
+    }
+    for (i, t) in table.tasks.iter().enumerate() {
+        let what = match names.get(i) {
+            Some(name) => format!("task {}", name),
+            None => format!("task {}", i),
+        };
+        records.insert(
+            what,
+            format!(
+                "entry {:#010x} stack {:#010x} priority {} flags {:#x} \
+                 regions {:?}",
+                t.entry_point,
+                t.initial_stack,
+                t.priority,
+                t.flags.bits(),
+                t.regions
+            ),
+        );
+    }
+    for irq in &table.irqs {
+        records.insert(
+            format!("irq {}", irq.irq),
+            format!("task {} notification {:#x}", irq.task, irq.notification),
+        );
+    }
+    records
+}
+
+/// Returns the size of each function and object in `elf`, by demangled name.
+/// Symbols that demangle to the same name are added together.
+fn symbols(elf: &[u8]) -> Result<BTreeMap<String, u64>> {
+    let elf = goblin::elf::Elf::parse(elf)?;
+    let mut sizes = BTreeMap::new();
+    for sym in elf.syms.iter() {
+        let kind = sym.st_type();
+        if (kind != STT_FUNC && kind != STT_OBJECT) || sym.st_size == 0 {
+            continue;
+        }
+        let name = elf.strtab.get_at(sym.st_name).unwrap_or("?");
+        *sizes.entry(stack::demangle(name)).or_default() += sym.st_size;
+    }
+    Ok(sizes)
+}
+
+/// Lists everything that differs between `old` and `new`.
+fn compare<T: Clone + PartialEq>(
+    old: &BTreeMap<String, T>,
+    new: &BTreeMap<String, T>,
+) -> Vec<Change<T>> {
+    let mut keys = old.keys().chain(new.keys()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+
+    keys.into_iter()
+        .filter(|k| old.get(*k) != new.get(*k))
+        .map(|k| Change {
+            what: k.clone(),
+            old: old.get(k).cloned(),
+            new: new.get(k).cloned(),
+        })
+        .collect()
+}
+
+impl Diff {
+    fn new(old: &Build, new: &Build) -> Result<Self> {
+        let (old_used, old_regions) = old.usage();
+        let (new_used, new_regions) = new.usage();
+
+        let mut by_component = IndexMap::new();
+        for name in new.components() {
+            if let (Some(o), Some(n)) = (old.elf(&name), new.elf(&name)) {
+                let mut changes = compare(&symbols(o)?, &symbols(n)?);
+                changes.sort_by_key(|c| {
+                    let delta =
+                        c.new.unwrap_or(0) as i64 - c.old.unwrap_or(0) as i64;
+                    std::cmp::Reverse(delta.abs())
+                });
+                if !changes.is_empty() {
+                    by_component.insert(name, changes);
+                }
+            }
+        }
+
+        Ok(Self {
+            sizes: compare(&old_used, &new_used),
+            regions: compare(&old_regions, &new_regions),
+            symbols: by_component,
+            interrupts: compare(&old.interrupts(), &new.interrupts()),
+            task_slots: compare(&old.task_slots(), &new.task_slots()),
+            descriptor: compare(&old.descriptor()?, &new.descriptor()?),
+            config: compare(&old.settings(), &new.settings()),
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+            && self.regions.is_empty()
+            && self.symbols.is_empty()
+            && self.interrupts.is_empty()
+            && self.task_slots.is_empty()
+            && self.descriptor.is_empty()
+            && self.config.is_empty()
+    }
+
+    fn print(&self) {
+        if self.is_empty() {
+            println!("no differences");
+            return;
+        }
+
+        if !self.sizes.is_empty() {
+            println!("bytes used:");
+            for c in &self.sizes {
+                print_change("  ", c, |s| s.to_string(), &delta(c));
+            }
+        }
+        let region = |r: &Region| format!("{:#010x}+{:#x}", r.base, r.size);
+        print_changes("regions", &self.regions, region);
+
+        if !self.symbols.is_empty() {
+            println!("symbol sizes:");
+            for (component, changes) in &self.symbols {
+                println!("  {}:", component);
+                for c in changes.iter().take(SYMBOLS_SHOWN) {
+                    print_change("    ", c, |s| s.to_string(), &delta(c));
+                }
+                if changes.len() > SYMBOLS_SHOWN {
+                    println!(
+                        "    ... and {} more",
+                        changes.len() - SYMBOLS_SHOWN
+                    );
+                }
+            }
+        }
+
+        let text = |s: &String| s.clone();
+        print_changes("interrupts", &self.interrupts, text);
+        print_changes("task slots", &self.task_slots, text);
+        print_changes("app descriptor", &self.descriptor, text);
+        print_changes("app.toml", &self.config, text);
+    }
+}
+
+/// Returns how much a size grew, for printing after it.
+fn delta<T: Copy + Into<u64>>(c: &Change<T>) -> String {
+    let old = c.old.map_or(0, Into::into) as i64;
+    let new = c.new.map_or(0, Into::into) as i64;
+    format!(" ({:+})", new - old)
+}
+
+fn print_changes<T>(
+    title: &str,
+    changes: &[Change<T>],
+    show: impl Fn(&T) -> String,
+) {
+    if changes.is_empty() {
+        return;
+    }
+    println!("{}:", title);
+    for c in changes {
+        print_change("  ", c, &show, "");
+    }
+}
+
+fn print_change<T>(
+    indent: &str,
+    c: &Change<T>,
+    show: impl Fn(&T) -> String,
+    suffix: &str,
+) {
+    let old = c.old.as_ref().map_or("(none)".to_string(), &show);
+    let new = c.new.as_ref().map_or("(none)".to_string(), &show);
+    println!("{}{}: {} -> {}{}", indent, c.what, old, new, suffix);
+}
+
+pub fn run(old: &Path, new: &Path, json: bool) -> Result<()> {
+    let old = Build::read(old)?;
+    let new = Build::read(new)?;
+    let diff = Diff::new(&old, &new)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        diff.print();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(entries: &[(&str, u32)]) -> BTreeMap<String, u32> {
+        entries.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    #[test]
+    fn changes() {
+        let old = map(&[("kernel flash", 100), ("ping flash", 50)]);
+        let new = map(&[("kernel flash", 120), ("pong flash", 60)]);
+        let changes = compare(&old, &new)
+            .into_iter()
+            .map(|c| (c.what, c.old, c.new))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                ("kernel flash".to_string(), Some(100), Some(120)),
+                ("ping flash".to_string(), Some(50), None),
+                ("pong flash".to_string(), None, Some(60)),
+            ]
+        );
+        assert!(compare(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn descriptor_records() {
+        let table = AppTable {
+            regions: vec![abi::RegionDesc {
+                base: 0x0800_0000,
+                size: 0x1000,
+                attributes: abi::RegionAttributes::READ
+                    | abi::RegionAttributes::EXECUTE,
+                reserved_zero: 0,
+            }],
+            tasks: vec![
+                abi::TaskDesc {
+                    regions: [0, 1, 0, 0, 0, 0, 0, 0],
+                    entry_point: 0x0800_0101,
+                    initial_stack: 0x2400_0400,
+                    priority: 0,
+                    flags: abi::TaskFlags::START_AT_BOOT,
+                };
+                2
+            ],
+            irqs: vec![abi::Interrupt {
+                irq: 33,
+                task: 1,
+                notification: 0b10,
+            }],
+            fault_notification: None,
+        };
+
+        // A task we have no name for is known by its index.
+        let jefe = "jefe".to_string();
+        let records = records(&table, &[&jefe]);
+        let task = "entry 0x08000101 stack 0x24000400 priority 0 flags 0x1 \
+                    regions [0, 1, 0, 0, 0, 0, 0, 0]";
+        let expected = [
+            ("header", "fault notification 0x0"),
+            ("irq 33", "task 1 notification 0x2"),
+            ("region 0", "0x08000000+0x1000 attributes 0x5"),
+            ("task 1", task),
+            ("task jefe", task),
+        ];
+        assert_eq!(
+            records,
+            expected
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        );
+    }
+}
@@ -16,9 +16,14 @@ use anyhow::{anyhow, bail, Context, Result};
 use indexmap::IndexMap;
 use path_slash::PathBufExt;
 
+use crate::descriptor::AppDescriptor;
+use crate::ipc::IpcGraph;
+use crate::manifest::{self, Manifest};
+use crate::sizes::{Component, SizeReport};
+use crate::stack::{self, TaskStack};
 use crate::{
-    elf, task_slot, Config, LoadSegment, Output, Peripheral, Signing,
-    Supervisor, Task,
+    config, elf, task_slot, validate, Config, LoadSegment, Output, Peripheral,
+    Requirement, Signing, Supervisor, Task,
 };
 
 use lpc55_sign::{crc_image, sign_ecc, signed_image};
@@ -28,14 +33,23 @@ use lpc55_sign::{crc_image, sign_ecc, signed_image};
 /// padded that a bit.
 const DEFAULT_KERNEL_STACK: u32 = 1024;
 
+/// Flash that stage0 lets tasks write, for testing, right after image A: one
+/// LPC55 flash page.
+const TEST_REGION_SIZE: u32 = 0x200;
+
+/// Flash that stage0 keeps its slot records in, right after the test region:
+/// one LPC55 flash page.
+const SLOT_RECORDS_SIZE: u32 = 0x200;
+
 pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
-    let cfg_contents = std::fs::read(&cfg)?;
-    let toml: Config = toml::from_slice(&cfg_contents)?;
+    let mut toml: Config = config::load(cfg)?;
 
+    // Hash the app.toml as merged, so that changing what it inherits also
+    // counts as changing it.
+    let merged = config::to_string(&config::read(cfg)?);
     let mut hasher = DefaultHasher::new();
-    hasher.write(&cfg_contents);
+    hasher.write(merged.as_bytes());
     let buildhash = hasher.finish();
-    drop(cfg_contents);
 
     let mut out = PathBuf::from("target");
     let buildstamp_file = out.join("buildstamp");
@@ -68,6 +82,32 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
     let mut src_dir = cfg.to_path_buf();
     src_dir.pop();
 
+    // Catch what we can before building anything.
+    let problems = validate::check(&toml, &src_dir);
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        bail!("{} has {} problem(s)", cfg.display(), problems.len());
+    }
+
+    // Check who can send to whom, and draw it.
+    let ipc = IpcGraph::new(&toml.tasks);
+    let mut dotfile = File::create(out.join("ipc.dot"))?;
+    ipc.write_dot(&mut dotfile)?;
+    drop(dotfile);
+    std::fs::write(out.join("ipc.json"), serde_json::to_string_pretty(&ipc)?)?;
+    let violations = ipc.violations();
+    if !violations.is_empty() {
+        for v in &violations {
+            eprintln!("error: {}", v);
+        }
+        bail!(
+            "tasks may deadlock; fix their priorities or task-slots, or, if \
+             that's impossible, list the receivers in `allow-send-to`"
+        );
+    }
+
     let mut memories = IndexMap::new();
     for (name, out) in &toml.outputs {
         if let Some(end) = out.address.checked_add(out.size) {
@@ -85,19 +125,10 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
     }
     let starting_memories = memories.clone();
 
-    // Allocate memories.
-    let allocs = allocate_all(&toml.kernel, &toml.tasks, &mut memories)?;
-
-    println!("Used:");
-    for (name, new_range) in &memories {
-        let orig_range = &starting_memories[name];
-        println!("{}: 0x{:x}", name, new_range.start - orig_range.start);
-    }
-
-    let mut infofile = File::create(out.join("allocations.txt"))?;
-    writeln!(infofile, "kernel: {:#x?}", allocs.kernel)?;
-    writeln!(infofile, "tasks: {:#x?}", allocs.tasks)?;
-    drop(infofile);
+    // Allocate memories. Tasks with `auto` sizes are left out for now; they
+    // are sized and allocated once the bootloader has been built, below.
+    let mut allocs =
+        allocate_all(&toml.target, &toml.kernel, &toml.tasks, &mut memories)?;
 
     // Build each task.
     let task_names = toml.tasks.keys().cloned().collect::<Vec<_>>();
@@ -162,13 +193,64 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
             std::process::exit(1);
         };
 
+        // stage0 keeps its records of the image slots in flash, where they
+        // survive power cycles, just past the test region.
+        let records =
+            match image_flash.end.checked_add(TEST_REGION_SIZE).and_then(
+                |start| Some(start..start.checked_add(SLOT_RECORDS_SIZE)?),
+            ) {
+                Some(records) => records,
+                None => {
+                    eprintln!("no room for slot records after image a");
+                    std::process::exit(1);
+                }
+            };
+
         bootloader_memory.insert(String::from("FLASH"), flash.clone());
+        bootloader_memory
+            .insert(String::from("RECORDS_FLASH"), records.clone());
         bootloader_memory.insert(String::from("RAM"), ram.clone());
         bootloader_memory.insert(String::from("SRAM"), sram.clone());
         bootloader_memory
             .insert(String::from("IMAGEA_FLASH"), image_flash.clone());
         bootloader_memory.insert(String::from("IMAGEA_RAM"), image_ram.clone());
 
+        match (bootloader.imageb_flash_start, bootloader.imageb_flash_size) {
+            (Some(start), Some(size)) => {
+                let image_b = match start.checked_add(size) {
+                    Some(end) => start..end,
+                    None => {
+                        eprintln!("image b flash size is incorrect");
+                        std::process::exit(1);
+                    }
+                };
+                // Image B must not overlap image A, nor what stage0 keeps
+                // after it.
+                let test_region = image_flash.end..records.start;
+                for (what, region) in [
+                    ("image a", &image_flash),
+                    ("the test region", &test_region),
+                    ("the slot records", &records),
+                ]
+                .iter()
+                {
+                    if image_b.start < region.end && region.start < image_b.end
+                    {
+                        eprintln!("image b flash overlaps {}", what);
+                        std::process::exit(1);
+                    }
+                }
+                bootloader_memory.insert(String::from("IMAGEB_FLASH"), image_b);
+            }
+            (None, None) => (),
+            _ => {
+                eprintln!(
+                    "imageb-flash-start and imageb-flash-size go together"
+                );
+                std::process::exit(1);
+            }
+        }
+
         let kernel_start = allocs.kernel.get("flash").unwrap().start;
 
         if kernel_start != bootloader_memory.get("FLASH").unwrap().end {
@@ -198,6 +280,7 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
             verbose,
             edges,
             &task_names,
+            None,
             &None,
             &shared_syms,
             &None,
@@ -237,6 +320,15 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
         let mut linkscr =
             File::create(Path::new(&format!("target/table.ld"))).unwrap();
 
+        // The image also needs to know where stage0 leaves its DICE
+        // handoff: at the start of stage0's SRAM.
+        writeln!(
+            linkscr,
+            "__stage0_handoff = ABSOLUTE(0x{:08x});",
+            sram.start
+        )
+        .unwrap();
+
         for b in bytes {
             writeln!(linkscr, "BYTE(0x{:x})", b).unwrap();
         }
@@ -247,50 +339,111 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
         File::create(Path::new(&format!("target/table.ld"))).unwrap();
     }
 
-    for name in toml.tasks.keys() {
-        let task_toml = &toml.tasks[name];
+    // Work out any task sizes given as `auto`: build the task with the whole
+    // of each such output to itself, see how much it used, and round that up
+    // to something the MPU can protect.
+    let mut resolved = vec![];
+    for (name, task_toml) in &toml.tasks {
+        if !task_toml.requires.values().any(|&r| r == Requirement::Auto) {
+            continue;
+        }
 
-        generate_task_linker_script(
-            "memory.x",
-            &allocs.tasks[name],
-            Some(&task_toml.sections),
-            task_toml.stacksize.or(toml.stacksize).ok_or_else(|| {
-                anyhow!(
-                    "{}: no stack size specified and there is no default",
-                    name
-                )
-            })?,
-        )
-        .context(format!("failed to generate linker script for {}", name))?;
+        let mut regions = BTreeMap::new();
+        for (mem, &req) in &task_toml.requires {
+            let output = starting_memories.get(mem).ok_or_else(|| {
+                anyhow!("{}: requires unknown output {}", name, mem)
+            })?;
+            // Final placement is at least 32-byte aligned; match that, so
+            // that alignment within the task comes out the same.
+            let base = (output.start + 31) & !31;
+            let end = match req {
+                Requirement::Size(size) => {
+                    base.saturating_add(size).min(output.end)
+                }
+                Requirement::Auto => output.end,
+            };
+            regions.insert(mem.clone(), base..end);
+        }
+
+        println!("sizing {}", name);
+        build_task(
+            &toml,
+            name,
+            &regions,
+            &src_dir,
+            &out,
+            verbose,
+            edges,
+            &task_names,
+            &shared_syms,
+        )?;
+        let usage = Component::from_elf(
+            name,
+            &out.join(name),
+            &regions,
+            task_stacksize(&toml, name)?,
+        )?;
+
+        for (mem, &req) in &task_toml.requires {
+            if req == Requirement::Auto {
+                let size =
+                    mpu_region_size(&toml.target, usage.regions[mem].used)?;
+                println!("{}: {} = {}", name, mem, size);
+                resolved.push((name.clone(), mem.clone(), size));
+            }
+        }
+    }
 
-        fs::copy("build/task-link.x", "target/link.x")?;
+    if !resolved.is_empty() {
+        for (name, mem, size) in resolved {
+            toml.tasks[&name].requires[&mem] = Requirement::Size(size);
+        }
 
-        build(
+        memories = starting_memories.clone();
+        allocs = allocate_all(
             &toml.target,
-            &toml.board,
-            &src_dir.join(&task_toml.path),
-            &task_toml.name,
-            &task_toml.features,
-            out.join(name),
+            &toml.kernel,
+            &toml.tasks,
+            &mut memories,
+        )
+        .context("tasks no longer fit once automatically sized")?;
+    }
+
+    println!("Used:");
+    for (name, new_range) in &memories {
+        let orig_range = &starting_memories[name];
+        println!("{}: 0x{:x}", name, new_range.start - orig_range.start);
+    }
+
+    let mut infofile = File::create(out.join("allocations.txt"))?;
+    writeln!(infofile, "kernel: {:#x?}", allocs.kernel)?;
+    writeln!(infofile, "tasks: {:#x?}", allocs.tasks)?;
+    drop(infofile);
+
+    for name in toml.tasks.keys() {
+        let task_toml = &toml.tasks[name];
+
+        build_task(
+            &toml,
+            name,
+            &allocs.tasks[name],
+            &src_dir,
+            &out,
             verbose,
             edges,
             &task_names,
-            &toml.secure,
             &shared_syms,
-            &task_toml.config,
-            &toml.config,
-        )
-        .context(format!("failed to build {}", name))?;
+        )?;
 
         resolve_task_slots(name, &toml.tasks, &out.join(name), verbose)?;
 
         let (ep, flash) = load_elf(&out.join(name), &mut all_output_sections)?;
 
-        if flash > task_toml.requires["flash"] as usize {
+        if flash > task_toml.required("flash") as usize {
             bail!(
                 "{} has insufficient flash: specified {} bytes, needs {}",
                 task_toml.name,
-                task_toml.requires["flash"],
+                task_toml.required("flash"),
                 flash
             );
         }
@@ -299,8 +452,7 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
     }
 
     // Format the descriptors for the kernel build.
-    let mut descriptor_text = vec![];
-    for word in make_descriptors(
+    let app_table = make_descriptors(
         &toml.target,
         &toml.tasks,
         &toml.peripherals,
@@ -310,7 +462,9 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
         &toml.outputs,
         &entry_points,
         &toml.extratext,
-    )? {
+    )?;
+    let mut descriptor_text = vec![];
+    for word in app_table.words() {
         descriptor_text.push(format!("LONG(0x{:08x});", word));
     }
     let descriptor_text = descriptor_text.join("\n");
@@ -336,6 +490,7 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
         verbose,
         edges,
         "",
+        None,
         &toml.secure,
         &None,
         &None,
@@ -343,6 +498,79 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
     )?;
     let (kentry, _) = load_elf(&out.join("kernel"), &mut all_output_sections)?;
 
+    // Describe the image for tools that don't want to read app.toml.
+    let app = AppDescriptor::new(
+        &toml,
+        &app_table,
+        kentry,
+        &allocs.kernel,
+        &allocs.tasks,
+    )?;
+    std::fs::write(out.join("app.json"), serde_json::to_string_pretty(&app)?)?;
+
+    // Compare what everything was allocated with what it uses.
+    let mut sizes = SizeReport::default();
+    sizes.components.push(Component::from_elf(
+        "kernel",
+        &out.join("kernel"),
+        &allocs.kernel,
+        toml.kernel.stacksize.unwrap_or(DEFAULT_KERNEL_STACK),
+    )?);
+    for name in toml.tasks.keys() {
+        sizes.components.push(Component::from_elf(
+            name,
+            &out.join(name),
+            &allocs.tasks[name],
+            task_stacksize(&toml, name)?,
+        )?);
+    }
+    sizes.tally_outputs(&starting_memories, &memories)?;
+
+    let mut sizefile = File::create(out.join("sizes.txt"))?;
+    sizes.write_table(&mut sizefile)?;
+    drop(sizefile);
+    std::fs::write(
+        out.join("sizes.json"),
+        serde_json::to_string_pretty(&sizes)?,
+    )?;
+    sizes.write_table(&mut std::io::stdout())?;
+
+    // Check that each task's stack is big enough for its deepest call chain.
+    let mut stacks = vec![];
+    for name in toml.tasks.keys() {
+        stacks.push(TaskStack::analyze(
+            name,
+            &out.join(name),
+            &toml.target,
+            task_stacksize(&toml, name)?,
+        )?);
+    }
+    let mut stackfile = File::create(out.join("stack.txt"))?;
+    stack::write_report(&stacks, &mut stackfile)?;
+    drop(stackfile);
+
+    let mut overflows = vec![];
+    for t in stacks.iter().filter(|t| t.overflows()) {
+        if t.is_bounded() {
+            overflows.push(format!(
+                "{} needs {} bytes of stack, but has {}",
+                t.name, t.depth, t.stacksize
+            ));
+        } else {
+            println!(
+                "warning: {} may need more than {} bytes of stack, but has {}",
+                t.name, t.depth, t.stacksize
+            );
+        }
+    }
+    if !overflows.is_empty() {
+        bail!(
+            "{}; see {}",
+            overflows.join(", "),
+            out.join("stack.txt").display()
+        );
+    }
+
     // Write a map file, because that seems nice.
     let mut mapfile = File::create(&out.join("map.txt"))?;
     writeln!(mapfile, "ADDRESS  END          SIZE FILE")?;
@@ -491,9 +719,17 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
         "README.TXT",
         "\
         This is a build archive containing firmware build artifacts.\n\n\
-        - app.toml is the config file used to build the firmware.\n\
+        - app.toml is the config file used to build the firmware, merged\n\
+          with any it inherits.\n\
         - git-rev is the commit it was built from, with optional dirty flag.\n\
+        - manifest.json hashes every other file, and records the toolchain,\n\
+          features and resolved app.toml, for `cargo xtask verify-archive`.\n\
         - info/ contains human-readable data like logs.\n\
+        - info/sizes.txt compares each task's allocations with its use.\n\
+        - info/stack.txt bounds each task's stack use.\n\
+        - info/ipc.dot and info/ipc.json show which tasks can send to which.\n\
+        - info/app.json describes the image: its regions, tasks, interrupts,\n\
+          task slots, peripherals and i2c devices.\n\
         - elf/ contains ELF images for all firmware components.\n\
         - elf/tasks/ contains each task by name.\n\
         - elf/kernel is the kernel.\n\
@@ -501,11 +737,10 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
     )?;
 
     let (git_rev, git_dirty) = get_git_status()?;
-    archive.text(
-        "git-rev",
-        format!("{}{}", git_rev, if git_dirty { "-dirty" } else { "" }),
-    )?;
-    archive.copy(cfg, "app.toml")?;
+    let git_rev =
+        format!("{}{}", git_rev, if git_dirty { "-dirty" } else { "" });
+    archive.text("git-rev", &git_rev)?;
+    archive.text("app.toml", &merged)?;
 
     let elf_dir = PathBuf::from("elf");
     let tasks_dir = elf_dir.join("task");
@@ -520,6 +755,12 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
         info_dir.join("allocations.txt"),
     )?;
     archive.copy(out.join("map.txt"), info_dir.join("map.txt"))?;
+    archive.copy(out.join("sizes.txt"), info_dir.join("sizes.txt"))?;
+    archive.copy(out.join("sizes.json"), info_dir.join("sizes.json"))?;
+    archive.copy(out.join("stack.txt"), info_dir.join("stack.txt"))?;
+    archive.copy(out.join("ipc.dot"), info_dir.join("ipc.dot"))?;
+    archive.copy(out.join("ipc.json"), info_dir.join("ipc.json"))?;
+    archive.copy(out.join("app.json"), info_dir.join("app.json"))?;
 
     let img_dir = PathBuf::from("img");
     archive.copy(out.join("combined.srec"), img_dir.join("combined.srec"))?;
@@ -535,6 +776,12 @@ pub fn package(verbose: bool, edges: bool, cfg: &Path) -> Result<()> {
         archive.copy(out.join(&name), img_dir.join(&name))?;
     }
 
+    // Finally, describe everything else, so that the archive can be checked.
+    let manifest = Manifest::new(&toml, cfg, git_rev, archive.files.clone())?;
+    let manifest = serde_json::to_string_pretty(&manifest)?;
+    std::fs::write(out.join(manifest::MANIFEST), &manifest)?;
+    archive.text(manifest::MANIFEST, manifest)?;
+
     archive.finish()?;
 
     Ok(())
@@ -701,17 +948,35 @@ fn generate_bootloader_linker_script(
         "  LONG(ORIGIN(IMAGEA_FLASH) + LENGTH(IMAGEA_FLASH));"
     )
     .unwrap();
+    // stage0 only looks for an image B if there are two slots.
+    let slots = if map.contains_key("IMAGEB_FLASH") {
+        2
+    } else {
+        1
+    };
+    writeln!(linkscr, "  PROVIDE(image_slot_count = .);").unwrap();
+    writeln!(linkscr, "  LONG({});", slots).unwrap();
     writeln!(linkscr, "  }} > FLASH").unwrap();
 
     writeln!(linkscr, "}} INSERT BEFORE .bss").unwrap();
 
+    // The DICE handoff goes at the start of SRAM, where the image is told to
+    // look for it (see table.ld).
     writeln!(linkscr, "SECTIONS {{").unwrap();
-    writeln!(linkscr, "  .attest (NOLOAD) : {{").unwrap();
+    writeln!(linkscr, "  .attest ORIGIN(SRAM) (NOLOAD) : {{").unwrap();
     writeln!(linkscr, "  KEEP(*(.attestation .attestation.*))").unwrap();
     writeln!(linkscr, "  }} > SRAM").unwrap();
     writeln!(linkscr, "}} INSERT AFTER .uninit").unwrap();
 
+    writeln!(linkscr, "SLOT_RECORDS = ORIGIN(RECORDS_FLASH);").unwrap();
     writeln!(linkscr, "IMAGEA = ORIGIN(IMAGEA_FLASH);").unwrap();
+    // With one slot, image B is never read (see image_slot_count above), but
+    // it must still resolve; point it at image A rather than at nothing.
+    if map.contains_key("IMAGEB_FLASH") {
+        writeln!(linkscr, "IMAGEB = ORIGIN(IMAGEB_FLASH);").unwrap();
+    } else {
+        writeln!(linkscr, "IMAGEB = ORIGIN(IMAGEA_FLASH);").unwrap();
+    }
 }
 
 fn generate_task_linker_script(
@@ -839,6 +1104,58 @@ fn generate_kernel_linker_script(
     Ok(())
 }
 
+fn task_stacksize(toml: &Config, name: &str) -> Result<u32> {
+    toml.tasks[name]
+        .stacksize
+        .or(toml.stacksize)
+        .ok_or_else(|| {
+            anyhow!("{}: no stack size specified and there is no default", name)
+        })
+}
+
+/// Builds task `name`, linked to run from `regions`, into `out`.
+fn build_task(
+    toml: &Config,
+    name: &str,
+    regions: &BTreeMap<String, Range<u32>>,
+    src_dir: &Path,
+    out: &Path,
+    verbose: bool,
+    edges: bool,
+    task_names: &str,
+    shared_syms: &Option<&[String]>,
+) -> Result<()> {
+    let task_toml = &toml.tasks[name];
+
+    generate_task_linker_script(
+        "memory.x",
+        regions,
+        Some(&task_toml.sections),
+        task_stacksize(toml, name)?,
+    )
+    .context(format!("failed to generate linker script for {}", name))?;
+
+    fs::copy("build/task-link.x", "target/link.x")?;
+
+    build(
+        &toml.target,
+        &toml.board,
+        &src_dir.join(&task_toml.path),
+        &task_toml.name,
+        &task_toml.features,
+        out.join(name),
+        verbose,
+        edges,
+        task_names,
+        Some(name),
+        &toml.secure,
+        shared_syms,
+        &task_toml.config,
+        &toml.config,
+    )
+    .context(format!("failed to build {}", name))
+}
+
 fn build(
     target: &str,
     board_name: &str,
@@ -849,6 +1166,7 @@ fn build(
     verbose: bool,
     edges: bool,
     task_names: &str,
+    task_name: Option<&str>,
     secure: &Option<bool>,
     shared_syms: &Option<&[String]>,
     config: &Option<toml::Value>,
@@ -891,6 +1209,17 @@ fn build(
     // rebuilds. by canonicalizing it, you get foo/target for every one.
     let canonical_cargo_out_dir = fs::canonicalize(&cargo_out)?;
 
+    // Paths to sources end up in panic messages and debug info, so that
+    // building the same source somewhere else would build a different image.
+    // Give the places sources come from the same names everywhere.
+    let mut remaps = format!(
+        "--remap-path-prefix={}=/hubris",
+        std::env::current_dir()?.display()
+    );
+    if let Some(home) = cargo_home() {
+        remaps += &format!(" --remap-path-prefix={}=/cargo", home.display());
+    }
+
     cmd.current_dir(path);
     cmd.env(
         "RUSTFLAGS",
@@ -900,14 +1229,21 @@ fn build(
              -C link-arg=-z -C link-arg=common-page-size=0x20 \
              -C link-arg=-z -C link-arg=max-page-size=0x20 \
              -C llvm-args=--enable-machine-outliner=never \
-             -C overflow-checks=y",
-            canonical_cargo_out_dir.display()
+             -C overflow-checks=y \
+             -Z emit-stack-sizes \
+             {}",
+            canonical_cargo_out_dir.display(),
+            remaps,
         ),
     );
 
     cmd.env("HUBRIS_TASKS", task_names);
     cmd.env("HUBRIS_BOARD", board_name);
 
+    if let Some(task_name) = task_name {
+        cmd.env("HUBRIS_TASK_NAME", task_name);
+    }
+
     if let Some(s) = shared_syms {
         if !s.is_empty() {
             cmd.env("SHARED_SYMS", s.join(","));
@@ -984,11 +1320,15 @@ struct Allocations {
     tasks: BTreeMap<String, BTreeMap<String, Range<u32>>>,
 }
 
+/// Pending task requests for one memory: alignment -> queue of (task name,
+/// size).
+type TaskQueues<'a> = BTreeMap<u32, VecDeque<(&'a str, u32)>>;
+
 /// Allocates address space from all regions for the kernel and all tasks.
 ///
 /// The allocation strategy is slightly involved, because of the limitations of
-/// the ARMv7-M MPU. (Currently we use the same strategy on ARMv8-M even though
-/// it's more flexible.)
+/// the ARMv7-M MPU. (We use the same strategy on ARMv8-M, even though it's
+/// more flexible, except as noted below.)
 ///
 /// Address space regions are required to be power-of-two in size and naturally
 /// aligned. In other words, all the addresses in a single region must have some
@@ -1018,43 +1358,50 @@ struct Allocations {
 ///
 /// This means that the algorithm needs to keep track of a queue of pending
 /// requests per alignment size.
+///
+/// On ARMv8-M, a request may instead be any multiple of 32 bytes, which only
+/// needs 32-byte alignment. Such requests are queued with the power-of-two
+/// requests of that alignment.
+///
+/// Task requirements still given as `auto` are skipped.
 fn allocate_all(
+    target: &str,
     kernel: &crate::Kernel,
     tasks: &IndexMap<String, crate::Task>,
     free: &mut IndexMap<String, Range<u32>>,
 ) -> Result<Allocations> {
     // Collect all allocation requests into queues, one per memory type, indexed
-    // by allocation size. This is equivalent to required alignment because of
-    // the naturally-aligned-power-of-two requirement.
+    // by required alignment.
     //
     // We keep kernel and task requests separate so we can always service the
     // kernel first.
     //
-    // The task map is: memory name -> allocation size -> queue of task name.
+    // The task map is: memory name -> TaskQueues.
     // The kernel map is: memory name -> allocation size
     let kernel_requests = &kernel.requires;
     for (name, &amt) in kernel_requests {
-        if !amt.is_power_of_two() {
-            bail!("kernel, memory region {}: requirement {} is not a power of two.",
-                name, amt);
-        }
+        check_region_size(target, amt)
+            .context(format!("kernel, memory region {}", name))?;
     }
 
-    let mut task_requests: BTreeMap<&str, BTreeMap<u32, VecDeque<&str>>> =
-        BTreeMap::new();
+    let mut task_requests: BTreeMap<&str, TaskQueues> = BTreeMap::new();
 
     for (name, task) in tasks {
-        for (mem, &amt) in &task.requires {
-            if !amt.is_power_of_two() {
-                bail!("task {}, memory region {}: requirement {} is not a power of two.",
-                    task.name, name, amt);
-            }
+        for (mem, &req) in &task.requires {
+            let amt = match req {
+                Requirement::Size(amt) => amt,
+                Requirement::Auto => continue,
+            };
+            check_region_size(target, amt).context(format!(
+                "task {}, memory region {}",
+                task.name, mem
+            ))?;
             task_requests
                 .entry(mem.as_str())
                 .or_default()
-                .entry(amt)
+                .entry(region_alignment(amt))
                 .or_default()
-                .push_back(name.as_str());
+                .push_back((name.as_str(), amt));
         }
     }
 
@@ -1064,9 +1411,7 @@ fn allocate_all(
         let mut k_req = kernel_requests.get(region.as_str());
         let mut t_reqs = task_requests.get_mut(region.as_str());
 
-        fn reqs_map_not_empty(
-            om: &Option<&mut BTreeMap<u32, VecDeque<&str>>>,
-        ) -> bool {
+        fn reqs_map_not_empty(om: &Option<&mut TaskQueues>) -> bool {
             om.iter()
                 .flat_map(|map| map.values())
                 .any(|q| !q.is_empty())
@@ -1084,9 +1429,9 @@ fn allocate_all(
             // Search order is:
             // - Kernel.
             // - Task requests equal to or smaller than this alignment, in
-            //   descending order of size.
+            //   descending order of alignment.
             // - Task requests larger than this alignment, in ascending order of
-            //   size.
+            //   alignment.
 
             if let Some(&sz) = k_req.take() {
                 // The kernel wants in on this.
@@ -1098,8 +1443,8 @@ fn allocate_all(
             }
 
             if let Some(t_reqs) = t_reqs.as_mut() {
-                for (&sz, q) in t_reqs.range_mut(..=align).rev() {
-                    if let Some(task) = q.pop_front() {
+                for (_, q) in t_reqs.range_mut(..=align).rev() {
+                    if let Some((task, sz)) = q.pop_front() {
                         // We can pack an equal or smaller one in.
                         allocs
                             .tasks
@@ -1113,8 +1458,8 @@ fn allocate_all(
                     }
                 }
 
-                for (&sz, q) in t_reqs.range_mut(align + 1..) {
-                    if let Some(task) = q.pop_front() {
+                for (_, q) in t_reqs.range_mut(align + 1..) {
+                    if let Some((task, sz)) = q.pop_front() {
                         // We've gotta use a larger one.
                         allocs
                             .tasks
@@ -1144,10 +1489,7 @@ fn allocate_one(
     size: u32,
     avail: &mut Range<u32>,
 ) -> Result<Range<u32>> {
-    // This condition is ensured by allocate_all.
-    assert!(size.is_power_of_two());
-
-    let size_mask = size - 1;
+    let size_mask = region_alignment(size) - 1;
 
     // Our base address will be larger than avail.start if it doesn't meet our
     // minimum requirements. Round up.
@@ -1169,6 +1511,50 @@ fn allocate_one(
     Ok(base..end)
 }
 
+/// Alignment of a region of `size` bytes: its size if that's a power of two,
+/// and otherwise (only allowed on ARMv8-M) the 32-byte MPU granule.
+fn region_alignment(size: u32) -> u32 {
+    if size.is_power_of_two() {
+        size
+    } else {
+        32
+    }
+}
+
+/// Checks that the MPU on `target` can protect a region of `size` bytes.
+fn check_region_size(target: &str, size: u32) -> Result<()> {
+    if size.is_power_of_two() {
+        return Ok(());
+    }
+    if target == "thumbv8m.main-none-eabihf" {
+        if size % 32 != 0 {
+            bail!("requirement {} is not a multiple of 32.", size);
+        }
+        return Ok(());
+    }
+    bail!("requirement {} is not a power of two.", size);
+}
+
+/// Rounds `size` up to the smallest region the MPU on `target` can protect.
+fn mpu_region_size(target: &str, size: u32) -> Result<u32> {
+    // Powers of two are always fine, so that app.tomls written for ARMv7-M
+    // still work on ARMv8-M; they're allocated just as they are there.
+    if size.is_power_of_two() {
+        return Ok(size.max(32));
+    }
+
+    let rounded = match target {
+        // ARMv8-M regions can be any multiple of 32 bytes...
+        "thumbv8m.main-none-eabihf" => size.checked_add(31).map(|s| s & !31),
+        // ...while ARMv7-M needs a power of two, of at least 32 bytes.
+        "thumbv7em-none-eabihf" => size.checked_next_power_of_two(),
+        t => bail!("Unknown mpu requirements for target '{}'", t),
+    };
+    rounded
+        .map(|s| s.max(32))
+        .ok_or_else(|| anyhow!("{} bytes is too large for a region", size))
+}
+
 fn cargo_output_dir(target: &str, path: &Path) -> Result<PathBuf> {
     // NOTE: current_dir's docs suggest that you should use canonicalize for
     // portability. However, that's for when you're doing stuff like:
@@ -1210,7 +1596,7 @@ fn make_descriptors(
     outputs: &IndexMap<String, Output>,
     entry_points: &HashMap<String, u32>,
     extra_text: &IndexMap<String, Peripheral>,
-) -> Result<Vec<u32>> {
+) -> Result<AppTable> {
     // Generate the three record sections concurrently, using three separate
     // vecs that we'll later concatenate.
     let mut regions = vec![];
@@ -1281,12 +1667,12 @@ fn make_descriptors(
     // The remaining regions are allocated to tasks on a first-come first-serve
     // basis.
     for (i, (name, task)) in tasks.iter().enumerate() {
-        if power_of_two_required && !task.requires["flash"].is_power_of_two() {
-            panic!("Flash for task '{}' is required to be a power of two, but has size {}", task.name, task.requires["flash"]);
+        if power_of_two_required && !task.required("flash").is_power_of_two() {
+            panic!("Flash for task '{}' is required to be a power of two, but has size {}", task.name, task.required("flash"));
         }
 
-        if power_of_two_required && !task.requires["ram"].is_power_of_two() {
-            panic!("Ram for task '{}' is required to be a power of two, but has size {}", task.name, task.requires["flash"]);
+        if power_of_two_required && !task.required("ram").is_power_of_two() {
+            panic!("Ram for task '{}' is required to be a power of two, but has size {}", task.name, task.required("flash"));
         }
 
         // Regions are referenced by index into the table we just generated.
@@ -1390,58 +1776,151 @@ fn make_descriptors(
         }
     }
 
-    // Assemble everything into the final image.
-    let mut words = vec![];
-
-    // App header
-    words.push(0x1DE_fa7a1);
-    words.push(task_descs.len() as u32);
-    words.push(regions.len() as u32);
-    words.push(irqs.len() as u32);
-    if let Some(supervisor) = supervisor {
-        words.push(supervisor.notification);
-    }
-    // pad out to 32 bytes
-    words.resize(32 / 4, 0);
-
-    // Flatten region descriptors
-    for rdesc in regions {
-        words.push(rdesc.base);
-        words.push(rdesc.size);
-        words.push(rdesc.attributes.bits());
-        words.push(rdesc.reserved_zero);
-    }
-
-    // Flatten task descriptors
-    for tdesc in task_descs {
-        // Region table indices
-        words.push(
-            u32::from(tdesc.regions[0])
-                | u32::from(tdesc.regions[1]) << 8
-                | u32::from(tdesc.regions[2]) << 16
-                | u32::from(tdesc.regions[3]) << 24,
-        );
-        words.push(
-            u32::from(tdesc.regions[4])
-                | u32::from(tdesc.regions[5]) << 8
-                | u32::from(tdesc.regions[6]) << 16
-                | u32::from(tdesc.regions[7]) << 24,
-        );
+    Ok(AppTable {
+        regions,
+        tasks: task_descs,
+        irqs,
+        fault_notification: supervisor.map(|s| s.notification),
+    })
+}
 
-        words.push(tdesc.entry_point);
-        words.push(tdesc.initial_stack);
-        words.push(tdesc.priority);
-        words.push(tdesc.flags.bits());
-    }
+/// The records of the app descriptor, as `make_descriptors` laid them out.
+pub struct AppTable {
+    pub regions: Vec<abi::RegionDesc>,
+    /// Task descriptors, in task index order.
+    pub tasks: Vec<abi::TaskDesc>,
+    pub irqs: Vec<abi::Interrupt>,
+    /// Notification the supervisor gets when a task faults, if there is one.
+    pub fault_notification: Option<u32>,
+}
 
-    // Flatten interrupt response records.
-    for idesc in irqs {
-        words.push(idesc.irq);
-        words.push(idesc.task);
-        words.push(idesc.notification);
+impl AppTable {
+    /// Flattens the records into the words of the kernel's app table.
+    fn words(&self) -> Vec<u32> {
+        let mut words = vec![];
+
+        // App header
+        words.push(0x1DE_fa7a1);
+        words.push(self.tasks.len() as u32);
+        words.push(self.regions.len() as u32);
+        words.push(self.irqs.len() as u32);
+        if let Some(notification) = self.fault_notification {
+            words.push(notification);
+        }
+        // pad out to 32 bytes
+        words.resize(32 / 4, 0);
+
+        // Flatten region descriptors
+        for rdesc in &self.regions {
+            words.push(rdesc.base);
+            words.push(rdesc.size);
+            words.push(rdesc.attributes.bits());
+            words.push(rdesc.reserved_zero);
+        }
+
+        // Flatten task descriptors
+        for tdesc in &self.tasks {
+            // Region table indices
+            words.push(
+                u32::from(tdesc.regions[0])
+                    | u32::from(tdesc.regions[1]) << 8
+                    | u32::from(tdesc.regions[2]) << 16
+                    | u32::from(tdesc.regions[3]) << 24,
+            );
+            words.push(
+                u32::from(tdesc.regions[4])
+                    | u32::from(tdesc.regions[5]) << 8
+                    | u32::from(tdesc.regions[6]) << 16
+                    | u32::from(tdesc.regions[7]) << 24,
+            );
+
+            words.push(tdesc.entry_point);
+            words.push(tdesc.initial_stack);
+            words.push(tdesc.priority);
+            words.push(tdesc.flags.bits());
+        }
+
+        // Flatten interrupt response records.
+        for idesc in &self.irqs {
+            words.push(idesc.irq);
+            words.push(idesc.task);
+            words.push(idesc.notification);
+        }
+
+        words
     }
 
-    Ok(words)
+    /// Reads the records back out of the words of a kernel's app table, as
+    /// laid out by `words`. The counts in the header are checked against the
+    /// table's length, rather than trusted.
+    pub fn from_words(words: &[u32]) -> Result<Self> {
+        use std::mem::size_of;
+
+        let too_short = || anyhow!("app table is too short for its counts");
+        let header = words
+            .get(..size_of::<abi::App>() / 4)
+            .ok_or_else(|| anyhow!("app table has no header"))?;
+        if header[0] != abi::CURRENT_APP_MAGIC {
+            bail!("app table has bad magic {:#x}", header[0]);
+        }
+
+        // Returns the `count` records of `size` bytes that start at word
+        // `start`, and the word after them.
+        let records = |start: usize, count: u32, size: usize| {
+            let end = (count as usize)
+                .checked_mul(size / 4)
+                .and_then(|len| len.checked_add(start))
+                .ok_or_else(too_short)?;
+            let words = words.get(start..end).ok_or_else(too_short)?;
+            Ok::<_, anyhow::Error>((words, end))
+        };
+        let (regions, end) =
+            records(header.len(), header[2], size_of::<abi::RegionDesc>())?;
+        let (tasks, end) = records(end, header[1], size_of::<abi::TaskDesc>())?;
+        let (irqs, _) = records(end, header[3], size_of::<abi::Interrupt>())?;
+
+        let regions = regions
+            .chunks(size_of::<abi::RegionDesc>() / 4)
+            .map(|r| abi::RegionDesc {
+                base: r[0],
+                size: r[1],
+                attributes: abi::RegionAttributes::from_bits_truncate(r[2]),
+                reserved_zero: r[3],
+            })
+            .collect();
+        let tasks = tasks
+            .chunks(size_of::<abi::TaskDesc>() / 4)
+            .map(|t| {
+                let mut regions = [0; abi::REGIONS_PER_TASK];
+                for (i, region) in regions.iter_mut().enumerate() {
+                    *region = t[i / 4].to_le_bytes()[i % 4];
+                }
+                abi::TaskDesc {
+                    regions,
+                    entry_point: t[2],
+                    initial_stack: t[3],
+                    priority: t[4],
+                    flags: abi::TaskFlags::from_bits_truncate(t[5]),
+                }
+            })
+            .collect();
+        let irqs = irqs
+            .chunks(size_of::<abi::Interrupt>() / 4)
+            .map(|i| abi::Interrupt {
+                irq: i[0],
+                task: i[1],
+                notification: i[2],
+            })
+            .collect();
+
+        Ok(AppTable {
+            regions,
+            tasks,
+            irqs,
+            // Without a supervisor, the word is left zero.
+            fault_notification: Some(header[4]).filter(|&n| n != 0),
+        })
+    }
 }
 
 /// Loads an SREC file into the same representation we use for ELF. This is
@@ -1544,7 +2023,7 @@ fn load_elf(
 }
 
 /// Keeps track of a build archive being constructed.
-struct Archive {
+pub(crate) struct Archive {
     /// Place where we'll put the final zip file.
     final_path: PathBuf,
     /// Name of temporary file used during construction.
@@ -1553,12 +2032,14 @@ struct Archive {
     inner: zip::ZipWriter<File>,
     /// Options used for every file.
     opts: zip::write::FileOptions,
+    /// SHA-256 of each file added so far, by path.
+    pub(crate) files: BTreeMap<String, String>,
 }
 
 impl Archive {
     /// Creates a new build archive that will, when finished, be placed at
     /// `dest`.
-    fn new(dest: impl AsRef<Path>) -> Result<Self> {
+    pub(crate) fn new(dest: impl AsRef<Path>) -> Result<Self> {
         let final_path = PathBuf::from(dest.as_ref());
 
         let mut tmp_path = final_path.clone();
@@ -1571,8 +2052,14 @@ impl Archive {
             final_path,
             tmp_path,
             inner,
+            // Every file gets the same timestamp and permissions, rather than
+            // the time it was added, so that building the same source twice
+            // gets the same archive.
             opts: zip::write::FileOptions::default()
-                .compression_method(zip::CompressionMethod::Bzip2),
+                .compression_method(zip::CompressionMethod::Bzip2)
+                .last_modified_time(zip::DateTime::default())
+                .unix_permissions(0o644),
+            files: BTreeMap::new(),
         })
     }
 
@@ -1582,22 +2069,27 @@ impl Archive {
         src_path: impl AsRef<Path>,
         zip_path: impl AsRef<Path>,
     ) -> Result<()> {
-        let mut input = File::open(src_path)?;
-        self.inner
-            .start_file_from_path(zip_path.as_ref(), self.opts)?;
-        std::io::copy(&mut input, &mut self.inner)?;
-        Ok(())
+        let contents = std::fs::read(src_path)?;
+        self.add(zip_path.as_ref(), &contents)
     }
 
     /// Creates a text file in the archive at `zip_path` with `contents`.
-    fn text(
+    pub(crate) fn text(
         &mut self,
         zip_path: impl AsRef<Path>,
         contents: impl AsRef<str>,
     ) -> Result<()> {
-        self.inner
-            .start_file_from_path(zip_path.as_ref(), self.opts)?;
-        self.inner.write_all(contents.as_ref().as_bytes())?;
+        self.add(zip_path.as_ref(), contents.as_ref().as_bytes())
+    }
+
+    /// Adds a file at `zip_path` with `contents`, and records its hash.
+    fn add(&mut self, zip_path: &Path, contents: &[u8]) -> Result<()> {
+        self.inner.start_file_from_path(zip_path, self.opts)?;
+        self.inner.write_all(contents)?;
+        self.files.insert(
+            zip_path.to_path_buf().to_slash().unwrap(),
+            manifest::sha256(contents),
+        );
         Ok(())
     }
 
@@ -1605,7 +2097,7 @@ impl Archive {
     ///
     /// If you drop an `Archive` without calling this, it will leave a temporary
     /// file rather than creating the final archive.
-    fn finish(self) -> Result<()> {
+    pub(crate) fn finish(self) -> Result<()> {
         let Self {
             tmp_path,
             final_path,
@@ -1642,6 +2134,15 @@ fn get_git_status() -> Result<(String, bool)> {
     Ok((rev, !status.success()))
 }
 
+/// Finds where cargo keeps the sources of our dependencies, if we can.
+fn cargo_home() -> Option<PathBuf> {
+    if let Some(home) = std::env::var_os("CARGO_HOME") {
+        return Some(PathBuf::from(home));
+    }
+    let home = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    std::env::var_os(home).map(|home| PathBuf::from(home).join(".cargo"))
+}
+
 fn write_srec(
     sections: &BTreeMap<u32, LoadSegment>,
     kentry: u32,
@@ -1741,7 +2242,7 @@ fn cargo_clean(name: &str, target: &str) -> Result<()> {
     Ok(())
 }
 
-fn resolve_task_slots(
+pub(crate) fn resolve_task_slots(
     task_name: &String,
     all_tasks_toml: &IndexMap<String, Task>,
     task_bin: &PathBuf,
@@ -1798,3 +2299,120 @@ fn resolve_task_slots(
 
     Ok(std::fs::write(task_bin, out_task_bin)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> AppTable {
+        let region = |base, size, attributes| abi::RegionDesc {
+            base,
+            size,
+            attributes,
+            reserved_zero: 0,
+        };
+        AppTable {
+            regions: vec![
+                region(0, 32, abi::RegionAttributes::empty()),
+                region(0x0800_0000, 0x1000, abi::RegionAttributes::READ),
+                region(0x2400_0000, 0x400, abi::RegionAttributes::WRITE),
+            ],
+            tasks: vec![abi::TaskDesc {
+                regions: [1, 2, 0, 0, 0, 0, 0, 7],
+                entry_point: 0x0800_0101,
+                initial_stack: 0x2400_0400,
+                priority: 3,
+                flags: abi::TaskFlags::START_AT_BOOT,
+            }],
+            irqs: vec![abi::Interrupt {
+                irq: 33,
+                task: 0,
+                notification: 0b10,
+            }],
+            fault_notification: Some(1),
+        }
+    }
+
+    #[test]
+    fn app_table_round_trip() -> Result<()> {
+        let words = table().words();
+        assert_eq!(words.len(), 8 + 3 * 4 + 6 + 3);
+        let read = AppTable::from_words(&words)?;
+        assert_eq!(read.words(), words);
+        assert_eq!(read.tasks[0].regions, [1, 2, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(read.fault_notification, Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn app_table_corrupt() {
+        let words = table().words();
+        let err = |words: &[u32]| {
+            AppTable::from_words(words).err().map(|e| e.to_string())
+        };
+        assert_eq!(err(&words[..4]), Some("app table has no header".into()));
+        assert_eq!(
+            err(&words[..words.len() - 1]),
+            Some("app table is too short for its counts".into())
+        );
+
+        let mut huge = words.clone();
+        huge[2] = u32::MAX;
+        assert_eq!(
+            err(&huge),
+            Some("app table is too short for its counts".into())
+        );
+
+        let mut magic = words;
+        magic[0] = 0;
+        assert_eq!(err(&magic), Some("app table has bad magic 0x0".into()));
+    }
+
+    /// Signs a fake image the way `dist` signs the combined image, and
+    /// checks that stage0's verifier accepts it -- and nothing else.
+    #[test]
+    fn ecc_signed_image_verifies() -> Result<()> {
+        let src_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../..");
+        let key_path = "support/fake_certs/p256-private-key.der";
+        let signing = Signing {
+            method: "ecc".into(),
+            priv_key: Some(key_path.into()),
+            root_cert: None,
+        };
+
+        let out = std::env::temp_dir()
+            .join(format!("xtask-sign-{}", std::process::id()));
+        fs::create_dir_all(&out)?;
+        let mut image: Vec<u8> = (0..0x400u32).map(|i| i as u8).collect();
+        image[..8].copy_from_slice(&[0, 0x80, 0, 0x20, 0x01, 0x81, 0, 0]);
+        fs::write(out.join("combined.bin"), &image)?;
+
+        do_sign_file(&signing, &out, &src_dir, "combined")?;
+        let signed = fs::read(out.join("combined_ecc.bin"))?;
+        fs::remove_dir_all(&out)?;
+
+        // The key hash to provision is that of the public key, which we
+        // work out independently of what the signer embedded.
+        let der = fs::read(src_dir.join(key_path))?;
+        let marker = [0x02, 0x01, 0x01, 0x04, 0x20];
+        let at = der.windows(marker.len()).position(|w| w == marker).unwrap()
+            + marker.len();
+        let key =
+            p256::ecdsa::SigningKey::from_bytes(&der[at..at + 32]).unwrap();
+        let public = p256::ecdsa::VerifyingKey::from(&key)
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec();
+        let hash = image_verify::key_hash(&public);
+
+        image_verify::verify_image(&signed, &hash)
+            .map_err(|e| anyhow!("signed image rejected: {:?}", e))?;
+        assert_eq!(&signed[0x100..0x200], &image[0x100..0x200]);
+
+        let mut tampered = signed.clone();
+        tampered[0x123] ^= 1;
+        assert!(image_verify::verify_image(&tampered, &hash).is_err());
+        assert!(image_verify::verify_image(&signed, &[0; 32]).is_err());
+        Ok(())
+    }
+}
@@ -9,13 +9,12 @@ use std::process::Command;
 
 use anyhow::Context;
 
-use crate::Config;
+use crate::{config, Config};
 
 pub fn run(verbose: bool, cfg: &Path) -> anyhow::Result<()> {
     ctrlc::set_handler(|| {}).expect("Error setting Ctrl-C handler");
 
-    let cfg_contents = std::fs::read(&cfg)?;
-    let toml: Config = toml::from_slice(&cfg_contents)?;
+    let toml: Config = config::load(cfg)?;
 
     let mut out = PathBuf::from("target");
     out.push(toml.name);
@@ -5,13 +5,12 @@
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use crate::Config;
+use crate::{config, Config};
 
 pub fn run(cfg: &Path, gdb_cfg: &Path) -> anyhow::Result<()> {
     ctrlc::set_handler(|| {}).expect("Error setting Ctrl-C handler");
 
-    let cfg_contents = std::fs::read(&cfg)?;
-    let toml: Config = toml::from_slice(&cfg_contents)?;
+    let toml: Config = config::load(cfg)?;
 
     let mut out = PathBuf::from("target");
     out.push(toml.name);
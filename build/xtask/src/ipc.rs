@@ -0,0 +1,277 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! IPC topology checks.
+//!
+//! Hubris avoids deadlock by having tasks only SEND to tasks that are more
+//! important than they are; a task blocked in SEND is then always waiting on
+//! something that will get to run first. A task's `task-slots` say which
+//! tasks it can find, and so which it can SEND to, so from those and each
+//! task's priority we can check the rule before building anything.
+//!
+//! - Sending to a less important task is a violation.
+//! - Sending to a task of equal priority is tolerated, since the scheduler
+//!   will get to both, but a cycle of such sends can still deadlock, so any
+//!   cycle in the graph is a violation too.
+//!
+//! A task can list, in `allow-send-to`, tasks it may send to regardless;
+//! those edges are still drawn, but not checked. Sends to the kernel are
+//! always up the ladder, so they're left out altogether.
+//!
+//! The graph is also written out, as DOT and JSON, for design reviews.
+
+use std::io::Write;
+
+use anyhow::Result;
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::Task;
+
+#[derive(Debug, Serialize)]
+pub struct IpcGraph {
+    pub tasks: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Node {
+    pub name: String,
+    pub priority: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    /// Name of the task slot that makes this edge.
+    pub slot: String,
+    /// Whether this goes to a task less important than `from`.
+    pub downward: bool,
+    /// Whether `from` has put `to` in its `allow-send-to`.
+    pub allowed: bool,
+}
+
+impl IpcGraph {
+    pub(crate) fn new(tasks: &IndexMap<String, Task>) -> Self {
+        let nodes = tasks
+            .iter()
+            .map(|(name, task)| Node {
+                name: name.clone(),
+                priority: task.priority,
+            })
+            .collect();
+
+        let mut edges = vec![];
+        for (name, task) in tasks {
+            for (slot, target) in &task.task_slots {
+                // Slots naming tasks that don't exist are caught elsewhere.
+                let to = match tasks.get(target) {
+                    Some(to) => to,
+                    None => continue,
+                };
+                edges.push(Edge {
+                    from: name.clone(),
+                    to: target.clone(),
+                    slot: slot.clone(),
+                    downward: to.priority > task.priority,
+                    allowed: task.allow_send_to.contains(target),
+                });
+            }
+        }
+
+        Self {
+            tasks: nodes,
+            edges,
+        }
+    }
+
+    fn priority(&self, task: &str) -> u32 {
+        self.tasks.iter().find(|t| t.name == task).unwrap().priority
+    }
+
+    /// Returns a description of every violation of the rules above.
+    pub fn violations(&self) -> Vec<String> {
+        let mut violations = vec![];
+
+        for e in self.edges.iter().filter(|e| e.downward && !e.allowed) {
+            violations.push(format!(
+                "{} (priority {}) can send to less important {} (priority \
+                 {}) through slot `{}`",
+                e.from,
+                self.priority(&e.from),
+                e.to,
+                self.priority(&e.to),
+                e.slot
+            ));
+        }
+
+        for cycle in self.cycles() {
+            violations.push(format!("send cycle: {}", cycle.join(" -> ")));
+        }
+
+        violations
+    }
+
+    /// Finds cycles among the edges that aren't allowed, returning each as
+    /// the list of tasks around it, with the first repeated at the end.
+    fn cycles(&self) -> Vec<Vec<String>> {
+        #[derive(Copy, Clone, PartialEq)]
+        enum State {
+            Unvisited,
+            OnPath,
+            Done,
+        }
+
+        fn visit<'a>(
+            graph: &'a IpcGraph,
+            task: &'a str,
+            state: &mut IndexMap<&'a str, State>,
+            path: &mut Vec<&'a str>,
+            cycles: &mut Vec<Vec<String>>,
+        ) {
+            state[task] = State::OnPath;
+            path.push(task);
+            for e in graph.edges.iter().filter(|e| e.from == task) {
+                if e.allowed {
+                    continue;
+                }
+                match state[e.to.as_str()] {
+                    State::Unvisited => {
+                        visit(graph, &e.to, state, path, cycles);
+                    }
+                    State::OnPath => {
+                        let start =
+                            path.iter().position(|&t| t == e.to).unwrap();
+                        let mut cycle = path[start..]
+                            .iter()
+                            .map(|t| t.to_string())
+                            .collect::<Vec<_>>();
+                        cycle.push(e.to.clone());
+                        cycles.push(cycle);
+                    }
+                    State::Done => (),
+                }
+            }
+            path.pop();
+            state[task] = State::Done;
+        }
+
+        let mut state = self
+            .tasks
+            .iter()
+            .map(|t| (t.name.as_str(), State::Unvisited))
+            .collect::<IndexMap<_, _>>();
+        let mut cycles = vec![];
+        for t in &self.tasks {
+            if state[t.name.as_str()] == State::Unvisited {
+                visit(self, &t.name, &mut state, &mut vec![], &mut cycles);
+            }
+        }
+        cycles
+    }
+
+    /// Writes the graph in Graphviz's DOT language. Sends to less important
+    /// tasks are drawn in red, or dashed if they're allowed.
+    pub fn write_dot(&self, out: &mut impl Write) -> Result<()> {
+        writeln!(out, "digraph ipc {{")?;
+        writeln!(out, "    rankdir=BT;")?;
+        writeln!(out, "    node [shape=box];")?;
+
+        for t in &self.tasks {
+            writeln!(
+                out,
+                "    \"{}\" [label=\"{}\\npriority {}\"];",
+                t.name, t.name, t.priority
+            )?;
+        }
+
+        for e in &self.edges {
+            let style = match (e.downward, e.allowed) {
+                (_, true) => " style=dashed",
+                (true, false) => " color=red",
+                (false, false) => "",
+            };
+            writeln!(
+                out,
+                "    \"{}\" -> \"{}\" [label=\"{}\"{}];",
+                e.from, e.to, e.slot, style
+            )?;
+        }
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes a graph of tasks given as (name, priority, task slots, allowed
+    /// sends).
+    fn graph(tasks: &[(&str, u32, &[&str], &[&str])]) -> IpcGraph {
+        let mut text = String::new();
+        for (name, priority, slots, allowed) in tasks {
+            text += &format!(
+                "[{}]\npath = \".\"\nname = \"task-{}\"\npriority = {}\n\
+                 task-slots = {:?}\nallow-send-to = {:?}\n",
+                name, name, priority, slots, allowed
+            );
+        }
+        IpcGraph::new(&toml::from_str(&text).unwrap())
+    }
+
+    #[test]
+    fn up_the_ladder() {
+        let g = graph(&[
+            ("jefe", 0, &[], &[]),
+            ("server", 1, &[], &[]),
+            ("client", 2, &["server", "jefe"], &[]),
+            ("peer", 2, &["client"], &[]),
+        ]);
+        assert_eq!(g.edges.len(), 3);
+        assert!(g.violations().is_empty());
+    }
+
+    #[test]
+    fn inversion() {
+        let g =
+            graph(&[("server", 2, &[], &[]), ("client", 1, &["server"], &[])]);
+        assert_eq!(
+            g.violations(),
+            ["client (priority 1) can send to less important server \
+                 (priority 2) through slot `server`"]
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let g = graph(&[
+            ("idle", 3, &[], &[]),
+            ("a", 1, &["b"], &[]),
+            ("b", 1, &["c"], &[]),
+            ("c", 1, &["a"], &[]),
+        ]);
+        assert_eq!(g.violations(), ["send cycle: a -> b -> c -> a"]);
+    }
+
+    #[test]
+    fn allowed() {
+        let g = graph(&[
+            ("server", 2, &["client"], &[]),
+            ("client", 1, &["server"], &["server"]),
+        ]);
+        // The allowed edge is neither an inversion nor part of a cycle.
+        assert!(g.violations().is_empty());
+        assert!(g.edges.iter().any(|e| e.downward && e.allowed));
+
+        let mut dot = vec![];
+        g.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains(
+            "\"client\" -> \"server\" [label=\"server\" style=dashed];"
+        ));
+        assert!(dot.contains("\"server\" -> \"client\" [label=\"client\"];"));
+    }
+}
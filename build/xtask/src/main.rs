@@ -13,14 +13,24 @@ use indexmap::IndexMap;
 
 mod check;
 mod clippy;
+mod config;
+mod descriptor;
+mod diff;
 mod dist;
 mod elf;
 mod flash;
 mod gdb;
 mod humility;
+mod ipc;
 mod license;
+mod manifest;
+#[cfg(unix)]
+mod sim;
+mod sizes;
+mod stack;
 mod task_slot;
 mod test;
+mod validate;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -37,6 +47,10 @@ enum Xtask {
         /// Run `cargo tree --edges features ...` before each invoction of `cargo rustc ...`
         #[structopt(short, long)]
         edges: bool,
+        /// Print the configuration, merged with any it inherits, instead of
+        /// building anything.
+        #[structopt(long)]
+        print_config: bool,
         /// Path to the image configuration file, in TOML.
         cfg: PathBuf,
     },
@@ -112,6 +126,47 @@ enum Xtask {
         all: bool,
     },
 
+    /// Builds an application's tasks for the host, and runs them there against
+    /// a simulated kernel. Tasks that use peripherals are replaced by mocks.
+    Sim {
+        /// Request verbosity from tools we shell out to.
+        #[structopt(short)]
+        verbose: bool,
+        /// Replace a task with a mock, even if it could run on the host.
+        #[structopt(long, number_of_values = 1)]
+        mock: Vec<String>,
+        /// Stop after this many milliseconds, rather than running until
+        /// every task is blocked for good.
+        #[structopt(long)]
+        duration: Option<u64>,
+        /// Path to the image configuration file, in TOML.
+        cfg: PathBuf,
+    },
+
+    /// Compares two build archives, showing what changed in sizes, symbols,
+    /// region layout, interrupts, task slots, the app descriptor and config.
+    Diff {
+        /// Print the comparison as JSON, e.g. for CI to comment with.
+        #[structopt(long)]
+        json: bool,
+        /// Path to the build archive to compare from.
+        old: PathBuf,
+        /// Path to the build archive to compare to.
+        new: PathBuf,
+    },
+
+    /// Rebuilds an image, and checks that the result is identical to a build
+    /// archive, using the archive's manifest.
+    VerifyArchive {
+        /// Request verbosity from tools we shell out to.
+        #[structopt(short)]
+        verbose: bool,
+        /// Path to the image configuration file, in TOML.
+        cfg: PathBuf,
+        /// Path to the build archive to check.
+        archive: PathBuf,
+    },
+
     /// Show a task's .task_slot_table contents
     TaskSlots {
         /// Path to task executable
@@ -168,6 +223,11 @@ struct Bootloader {
     imagea_flash_size: u32,
     imagea_ram_start: u32,
     imagea_ram_size: u32,
+    /// Flash for a second image slot, if the board has room for one. Images
+    /// in it run from the same RAM as image A, but must be linked for their
+    /// own flash address.
+    imageb_flash_start: Option<u32>,
+    imageb_flash_size: Option<u32>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -207,7 +267,10 @@ struct Output {
 struct Task {
     path: PathBuf,
     name: String,
-    requires: IndexMap<String, u32>,
+    /// Memory needed, by output name. Leaving this out is the same as
+    /// asking for `flash = "auto", ram = "auto"`.
+    #[serde(default = "auto_requires")]
+    requires: IndexMap<String, Requirement>,
     priority: u32,
     stacksize: Option<u32>,
     #[serde(default)]
@@ -222,10 +285,65 @@ struct Task {
     sections: IndexMap<String, String>,
     #[serde(default, deserialize_with = "deserialize_task_slot")]
     task_slots: IndexMap<String, String>,
+    /// Tasks this one may send to even though that breaks the rules checked
+    /// in `ipc`, e.g. because they're less important.
+    #[serde(default)]
+    allow_send_to: Vec<String>,
     #[serde(default)]
     config: Option<toml::Value>,
 }
 
+impl Task {
+    /// Returns the size of `mem` this task requires. This panics if the size
+    /// is still `auto`, which `xtask dist` resolves before allocating.
+    fn required(&self, mem: &str) -> u32 {
+        match self.requires[mem] {
+            Requirement::Size(size) => size,
+            Requirement::Auto => {
+                panic!("{}: {} size was never resolved", self.name, mem)
+            }
+        }
+    }
+}
+
+/// How much of an output a task requires.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Requirement {
+    Size(u32),
+    /// Work it out from a trial build, written as `"auto"` in the app.toml.
+    Auto,
+}
+
+impl<'de> Deserialize<'de> for Requirement {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Size(u32),
+            Keyword(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Size(size) => Ok(Requirement::Size(size)),
+            Raw::Keyword(s) if s == "auto" => Ok(Requirement::Auto),
+            Raw::Keyword(s) => Err(serde::de::Error::invalid_value(
+                serde::de::Unexpected::Str(&s),
+                &"a size in bytes or \"auto\"",
+            )),
+        }
+    }
+}
+
+fn auto_requires() -> IndexMap<String, Requirement> {
+    let mut requires = IndexMap::new();
+    requires.insert(String::from("flash"), Requirement::Auto);
+    requires.insert(String::from("ram"), Requirement::Auto);
+    requires
+}
+
 /// In the common case, task slots map back to a task of the same name (e.g.
 /// `gpio_driver`, `rcc_driver`).  However, certain tasks need generic task
 /// slot names, e.g. they'll have a task slot named `spi_driver` which will
@@ -364,9 +482,14 @@ fn main() -> Result<()> {
         Xtask::Dist {
             verbose,
             edges,
+            print_config,
             cfg,
         } => {
-            dist::package(verbose, edges, &cfg)?;
+            if print_config {
+                print!("{}", config::to_string(&config::read(&cfg)?));
+            } else {
+                dist::package(verbose, edges, &cfg)?;
+            }
         }
         Xtask::Flash { verbose, cfg } => {
             dist::package(verbose, false, &cfg)?;
@@ -407,6 +530,32 @@ fn main() -> Result<()> {
             let requested = RequestedPackages::new(package, target, all);
             run_for_packages(requested, clippy::run)?;
         }
+        Xtask::Sim {
+            verbose,
+            mock,
+            duration,
+            cfg,
+        } => {
+            #[cfg(unix)]
+            sim::run(verbose, &cfg, &mock, duration)?;
+            #[cfg(not(unix))]
+            {
+                let _ = (verbose, cfg, mock, duration);
+                anyhow::bail!(
+                    "xtask sim needs Unix sockets, so only runs on Unix"
+                );
+            }
+        }
+        Xtask::Diff { json, old, new } => {
+            diff::run(&old, &new, json)?;
+        }
+        Xtask::VerifyArchive {
+            verbose,
+            cfg,
+            archive,
+        } => {
+            manifest::verify_archive(verbose, &cfg, &archive)?;
+        }
         Xtask::TaskSlots { task_bin } => {
             task_slot::dump_task_slot_table(&task_bin)?;
         }
@@ -0,0 +1,497 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Build manifests, and checking archives against them.
+//!
+//! `xtask dist` writes a `manifest.json` into each build archive, recording
+//! the SHA-256 of every other file in it, the toolchain it was built with, the
+//! features each component was built with, and the app.toml as resolved
+//! (with any `auto` sizes replaced by the sizes chosen). Since the archive is
+//! built to be reproducible, `xtask verify-archive` can then check an archive
+//! by rebuilding it from the same source and comparing the manifests, which is
+//! how we make sure an image came from the source it claims to before signing
+//! it.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{config, dist, Config, Requirement};
+
+/// Version of the manifest format, bumped when its meaning changes.
+const VERSION: u32 = 1;
+
+/// Name of the manifest, both in the archive and in the `dist` directory.
+pub const MANIFEST: &str = "manifest.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub name: String,
+    /// Commit the archive was built from, as in the archive's `git-rev`.
+    pub git_rev: String,
+    /// The compiler, as reported by `rustc -V`.
+    pub toolchain: String,
+    pub kernel: Component,
+    /// Each task, in app.toml order.
+    pub tasks: IndexMap<String, Component>,
+    /// The app.toml, as resolved.
+    pub config: serde_json::Value,
+    /// SHA-256 of every other file in the archive, by path.
+    pub files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Component {
+    /// The crate the component was built from.
+    pub package: String,
+    pub features: Vec<String>,
+}
+
+impl Manifest {
+    /// Describes the build of `toml`, from the app.toml at `cfg`, given the
+    /// hashes of the files in its archive.
+    pub(crate) fn new(
+        toml: &Config,
+        cfg: &Path,
+        git_rev: String,
+        files: BTreeMap<String, String>,
+    ) -> Result<Self> {
+        let component = |package: &String, features: &Vec<String>| Component {
+            package: package.clone(),
+            features: features.clone(),
+        };
+
+        Ok(Self {
+            version: VERSION,
+            name: toml.name.clone(),
+            git_rev,
+            toolchain: toolchain()?,
+            kernel: component(&toml.kernel.name, &toml.kernel.features),
+            tasks: toml
+                .tasks
+                .iter()
+                .map(|(name, task)| {
+                    (name.clone(), component(&task.name, &task.features))
+                })
+                .collect(),
+            config: resolved_config(toml, cfg)?,
+            files,
+        })
+    }
+}
+
+/// Returns the SHA-256 of `contents`, in hex.
+pub fn sha256(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/// Asks the compiler who it is.
+fn toolchain() -> Result<String> {
+    let mut cmd = Command::new("rustc");
+    cmd.arg("-V");
+    let out = cmd
+        .output()
+        .context(format!("failed to run rustc ({:?})", cmd))?;
+    if !out.status.success() {
+        bail!("rustc -V failed");
+    }
+    Ok(std::str::from_utf8(&out.stdout)?.trim().to_string())
+}
+
+/// Reads the app.toml at `cfg`, merged with what it inherits, and fills in
+/// the sizes that `xtask dist` resolved in `toml`.
+fn resolved_config(toml: &Config, cfg: &Path) -> Result<serde_json::Value> {
+    let mut value = config::read(cfg)?;
+
+    for (name, task) in &toml.tasks {
+        let mut requires = toml::value::Table::new();
+        for (mem, req) in &task.requires {
+            let size = match req {
+                Requirement::Size(size) => i64::from(*size),
+                Requirement::Auto => {
+                    bail!("task {}: {} size was never resolved", name, mem)
+                }
+            };
+            requires.insert(mem.clone(), toml::Value::Integer(size));
+        }
+        if let Some(task) = value
+            .get_mut("tasks")
+            .and_then(|tasks| tasks.get_mut(name))
+            .and_then(|task| task.as_table_mut())
+        {
+            task.insert("requires".to_string(), toml::Value::Table(requires));
+        }
+    }
+
+    Ok(serde_json::to_value(value)?)
+}
+
+/// Reads the manifest from the archive at `path`, and checks the archive's
+/// files against it, returning the manifest and whatever didn't match.
+fn read_archive(path: &Path) -> Result<(Manifest, Vec<String>)> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let mut zip = zip::ZipArchive::new(file)?;
+
+    let mut contents = BTreeMap::new();
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        contents.insert(entry.name().to_string(), data);
+    }
+
+    let manifest: Manifest = match contents.remove(MANIFEST) {
+        Some(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("failed to parse {}", MANIFEST))?,
+        None => bail!(
+            "{} has no {}; it was built before archives had one",
+            path.display(),
+            MANIFEST
+        ),
+    };
+    if manifest.version != VERSION {
+        bail!(
+            "{} has a version {} manifest, but we only know version {}",
+            path.display(),
+            manifest.version,
+            VERSION
+        );
+    }
+
+    let mut problems = vec![];
+    for (name, hash) in &manifest.files {
+        match contents.remove(name) {
+            Some(data) if &sha256(&data) == hash => (),
+            Some(_) => problems.push(format!(
+                "{} in the archive doesn't match its manifest",
+                name
+            )),
+            None => problems.push(format!(
+                "{} is in the manifest, but not the archive",
+                name
+            )),
+        }
+    }
+    for name in contents.keys() {
+        problems
+            .push(format!("{} is in the archive, but not its manifest", name));
+    }
+
+    Ok((manifest, problems))
+}
+
+/// Rebuilds the app.toml at `cfg`, and checks that the result is identical to
+/// the archive at `archive`.
+pub fn verify_archive(verbose: bool, cfg: &Path, archive: &Path) -> Result<()> {
+    // Read the archive first, in case rebuilding would overwrite it.
+    let (expected, mut problems) = read_archive(archive)?;
+    if expected.git_rev.ends_with("-dirty") {
+        println!(
+            "warning: {} was built with uncommitted changes, so the source \
+             it came from may not be recoverable",
+            archive.display()
+        );
+    }
+
+    dist::package(verbose, false, cfg)?;
+
+    let toml = config::load(cfg)?;
+    let manifest = PathBuf::from("target")
+        .join(&toml.name)
+        .join("dist")
+        .join(MANIFEST);
+    let actual: Manifest = serde_json::from_slice(&std::fs::read(&manifest)?)?;
+    problems.extend(compare(&expected, &actual));
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("error: {}", problem);
+        }
+        bail!(
+            "{} can't be reproduced from this source ({} problem(s))",
+            archive.display(),
+            problems.len()
+        );
+    }
+
+    println!(
+        "{} is reproducible: {} files match",
+        archive.display(),
+        expected.files.len()
+    );
+    Ok(())
+}
+
+/// Describes every way in which the `actual` build differs from the
+/// `expected` one.
+fn compare(expected: &Manifest, actual: &Manifest) -> Vec<String> {
+    let mut problems = vec![];
+
+    if actual.name != expected.name {
+        problems.push(format!(
+            "archive is of {}, not {}",
+            expected.name, actual.name
+        ));
+    }
+    if actual.git_rev != expected.git_rev {
+        problems.push(format!(
+            "archive was built from {}, but this is {}",
+            expected.git_rev, actual.git_rev
+        ));
+    }
+    if actual.toolchain != expected.toolchain {
+        problems.push(format!(
+            "archive was built by {}, but this is {}",
+            expected.toolchain, actual.toolchain
+        ));
+    }
+    if actual.kernel != expected.kernel {
+        problems.push(format!(
+            "kernel was built as {:?}, but is now {:?}",
+            expected.kernel, actual.kernel
+        ));
+    }
+    for (name, component) in &expected.tasks {
+        match actual.tasks.get(name) {
+            Some(c) if c == component => (),
+            Some(c) => problems.push(format!(
+                "task {} was built as {:?}, but is now {:?}",
+                name, component, c
+            )),
+            None => problems.push(format!("task {} no longer exists", name)),
+        }
+    }
+    for name in actual.tasks.keys() {
+        if !expected.tasks.contains_key(name) {
+            problems.push(format!("task {} is new", name));
+        }
+    }
+    if actual.config != expected.config {
+        problems.push("the resolved app.toml differs".to_string());
+    }
+    for (name, hash) in &expected.files {
+        match actual.files.get(name) {
+            Some(h) if h == hash => (),
+            Some(_) => problems.push(format!("{} differs", name)),
+            None => problems.push(format!("{} wasn't rebuilt", name)),
+        }
+    }
+    for name in actual.files.keys() {
+        if !expected.files.contains_key(name) {
+            problems.push(format!("{} is new", name));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dist::Archive;
+
+    const APP: &str = r#"
+        name = "demo"
+        target = "thumbv7em-none-eabihf"
+        board = "demo-board"
+
+        [kernel]
+        path = "."
+        name = "kernel"
+        requires = { flash = 1024, ram = 1024 }
+
+        [outputs.flash]
+        address = 0x08000000
+        size = 0x10000
+        read = true
+        execute = true
+
+        [tasks.jefe]
+        path = "."
+        name = "task-jefe"
+        priority = 0
+        requires = { flash = "auto", ram = 256 }
+    "#;
+
+    /// Makes a fresh directory for `name`, returning its path.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "xtask-manifest-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn manifest(files: BTreeMap<String, String>) -> Manifest {
+        let component = |package: &str| Component {
+            package: package.to_string(),
+            features: vec!["itm".to_string()],
+        };
+        let mut tasks = IndexMap::new();
+        tasks.insert("jefe".to_string(), component("task-jefe"));
+        Manifest {
+            version: VERSION,
+            name: "demo".to_string(),
+            git_rev: "0123456789abcdef".to_string(),
+            toolchain: "rustc 1.57.0-nightly (2021-09-22)".to_string(),
+            kernel: component("kernel"),
+            tasks,
+            config: serde_json::json!({ "name": "demo" }),
+            files,
+        }
+    }
+
+    /// Writes an archive of `files` at `path`, the way `xtask dist` does,
+    /// returning the manifest it contains.
+    fn archive(path: &Path, files: &[(&str, &str)]) -> Manifest {
+        let mut archive = Archive::new(path).unwrap();
+        for (name, text) in files {
+            archive.text(name, text).unwrap();
+        }
+        let manifest = manifest(archive.files.clone());
+        archive
+            .text(MANIFEST, serde_json::to_string_pretty(&manifest).unwrap())
+            .unwrap();
+        archive.finish().unwrap();
+        manifest
+    }
+
+    #[test]
+    fn resolves_sizes() -> Result<()> {
+        let cfg = scratch("resolves").join("app.toml");
+        std::fs::write(&cfg, APP)?;
+        let mut toml = config::load(&cfg)?;
+        toml.tasks["jefe"]
+            .requires
+            .insert("flash".to_string(), Requirement::Size(0x800));
+
+        let config = resolved_config(&toml, &cfg)?;
+        assert_eq!(
+            config["tasks"]["jefe"]["requires"],
+            serde_json::json!({ "flash": 0x800, "ram": 256 })
+        );
+        assert_eq!(config["kernel"]["requires"]["flash"], 1024);
+        Ok(())
+    }
+
+    #[test]
+    fn unresolved_size() -> Result<()> {
+        let cfg = scratch("unresolved").join("app.toml");
+        std::fs::write(&cfg, APP)?;
+        let toml = config::load(&cfg)?;
+
+        assert_eq!(
+            resolved_config(&toml, &cfg).unwrap_err().to_string(),
+            "task jefe: flash size was never resolved"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn archive_round_trip() -> Result<()> {
+        let path = scratch("round-trip").join("build-demo.zip");
+        let written = archive(
+            &path,
+            &[("elf/kernel", "kernel"), ("info/map.txt", "map")],
+        );
+
+        let (read, problems) = read_archive(&path)?;
+        assert_eq!(problems, Vec::<String>::new());
+        assert_eq!(read.files.len(), 2);
+        assert_eq!(compare(&written, &read), Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn archive_tampered() -> Result<()> {
+        // Build an archive whose manifest describes files other than the
+        // ones it holds, as if it were edited after the fact.
+        let path = scratch("tampered").join("build-demo.zip");
+        let mut files = BTreeMap::new();
+        files.insert("elf/kernel".to_string(), sha256(b"kernel"));
+        files.insert("info/map.txt".to_string(), sha256(b"map"));
+        let manifest = manifest(files);
+        let mut archive = Archive::new(&path)?;
+        archive.text("elf/kernel", "patched")?;
+        archive.text("img/extra.bin", "extra")?;
+        archive.text(MANIFEST, serde_json::to_string(&manifest)?)?;
+        archive.finish()?;
+
+        let (_, problems) = read_archive(&path)?;
+        assert_eq!(
+            problems,
+            [
+                "elf/kernel in the archive doesn't match its manifest",
+                "info/map.txt is in the manifest, but not the archive",
+                "img/extra.bin is in the archive, but not its manifest",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn archive_without_manifest() -> Result<()> {
+        let path = scratch("old").join("build-demo.zip");
+        let mut archive = Archive::new(&path)?;
+        archive.text("elf/kernel", "kernel")?;
+        archive.finish()?;
+
+        let err = read_archive(&path).unwrap_err().to_string();
+        assert!(err.ends_with(
+            "has no manifest.json; it was built before archives had one"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn differences() {
+        let mut files = BTreeMap::new();
+        files.insert("elf/kernel".to_string(), sha256(b"kernel"));
+        files.insert("info/map.txt".to_string(), sha256(b"map"));
+        let expected = manifest(files.clone());
+
+        files.insert("elf/kernel".to_string(), sha256(b"patched"));
+        files.remove("info/map.txt");
+        files.insert("img/extra.bin".to_string(), sha256(b"extra"));
+        let mut actual = manifest(files);
+        actual.git_rev = "fedcba9876543210".to_string();
+        actual.tasks["jefe"].features.clear();
+        actual.tasks.insert(
+            "idle".to_string(),
+            Component {
+                package: "task-idle".to_string(),
+                features: vec![],
+            },
+        );
+        actual.config["name"] = "other".into();
+
+        assert_eq!(
+            compare(&expected, &actual),
+            [
+                "archive was built from 0123456789abcdef, but this is \
+                 fedcba9876543210",
+                "task jefe was built as Component { package: \"task-jefe\", \
+                 features: [\"itm\"] }, but is now Component { package: \
+                 \"task-jefe\", features: [] }",
+                "task idle is new",
+                "the resolved app.toml differs",
+                "elf/kernel differs",
+                "info/map.txt wasn't rebuilt",
+                "img/extra.bin is new",
+            ]
+        );
+    }
+}
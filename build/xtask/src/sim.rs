@@ -0,0 +1,1603 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Running an application on the host.
+//!
+//! `xtask sim` builds each task in an application as a host program, with
+//! userlib's `host-sim` feature, and runs each as its own process. Their
+//! syscalls come here, over a Unix socket, to a kernel that implements them
+//! with the same rules as the real one: the wire format is described in
+//! userlib's `sim` module.
+//!
+//! A task blocks in every syscall until the kernel answers, and the kernel
+//! only lets one task run at a time, picking the most important runnable
+//! task each time the running one makes a syscall. That gets us Hubris's
+//! scheduling everywhere except in the middle of a long computation, which
+//! we can't preempt. Time is real time, in milliseconds, from when the
+//! simulation starts. A task that dies -- by panicking, or by taking a signal
+//! such as `SIGSEGV` -- is marked as faulted, and the supervisor is notified,
+//! so that it can restart it over `kipc` as it would on hardware.
+//!
+//! Tasks that `use` peripherals can't run on the host, so they're replaced
+//! by mocks, which always wait in an open receive and answer every message
+//! straight away. An I2C server -- a task that uses the peripheral of an
+//! initiator controller in the app.toml's `i2c.controllers`, e.g. `i2c2` --
+//! is replaced with a model of the devices on its controllers in the
+//! `i2c.devices` table, each with a file of 256 registers that reads back
+//! what was last written to it. Any other mock succeeds at
+//! everything, and replies with zeros. The idle task is left out, since the
+//! simulated kernel can wait on its own, and any other task can be mocked
+//! with `--mock`.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+use abi::{
+    FaultInfo, FaultSource, Generation, LeaseAttributes, SchedState, Sysnum,
+    TaskId, TaskState, UsageError,
+};
+
+use crate::{config, dist, validate, Config, Task};
+
+pub fn run(
+    verbose: bool,
+    cfg: &Path,
+    mocks: &[String],
+    duration: Option<u64>,
+) -> Result<()> {
+    let toml = config::load(cfg)?;
+
+    let mut src_dir = cfg.to_path_buf();
+    src_dir.pop();
+
+    let problems = validate::check(&toml, &src_dir);
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        bail!("{} has {} problem(s)", cfg.display(), problems.len());
+    }
+
+    for name in mocks {
+        if !toml.tasks.contains_key(name) {
+            bail!("can't mock {}: there's no such task", name);
+        }
+    }
+
+    let out = PathBuf::from("target").join(&toml.name).join("sim");
+    std::fs::create_dir_all(&out)?;
+
+    let task_names = toml.tasks.keys().cloned().collect::<Vec<_>>();
+    let task_names = task_names.join(",");
+
+    let i2c = I2cConfig::load(&toml)?;
+
+    let mut tasks = vec![];
+    for (name, task) in &toml.tasks {
+        let body = if task.name == "task-idle" {
+            println!("sim: leaving out {}", name);
+            Body::Mock(Mock::Idle)
+        } else if !task.uses.is_empty() || mocks.contains(name) {
+            let controllers = i2c.initiators(task);
+            if !controllers.is_empty() {
+                println!("sim: mocking {} with an I2C bus model", name);
+                Body::Mock(Mock::I2c(I2cBus::new(&i2c, &controllers)))
+            } else {
+                println!("sim: mocking {}", name);
+                Body::Mock(Mock::Generic)
+            }
+        } else {
+            build(verbose, &toml, name, &src_dir, &out, &task_names)?;
+            Body::Process {
+                path: out.join(name),
+                child: None,
+                stream: None,
+            }
+        };
+        tasks.push(SimTask::new(name, task, body));
+    }
+
+    let socket = std::env::current_dir()?.join(&out).join("kernel.sock");
+    let _ = std::fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket)
+        .with_context(|| format!("failed to listen on {}", socket.display()))?;
+    listener.set_nonblocking(true)?;
+
+    let mut sim = Sim {
+        tasks,
+        listener,
+        socket,
+        start: Instant::now(),
+        fault_notification: toml
+            .supervisor
+            .as_ref()
+            .map_or(0, |s| s.notification),
+        last: 0,
+    };
+    sim.run(duration)
+}
+
+/// Builds task `name` for the host, into `out`, and fills in its task slots.
+fn build(
+    verbose: bool,
+    toml: &Config,
+    name: &str,
+    src_dir: &Path,
+    out: &Path,
+    task_names: &str,
+) -> Result<()> {
+    let task = &toml.tasks[name];
+    println!("building {} for the host", name);
+
+    let mut features = task.features.clone();
+    features.push("userlib/host-sim".into());
+
+    // We use the host's own target here, so anything we pass to rustc through
+    // RUSTFLAGS would also apply to build scripts and proc macros; instead,
+    // we pass our flags to the task alone. It's linked at a fixed address, so
+    // that we can fill in its task slots just like on a real target, and with
+    // dead code kept, so that the table of them is kept too.
+    let mut cmd = Command::new("cargo");
+    cmd.arg("rustc")
+        .arg("--release")
+        .arg("--no-default-features")
+        .arg("--features")
+        .arg(features.join(","));
+    if verbose {
+        cmd.arg("-v");
+    }
+    cmd.arg("--")
+        .arg("-C")
+        .arg("relocation-model=static")
+        .arg("-C")
+        .arg("link-dead-code");
+
+    let target_dir = std::env::current_dir()?.join("target").join("sim");
+    cmd.current_dir(src_dir.join(&task.path));
+    cmd.env("CARGO_TARGET_DIR", &target_dir);
+    cmd.env("HUBRIS_TASKS", task_names);
+    cmd.env("HUBRIS_BOARD", &toml.board);
+    cmd.env("HUBRIS_TASK_NAME", name);
+    if let Some(config) = &task.config {
+        cmd.env("HUBRIS_TASK_CONFIG", toml::to_string(config)?);
+    }
+    if let Some(config) = &toml.config {
+        cmd.env("HUBRIS_APP_CONFIG", toml::to_string(config)?);
+    }
+
+    let status = cmd
+        .status()
+        .context(format!("failed to run rustc ({:?})", cmd))?;
+    if !status.success() {
+        bail!(
+            "failed to build {} for the host; if it can't run there, \
+             use `--mock {}` to stand in a mock for it",
+            name,
+            name
+        );
+    }
+
+    let dest = out.join(name);
+    std::fs::copy(target_dir.join("release").join(&task.name), &dest)?;
+
+    // The host's linker drops the table of task slots if it's empty, rather
+    // than keeping it as our linker script does.
+    if !task.task_slots.is_empty() {
+        dist::resolve_task_slots(
+            &name.to_string(),
+            &toml.tasks,
+            &dest,
+            verbose,
+        )?;
+    }
+    Ok(())
+}
+
+/// The simulated kernel.
+struct Sim {
+    tasks: Vec<SimTask>,
+    listener: UnixListener,
+    socket: PathBuf,
+    start: Instant,
+    /// Notification bits posted to the supervisor when a task faults.
+    fault_notification: u32,
+    /// The task that ran last, after which the search for the next starts.
+    last: usize,
+}
+
+struct SimTask {
+    name: String,
+    priority: u32,
+    generation: Generation,
+    state: TaskState,
+    body: Body,
+    /// Results of the task's last syscall, to give it when it next runs.
+    resume: Option<Vec<u8>>,
+    /// The message the task is sending, kept from its SEND until its reply
+    /// so that the receiver can borrow from its leases.
+    outgoing: Option<Message>,
+    /// Size of the buffer passed to the task's last RECV.
+    recv_capacity: usize,
+    /// Notification mask passed to the task's last RECV.
+    recv_mask: u32,
+    notifications: u32,
+    /// Timer deadline, and the notification bits to post then.
+    timer: Option<(u64, u32)>,
+    faults: u32,
+}
+
+enum Body {
+    Process {
+        path: PathBuf,
+        child: Option<Child>,
+        stream: Option<UnixStream>,
+    },
+    Mock(Mock),
+}
+
+struct Message {
+    operation: u16,
+    data: Vec<u8>,
+    response_capacity: usize,
+    leases: Vec<Lease>,
+}
+
+struct Lease {
+    attributes: LeaseAttributes,
+    data: Vec<u8>,
+}
+
+/// A syscall's results, on their way back to the task.
+struct Frame(Vec<u8>);
+
+impl Frame {
+    fn new() -> Self {
+        Self(vec![])
+    }
+
+    fn word(mut self, word: u32) -> Self {
+        self.0.extend_from_slice(&word.to_le_bytes());
+        self
+    }
+
+    fn bytes(self, bytes: &[u8]) -> Self {
+        let mut this = self.word(bytes.len() as u32);
+        this.0.extend_from_slice(bytes);
+        this
+    }
+}
+
+/// A syscall's arguments, as they came from the task.
+struct Args {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Args {
+    /// Takes `len` bytes of arguments. A frame too short for its syscall's
+    /// arguments is the closest thing we have to a bad slice.
+    fn take(&mut self, len: usize) -> Result<&[u8], FaultInfo> {
+        if self.data.len() - self.pos < len {
+            return Err(FaultInfo::SyscallUsage(UsageError::InvalidSlice));
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+
+    fn word(&mut self) -> Result<u32, FaultInfo> {
+        let mut word = [0; 4];
+        word.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, FaultInfo> {
+        let len = self.word()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+fn write_frame(stream: &mut UnixStream, payload: &[u8]) -> io::Result<()> {
+    stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+    stream.write_all(payload)
+}
+
+fn read_frame(stream: &mut UnixStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut payload = vec![0; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Works out the fault a task took from how its process ended.
+fn exit_fault(status: ExitStatus) -> FaultInfo {
+    // Signal numbers, as on Linux and macOS.
+    const SIGILL: i32 = 4;
+    const SIGTRAP: i32 = 5;
+    const SIGFPE: i32 = 8;
+    const SIGSEGV: i32 = 11;
+
+    match status.signal() {
+        Some(SIGSEGV) => FaultInfo::MemoryAccess {
+            address: None,
+            source: FaultSource::User,
+        },
+        Some(SIGFPE) => FaultInfo::DivideByZero,
+        Some(SIGILL) | Some(SIGTRAP) => FaultInfo::IllegalInstruction,
+        _ => FaultInfo::Panic,
+    }
+}
+
+impl SimTask {
+    fn new(name: &str, task: &Task, body: Body) -> Self {
+        let state = match body {
+            Body::Mock(Mock::Idle) => SchedState::Stopped,
+            Body::Mock(_) => SchedState::InRecv(None),
+            Body::Process { .. } if task.start => SchedState::Runnable,
+            Body::Process { .. } => SchedState::Stopped,
+        };
+        Self {
+            name: name.to_string(),
+            priority: task.priority,
+            generation: Generation::default(),
+            state: state.into(),
+            body,
+            resume: None,
+            outgoing: None,
+            recv_capacity: 0,
+            recv_mask: 0,
+            notifications: 0,
+            timer: None,
+            faults: 0,
+        }
+    }
+
+    /// Kills the task's process, if it has one.
+    fn stop(&mut self) {
+        if let Body::Process { child, stream, .. } = &mut self.body {
+            // Kill it before hanging up, so that it doesn't complain about
+            // losing us.
+            if let Some(mut child) = child.take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            *stream = None;
+        }
+    }
+
+    /// Gives the task the results of its last syscall, and waits for its
+    /// next one. If the process has died, returns the fault it took.
+    fn exchange(&mut self, results: &[u8]) -> Result<Vec<u8>, FaultInfo> {
+        let (child, stream) = match &mut self.body {
+            Body::Process {
+                child: Some(child),
+                stream: Some(stream),
+                ..
+            } => (child, stream),
+            _ => unreachable!("{} isn't running", self.name),
+        };
+        match write_frame(stream, results).and_then(|_| read_frame(stream)) {
+            Ok(frame) => Ok(frame),
+            Err(_) => Err(child.wait().map_or(FaultInfo::Panic, exit_fault)),
+        }
+    }
+
+    /// Makes the task runnable, with `results` to give it when it runs.
+    fn finish(&mut self, results: Frame) {
+        self.resume = Some(results.0);
+        self.state = SchedState::Runnable.into();
+    }
+
+    /// Takes the notifications the task is waiting for, if any have fired.
+    fn take_notifications(&mut self) -> Option<u32> {
+        let firing = self.notifications & self.recv_mask;
+        if firing != 0 {
+            self.notifications &= !firing;
+            Some(firing)
+        } else {
+            None
+        }
+    }
+
+    fn finish_recv(
+        &mut self,
+        rc: u32,
+        sender: TaskId,
+        operation: u32,
+        message: &[u8],
+        response_capacity: usize,
+        lease_count: usize,
+    ) {
+        let len = message.len().min(self.recv_capacity);
+        self.finish(
+            Frame::new()
+                .word(rc)
+                .word(u32::from(sender.0))
+                .word(operation)
+                .bytes(&message[..len])
+                .word(response_capacity as u32)
+                .word(lease_count as u32),
+        );
+    }
+
+    /// Completes the task's SEND with `code` and `reply`, handing back its
+    /// leases.
+    fn finish_send(&mut self, code: u32, reply: &[u8]) {
+        let message = self.outgoing.take();
+        let (capacity, leases) = match &message {
+            Some(m) => (m.response_capacity, &m.leases[..]),
+            None => (0, &[][..]),
+        };
+        let len = reply.len().min(capacity);
+        let mut frame = Frame::new()
+            .word(code)
+            .bytes(&reply[..len])
+            .word(leases.len() as u32);
+        for lease in leases {
+            frame = frame.bytes(&lease.data);
+        }
+        self.finish(frame);
+    }
+
+    /// Fails whatever the task is blocked in with `code`, as the kernel does
+    /// when its peer is restarted.
+    fn finish_with_error(&mut self, code: u32) {
+        match self.state {
+            TaskState::Healthy(SchedState::InRecv(_)) => {
+                self.finish_recv(code, TaskId(0), 0, &[], 0, 0)
+            }
+            _ => self.finish_send(code, &[]),
+        }
+    }
+}
+
+impl Sim {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn run(&mut self, duration: Option<u64>) -> Result<()> {
+        loop {
+            let now = self.now();
+            if duration.map_or(false, |d| now >= d) {
+                break;
+            }
+
+            for i in 0..self.tasks.len() {
+                if let Some((deadline, bits)) = self.tasks[i].timer {
+                    if deadline <= now {
+                        self.tasks[i].timer = None;
+                        self.post(i, bits);
+                    }
+                }
+            }
+
+            if let Some(i) = self.select() {
+                self.step(i)?;
+                continue;
+            }
+
+            // Nothing to do until a timer goes off, if one ever will.
+            let wake = self
+                .tasks
+                .iter()
+                .filter_map(|t| t.timer.map(|(deadline, _)| deadline))
+                .chain(duration)
+                .min();
+            match wake {
+                Some(wake) => std::thread::sleep(Duration::from_millis(
+                    wake.saturating_sub(now),
+                )),
+                None => {
+                    self.summarize();
+                    bail!("every task is blocked, and no timers are set");
+                }
+            }
+        }
+
+        self.summarize();
+        Ok(())
+    }
+
+    fn summarize(&self) {
+        println!("sim: stopped after {} ms", self.now());
+        for t in &self.tasks {
+            println!("  {:<16} {:?} ({} faults)", t.name, t.state, t.faults);
+        }
+    }
+
+    /// Picks the most important runnable task, taking turns among those of
+    /// equal priority.
+    fn select(&self) -> Option<usize> {
+        let n = self.tasks.len();
+        let mut best: Option<usize> = None;
+        for k in 1..=n {
+            let i = (self.last + k) % n;
+            let t = &self.tasks[i];
+            if t.state == TaskState::Healthy(SchedState::Runnable)
+                && best.map_or(true, |b| t.priority < self.tasks[b].priority)
+            {
+                best = Some(i);
+            }
+        }
+        best
+    }
+
+    /// Runs task `i` until its next syscall, and carries that out.
+    fn step(&mut self, i: usize) -> Result<()> {
+        self.last = i;
+        let running = matches!(
+            self.tasks[i].body,
+            Body::Process {
+                stream: Some(_),
+                ..
+            }
+        );
+        let syscall = if running {
+            let results = self.tasks[i].resume.take().unwrap_or_default();
+            self.tasks[i].exchange(&results)
+        } else {
+            self.spawn(i)?
+        };
+
+        let result = syscall.and_then(|frame| {
+            self.syscall(
+                i,
+                &mut Args {
+                    data: frame,
+                    pos: 0,
+                },
+            )
+        });
+        if let Err(fault) = result {
+            self.fault(i, fault);
+        }
+        Ok(())
+    }
+
+    /// Starts task `i`'s process, and waits for its first syscall.
+    fn spawn(&mut self, i: usize) -> Result<Result<Vec<u8>, FaultInfo>> {
+        let t = &mut self.tasks[i];
+        let (path, child, stream) = match &mut t.body {
+            Body::Process {
+                path,
+                child,
+                stream,
+            } => (path, child, stream),
+            Body::Mock(_) => unreachable!("{} is a mock", t.name),
+        };
+
+        let mut process = Command::new(&*path)
+            .env(userlib_env::SOCKET, &self.socket)
+            .env(userlib_env::TASK, i.to_string())
+            .env(userlib_env::NAME, &t.name)
+            .spawn()
+            .with_context(|| format!("failed to start {}", path.display()))?;
+
+        // The task connects when it makes its first syscall, which it might
+        // not live to do.
+        let mut conn = loop {
+            match self.listener.accept() {
+                Ok((conn, _)) => break conn,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if let Some(status) = process.try_wait()? {
+                        return Ok(Err(exit_fault(status)));
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        conn.set_nonblocking(false)?;
+
+        let hello = read_frame(&mut conn)?;
+        if hello[..] != (i as u32).to_le_bytes() {
+            bail!("{} introduced itself as someone else", t.name);
+        }
+        let first = read_frame(&mut conn);
+        *child = Some(process);
+        *stream = Some(conn);
+        Ok(match first {
+            Ok(frame) => Ok(frame),
+            Err(_) => Err(child
+                .as_mut()
+                .unwrap()
+                .wait()
+                .map_or(FaultInfo::Panic, exit_fault)),
+        })
+    }
+
+    fn current_id(&self, i: usize) -> TaskId {
+        TaskId::for_index_and_gen(i, self.tasks[i].generation)
+    }
+
+    /// Finds the task `id` names. As in the kernel, naming a task that
+    /// doesn't exist is a fault, while naming an earlier generation of one
+    /// gets the caller a dead code, which is returned as the inner error.
+    fn check_id(&self, id: TaskId) -> Result<Result<usize, u32>, FaultInfo> {
+        if id.index() >= self.tasks.len() {
+            return Err(FaultInfo::SyscallUsage(UsageError::TaskOutOfRange));
+        }
+        let generation = self.tasks[id.index()].generation;
+        if generation != id.generation() {
+            return Ok(Err(abi::dead_response_code(generation)));
+        }
+        Ok(Ok(id.index()))
+    }
+
+    fn post(&mut self, i: usize, bits: u32) {
+        let t = &mut self.tasks[i];
+        t.notifications |= bits;
+        if t.state.can_accept_notification() {
+            if let Some(firing) = t.take_notifications() {
+                t.finish_recv(0, TaskId::KERNEL, firing, &[], 0, 0);
+            }
+        }
+    }
+
+    fn fault(&mut self, i: usize, fault: FaultInfo) {
+        let t = &mut self.tasks[i];
+        println!("sim: {} faulted: {:?}", t.name, fault);
+        t.stop();
+        t.resume = None;
+        t.faults += 1;
+        t.state = match t.state {
+            TaskState::Healthy(original_state)
+            | TaskState::Faulted { original_state, .. } => TaskState::Faulted {
+                fault,
+                original_state,
+            },
+        };
+        self.post(0, self.fault_notification);
+    }
+
+    /// Restarts task `index`, at the request of task `caller`.
+    fn restart(&mut self, index: usize, start: bool, caller: usize) {
+        let old_id = self.current_id(index);
+
+        let t = &mut self.tasks[index];
+        t.stop();
+        t.generation = t.generation.next();
+        t.resume = None;
+        t.outgoing = None;
+        t.notifications = 0;
+        t.timer = None;
+        t.state = match (&t.body, start) {
+            (Body::Mock(Mock::Idle), _) | (_, false) => SchedState::Stopped,
+            (Body::Mock(_), true) => SchedState::InRecv(None),
+            (Body::Process { .. }, true) => SchedState::Runnable,
+        }
+        .into();
+
+        // Anyone waiting on the old incarnation gets a dead code, as in the
+        // kernel.
+        for (j, t) in self.tasks.iter_mut().enumerate() {
+            if j == caller || j == index {
+                continue;
+            }
+            if let TaskState::Healthy(
+                SchedState::InRecv(Some(peer))
+                | SchedState::InSend(peer)
+                | SchedState::InReply(peer),
+            ) = t.state
+            {
+                if peer == old_id {
+                    t.finish_with_error(abi::dead_response_code(
+                        peer.generation(),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Delivers the message task `sender` is sending to task `receiver`,
+    /// which is waiting for it.
+    fn deliver(&mut self, sender: usize, receiver: usize) {
+        let sender_id = self.current_id(sender);
+        let receiver_id = self.current_id(receiver);
+        let message = self.tasks[sender].outgoing.as_ref().unwrap();
+        let (operation, data, capacity, count) = (
+            u32::from(message.operation),
+            message.data.clone(),
+            message.response_capacity,
+            message.leases.len(),
+        );
+        self.tasks[receiver]
+            .finish_recv(0, sender_id, operation, &data, capacity, count);
+        self.tasks[sender].state = SchedState::InReply(receiver_id).into();
+    }
+
+    /// Finds the most important task blocked sending to task `receiver`.
+    fn find_sender(&self, receiver: usize) -> Option<usize> {
+        let receiver_id = self.current_id(receiver);
+        self.tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.state.is_sending_to(receiver_id))
+            .min_by_key(|(_, t)| t.priority)
+            .map(|(i, _)| i)
+    }
+
+    /// Finds lease `index` of the message that task `lender` is waiting on
+    /// task `borrower` to reply to, with the kernel's checks.
+    fn lease(
+        &mut self,
+        borrower: usize,
+        lender: u32,
+        index: u32,
+        offset: u32,
+    ) -> Result<Option<&mut Lease>, FaultInfo> {
+        let lender = match self.check_id(TaskId(lender as u16))? {
+            Ok(lender) => lender,
+            Err(_) => return Ok(None),
+        };
+        let borrower_id = self.current_id(borrower);
+        let t = &mut self.tasks[lender];
+        if t.state != TaskState::Healthy(SchedState::InReply(borrower_id)) {
+            // Not lending us anything, so defecting.
+            return Ok(None);
+        }
+        let lease = t
+            .outgoing
+            .as_mut()
+            .and_then(|m| m.leases.get_mut(index as usize))
+            .ok_or(FaultInfo::SyscallUsage(UsageError::LeaseOutOfRange))?;
+        if offset as usize > lease.data.len() {
+            return Err(FaultInfo::SyscallUsage(UsageError::OffsetOutOfRange));
+        }
+        Ok(Some(lease))
+    }
+
+    /// Carries out a syscall made by task `i`. Errors are faults to apply to
+    /// the task.
+    fn syscall(&mut self, i: usize, args: &mut Args) -> Result<(), FaultInfo> {
+        let sysnum = Sysnum::try_from(args.word()?).map_err(|_| {
+            FaultInfo::SyscallUsage(UsageError::BadSyscallNumber)
+        })?;
+
+        match sysnum {
+            Sysnum::Send => {
+                let packed = args.word()?;
+                let target = TaskId((packed >> 16) as u16);
+                let operation = packed as u16;
+                let data = args.bytes()?;
+                let response_capacity = args.word()? as usize;
+                let mut leases = vec![];
+                for _ in 0..args.word()? {
+                    leases.push(Lease {
+                        attributes: LeaseAttributes::from_bits_truncate(
+                            args.word()?,
+                        ),
+                        data: args.bytes()?,
+                    });
+                }
+                self.tasks[i].outgoing = Some(Message {
+                    operation,
+                    data,
+                    response_capacity,
+                    leases,
+                });
+                self.send(i, target)?;
+            }
+
+            Sysnum::Recv => {
+                let capacity = args.word()? as usize;
+                let mask = args.word()?;
+                let specific = args.word()?;
+                let specific = if specific & (1 << 31) != 0 {
+                    Some(TaskId(specific as u16))
+                } else {
+                    None
+                };
+                self.recv(i, capacity, mask, specific)?;
+            }
+
+            Sysnum::Reply => {
+                let peer = TaskId(args.word()? as u16);
+                let code = args.word()?;
+                let reply = args.bytes()?;
+                let caller_id = self.current_id(i);
+                // Like the kernel, we ignore replies to tasks that have
+                // been restarted or have stopped waiting.
+                if let Ok(peer) = self.check_id(peer)? {
+                    let waiting = SchedState::InReply(caller_id).into();
+                    if self.tasks[peer].state == waiting {
+                        self.tasks[peer].finish_send(code, &reply);
+                    }
+                }
+                self.tasks[i].finish(Frame::new());
+            }
+
+            Sysnum::SetTimer => {
+                let set = args.word()?;
+                let deadline =
+                    u64::from(args.word()?) | u64::from(args.word()?) << 32;
+                let bits = args.word()?;
+                let t = &mut self.tasks[i];
+                t.timer = if set != 0 {
+                    Some((deadline, bits))
+                } else {
+                    None
+                };
+                t.finish(Frame::new());
+            }
+
+            Sysnum::BorrowRead => {
+                let lender = args.word()?;
+                let index = args.word()?;
+                let offset = args.word()?;
+                let capacity = args.word()? as usize;
+                let frame = match self.lease(i, lender, index, offset)? {
+                    Some(lease)
+                        if lease.attributes.contains(LeaseAttributes::READ) =>
+                    {
+                        let src = &lease.data[offset as usize..];
+                        let n = src.len().min(capacity);
+                        Frame::new().word(0).bytes(&src[..n])
+                    }
+                    _ => Frame::new().word(abi::DEFECT).bytes(&[]),
+                };
+                self.tasks[i].finish(frame);
+            }
+
+            Sysnum::BorrowWrite => {
+                let lender = args.word()?;
+                let index = args.word()?;
+                let offset = args.word()?;
+                let src = args.bytes()?;
+                let frame = match self.lease(i, lender, index, offset)? {
+                    Some(lease)
+                        if lease
+                            .attributes
+                            .contains(LeaseAttributes::WRITE) =>
+                    {
+                        let dest = &mut lease.data[offset as usize..];
+                        let n = src.len().min(dest.len());
+                        dest[..n].copy_from_slice(&src[..n]);
+                        Frame::new().word(0).word(n as u32)
+                    }
+                    _ => Frame::new().word(abi::DEFECT).word(0),
+                };
+                self.tasks[i].finish(frame);
+            }
+
+            Sysnum::BorrowInfo => {
+                let lender = args.word()?;
+                let index = args.word()?;
+                let frame = match self.lease(i, lender, index, 0)? {
+                    Some(lease) => Frame::new()
+                        .word(0)
+                        .word(lease.attributes.bits())
+                        .word(lease.data.len() as u32),
+                    None => Frame::new().word(abi::DEFECT).word(0).word(0),
+                };
+                self.tasks[i].finish(frame);
+            }
+
+            Sysnum::IrqControl => {
+                // There are no interrupts here to control.
+                args.word()?;
+                args.word()?;
+                self.tasks[i].finish(Frame::new());
+            }
+
+            Sysnum::Panic => {
+                let message = args.bytes()?;
+                println!(
+                    "sim: {} panicked: {}",
+                    self.tasks[i].name,
+                    String::from_utf8_lossy(&message)
+                );
+                return Err(FaultInfo::Panic);
+            }
+
+            Sysnum::GetTimer => {
+                let now = self.now();
+                let (set, deadline, bits) = match self.tasks[i].timer {
+                    Some((deadline, bits)) => (1, deadline, bits),
+                    None => (0, 0, 0),
+                };
+                self.tasks[i].finish(
+                    Frame::new()
+                        .word(now as u32)
+                        .word((now >> 32) as u32)
+                        .word(set)
+                        .word(deadline as u32)
+                        .word((deadline >> 32) as u32)
+                        .word(bits),
+                );
+            }
+
+            Sysnum::RefreshTaskId => {
+                let id = TaskId(args.word()? as u16);
+                if id.index() >= self.tasks.len() {
+                    return Err(FaultInfo::SyscallUsage(
+                        UsageError::TaskOutOfRange,
+                    ));
+                }
+                let id = self.current_id(id.index());
+                self.tasks[i].finish(Frame::new().word(u32::from(id.0)));
+            }
+
+            Sysnum::Post => {
+                let id = TaskId(args.word()? as u16);
+                let bits = args.word()?;
+                let rc = match self.check_id(id)? {
+                    Ok(peer) => {
+                        self.post(peer, bits);
+                        0
+                    }
+                    Err(code) => code,
+                };
+                self.tasks[i].finish(Frame::new().word(rc));
+            }
+        }
+        Ok(())
+    }
+
+    /// Carries out the rest of a SEND from task `i` to `target`, once its
+    /// message has been stashed in `outgoing`.
+    fn send(&mut self, i: usize, target: TaskId) -> Result<(), FaultInfo> {
+        if target == TaskId::KERNEL {
+            return self.kipc(i);
+        }
+
+        let callee = match self.check_id(target)? {
+            Ok(callee) => callee,
+            Err(code) => {
+                self.tasks[i].finish_send(code, &[]);
+                return Ok(());
+            }
+        };
+
+        // Mocks answer straight away, as long as they haven't been faulted.
+        let mut message = self.tasks[i].outgoing.take().unwrap();
+        let answer = match &mut self.tasks[callee] {
+            SimTask {
+                state: TaskState::Healthy(_),
+                body: Body::Mock(mock),
+                ..
+            } if !matches!(mock, Mock::Idle) => Some(mock.handle(&mut message)),
+            _ => None,
+        };
+        self.tasks[i].outgoing = Some(message);
+        if let Some((code, reply)) = answer {
+            self.tasks[i].finish_send(code, &reply);
+            return Ok(());
+        }
+
+        let caller_id = self.current_id(i);
+        self.tasks[i].state = SchedState::InSend(target).into();
+        if self.tasks[callee].state.can_accept_message_from(caller_id) {
+            self.deliver(i, callee);
+        }
+        Ok(())
+    }
+
+    fn recv(
+        &mut self,
+        i: usize,
+        capacity: usize,
+        mask: u32,
+        specific: Option<TaskId>,
+    ) -> Result<(), FaultInfo> {
+        let t = &mut self.tasks[i];
+        t.recv_capacity = capacity;
+        t.recv_mask = mask;
+        if let Some(firing) = t.take_notifications() {
+            t.finish_recv(0, TaskId::KERNEL, firing, &[], 0, 0);
+            return Ok(());
+        }
+
+        let caller_id = self.current_id(i);
+        let sender = match specific {
+            Some(TaskId::KERNEL) => None,
+            Some(sender) => match self.check_id(sender)? {
+                Ok(sender) => Some(sender)
+                    .filter(|&s| self.tasks[s].state.is_sending_to(caller_id)),
+                Err(code) => {
+                    self.tasks[i].finish_recv(code, TaskId(0), 0, &[], 0, 0);
+                    return Ok(());
+                }
+            },
+            None => self.find_sender(i),
+        };
+
+        match sender {
+            Some(sender) => self.deliver(sender, i),
+            None => self.tasks[i].state = SchedState::InRecv(specific).into(),
+        }
+        Ok(())
+    }
+
+    /// Handles a message from task `i` to the kernel; see `kipc` in userlib.
+    fn kipc(&mut self, i: usize) -> Result<(), FaultInfo> {
+        let bad = FaultInfo::SyscallUsage(UsageError::BadKernelMessage);
+        let out_of_range = FaultInfo::SyscallUsage(UsageError::TaskOutOfRange);
+        let message = self.tasks[i].outgoing.as_ref().unwrap();
+        let (operation, data) = (message.operation, message.data.clone());
+
+        match operation {
+            1 => {
+                let (index, _): (u32, _) =
+                    ssmarshal::deserialize(&data).map_err(|_| bad)?;
+                let state =
+                    self.tasks.get(index as usize).ok_or(out_of_range)?.state;
+                let mut buf = [0; 64];
+                let n =
+                    ssmarshal::serialize(&mut buf, &state).map_err(|_| bad)?;
+                self.tasks[i].finish_send(0, &buf[..n]);
+            }
+            2 => {
+                let ((index, start), _): ((u32, bool), _) =
+                    ssmarshal::deserialize(&data).map_err(|_| bad)?;
+                let index = index as usize;
+                if index >= self.tasks.len() {
+                    return Err(out_of_range);
+                }
+                self.restart(index, start, i);
+                if index != i {
+                    self.tasks[i].finish_send(0, &[]);
+                }
+            }
+            3 => {
+                let (index, _): (u32, _) =
+                    ssmarshal::deserialize(&data).map_err(|_| bad)?;
+                let index = index as usize;
+                if index == 0 || index == i {
+                    return Err(FaultInfo::SyscallUsage(
+                        UsageError::IllegalTask,
+                    ));
+                }
+                if index >= self.tasks.len() {
+                    return Err(out_of_range);
+                }
+                let id = self.current_id(i);
+                self.fault(index, FaultInfo::Injected(id));
+                self.tasks[i].finish_send(0, &[]);
+            }
+            _ => return Err(bad),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        for t in &mut self.tasks {
+            t.stop();
+        }
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+/// The environment variables userlib's `sim` module reads.
+mod userlib_env {
+    pub const SOCKET: &str = "HUBRIS_SIM_SOCKET";
+    pub const TASK: &str = "HUBRIS_SIM_TASK";
+    pub const NAME: &str = "HUBRIS_SIM_NAME";
+}
+
+/// A stand-in for a task we don't run.
+enum Mock {
+    /// The idle task, which is never needed: the simulated kernel can wait
+    /// for something to do all by itself.
+    Idle,
+    /// An I2C server, answering for the devices on its buses.
+    I2c(I2cBus),
+    /// Anything else, which succeeds at everything.
+    Generic,
+}
+
+impl Mock {
+    /// Handles `message`, returning the response code and reply.
+    fn handle(&mut self, message: &mut Message) -> (u32, Vec<u8>) {
+        match self {
+            Mock::Idle => unreachable!(),
+            Mock::I2c(bus) => bus.handle(message),
+            Mock::Generic => (0, vec![0; message.response_capacity]),
+        }
+    }
+}
+
+/// The subset of `config.i2c` we need to find devices, as `build-i2c` does.
+#[derive(Deserialize)]
+struct I2cConfig {
+    #[serde(default)]
+    controllers: Vec<I2cController>,
+    #[serde(default)]
+    devices: Vec<I2cDevice>,
+}
+
+#[derive(Deserialize)]
+struct I2cController {
+    controller: u8,
+    ports: IndexMap<String, I2cPort>,
+    #[serde(default)]
+    target: bool,
+}
+
+#[derive(Deserialize)]
+struct I2cPort {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct I2cDevice {
+    device: String,
+    controller: Option<u8>,
+    bus: Option<String>,
+    port: Option<String>,
+    address: u8,
+}
+
+impl I2cConfig {
+    fn load(toml: &Config) -> Result<Self> {
+        Ok(match toml.config.as_ref().and_then(|c| c.get("i2c")) {
+            Some(i2c) => i2c.clone().try_into::<I2cConfig>()?,
+            None => I2cConfig {
+                controllers: vec![],
+                devices: vec![],
+            },
+        })
+    }
+
+    /// Returns the controllers `task` drives as an initiator, i.e. those
+    /// whose peripheral it uses; a task that drives any is an I2C server.
+    fn initiators(&self, task: &Task) -> Vec<u8> {
+        self.controllers
+            .iter()
+            .filter(|c| !c.target)
+            .map(|c| c.controller)
+            .filter(|n| task.uses.contains(&format!("i2c{}", n)))
+            .collect()
+    }
+}
+
+/// Devices on a simulated I2C server's buses.
+struct I2cBus {
+    /// Each device, by controller, port index and address.
+    devices: BTreeMap<(u8, u8, u8), I2cRegisters>,
+}
+
+/// A device, modeled as a register file with an auto-incrementing pointer:
+/// the first byte of a write sets the pointer, the rest are written to the
+/// registers from there, and reads start at the pointer.
+struct I2cRegisters {
+    pointer: u8,
+    registers: [u8; 256],
+}
+
+impl I2cRegisters {
+    fn write(&mut self, data: &[u8]) {
+        if let Some((&pointer, data)) = data.split_first() {
+            self.pointer = pointer;
+            for &byte in data {
+                self.registers[usize::from(self.pointer)] = byte;
+                self.pointer = self.pointer.wrapping_add(1);
+            }
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = self.registers[usize::from(self.pointer)];
+            self.pointer = self.pointer.wrapping_add(1);
+        }
+    }
+}
+
+impl I2cBus {
+    /// Models the devices in `config` that are on `controllers`.
+    fn new(config: &I2cConfig, controllers: &[u8]) -> Self {
+        let mut devices = BTreeMap::new();
+        for d in &config.devices {
+            let port = config.controllers.iter().find_map(|c| {
+                let index = match (d.controller, &d.bus, &d.port) {
+                    (_, Some(bus), _) => c
+                        .ports
+                        .values()
+                        .position(|p| p.name.as_ref() == Some(bus))?,
+                    (Some(n), None, Some(port)) if n == c.controller => {
+                        c.ports.get_index_of(port)?
+                    }
+                    (Some(n), None, None)
+                        if n == c.controller && c.ports.len() == 1 =>
+                    {
+                        0
+                    }
+                    _ => return None,
+                };
+                Some((c.controller, index as u8))
+            });
+            match port {
+                Some((controller, _)) if !controllers.contains(&controller) => {
+                    // Someone else's device.
+                }
+                Some((controller, port)) => {
+                    devices.insert(
+                        (controller, port, d.address),
+                        I2cRegisters {
+                            pointer: 0,
+                            registers: [0; 256],
+                        },
+                    );
+                }
+                None => println!(
+                    "sim: can't find the bus for {} at {:#x}; leaving it out",
+                    d.device, d.address
+                ),
+            }
+        }
+        Self { devices }
+    }
+
+    /// Handles a message in the protocol of `drv-i2c-api`.
+    fn handle(&mut self, message: &mut Message) -> (u32, Vec<u8>) {
+        const WRITE_READ: u16 = 1;
+        const WRITE_READ_BLOCK: u16 = 2;
+        const BAD_ARG: u32 = 2;
+        const NO_DEVICE: u32 = 3;
+
+        // The message is the address, controller and port; the last byte
+        // names a mux segment, which we don't model.
+        let key = match message.data[..] {
+            [address, controller, port, _] => (controller, port, address),
+            _ => return (BAD_ARG, vec![]),
+        };
+        let device = match self.devices.get_mut(&key) {
+            Some(device) => device,
+            None => return (NO_DEVICE, vec![]),
+        };
+
+        // The first lease is written to the device, and the second, if
+        // there is one, is read back from it.
+        if let Some(write) = message.leases.first() {
+            device.write(&write.data);
+        }
+        let read = match message.leases.get_mut(1) {
+            Some(read) => &mut read.data[..],
+            None => &mut [],
+        };
+        let len = match message.operation {
+            WRITE_READ => {
+                device.read(read);
+                read.len()
+            }
+            WRITE_READ_BLOCK => {
+                // The device sends its own length first.
+                let mut count = [0];
+                device.read(&mut count);
+                let len = usize::from(count[0]).min(read.len());
+                device.read(&mut read[..len]);
+                len
+            }
+            _ => return (BAD_ARG, vec![]),
+        };
+
+        // The reply is the length read, as a `usize`.
+        let mut reply = (len as u64).to_le_bytes().to_vec();
+        reply.truncate(message.response_capacity);
+        (0, reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes a simulation of tasks given as (name, priority), all runnable
+    /// processes that are never spawned: their syscalls are made by hand.
+    fn sim(name: &str, tasks: &[(&str, u32)]) -> Sim {
+        let socket = std::env::temp_dir().join(format!(
+            "xtask-sim-{}-{}.sock",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&socket);
+        let tasks = tasks
+            .iter()
+            .map(|(name, priority)| {
+                let task: Task = toml::from_str(&format!(
+                    "path = \".\"\nname = \"task-{}\"\npriority = {}\n\
+                     start = true\n",
+                    name, priority
+                ))
+                .unwrap();
+                let body = Body::Process {
+                    path: PathBuf::new(),
+                    child: None,
+                    stream: None,
+                };
+                SimTask::new(name, &task, body)
+            })
+            .collect();
+        Sim {
+            tasks,
+            listener: UnixListener::bind(&socket).unwrap(),
+            socket,
+            start: Instant::now(),
+            fault_notification: 1,
+            last: 0,
+        }
+    }
+
+    fn call(sim: &mut Sim, i: usize, frame: Frame) -> Result<(), FaultInfo> {
+        sim.syscall(
+            i,
+            &mut Args {
+                data: frame.0,
+                pos: 0,
+            },
+        )
+    }
+
+    /// Takes the results task `i` is to be resumed with.
+    fn results(sim: &mut Sim, i: usize) -> Args {
+        Args {
+            data: sim.tasks[i].resume.take().unwrap(),
+            pos: 0,
+        }
+    }
+
+    fn state(sim: &Sim, i: usize) -> TaskState {
+        sim.tasks[i].state
+    }
+
+    fn send(target: TaskId, op: u16, data: &[u8], leases: &[Lease]) -> Frame {
+        let mut frame = Frame::new()
+            .word(Sysnum::Send as u32)
+            .word(u32::from(target.0) << 16 | u32::from(op))
+            .bytes(data)
+            .word(8)
+            .word(leases.len() as u32);
+        for lease in leases {
+            frame = frame.word(lease.attributes.bits()).bytes(&lease.data);
+        }
+        frame
+    }
+
+    fn recv(mask: u32) -> Frame {
+        Frame::new()
+            .word(Sysnum::Recv as u32)
+            .word(16)
+            .word(mask)
+            .word(0)
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut s = sim("round-trip", &[("server", 1), ("client", 2)]);
+        let (server, client) = (s.current_id(0), s.current_id(1));
+
+        call(&mut s, 0, recv(0)).unwrap();
+        assert_eq!(state(&s, 0), SchedState::InRecv(None).into());
+
+        let lease = Lease {
+            attributes: LeaseAttributes::READ | LeaseAttributes::WRITE,
+            data: b"abc".to_vec(),
+        };
+        call(&mut s, 1, send(server, 5, b"hi", &[lease])).unwrap();
+        assert_eq!(state(&s, 1), SchedState::InReply(server).into());
+        let mut r = results(&mut s, 0);
+        assert_eq!(r.word(), Ok(0));
+        assert_eq!(r.word(), Ok(u32::from(client.0)));
+        assert_eq!(r.word(), Ok(5));
+        assert_eq!(r.bytes(), Ok(b"hi".to_vec()));
+        assert_eq!(r.word(), Ok(8));
+        assert_eq!(r.word(), Ok(1));
+
+        let borrow = Frame::new()
+            .word(Sysnum::BorrowWrite as u32)
+            .word(u32::from(client.0))
+            .word(0)
+            .word(1)
+            .bytes(b"ZZZ");
+        call(&mut s, 0, borrow).unwrap();
+        let mut r = results(&mut s, 0);
+        assert_eq!((r.word(), r.word()), (Ok(0), Ok(2)));
+
+        let reply = Frame::new()
+            .word(Sysnum::Reply as u32)
+            .word(u32::from(client.0))
+            .word(7)
+            .bytes(b"ok");
+        call(&mut s, 0, reply).unwrap();
+        assert_eq!(state(&s, 1), SchedState::Runnable.into());
+        let mut r = results(&mut s, 1);
+        assert_eq!(r.word(), Ok(7));
+        assert_eq!(r.bytes(), Ok(b"ok".to_vec()));
+        assert_eq!(r.word(), Ok(1));
+        assert_eq!(r.bytes(), Ok(b"aZZ".to_vec()));
+    }
+
+    #[test]
+    fn borrow_without_lender() {
+        let mut s = sim("no-lender", &[("server", 1), ("client", 2)]);
+        let client = s.current_id(1);
+        let borrow = Frame::new()
+            .word(Sysnum::BorrowRead as u32)
+            .word(u32::from(client.0))
+            .word(0)
+            .word(0)
+            .word(4);
+        call(&mut s, 0, borrow).unwrap();
+        assert_eq!(results(&mut s, 0).word(), Ok(abi::DEFECT));
+    }
+
+    #[test]
+    fn dead_after_restart() {
+        let mut s = sim("restart", &[("jefe", 0), ("server", 1), ("c", 2)]);
+        let old = s.current_id(1);
+        call(&mut s, 2, send(old, 1, &[], &[])).unwrap();
+        assert_eq!(state(&s, 2), SchedState::InSend(old).into());
+
+        // The client, blocked on the old server, is told it died...
+        s.restart(1, true, 0);
+        let dead = abi::dead_response_code(old.generation());
+        assert_eq!(results(&mut s, 2).word(), Ok(dead));
+
+        // ...and so is anyone who names it later.
+        call(&mut s, 2, send(old, 1, &[], &[])).unwrap();
+        let dead = abi::dead_response_code(s.tasks[1].generation);
+        assert_eq!(results(&mut s, 2).word(), Ok(dead));
+    }
+
+    #[test]
+    fn usage_faults() {
+        let mut s = sim("faults", &[("a", 1)]);
+        assert_eq!(
+            call(&mut s, 0, send(TaskId(7), 1, &[], &[])),
+            Err(FaultInfo::SyscallUsage(UsageError::TaskOutOfRange))
+        );
+        assert_eq!(
+            call(&mut s, 0, Frame::new().word(Sysnum::Recv as u32).word(16)),
+            Err(FaultInfo::SyscallUsage(UsageError::InvalidSlice))
+        );
+        assert_eq!(
+            call(&mut s, 0, Frame::new().word(99)),
+            Err(FaultInfo::SyscallUsage(UsageError::BadSyscallNumber))
+        );
+    }
+
+    #[test]
+    fn notifications() {
+        let mut s = sim("notifications", &[("a", 1)]);
+        call(&mut s, 0, recv(0b10)).unwrap();
+        s.post(0, 0b01);
+        assert_eq!(state(&s, 0), SchedState::InRecv(None).into());
+        s.post(0, 0b10);
+        let mut r = results(&mut s, 0);
+        assert_eq!(r.word(), Ok(0));
+        assert_eq!(r.word(), Ok(u32::from(TaskId::KERNEL.0)));
+        assert_eq!(r.word(), Ok(0b10));
+
+        // The bit we weren't waiting for is still pending.
+        call(&mut s, 0, recv(0b01)).unwrap();
+        assert_eq!(results(&mut s, 0).word().unwrap(), 0);
+        assert_eq!(s.tasks[0].notifications, 0);
+    }
+
+    #[test]
+    fn scheduling() {
+        let s = &mut sim("select", &[("a", 2), ("b", 1), ("c", 1)]);
+        assert_eq!(s.select(), Some(1));
+        s.last = 1;
+        assert_eq!(s.select(), Some(2));
+        s.tasks[1].state = SchedState::Stopped.into();
+        s.tasks[2].state = SchedState::Stopped.into();
+        assert_eq!(s.select(), Some(0));
+    }
+
+    #[test]
+    fn mock_answers() {
+        let mut s = sim("mock", &[("mock", 1), ("client", 2)]);
+        s.tasks[0].body = Body::Mock(Mock::Generic);
+        let mock = s.current_id(0);
+        call(&mut s, 1, send(mock, 3, b"x", &[])).unwrap();
+        let mut r = results(&mut s, 1);
+        assert_eq!(r.word(), Ok(0));
+        assert_eq!(r.bytes(), Ok(vec![0; 8]));
+    }
+
+    fn i2c_config() -> I2cConfig {
+        toml::from_str(
+            r#"
+            [[controllers]]
+            controller = 1
+            target = true
+            ports.A = {}
+
+            [[controllers]]
+            controller = 2
+            ports.B = { name = "front" }
+            ports.F = {}
+
+            [[devices]]
+            device = "tmp117"
+            bus = "front"
+            address = 0x48
+
+            [[devices]]
+            device = "max31790"
+            controller = 2
+            port = "F"
+            address = 0x20
+
+            [[devices]]
+            device = "spd"
+            controller = 1
+            address = 0x50
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn i2c_initiators() {
+        let task: Task = toml::from_str(
+            "path = \".\"\nname = \"i2c\"\npriority = 1\n\
+             uses = [\"i2c1\", \"i2c2\"]\n",
+        )
+        .unwrap();
+        let config = i2c_config();
+        assert_eq!(config.initiators(&task), [2]);
+
+        let bus = I2cBus::new(&config, &[2]);
+        let keys: Vec<_> = bus.devices.keys().copied().collect();
+        assert_eq!(keys, [(2, 0, 0x48), (2, 1, 0x20)]);
+    }
+
+    fn i2c(op: u16, device: [u8; 4], write: &[u8], read: usize) -> Message {
+        Message {
+            operation: op,
+            data: device.to_vec(),
+            response_capacity: 8,
+            leases: vec![
+                Lease {
+                    attributes: LeaseAttributes::READ,
+                    data: write.to_vec(),
+                },
+                Lease {
+                    attributes: LeaseAttributes::WRITE,
+                    data: vec![0; read],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn i2c_registers() {
+        let mut bus = I2cBus::new(&i2c_config(), &[2]);
+        let tmp117 = [0x48, 2, 0, 0];
+
+        let mut m = i2c(1, tmp117, &[0x10, 3, 0xaa, 0xbb], 0);
+        assert_eq!(bus.handle(&mut m), (0, 0u64.to_le_bytes().to_vec()));
+
+        let mut m = i2c(1, tmp117, &[0x11], 2);
+        assert_eq!(bus.handle(&mut m), (0, 2u64.to_le_bytes().to_vec()));
+        assert_eq!(m.leases[1].data, [0xaa, 0xbb]);
+
+        // A block read gets the length from the device.
+        let mut m = i2c(2, tmp117, &[0x10], 8);
+        assert_eq!(bus.handle(&mut m), (0, 3u64.to_le_bytes().to_vec()));
+        assert_eq!(m.leases[1].data[..3], [0xaa, 0xbb, 0]);
+
+        assert_eq!(bus.handle(&mut i2c(1, [0x50, 1, 0, 0], &[0], 1)).0, 3);
+        assert_eq!(bus.handle(&mut i2c(9, tmp117, &[0], 1)).0, 2);
+        let mut m = i2c(1, tmp117, &[0], 1);
+        m.data.pop();
+        assert_eq!(bus.handle(&mut m).0, 2);
+    }
+}
@@ -0,0 +1,404 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Memory map and size report.
+//!
+//! After `xtask dist` has built everything, this compares what the kernel
+//! and each task were allocated -- the `requires` in the app.toml -- with
+//! what their ELF files actually use, and adds up how much of each output
+//! went to allocations, to alignment padding between them, and how much is
+//! left. The report is printed, and written to the archive as both a table
+//! (`info/sizes.txt`) and JSON (`info/sizes.json`).
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::section_header::{SHF_ALLOC, SHT_NOBITS};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SizeReport {
+    /// The kernel, then each task, in app.toml order.
+    pub components: Vec<Component>,
+    /// Each output, by name.
+    pub outputs: IndexMap<String, OutputUsage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Component {
+    pub name: String,
+    /// Stack size, which is carved out of the start of `ram`.
+    pub stack: u32,
+    /// Allocated region in each memory, by memory name.
+    pub regions: BTreeMap<String, RegionUsage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegionUsage {
+    pub base: u32,
+    pub size: u32,
+    /// Bytes from the start of the first allocated ELF section (or the stack,
+    /// in `ram`) to the end of the last, including any padding between them.
+    pub used: u32,
+    pub free: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutputUsage {
+    pub base: u32,
+    pub size: u32,
+    /// Bytes given to the kernel and tasks.
+    pub allocated: u32,
+    /// Bytes skipped between allocations to align them.
+    pub padding: u32,
+    /// Bytes left after the last allocation.
+    pub free: u32,
+}
+
+impl Component {
+    /// Works out how much of each of its `regions` the kernel or task in
+    /// the ELF file at `elf_path` uses.
+    pub fn from_elf(
+        name: &str,
+        elf_path: &Path,
+        regions: &BTreeMap<String, Range<u32>>,
+        stack: u32,
+    ) -> Result<Self> {
+        let file_image = std::fs::read(elf_path)?;
+        let elf = goblin::elf::Elf::parse(&file_image)?;
+
+        // The lowest start and highest end of anything placed in each
+        // memory. Adding up section sizes instead would miss the padding the
+        // linker inserts to align them.
+        let mut spans: BTreeMap<&str, Range<u64>> = BTreeMap::new();
+        let mut charge = |addr: u64, size: u64| {
+            for (mem, range) in regions {
+                if addr >= range.start as u64 && addr < range.end as u64 {
+                    let span =
+                        spans.entry(mem.as_str()).or_insert(addr..addr + size);
+                    span.start = span.start.min(addr);
+                    span.end = span.end.max(addr + size);
+                }
+            }
+        };
+
+        // The stack is carved out of the start of `ram`.
+        for (mem, range) in regions {
+            if mem.eq_ignore_ascii_case("ram") && stack != 0 {
+                charge(range.start as u64, stack as u64);
+            }
+        }
+
+        for section in &elf.section_headers {
+            if section.sh_flags & SHF_ALLOC as u64 == 0 || section.sh_size == 0
+            {
+                continue;
+            }
+            charge(section.sh_addr, section.sh_size);
+
+            // Sections with initial contents in RAM, like .data, also take
+            // up space in flash at their load address.
+            if section.sh_type == SHT_NOBITS {
+                continue;
+            }
+            let lma = elf
+                .program_headers
+                .iter()
+                .filter(|phdr| phdr.p_type == PT_LOAD)
+                .find(|phdr| {
+                    section.sh_addr >= phdr.p_vaddr
+                        && section.sh_addr < phdr.p_vaddr + phdr.p_memsz
+                })
+                .map(|phdr| section.sh_addr - phdr.p_vaddr + phdr.p_paddr);
+            if let Some(lma) = lma {
+                if lma != section.sh_addr {
+                    charge(lma, section.sh_size);
+                }
+            }
+        }
+
+        let mut usage = BTreeMap::new();
+        for (mem, range) in regions {
+            let size = range.end - range.start;
+            let mem_used = spans
+                .get(mem.as_str())
+                .map(|span| span.end - span.start)
+                .unwrap_or(0);
+            // The linker won't have let anything overflow its region, so
+            // this is only for peace of mind.
+            let mem_used = mem_used.min(size as u64) as u32;
+            usage.insert(
+                mem.clone(),
+                RegionUsage {
+                    base: range.start,
+                    size,
+                    used: mem_used,
+                    free: size - mem_used,
+                },
+            );
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            stack,
+            regions: usage,
+        })
+    }
+}
+
+impl SizeReport {
+    /// Adds up how each output was divided, given its full range in
+    /// `starting`, and what was left over after allocation in `remaining`.
+    /// This must be called after all components have been added, and fails if
+    /// they don't add up to what was allocated.
+    pub fn tally_outputs(
+        &mut self,
+        starting: &IndexMap<String, Range<u32>>,
+        remaining: &IndexMap<String, Range<u32>>,
+    ) -> Result<()> {
+        for (name, range) in starting {
+            let allocated = self
+                .components
+                .iter()
+                .filter_map(|c| c.regions.get(name))
+                .try_fold(0u32, |sum, r| sum.checked_add(r.size));
+            let left = match remaining.get(name) {
+                Some(left) if left.start >= range.start => left,
+                _ => bail!("{} has no range left after allocation", name),
+            };
+            let consumed = left.start - range.start;
+
+            // Both ranges come from the same allocator, so this can only
+            // fail if the components don't match what it handed out.
+            let (allocated, padding) = match allocated {
+                Some(allocated) if allocated <= consumed => {
+                    (allocated, consumed - allocated)
+                }
+                _ => bail!(
+                    "{}: components were given more than the {} bytes \
+                     allocated from it",
+                    name,
+                    consumed
+                ),
+            };
+
+            self.outputs.insert(
+                name.clone(),
+                OutputUsage {
+                    base: range.start,
+                    size: range.end - range.start,
+                    allocated,
+                    padding,
+                    free: left.end - left.start,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Writes the report as human-readable tables.
+    pub fn write_table(&self, out: &mut impl Write) -> Result<()> {
+        writeln!(
+            out,
+            "{:<16} {:<8} {:>8} {:>8} {:>8} {:>8} {:>6} {:>4}",
+            "COMPONENT",
+            "MEMORY",
+            "BASE",
+            "SIZE",
+            "USED",
+            "FREE",
+            "STACK",
+            "USE"
+        )?;
+        for c in &self.components {
+            for (mem, r) in &c.regions {
+                let stack = if mem.eq_ignore_ascii_case("ram") {
+                    c.stack.to_string()
+                } else {
+                    String::new()
+                };
+                writeln!(
+                    out,
+                    "{:<16} {:<8} {:08x} {:>8} {:>8} {:>8} {:>6} {:>3}%",
+                    c.name,
+                    mem,
+                    r.base,
+                    r.size,
+                    r.used,
+                    r.free,
+                    stack,
+                    percent(r.used, r.size)
+                )?;
+            }
+        }
+
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<16} {:>8} {:>8} {:>9} {:>8} {:>8}",
+            "OUTPUT", "BASE", "SIZE", "ALLOCATED", "PADDING", "FREE"
+        )?;
+        for (name, o) in &self.outputs {
+            writeln!(
+                out,
+                "{:<16} {:08x} {:>8} {:>9} {:>8} {:>8}",
+                name, o.base, o.size, o.allocated, o.padding, o.free
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn percent(part: u32, whole: u32) -> u64 {
+    if whole == 0 {
+        0
+    } else {
+        part as u64 * 100 / whole as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component(name: &str, regions: &[(&str, u32, u32, u32)]) -> Component {
+        Component {
+            name: name.to_string(),
+            stack: 256,
+            regions: regions
+                .iter()
+                .map(|&(mem, base, size, used)| {
+                    let usage = RegionUsage {
+                        base,
+                        size,
+                        used,
+                        free: size - used,
+                    };
+                    (mem.to_string(), usage)
+                })
+                .collect(),
+        }
+    }
+
+    fn ranges(ranges: &[(&str, Range<u32>)]) -> IndexMap<String, Range<u32>> {
+        ranges
+            .iter()
+            .map(|(name, range)| (name.to_string(), range.clone()))
+            .collect()
+    }
+
+    /// A report of two components, with 0x100 bytes of flash skipped between
+    /// them for alignment.
+    fn report() -> SizeReport {
+        SizeReport {
+            components: vec![
+                component(
+                    "kernel",
+                    &[
+                        ("flash", 0x0800_0000, 0x100, 0xc0),
+                        ("ram", 0x2000_0000, 0x400, 0x400),
+                    ],
+                ),
+                component(
+                    "jefe",
+                    &[
+                        ("flash", 0x0800_0200, 0x200, 0x180),
+                        ("ram", 0x2000_0400, 0x400, 0x300),
+                    ],
+                ),
+            ],
+            outputs: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn tally() -> Result<()> {
+        let mut sizes = report();
+        sizes.tally_outputs(
+            &ranges(&[
+                ("flash", 0x0800_0000..0x0800_1000),
+                ("ram", 0x2000_0000..0x2000_1000),
+            ]),
+            &ranges(&[
+                ("flash", 0x0800_0400..0x0800_1000),
+                ("ram", 0x2000_0800..0x2000_1000),
+            ]),
+        )?;
+
+        let flash = &sizes.outputs["flash"];
+        assert_eq!(
+            (flash.size, flash.allocated, flash.padding, flash.free),
+            (0x1000, 0x300, 0x100, 0xc00)
+        );
+        let ram = &sizes.outputs["ram"];
+        assert_eq!(
+            (ram.size, ram.allocated, ram.padding, ram.free),
+            (0x1000, 0x800, 0, 0x800)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tally_overflow() {
+        // Flash that only moved on by 0x200 can't hold 0x300 of components.
+        let mut sizes = report();
+        let err = sizes
+            .tally_outputs(
+                &ranges(&[("flash", 0x0800_0000..0x0800_1000)]),
+                &ranges(&[("flash", 0x0800_0200..0x0800_1000)]),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "flash: components were given more than the 512 bytes allocated \
+             from it"
+        );
+
+        let err = sizes
+            .tally_outputs(
+                &ranges(&[("flash", 0x0800_0000..0x0800_1000)]),
+                &ranges(&[("ram", 0x2000_0000..0x2000_1000)]),
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "flash has no range left after allocation");
+    }
+
+    #[test]
+    fn table() -> Result<()> {
+        let mut sizes = report();
+        sizes.tally_outputs(
+            &ranges(&[("flash", 0x0800_0000..0x0800_1000)]),
+            &ranges(&[("flash", 0x0800_0400..0x0800_1000)]),
+        )?;
+
+        let mut out = vec![];
+        sizes.write_table(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "\
+COMPONENT        MEMORY       BASE     SIZE     USED     FREE  STACK  USE
+kernel           flash    08000000      256      192       64         75%
+kernel           ram      20000000     1024     1024        0    256 100%
+jefe             flash    08000200      512      384      128         75%
+jefe             ram      20000400     1024      768      256    256  75%
+
+OUTPUT               BASE     SIZE ALLOCATED  PADDING     FREE
+flash            08000000     4096       768      256     3072
+"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(percent(0, 0), 0);
+        assert_eq!(percent(1, 3), 33);
+        assert_eq!(percent(u32::MAX, u32::MAX), 100);
+    }
+}
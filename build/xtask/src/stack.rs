@@ -0,0 +1,660 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Static stack depth analysis.
+//!
+//! A task whose `stacksize` is too small only finds out when it takes a
+//! `StackOverflow` fault, possibly long after it shipped. To catch that at
+//! build time, we work out an upper bound on each task's stack use from its
+//! ELF file:
+//!
+//! - Each function's frame size comes from the `.stack_sizes` section that
+//!   rustc emits with `-Z emit-stack-sizes`. Functions without an entry
+//!   there, like those in the precompiled `core`, get one from adding up the
+//!   `push`, `vpush` and `sub sp` instructions in their code.
+//! - The call graph comes from decoding the direct calls (`bl`) and tail
+//!   calls (branches to the start of another function) in the Thumb code.
+//! - The bound is then the deepest path through the call graph from the
+//!   entry point, plus room for the exception frame that the hardware pushes
+//!   onto the task's stack when it's interrupted.
+//!
+//! Recursion, indirect calls and calls out of the task (e.g. into the
+//! bootloader) can't be bounded this way. They're reported, and the bound is
+//! then only a lower one.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use goblin::elf::section_header::SHT_NOBITS;
+use goblin::elf::sym::STT_FUNC;
+use scroll::Pread;
+
+use crate::elf;
+
+pub const STACK_SIZES_SECTION: &str = ".stack_sizes";
+
+/// Stack analysis results for one task.
+#[derive(Debug)]
+pub struct TaskStack {
+    pub name: String,
+    /// The task's configured stack size.
+    pub stacksize: u32,
+    /// Worst-case stack depth found, exception frame included.
+    pub depth: u32,
+    /// Bytes of `depth` set aside for an exception frame.
+    pub exception_frame: u32,
+    /// The deepest call chain, outermost first, with each function's frame.
+    pub path: Vec<(String, u32)>,
+    /// Functions that can end up calling themselves.
+    pub recursion: BTreeSet<String>,
+    /// Functions making calls we can't follow: indirect calls, and calls to
+    /// addresses outside the task.
+    pub indirect: BTreeSet<String>,
+    /// Functions whose frame size we couldn't work out, and counted as 0.
+    pub unknown_frames: BTreeSet<String>,
+}
+
+impl TaskStack {
+    /// Analyzes the task `name`, built for `target` into the ELF file at
+    /// `elf_path`.
+    pub fn analyze(
+        name: &str,
+        elf_path: &Path,
+        target: &str,
+        stacksize: u32,
+    ) -> Result<Self> {
+        let file_image = std::fs::read(elf_path)?;
+        let elf = goblin::elf::Elf::parse(&file_image)?;
+
+        let frames = read_stack_sizes(&file_image, &elf)?;
+
+        let mut functions = BTreeMap::new();
+        for sym in elf.syms.iter() {
+            if sym.st_type() != STT_FUNC || sym.st_size == 0 {
+                continue;
+            }
+            let addr = (sym.st_value & !1) as u32;
+            let name = elf.strtab.get_at(sym.st_name).unwrap_or("?");
+            functions.entry(addr).or_insert_with(|| Function {
+                name: demangle(name),
+                size: sym.st_size as u32,
+                frame: 0,
+                unknown_frame: false,
+                calls: vec![],
+                tail_calls: vec![],
+                indirect: false,
+            });
+        }
+
+        let starts = functions.keys().copied().collect::<BTreeSet<_>>();
+        for (&addr, f) in &mut functions {
+            let code = match function_code(&file_image, &elf, addr, f.size) {
+                Some(code) => code,
+                None => continue,
+            };
+
+            let mut pushed = 0;
+            for op in Decoder::new(code, addr) {
+                match op {
+                    Op::Call(to) if starts.contains(&to) => f.calls.push(to),
+                    Op::Jump(to)
+                        if starts.contains(&to)
+                            && !(addr..addr + f.size).contains(&to) =>
+                    {
+                        f.tail_calls.push(to)
+                    }
+                    Op::Call(_) | Op::IndirectCall | Op::IndirectJump => {
+                        f.indirect = true
+                    }
+                    Op::Frame(n) => pushed += n,
+                    Op::DynamicFrame => f.unknown_frame = true,
+                    Op::Jump(_) | Op::Other => (),
+                }
+            }
+
+            match frames.get(&addr) {
+                Some(&frame) => {
+                    f.frame = frame;
+                    f.unknown_frame = false;
+                }
+                None => f.frame = pushed,
+            }
+        }
+
+        let entry = (elf.entry & !1) as u32;
+        if !functions.contains_key(&entry) {
+            bail!("{}: entry point {:#x} is not a function", name, entry);
+        }
+
+        let mut graph = Graph {
+            functions: &functions,
+            visits: BTreeMap::new(),
+            recursion: BTreeSet::new(),
+        };
+        let depth = graph.visit(entry);
+
+        let mut path = vec![];
+        let mut next = Some(entry);
+        while let Some(addr) = next {
+            let f = &functions[&addr];
+            path.push((f.name.clone(), f.frame));
+            next = match graph.visits[&addr] {
+                Visit::Done(_, next) => next,
+                Visit::InProgress => None,
+            };
+        }
+
+        let reachable = graph.visits.keys().map(|a| &functions[a]);
+        let exception_frame = exception_frame_size(target);
+        Ok(Self {
+            name: name.to_string(),
+            stacksize,
+            depth: depth + exception_frame,
+            exception_frame,
+            path,
+            recursion: graph
+                .recursion
+                .iter()
+                .map(|a| functions[a].name.clone())
+                .collect(),
+            indirect: reachable
+                .clone()
+                .filter(|f| f.indirect)
+                .map(|f| f.name.clone())
+                .collect(),
+            unknown_frames: reachable
+                .filter(|f| f.unknown_frame)
+                .map(|f| f.name.clone())
+                .collect(),
+        })
+    }
+
+    /// Returns true if `depth` is a true upper bound, rather than just the
+    /// deepest path we could follow.
+    pub fn is_bounded(&self) -> bool {
+        self.recursion.is_empty()
+            && self.indirect.is_empty()
+            && self.unknown_frames.is_empty()
+    }
+
+    pub fn overflows(&self) -> bool {
+        self.depth > self.stacksize
+    }
+}
+
+/// Writes a summary of all `tasks`, followed by the details of each.
+pub fn write_report(tasks: &[TaskStack], out: &mut impl Write) -> Result<()> {
+    writeln!(out, "{:<16} {:>8} {:>8}  NOTES", "TASK", "STACK", "DEPTH")?;
+    for t in tasks {
+        let mut notes = vec![];
+        if t.overflows() {
+            notes.push("OVERFLOWS");
+        }
+        if !t.recursion.is_empty() {
+            notes.push("recursion");
+        }
+        if !t.indirect.is_empty() {
+            notes.push("indirect calls");
+        }
+        if !t.unknown_frames.is_empty() {
+            notes.push("unknown frames");
+        }
+        writeln!(
+            out,
+            "{:<16} {:>8} {:>7}{} {}",
+            t.name,
+            t.stacksize,
+            t.depth,
+            if t.is_bounded() { " " } else { "+" },
+            notes.join(", ")
+        )?;
+    }
+
+    for t in tasks {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{}: deepest path, {} bytes with a {}-byte exception frame:",
+            t.name, t.depth, t.exception_frame
+        )?;
+        for (name, frame) in &t.path {
+            writeln!(out, "    {:>6} {}", frame, name)?;
+        }
+        let lists = [
+            ("recursion through", &t.recursion),
+            ("calls that can't be followed in", &t.indirect),
+            ("unknown frame size for", &t.unknown_frames),
+        ];
+        for (what, names) in &lists {
+            if !names.is_empty() {
+                writeln!(out, "  {}:", what)?;
+                for name in names.iter() {
+                    writeln!(out, "    {}", name)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The most the hardware pushes onto a task's stack when an exception
+/// interrupts it: the basic frame, or, if the task could be using the FPU,
+/// the extended frame with the floating point registers.
+fn exception_frame_size(target: &str) -> u32 {
+    if target.ends_with("eabihf") {
+        26 * 4
+    } else {
+        8 * 4
+    }
+}
+
+#[derive(Debug)]
+struct Function {
+    name: String,
+    size: u32,
+    frame: u32,
+    unknown_frame: bool,
+    /// Addresses of functions called, then returned from.
+    calls: Vec<u32>,
+    /// Addresses of functions branched to after this one's frame is gone.
+    tail_calls: Vec<u32>,
+    indirect: bool,
+}
+
+enum Visit {
+    InProgress,
+    /// Worst-case depth, and the callee on the deepest path, if any.
+    Done(u32, Option<u32>),
+}
+
+struct Graph<'a> {
+    functions: &'a BTreeMap<u32, Function>,
+    visits: BTreeMap<u32, Visit>,
+    recursion: BTreeSet<u32>,
+}
+
+impl Graph<'_> {
+    /// Returns the worst-case depth of calling the function at `addr`.
+    fn visit(&mut self, addr: u32) -> u32 {
+        match self.visits.get(&addr) {
+            Some(Visit::Done(depth, _)) => return *depth,
+            Some(Visit::InProgress) => {
+                // We can't bound this, so count the cycle once and move on.
+                self.recursion.insert(addr);
+                return 0;
+            }
+            None => (),
+        }
+        self.visits.insert(addr, Visit::InProgress);
+
+        let f = &self.functions[&addr];
+        let mut worst = (f.frame, None);
+        for &callee in &f.calls {
+            let depth = f.frame + self.visit(callee);
+            if depth > worst.0 {
+                worst = (depth, Some(callee));
+            }
+        }
+        for &callee in &f.tail_calls {
+            let depth = self.visit(callee);
+            if depth > worst.0 {
+                worst = (depth, Some(callee));
+            }
+        }
+
+        self.visits.insert(addr, Visit::Done(worst.0, worst.1));
+        worst.0
+    }
+}
+
+/// Reads the `.stack_sizes` section, if there is one, into a map from
+/// function address to frame size.
+fn read_stack_sizes(
+    file_image: &[u8],
+    elf: &goblin::elf::Elf,
+) -> Result<BTreeMap<u32, u32>> {
+    let mut frames = BTreeMap::new();
+    let section = match elf::get_section_by_name(elf, STACK_SIZES_SECTION) {
+        Some(section) => section,
+        None => return Ok(frames),
+    };
+    let start = section.sh_offset as usize;
+    let data = &file_image[start..start + section.sh_size as usize];
+    let endianness = elf::get_endianness(elf);
+
+    // Each entry is a function's address followed by its frame size as an
+    // unsigned LEB128.
+    let offset = &mut 0;
+    while *offset < data.len() {
+        let addr = data.gread_with::<u32>(offset, endianness)?;
+        let mut frame = 0u32;
+        let mut shift = 0;
+        loop {
+            let byte = data.gread::<u8>(offset)?;
+            if shift >= 32 {
+                bail!("bad {} entry at {:#x}", STACK_SIZES_SECTION, addr);
+            }
+            frame |= ((byte & 0x7f) as u32) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        // Entries for functions that were garbage collected are left behind
+        // pointing at address 0.
+        if addr != 0 {
+            frames.insert(addr & !1, frame);
+        }
+    }
+    Ok(frames)
+}
+
+fn function_code<'a>(
+    file_image: &'a [u8],
+    elf: &goblin::elf::Elf,
+    addr: u32,
+    size: u32,
+) -> Option<&'a [u8]> {
+    let section = elf::get_section_by_vma(elf, addr as u64)?;
+    if section.sh_type == SHT_NOBITS {
+        return None;
+    }
+    let start = (addr as u64 - section.sh_addr + section.sh_offset) as usize;
+    let end = start.checked_add(size as usize)?;
+    file_image.get(start..end)
+}
+
+/// The instructions that matter to us.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Op {
+    /// A call to an address.
+    Call(u32),
+    /// A branch to an address.
+    Jump(u32),
+    IndirectCall,
+    /// A branch to an address in a register, other than a return.
+    IndirectJump,
+    /// Grows the stack by a fixed amount.
+    Frame(u32),
+    /// Grows the stack by an amount in a register.
+    DynamicFrame,
+    Other,
+}
+
+/// Decodes Thumb code into `Op`s.
+///
+/// This just walks the code from the start, so anything embedded in it that
+/// isn't code, like a literal pool, will decode as garbage. It's usually at
+/// the end of a function, though, and the garbage is harmless as long as it
+/// doesn't look like a call to the start of a function.
+struct Decoder<'a> {
+    code: &'a [u8],
+    addr: u32,
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(code: &'a [u8], addr: u32) -> Self {
+        Self {
+            code,
+            addr,
+            offset: 0,
+        }
+    }
+
+    fn halfword(&self, offset: usize) -> Option<u16> {
+        let b = self.code.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([b[0], b[1]]))
+    }
+}
+
+impl Iterator for Decoder<'_> {
+    type Item = Op;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pc = self.addr + self.offset as u32;
+        let hw1 = self.halfword(self.offset)?;
+        if hw1 >> 11 >= 0b11101 {
+            let hw2 = self.halfword(self.offset + 2)?;
+            self.offset += 4;
+            Some(decode32(pc, hw1, hw2))
+        } else {
+            self.offset += 2;
+            Some(decode16(pc, hw1))
+        }
+    }
+}
+
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+fn decode16(pc: u32, hw: u16) -> Op {
+    let hw = hw as u32;
+    if hw & 0xfe00 == 0xb400 {
+        // push {reglist}
+        Op::Frame(((hw & 0xff).count_ones() + ((hw >> 8) & 1)) * 4)
+    } else if hw & 0xff80 == 0xb080 {
+        // sub sp, #imm
+        Op::Frame((hw & 0x7f) * 4)
+    } else if hw & 0xff87 == 0x4485 {
+        // add sp, rm
+        Op::DynamicFrame
+    } else if hw & 0xff87 == 0x4780 {
+        // blx rm
+        Op::IndirectCall
+    } else if hw & 0xff87 == 0x4700 {
+        // bx rm, which is a return if it's bx lr.
+        if (hw >> 3) & 0xf == 14 {
+            Op::Other
+        } else {
+            Op::IndirectJump
+        }
+    } else if hw & 0xf800 == 0xe000 {
+        // b label
+        Op::Jump(
+            pc.wrapping_add(4)
+                .wrapping_add(sign_extend((hw & 0x7ff) << 1, 12)),
+        )
+    } else if hw & 0xf000 == 0xd000 && (hw >> 9) & 0x7 != 0x7 {
+        // b<cond> label
+        Op::Jump(
+            pc.wrapping_add(4)
+                .wrapping_add(sign_extend((hw & 0xff) << 1, 9)),
+        )
+    } else {
+        Op::Other
+    }
+}
+
+fn decode32(pc: u32, hw1: u16, hw2: u16) -> Op {
+    let (hw1, hw2) = (hw1 as u32, hw2 as u32);
+    if hw1 & 0xf800 == 0xf000 && hw2 & 0x8000 == 0x8000 {
+        let s = (hw1 >> 10) & 1;
+        let j1 = (hw2 >> 13) & 1;
+        let j2 = (hw2 >> 11) & 1;
+        if hw2 & 0x5000 == 0x1000 || hw2 & 0x5000 == 0x5000 {
+            // b.w label or bl label, which share an encoding of the offset.
+            let i1 = !(j1 ^ s) & 1;
+            let i2 = !(j2 ^ s) & 1;
+            let imm = (s << 24)
+                | (i1 << 23)
+                | (i2 << 22)
+                | ((hw1 & 0x3ff) << 12)
+                | ((hw2 & 0x7ff) << 1);
+            let to = pc.wrapping_add(4).wrapping_add(sign_extend(imm, 25));
+            if hw2 & 0x4000 != 0 {
+                Op::Call(to)
+            } else {
+                Op::Jump(to)
+            }
+        } else if hw2 & 0x5000 == 0 && (hw1 >> 7) & 0x7 != 0x7 {
+            // b<cond>.w label
+            let imm = (s << 20)
+                | (j2 << 19)
+                | (j1 << 18)
+                | ((hw1 & 0x3f) << 12)
+                | ((hw2 & 0x7ff) << 1);
+            Op::Jump(pc.wrapping_add(4).wrapping_add(sign_extend(imm, 21)))
+        } else {
+            Op::Other
+        }
+    } else if hw1 & 0xfbef == 0xf1ad && hw2 & 0x8f00 == 0x0d00 {
+        // sub.w sp, sp, #const
+        let imm12 = ((hw1 & 0x400) << 1) | ((hw2 & 0x7000) >> 4) | (hw2 & 0xff);
+        Op::Frame(thumb_expand_imm(imm12))
+    } else if hw1 & 0xfbff == 0xf2ad && hw2 & 0x8f00 == 0x0d00 {
+        // subw sp, sp, #imm
+        Op::Frame(((hw1 & 0x400) << 1) | ((hw2 & 0x7000) >> 4) | (hw2 & 0xff))
+    } else if hw1 & 0xffef == 0xebad && hw2 & 0x8f00 == 0x0d00 {
+        // sub.w sp, sp, rm
+        Op::DynamicFrame
+    } else if hw1 == 0xe92d {
+        // push.w {reglist}
+        Op::Frame((hw2 & 0x5fff).count_ones() * 4)
+    } else if hw1 == 0xf84d && hw2 & 0x0fff == 0x0d04 {
+        // str.w rt, [sp, #-4]!, which is push.w of one register
+        Op::Frame(4)
+    } else if hw1 & 0xffbf == 0xed2d && hw2 & 0x0e00 == 0x0a00 {
+        // vpush {reglist}
+        Op::Frame((hw2 & 0xff) * 4)
+    } else {
+        Op::Other
+    }
+}
+
+/// Expands a modified immediate constant, as in the ARMv7-M ARM's
+/// `ThumbExpandImm`.
+fn thumb_expand_imm(imm12: u32) -> u32 {
+    let imm8 = imm12 & 0xff;
+    if imm12 >> 10 == 0 {
+        match (imm12 >> 8) & 0x3 {
+            0 => imm8,
+            1 => (imm8 << 16) | imm8,
+            2 => (imm8 << 24) | (imm8 << 8),
+            _ => (imm8 << 24) | (imm8 << 16) | (imm8 << 8) | imm8,
+        }
+    } else {
+        (0x80 | (imm12 & 0x7f)).rotate_right(imm12 >> 7)
+    }
+}
+
+/// Demangles a legacy Rust symbol name, dropping the hash. Anything else is
+/// returned as it is.
+pub fn demangle(name: &str) -> String {
+    let mut rest =
+        match name.strip_prefix("_ZN").and_then(|s| s.strip_suffix('E')) {
+            Some(rest) => rest,
+            None => return name.to_string(),
+        };
+
+    let mut parts = vec![];
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len = match rest[..digits].parse::<usize>() {
+            Ok(len) if digits + len <= rest.len() => len,
+            _ => return name.to_string(),
+        };
+        let part = &rest[digits..digits + len];
+        // Parts that would start with `$` get an underscore in front.
+        parts.push(part.strip_prefix("_$").map_or(part, |_| &part[1..]));
+        rest = &rest[digits + len..];
+    }
+    if let Some(last) = parts.last() {
+        if last.len() == 17 && last.starts_with('h') {
+            parts.pop();
+        }
+    }
+
+    let mut out = parts.join("::");
+    for (from, to) in &[
+        ("..", "::"),
+        ("$SP$", "@"),
+        ("$BP$", "*"),
+        ("$RF$", "&"),
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$C$", ","),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$u7e$", "~"),
+    ] {
+        out = out.replace(from, to);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(pc: u32, code: &[u16]) -> Op {
+        let bytes = code
+            .iter()
+            .flat_map(|hw| hw.to_le_bytes())
+            .collect::<Vec<_>>();
+        Decoder::new(&bytes, pc).next().unwrap()
+    }
+
+    #[test]
+    fn decodes_branches() {
+        // bl, backwards and forwards
+        assert_eq!(decode(0, &[0xf7ff, 0xfffe]), Op::Call(0));
+        assert_eq!(decode(4, &[0xf000, 0xf800]), Op::Call(8));
+        assert_eq!(decode(0x14, &[0xf010, 0xf814]), Op::Call(0x10040));
+        // b.w, b, beq, beq.w
+        assert_eq!(decode(8, &[0xf7ff, 0xbffa]), Op::Jump(0));
+        assert_eq!(decode(0xc, &[0xe7f8]), Op::Jump(0));
+        assert_eq!(decode(0xe, &[0xd0f7]), Op::Jump(0));
+        assert_eq!(decode(0x10, &[0xf43f, 0xaff6]), Op::Jump(0));
+        // blx r3, bx lr, bx r3
+        assert_eq!(decode(0, &[0x4798]), Op::IndirectCall);
+        assert_eq!(decode(0, &[0x4770]), Op::Other);
+        assert_eq!(decode(0, &[0x4718]), Op::IndirectJump);
+    }
+
+    #[test]
+    fn decodes_frames() {
+        // push {r4, r5, r7, lr}
+        assert_eq!(decode(0, &[0xb5b0]), Op::Frame(16));
+        // sub sp, #24
+        assert_eq!(decode(0, &[0xb086]), Op::Frame(24));
+        // push.w {r4-r11, lr}
+        assert_eq!(decode(0, &[0xe92d, 0x4ff0]), Op::Frame(36));
+        // str r4, [sp, #-4]!
+        assert_eq!(decode(0, &[0xf84d, 0x4d04]), Op::Frame(4));
+        // sub.w sp, sp, #1024
+        assert_eq!(decode(0, &[0xf5ad, 0x6d80]), Op::Frame(1024));
+        // subw sp, sp, #1234
+        assert_eq!(decode(0, &[0xf2ad, 0x4dd2]), Op::Frame(1234));
+        // vpush {d8, d9}, vpush {s16-s18}
+        assert_eq!(decode(0, &[0xed2d, 0x8b04]), Op::Frame(16));
+        assert_eq!(decode(0, &[0xed2d, 0x8a03]), Op::Frame(12));
+        // sub.w sp, sp, r4; add sp, r4
+        assert_eq!(decode(0, &[0xebad, 0x0d04]), Op::DynamicFrame);
+        assert_eq!(decode(0, &[0x44a5]), Op::DynamicFrame);
+    }
+
+    #[test]
+    fn demangles() {
+        assert_eq!(
+            demangle("_ZN4core3fmt5write17h0123456789abcdefE"),
+            "core::fmt::write"
+        );
+        assert_eq!(
+            demangle("_ZN44_$LT$task_ping..Foo$u20$as$u20$core..Bar$GT$3baz17h0123456789abcdefE"),
+            "<task_ping::Foo as core::Bar>::baz"
+        );
+        assert_eq!(demangle("main"), "main");
+    }
+}
@@ -7,11 +7,10 @@ use std::process::Command;
 
 use anyhow::Context;
 
-use crate::Config;
+use crate::{config, Config};
 
 pub fn run(verbose: bool, cfg: &Path) -> anyhow::Result<()> {
-    let cfg_contents = std::fs::read(&cfg)?;
-    let toml: Config = toml::from_slice(&cfg_contents)?;
+    let toml: Config = config::load(cfg)?;
 
     let mut archive = PathBuf::from("target");
     archive.push(&toml.name);
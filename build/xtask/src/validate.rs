@@ -0,0 +1,618 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks an app.toml for mistakes before anything is built.
+//!
+//! Deserializing the config catches entries that are malformed; this catches
+//! the ones that are well-formed but make no sense together, like a task slot
+//! naming a task that doesn't exist, or an interrupt routed to a notification
+//! bit the task already uses for something else, including another
+//! interrupt. Otherwise, these show up late in the build, or not until the
+//! image runs.
+//!
+//! Every problem found is reported, with the TOML path of the entry at fault
+//! and, where we can guess one, a fix.
+//!
+//! Task crates can declare the notification bits their code uses for its
+//! own purposes, like timers, so that interrupts aren't routed onto them:
+//!
+//! ```toml
+//! [package.metadata.build.notifications]
+//! timer = 0b10
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+
+use indexmap::IndexMap;
+
+use crate::Config;
+
+/// Something wrong with the config.
+#[derive(Debug)]
+pub struct Problem {
+    /// TOML path of the entry at fault, e.g. `tasks.ping.task-slots.peer`.
+    pub path: String,
+    pub message: String,
+    pub help: Option<String>,
+}
+
+impl Problem {
+    fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    fn help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// Suggests the closest of `candidates` to `name`, if any is close.
+    fn did_you_mean<'a>(
+        self,
+        name: &str,
+        candidates: impl IntoIterator<Item = &'a String>,
+    ) -> Self {
+        match closest(name, candidates) {
+            Some(c) => self.help(format!("did you mean `{}`?", c)),
+            None => self,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error: {}: {}", self.path, self.message)?;
+        if let Some(help) = &self.help {
+            write!(f, "\n  help: {}", help)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks `toml`, whose paths are relative to `src_dir`, returning every
+/// problem found.
+pub(crate) fn check(toml: &Config, src_dir: &Path) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    let kernel = read_crate(src_dir, &toml.kernel.path, "kernel.path");
+    match kernel {
+        Ok(krate) => {
+            krate.check_name(&toml.kernel.name, "kernel.name", &mut problems);
+            krate.check_features(
+                &toml.kernel.features,
+                "kernel.features",
+                &mut problems,
+            );
+        }
+        Err(p) => problems.push(p),
+    }
+
+    let task_names = toml.tasks.keys().cloned().collect::<Vec<_>>();
+    let output_names = toml.outputs.keys().cloned().collect::<Vec<_>>();
+    let region_names = toml
+        .peripherals
+        .keys()
+        .chain(toml.extratext.keys())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // IRQ number -> path of the entry routing it.
+    let mut irq_owners: BTreeMap<u32, String> = BTreeMap::new();
+
+    for (i, (name, task)) in toml.tasks.iter().enumerate() {
+        let path = format!("tasks.{}", name);
+
+        let krate = match read_crate(src_dir, &task.path, &path) {
+            Ok(krate) => {
+                krate.check_name(
+                    &task.name,
+                    &format!("{}.name", path),
+                    &mut problems,
+                );
+                krate.check_features(
+                    &task.features,
+                    &format!("{}.features", path),
+                    &mut problems,
+                );
+                Some(krate)
+            }
+            Err(p) => {
+                problems.push(p);
+                None
+            }
+        };
+
+        for mem in task.requires.keys() {
+            if !toml.outputs.contains_key(mem) {
+                problems.push(
+                    Problem::new(
+                        format!("{}.requires.{}", path, mem),
+                        format!("there is no output named `{}`", mem),
+                    )
+                    .did_you_mean(mem, &output_names),
+                );
+            }
+        }
+
+        for peripheral in &task.uses {
+            if !region_names.contains(peripheral) {
+                problems.push(
+                    Problem::new(
+                        format!("{}.uses", path),
+                        format!(
+                            "there is no peripheral named `{}`",
+                            peripheral
+                        ),
+                    )
+                    .did_you_mean(peripheral, &region_names),
+                );
+            }
+        }
+
+        for (slot, target) in &task.task_slots {
+            if !toml.tasks.contains_key(target) {
+                problems.push(
+                    Problem::new(
+                        format!("{}.task-slots.{}", path, slot),
+                        format!("there is no task named `{}`", target),
+                    )
+                    .did_you_mean(target, &task_names),
+                );
+            }
+        }
+
+        for target in &task.allow_send_to {
+            if !task.task_slots.values().any(|t| t == target) {
+                problems.push(
+                    Problem::new(
+                        format!("{}.allow-send-to", path),
+                        format!("no task slot refers to `{}`", target),
+                    )
+                    .did_you_mean(target, task.task_slots.values()),
+                );
+            }
+        }
+
+        if task.stacksize.is_none() && toml.stacksize.is_none() {
+            problems.push(
+                Problem::new(
+                    format!("{}.stacksize", path),
+                    "no stack size given, and there is no default",
+                )
+                .help("set `stacksize` here, or at the top level"),
+            );
+        }
+
+        // Notification bits in use so far, and what for, so that we can say
+        // what collides with what. Interrupts can't share a bit, even with
+        // each other: a task that's told of one couldn't tell which fired.
+        let mut bits: Vec<(u32, String)> = vec![];
+        if let Some(krate) = &krate {
+            for (what, &mask) in &krate.notifications {
+                bits.push((mask, format!("`{}` in the task's code", what)));
+            }
+        }
+        let mut used = bits.iter().fold(0, |acc, (mask, _)| acc | mask);
+        used |= task.interrupts.values().fold(0, |acc, mask| acc | mask);
+
+        // The supervisor is always the first task.
+        if let (0, Some(supervisor)) = (i, &toml.supervisor) {
+            let mask = supervisor.notification;
+            if let Some((_, what)) =
+                bits.iter().find(|&&(other, _)| other & mask != 0)
+            {
+                problems.push(
+                    Problem::new(
+                        "supervisor.notification",
+                        format!(
+                            "notification bit {} is also used by {}, in \
+                             supervisor task {}",
+                            mask.trailing_zeros(),
+                            what,
+                            name
+                        ),
+                    )
+                    .help(suggest_bit(used | mask)),
+                );
+            }
+            used |= mask;
+            bits.push((mask, "`supervisor.notification`".to_string()));
+        }
+
+        for (irq, &mask) in &task.interrupts {
+            let irq_path = format!("{}.interrupts.{}", path, irq);
+
+            match irq.parse::<u32>() {
+                Ok(num) => {
+                    if let Some(owner) = irq_owners.get(&num) {
+                        problems.push(
+                            Problem::new(
+                                &irq_path,
+                                format!(
+                                    "IRQ {} is already routed by {}",
+                                    num, owner
+                                ),
+                            )
+                            .help("each IRQ can go to only one task"),
+                        );
+                    } else {
+                        irq_owners.insert(num, irq_path.clone());
+                    }
+                }
+                Err(_) => problems.push(
+                    Problem::new(
+                        &irq_path,
+                        format!("`{}` is not an IRQ number", irq),
+                    )
+                    .help("IRQs are given by number, e.g. `\"37\" = 0b1`"),
+                ),
+            }
+
+            if mask.count_ones() != 1 {
+                problems.push(
+                    Problem::new(
+                        &irq_path,
+                        format!(
+                            "notification mask {:#x} must have exactly one \
+                             bit set",
+                            mask
+                        ),
+                    )
+                    .help(suggest_bit(used)),
+                );
+                continue;
+            }
+
+            if let Some((_, what)) =
+                bits.iter().find(|&&(other, _)| other & mask != 0)
+            {
+                problems.push(
+                    Problem::new(
+                        &irq_path,
+                        format!(
+                            "notification bit {} is also used by {}",
+                            mask.trailing_zeros(),
+                            what
+                        ),
+                    )
+                    .help(suggest_bit(used)),
+                );
+            }
+            bits.push((mask, format!("`{}`", irq_path)));
+        }
+    }
+
+    if let Some(supervisor) = &toml.supervisor {
+        if supervisor.notification.count_ones() != 1 {
+            problems.push(
+                Problem::new(
+                    "supervisor.notification",
+                    format!(
+                        "notification mask {:#x} must have exactly one bit set",
+                        supervisor.notification
+                    ),
+                )
+                .help("the supervisor is told of faults with a single bit"),
+            );
+        }
+    }
+
+    problems
+}
+
+fn suggest_bit(used: u32) -> String {
+    if used == u32::MAX {
+        "the task has no notification bits left".to_string()
+    } else {
+        let free = (!used).trailing_zeros();
+        format!("bit {} ({:#x}) is free", free, 1u32 << free)
+    }
+}
+
+/// What we need to know about a task or kernel crate.
+struct Crate {
+    manifest: String,
+    package: String,
+    bins: Vec<String>,
+    features: BTreeSet<String>,
+    optional_deps: BTreeSet<String>,
+    deps: BTreeSet<String>,
+    /// Notification bits the crate's code uses, by purpose.
+    notifications: IndexMap<String, u32>,
+}
+
+/// Reads the crate at `dir`, which was given by the entry at `path`.
+fn read_crate(
+    src_dir: &Path,
+    dir: &Path,
+    path: &str,
+) -> Result<Crate, Problem> {
+    let manifest = src_dir.join(dir).join("Cargo.toml");
+    let text = std::fs::read_to_string(&manifest).map_err(|e| {
+        Problem::new(path, format!("can't read {}: {}", manifest.display(), e))
+    })?;
+    let bad = |e: String| {
+        Problem::new(path, format!("{}: {}", manifest.display(), e))
+    };
+    let cargo: toml::Value =
+        toml::from_str(&text).map_err(|e| bad(e.to_string()))?;
+
+    let package = cargo
+        .get("package")
+        .and_then(|p| p.get("name"))
+        .and_then(|n| n.as_str())
+        .ok_or_else(|| bad("no package name".to_string()))?
+        .to_string();
+
+    let bins = cargo
+        .get("bin")
+        .and_then(|b| b.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|b| b.get("name")?.as_str())
+        .map(String::from)
+        .collect();
+
+    let features = cargo
+        .get("features")
+        .and_then(|f| f.as_table())
+        .into_iter()
+        .flat_map(|f| f.keys().cloned())
+        .collect();
+
+    let mut dep_tables = vec![cargo.get("dependencies")];
+    if let Some(targets) = cargo.get("target").and_then(|t| t.as_table()) {
+        dep_tables.extend(targets.values().map(|t| t.get("dependencies")));
+    }
+    let mut deps = BTreeSet::new();
+    let mut optional_deps = BTreeSet::new();
+    for (dep, spec) in dep_tables
+        .into_iter()
+        .flatten()
+        .filter_map(|d| d.as_table())
+        .flatten()
+    {
+        deps.insert(dep.clone());
+        if spec.get("optional").and_then(|o| o.as_bool()) == Some(true) {
+            optional_deps.insert(dep.clone());
+        }
+    }
+
+    let mut notifications = IndexMap::new();
+    let declared = cargo
+        .get("package")
+        .and_then(|p| p.get("metadata"))
+        .and_then(|m| m.get("build"))
+        .and_then(|b| b.get("notifications"));
+    if let Some(declared) = declared {
+        let table = declared.as_table().ok_or_else(|| {
+            bad("package.metadata.build.notifications must be a table"
+                .to_string())
+        })?;
+        for (what, mask) in table {
+            let mask = mask
+                .as_integer()
+                .filter(|&m| m > 0 && m <= u32::MAX as i64)
+                .ok_or_else(|| {
+                    bad(format!(
+                        "package.metadata.build.notifications.{} must be a \
+                         32-bit mask",
+                        what
+                    ))
+                })?;
+            notifications.insert(what.clone(), mask as u32);
+        }
+    }
+
+    Ok(Crate {
+        manifest: manifest.display().to_string(),
+        package,
+        bins,
+        features,
+        optional_deps,
+        deps,
+        notifications,
+    })
+}
+
+impl Crate {
+    fn check_name(&self, name: &str, path: &str, problems: &mut Vec<Problem>) {
+        if name != self.package && !self.bins.iter().any(|b| b == name) {
+            problems.push(
+                Problem::new(
+                    path,
+                    format!("{} doesn't build `{}`", self.manifest, name),
+                )
+                .help(format!("the package is called `{}`", self.package)),
+            );
+        }
+    }
+
+    fn check_features(
+        &self,
+        features: &[String],
+        path: &str,
+        problems: &mut Vec<Problem>,
+    ) {
+        for feature in features {
+            let known = match feature.split_once('/') {
+                Some((dep, _)) => self.deps.contains(dep),
+                None => {
+                    self.features.contains(feature)
+                        || self.optional_deps.contains(feature)
+                }
+            };
+            if !known {
+                problems.push(
+                    Problem::new(
+                        path,
+                        format!(
+                            "{} has no feature `{}`",
+                            self.manifest, feature
+                        ),
+                    )
+                    .did_you_mean(
+                        feature,
+                        self.features.iter().chain(&self.optional_deps),
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Returns whichever of `candidates` is closest to `name`, as long as it's
+/// close enough to be a plausible typo.
+fn closest<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a String>,
+) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|&(d, _)| d <= limit)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, c)| c.as_str())
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let next = (row[j] + 1)
+                .min(row[j + 1] + 1)
+                .min(diag + (ca != cb) as usize);
+            diag = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks an app with one task, `t`, whose crate declares a timer on
+    /// bit 0, and which routes `interrupts` (given as TOML); returns the
+    /// problems found, as (path, message).
+    fn check_irqs(name: &str, interrupts: &str) -> Vec<(String, String)> {
+        let dir = std::env::temp_dir().join(format!(
+            "xtask-validate-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (krate, extra) in [
+            ("kernel", ""),
+            (
+                "task",
+                "[package.metadata.build.notifications]\ntimer = 1\n",
+            ),
+        ] {
+            std::fs::create_dir_all(dir.join(krate)).unwrap();
+            std::fs::write(
+                dir.join(krate).join("Cargo.toml"),
+                format!("[package]\nname = \"{}\"\n{}", krate, extra),
+            )
+            .unwrap();
+        }
+
+        let toml = toml::from_str(&format!(
+            r#"
+            name = "test"
+            target = "thumbv7em-none-eabihf"
+            board = "test"
+            stacksize = 1024
+
+            [kernel]
+            path = "kernel"
+            name = "kernel"
+            requires = {{flash = 1024, ram = 1024}}
+
+            [outputs.flash]
+            address = 0
+            size = 1024
+
+            [tasks.t]
+            path = "task"
+            name = "task"
+            priority = 0
+            requires = {{flash = 1024}}
+
+            [tasks.t.interrupts]
+            {}
+            "#,
+            interrupts
+        ))
+        .unwrap();
+
+        let problems = check(&toml, &dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        problems.into_iter().map(|p| (p.path, p.message)).collect()
+    }
+
+    #[test]
+    fn separate_bits() {
+        assert!(check_irqs("separate", "31 = 0b10\n32 = 0b100").is_empty());
+    }
+
+    #[test]
+    fn shared_bit() {
+        assert_eq!(
+            check_irqs("shared", "31 = 0b10\n32 = 0b10"),
+            [(
+                "tasks.t.interrupts.32".to_string(),
+                "notification bit 1 is also used by `tasks.t.interrupts.31`"
+                    .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_eq!(
+            check_irqs("range", "31 = 0\n32 = 0b110\nx = 0b10"),
+            [
+                (
+                    "tasks.t.interrupts.31".to_string(),
+                    "notification mask 0x0 must have exactly one bit set"
+                        .to_string()
+                ),
+                (
+                    "tasks.t.interrupts.32".to_string(),
+                    "notification mask 0x6 must have exactly one bit set"
+                        .to_string()
+                ),
+                (
+                    "tasks.t.interrupts.x".to_string(),
+                    "`x` is not an IRQ number".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn timer_overlap() {
+        assert_eq!(
+            check_irqs("timer", "31 = 0b1"),
+            [(
+                "tasks.t.interrupts.31".to_string(),
+                "notification bit 0 is also used by `timer` in the task's code"
+                    .to_string()
+            )]
+        );
+    }
+}
@@ -7,17 +7,51 @@ use std::{env, fs, path::PathBuf};
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     build_util::expose_target_board();
 
+    #[cfg(feature = "standalone")]
+    let (artifact, gpio_artifact) = (
+        build_spi::Artifact::Standalone,
+        build_gpio::Artifact::Standalone,
+    );
+
+    #[cfg(not(feature = "standalone"))]
+    let (artifact, gpio_artifact) =
+        (build_spi::Artifact::Dist, build_gpio::Artifact::Dist);
+
+    build_spi::codegen(build_spi::Disposition::Devices, artifact)
+        .map_err(|e| format!("code generation failed: {}", e))?;
+
+    //
+    // The global reset and the pin that muxes the iCE40's flash out of the
+    // way are only on some boards.
+    //
+    let pins = build_gpio::Pins {
+        required: &[
+            "creset",
+            "cdone",
+            "enable_v1p2",
+            "enable_v3p3",
+            "pg_v1p2",
+            "pg_v3p3",
+        ],
+        optional: &["global_reset", "mux_sel"],
+    };
+
+    build_gpio::codegen(build_gpio::Family::Stm32h7, gpio_artifact, pins)
+        .map_err(|e| format!("code generation failed: {}", e))?;
+
+    // The bitstream repeats in longer patterns than single bytes, so the LZ
+    // codec packs it noticeably tighter than plain RLE.
     let fpga_image = fs::read("fpga.bin")?;
     let compressed = compress(&fpga_image);
 
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    fs::write(out.join("fpga.bin.rle"), compressed)?;
+    fs::write(out.join("fpga.bin.lz"), compressed)?;
     Ok(())
 }
 
 fn compress(input: &[u8]) -> Vec<u8> {
     let mut output = vec![];
-    gnarle::compress(input, |chunk| {
+    gnarle::lz::compress(input, |chunk| {
         output.extend_from_slice(chunk);
         Ok::<_, std::convert::Infallible>(())
     })
@@ -14,13 +14,29 @@ use userlib::*;
 use drv_ice40_spi_program as ice40;
 use drv_spi_api as spi_api;
 use drv_stm32h7_gpio_api as gpio_api;
+use gnarle::lz;
 
 task_slot!(GPIO, gpio_driver);
 task_slot!(SPI, spi_driver);
 
+include!(concat!(env!("OUT_DIR"), "/spi_config.rs"));
+include!(concat!(env!("OUT_DIR"), "/gpio_config.rs"));
+
+const ICE40_CONFIG: ice40::Config = ice40::Config {
+    creset_port: gpio_config::CRESET.pin.port,
+    creset_pin_mask: gpio_config::CRESET.pin.pin_mask,
+    cdone_port: gpio_config::CDONE.pin.port,
+    cdone_pin_mask: gpio_config::CDONE.pin.pin_mask,
+};
+
 #[export_name = "main"]
 fn main() -> ! {
-    let spi = spi_api::Spi::from(SPI.get_task_id());
+    #[cfg(not(feature = "standalone"))]
+    let prog = spi_config::devices::ice40(SPI.get_task_id());
+
+    #[cfg(feature = "standalone")]
+    let prog = spi_config::devices::mock(SPI.get_task_id());
+
     let gpio = gpio_api::Gpio::from(GPIO.get_task_id());
 
     // To allow for the possibility that we are restarting, rather than
@@ -31,16 +47,8 @@ fn main() -> ! {
     // Unconditionally set our power-good detects as inputs.
     //
     // This is the expected reset state, but, good to be sure.
-    gpio.configure(
-        PGS_PORT,
-        PG_V1P2_MASK | PG_V3P3_MASK,
-        gpio_api::Mode::Input,
-        gpio_api::OutputType::PushPull, // doesn't matter
-        gpio_api::Speed::High,
-        PGS_PULL,
-        gpio_api::Alternate::AF0, // doesn't matter
-    )
-    .unwrap();
+    gpio.configure_pin(&gpio_config::PG_V1P2).unwrap();
+    gpio.configure_pin(&gpio_config::PG_V3P3).unwrap();
 
     // Unconditionally set our sequencing-related GPIOs to outputs.
     //
@@ -50,17 +58,11 @@ fn main() -> ! {
     // starts coming up.
     //
     // If it's just our driver that has reset, this will have no effect, and
-    // will continue driving the lines at whatever level we left them in.
-    gpio.configure(
-        ENABLES_PORT,
-        ENABLE_V1P2_MASK | ENABLE_V3P3_MASK,
-        gpio_api::Mode::Output,
-        gpio_api::OutputType::PushPull,
-        gpio_api::Speed::High,
-        gpio_api::Pull::None,
-        gpio_api::Alternate::AF0, // doesn't matter
-    )
-    .unwrap();
+    // will continue driving the lines at whatever level we left them in. (The
+    // enables have no initial level in config.gpio, so configuring them
+    // doesn't touch the levels.)
+    gpio.configure_pin(&gpio_config::ENABLE_V1P2).unwrap();
+    gpio.configure_pin(&gpio_config::ENABLE_V3P3).unwrap();
 
     // To talk to the sequencer we need to configure its pins, obvs. Note that
     // the SPI and CS lines are separately managed by the SPI server; the ice40
@@ -82,7 +84,7 @@ fn main() -> ! {
     // of ours. Ensuring that it's on by writing the pin is just as cheap as
     // sensing its current state, and less code than _conditionally_ writing the
     // pin, so:
-    gpio.set_reset(ENABLES_PORT, ENABLE_V1P2_MASK, 0).unwrap();
+    gpio.set(gpio_config::ENABLE_V1P2.pin).unwrap();
 
     // We don't actually know how long ago the regulator turned on. Could have
     // been _just now_ (above) or may have already been on. We'll use the PG pin
@@ -95,7 +97,7 @@ fn main() -> ! {
     // Now, monitor the PG pin.
     loop {
         // active high
-        let pg = gpio.read_input(PGS_PORT).unwrap() & PG_V1P2_MASK != 0;
+        let pg = gpio.read(gpio_config::PG_V1P2.pin).unwrap() != 0;
         if pg {
             break;
         }
@@ -107,7 +109,7 @@ fn main() -> ! {
     }
 
     // We believe V1P2 is good. Now, for V3P3! Set it active (high).
-    gpio.set_reset(ENABLES_PORT, ENABLE_V3P3_MASK, 0).unwrap();
+    gpio.set(gpio_config::ENABLE_V3P3.pin).unwrap();
 
     // Delay to be sure.
     hl::sleep_for(2);
@@ -115,7 +117,7 @@ fn main() -> ! {
     // Now, monitor the PG pin.
     loop {
         // active high
-        let pg = gpio.read_input(PGS_PORT).unwrap() & PG_V3P3_MASK != 0;
+        let pg = gpio.read(gpio_config::PG_V3P3.pin).unwrap() != 0;
         if pg {
             break;
         }
@@ -133,48 +135,23 @@ fn main() -> ! {
 
     // Now, let's find out if we need to program the sequencer.
 
-    if let Some(hacks) = FPGA_HACK_PINS {
+    if let Some(mux_sel) = gpio_config::MUX_SEL {
         // Some boards require certain pins to be put in certain states before
         // we can perform SPI communication with the design (rather than the
-        // programming port). If this is such a board, apply those changes:
-        for &(port, pin_mask, is_high) in hacks {
-            gpio.set_reset(
-                port,
-                if is_high { pin_mask } else { 0 },
-                if is_high { 0 } else { pin_mask },
-            )
-            .unwrap();
-
-            gpio.configure(
-                port,
-                pin_mask,
-                gpio_api::Mode::Output,
-                gpio_api::OutputType::PushPull,
-                gpio_api::Speed::High,
-                gpio_api::Pull::None,
-                gpio_api::Alternate::AF0, // doesn't matter
-            )
-            .unwrap();
-        }
+        // programming port). On gimlet-1, we need to mux the iCE40 SPI flash
+        // out of circuit, because we accidentally share a CS net between
+        // Flash and the iCE40. Configuring the pin drives it to its initial
+        // level before making it an output.
+        gpio.configure_pin(&mux_sel).unwrap();
     }
 
-    if let Some((port, pin_mask)) = GLOBAL_RESET {
+    if let Some(reset) = gpio_config::GLOBAL_RESET {
         // Also configure our design reset net -- the signal that resets the
         // logic _inside_ the FPGA instead of the FPGA itself. We're assuming
         // push-pull because all our boards with reset nets are lacking pullups
-        // right now. It's active low, so, set up the pin before exposing the
-        // output to ensure we don't glitch.
-        gpio.set_reset(port, pin_mask, 0).unwrap();
-        gpio.configure(
-            port,
-            pin_mask,
-            gpio_api::Mode::Output,
-            gpio_api::OutputType::PushPull,
-            gpio_api::Speed::High,
-            gpio_api::Pull::None,
-            gpio_api::Alternate::AF0, // doesn't matter
-        )
-        .unwrap();
+        // right now. It's active low, so its initial level is high, which is
+        // set up before the output is exposed to ensure we don't glitch.
+        gpio.configure_pin(&reset).unwrap();
     }
 
     // If the sequencer is already loaded and operational, the design loaded
@@ -188,16 +165,15 @@ fn main() -> ! {
 
     // We only want to reset and reprogram the FPGA when absolutely required.
     if reprogram {
-        if let Some((port, pin_mask)) = GLOBAL_RESET {
+        if let Some(reset) = gpio_config::GLOBAL_RESET {
             // Assert the design reset signal (not the same as the FPGA
             // programming logic reset signal). We do this during reprogramming
             // to avoid weird races that make our brains hurt.
-            gpio.set_reset(port, 0, pin_mask).unwrap();
+            gpio.reset(reset.pin).unwrap();
         }
 
         // Reprogramming will continue until morale improves.
         loop {
-            let prog = spi.device(ICE40_SPI_DEVICE);
             match reprogram_fpga(&prog, &gpio, &ICE40_CONFIG) {
                 Ok(()) => {
                     // yay
@@ -213,10 +189,10 @@ fn main() -> ! {
             }
         }
 
-        if let Some((port, pin_mask)) = GLOBAL_RESET {
+        if let Some(reset) = gpio_config::GLOBAL_RESET {
             // Deassert design reset signal. We set the pin, as it's
             // active low.
-            gpio.set_reset(port, pin_mask, 0).unwrap();
+            gpio.set(reset.pin).unwrap();
         }
     }
 
@@ -238,11 +214,10 @@ fn reprogram_fpga(
     // one transaction, but we'll want chunking later -- so let's make sure
     // chunking works.
     let mut bitstream = COMPRESSED_BITSTREAM;
-    let mut decompressor = gnarle::Decompressor::default();
+    let mut decompressor = lz::Decompressor::default();
     let mut chunk = [0; 256];
     while !bitstream.is_empty() || !decompressor.is_idle() {
-        let out =
-            gnarle::decompress(&mut decompressor, &mut bitstream, &mut chunk);
+        let out = lz::decompress(&mut decompressor, &mut bitstream, &mut chunk);
         ice40::continue_bitstream_load(&spi, out)?;
     }
 
@@ -250,100 +225,4 @@ fn reprogram_fpga(
 }
 
 static COMPRESSED_BITSTREAM: &[u8] =
-    include_bytes!(concat!(env!("OUT_DIR"), "/fpga.bin.rle"));
-
-cfg_if::cfg_if! {
-    if #[cfg(target_board = "gimletlet-2")] {
-        const ICE40_SPI_DEVICE: u8 = 0;
-
-        const ICE40_CONFIG: ice40::Config = ice40::Config {
-            creset_port: gpio_api::Port::B,
-            creset_pin_mask: 1 << 10,
-            cdone_port: gpio_api::Port::E,
-            cdone_pin_mask: 1 << 15,
-        };
-
-        const GLOBAL_RESET: Option<(gpio_api::Port, u16)> = None;
-
-        const FPGA_HACK_PINS: Option<&[(gpio_api::Port, u16, bool)]> = None;
-
-        // On Gimletlet we bring the extra GPIOs out to the uncommitted GPIO
-        // headers.
-        const ENABLES_PORT: gpio_api::Port = gpio_api::Port::E;
-        const ENABLE_V1P2_MASK: u16 = 1 << 2; // J17 pin 2
-        const ENABLE_V3P3_MASK: u16 = 1 << 3; // J17 pin 3
-
-        const PGS_PORT: gpio_api::Port = gpio_api::Port::B;
-        const PG_V1P2_MASK: u16 = 1 << 14; // J16 pin 2
-        const PG_V3P3_MASK: u16 = 1 << 15; // J16 pin 3
-        // Gimletlet has no actual regulators onboard, so we pull down to
-        // simulate "power not good" until the person hacking on the board
-        // installs a jumper or whatever.
-        const PGS_PULL: gpio_api::Pull = gpio_api::Pull::Down;
-    } else if #[cfg(target_board = "gimlet-1")] {
-        const ICE40_SPI_DEVICE: u8 = 1;
-
-        const ICE40_CONFIG: ice40::Config = ice40::Config {
-            // CRESET net is SEQ_TO_SP_CRESET_L and hits PD5.
-            creset_port: gpio_api::Port::D,
-            creset_pin_mask: 1 << 5,
-            // CDONE net is SEQ_TO_SP_CDONE_L and hits PB4.
-            cdone_port: gpio_api::Port::B,
-            cdone_pin_mask: 1 << 4,
-        };
-
-        const GLOBAL_RESET: Option<(gpio_api::Port, u16)> = Some((
-            gpio_api::Port::A,
-            1 << 6,
-        ));
-
-        // gimlet-1 needs to have a pin flipped to mux the iCE40 SPI flash out
-        // of circuit to be able to program the FPGA, because we accidentally
-        // share a CS net between Flash and the iCE40.
-        //
-        // (port, mask, high_flag)
-        const FPGA_HACK_PINS: Option<&[(gpio_api::Port, u16, bool)]> = Some(&[
-            // SEQ_TO_SEQ_MUX_SEL, pulled high, we drive it low
-            (gpio_api::Port::I, 1 << 8, false),
-        ]);
-
-        const ENABLES_PORT: gpio_api::Port = gpio_api::Port::A;
-        const ENABLE_V1P2_MASK: u16 = 1 << 15;
-        const ENABLE_V3P3_MASK: u16 = 1 << 4;
-
-        const PGS_PORT: gpio_api::Port = gpio_api::Port::C;
-        const PG_V1P2_MASK: u16 = 1 << 7;
-        const PG_V3P3_MASK: u16 = 1 << 6;
-        // Gimlet provides external pullups.
-        const PGS_PULL: gpio_api::Pull = gpio_api::Pull::None;
-    } else if #[cfg(feature = "standalone")] {
-        // This is all nonsense to get xtask check to work.
-
-        const ICE40_SPI_DEVICE: u8 = 1;
-
-        const ICE40_CONFIG: ice40::Config = ice40::Config {
-            creset_port: gpio_api::Port::D,
-            creset_pin_mask: 1 << 5,
-            cdone_port: gpio_api::Port::B,
-            cdone_pin_mask: 1 << 4,
-        };
-
-        const GLOBAL_RESET: Option<(gpio_api::Port, u16)> = Some((
-            gpio_api::Port::A,
-            1 << 6,
-        ));
-
-        const FPGA_HACK_PINS: Option<&[(gpio_api::Port, u16, bool)]> = None;
-
-        const ENABLES_PORT: gpio_api::Port = gpio_api::Port::A;
-        const ENABLE_V1P2_MASK: u16 = 1 << 15;
-        const ENABLE_V3P3_MASK: u16 = 1 << 4;
-
-        const PGS_PORT: gpio_api::Port = gpio_api::Port::C;
-        const PG_V1P2_MASK: u16 = 1 << 7;
-        const PG_V3P3_MASK: u16 = 1 << 6;
-        const PGS_PULL: gpio_api::Pull = gpio_api::Pull::None;
-    } else {
-        compiler_error!("unsupported target board");
-    }
-}
+    include_bytes!(concat!(env!("OUT_DIR"), "/fpga.bin.lz"));
@@ -280,6 +280,49 @@ impl I2cDevice {
     }
 }
 
+///
+/// What the application's configuration says about an I2C device.  For each
+/// entry in `config.i2c.devices`, `build-i2c` generates one of these into
+/// `i2c_config::inventory::DEVICES`, allowing a task to find its devices by
+/// what they are (e.g., every `tmp117`) rather than by board.
+///
+#[derive(Copy, Clone, Debug)]
+pub struct I2cDeviceInfo {
+    /// device part name, e.g. `tmp117`
+    pub device: &'static str,
+    /// device name, if any
+    pub name: Option<&'static str>,
+    /// name of the bus the device is on, if the bus is named
+    pub bus: Option<&'static str>,
+    pub description: &'static str,
+    /// reference designator, if any
+    pub refdes: Option<&'static str>,
+    /// device can be removed (and so may be absent)
+    pub removable: bool,
+    /// PMBus rails, by rail index; rails that are unused have empty names
+    pub rails: &'static [&'static str],
+    pub controller: Controller,
+    pub port: PortIndex,
+    pub segment: Option<(Mux, Segment)>,
+    pub address: u8,
+}
+
+impl I2cDeviceInfo {
+    ///
+    /// Returns an [`I2cDevice`] for this device, given the I2C driver's task
+    /// identifier.
+    ///
+    pub fn device(&self, task: TaskId) -> I2cDevice {
+        I2cDevice::new(
+            task,
+            self.controller,
+            self.port,
+            self.segment,
+            self.address,
+        )
+    }
+}
+
 impl From<ResponseCode> for u32 {
     fn from(rc: ResponseCode) -> Self {
         rc as u32
@@ -211,18 +211,36 @@ pub enum AltFn {
     Alt9 = 9,
 }
 
-#[derive(Debug, FromPrimitive)]
+#[derive(Copy, Clone, Debug, FromPrimitive)]
 pub enum Direction {
     Input = 0,
     Output = 1,
 }
 
-#[derive(Debug, FromPrimitive)]
+#[derive(Copy, Clone, Debug, FromPrimitive)]
 pub enum Value {
     Zero = 0,
     One = 1,
 }
 
+/// The complete configuration of a single pin, as generated from an
+/// application's `config.gpio` by `build-gpio`, and applied with
+/// `Gpio::configure_pin`.
+#[derive(Copy, Clone, Debug)]
+pub struct PinConfig {
+    pub pin: Pin,
+    pub alt: AltFn,
+    pub mode: Mode,
+    pub slew: Slew,
+    pub invert: Invert,
+    pub digimode: Digimode,
+    pub od: Opendrain,
+    /// Direction of the pin, if it's used as a GPIO (i.e., in `AltFn::Alt0`)
+    pub direction: Option<Direction>,
+    /// Value to set before the pin becomes an output, if any
+    pub initial: Option<Value>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Gpio(Cell<TaskId>);
 
@@ -345,6 +363,31 @@ impl Gpio {
         )
     }
 
+    /// Configures a pin as described by `config`: IOCON first, then the
+    /// initial value (if any), and only then the direction, so that an output
+    /// doesn't glitch on its way to its initial value.
+    pub fn configure_pin(&self, config: &PinConfig) -> Result<(), GpioError> {
+        self.iocon_configure(
+            config.pin,
+            config.alt,
+            config.mode,
+            config.slew,
+            config.invert,
+            config.digimode,
+            config.od,
+        )?;
+
+        if let Some(val) = config.initial {
+            self.set_val(config.pin, val)?;
+        }
+
+        if let Some(direction) = config.direction {
+            self.set_dir(config.pin, direction)?;
+        }
+
+        Ok(())
+    }
+
     // Direction is treated as a property of GPIO as opposed to IOCON which
     // deals with the raw pins. It's a bit of a odd split but we only use
     // direction in a few actual places in the code
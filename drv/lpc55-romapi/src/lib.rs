@@ -105,7 +105,7 @@ pub struct FfrKeyStore {
 
 const FLASH_PAGE_SIZE: usize = 512;
 
-const ACTIVATION_CODE_SIZE: usize = 1192;
+pub const ACTIVATION_CODE_SIZE: usize = 1192;
 
 // - Start addresses and lengths are given as u32 as this results in the
 //   fewest casts given the amount of math needed to read/write from the
@@ -713,12 +713,13 @@ pub fn write_keystore(key_store: &mut FfrKeyStore) -> Result<(), FlashStatus> {
     })
 }
 
+/// Reads `len` bytes from `offset` in the customer data area of the CMPA.
 pub fn get_cmpa_data(
     data: &mut [u32],
     offset: u32,
     len: u32,
 ) -> Result<(), FlashStatus> {
-    assert!(len <= (data.len() as u32));
+    assert!(len as usize <= core::mem::size_of_val(data));
 
     let mut f: FlashConfig = Default::default();
     f.mode_config.sys_freq_in_mhz = 100;
@@ -132,7 +132,8 @@ impl Spi {
     /// `device_index` must be in range for the server.
     ///
     /// If the controller is not locked, this will assert CS before driving the
-    /// clock and release it after.
+    /// clock and release it after. If it is, `device_index` must be the device
+    /// it was locked to; see `lock`.
     pub fn exchange(
         &self,
         device_index: u8,
@@ -157,7 +158,8 @@ impl Spi {
     /// `device_index` must be in range for the server.
     ///
     /// If the controller is not locked, this will assert CS before driving the
-    /// clock and release it after.
+    /// clock and release it after. If it is, `device_index` must be the device
+    /// it was locked to; see `lock`.
     pub fn write(
         &self,
         device_index: u8,
@@ -181,7 +183,8 @@ impl Spi {
     /// `device_index` must be in range for the server.
     ///
     /// If the controller is not locked, this will assert CS before driving the
-    /// clock and release it after.
+    /// clock and release it after. If it is, `device_index` must be the device
+    /// it was locked to; see `lock`.
     pub fn read(
         &self,
         device_index: u8,
@@ -208,7 +211,10 @@ impl Spi {
     /// you send `release` or crash.
     ///
     /// During this time, the server will refuse any attempts to manipulate a
-    /// device other than the `device_index` given here.
+    /// device other than the `device_index` given here, with `BadDevice`.
+    /// Switching devices can move the controller to another mux option and
+    /// change its clock settings, which would corrupt a transaction in
+    /// progress on the locked device while you hold its CS asserted.
     ///
     /// `assert_cs` can be used to force CS into the asserted (low) state, or
     /// keep it deasserted. If you choose to assert it, then SPI transactions
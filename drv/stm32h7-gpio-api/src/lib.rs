@@ -120,6 +120,24 @@ pub enum Alternate {
     AF15 = 15,
 }
 
+/// The complete configuration of a single pin, as generated from an
+/// application's `config.gpio` by `build-gpio`, and applied with
+/// `Gpio::configure_pin`.
+#[derive(Copy, Clone, Debug)]
+pub struct PinConfig {
+    /// The pin, as a `PinSet` with exactly one pin in it.
+    pub pin: PinSet,
+    pub mode: Mode,
+    pub output_type: OutputType,
+    pub speed: Speed,
+    pub pull: Pull,
+    pub af: Alternate,
+    /// Level to drive the pin to before it becomes an output -- or `None` to
+    /// leave the output register alone, e.g. to keep driving whatever a
+    /// previous incarnation of the task left there.
+    pub initial: Option<bool>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Gpio(Cell<TaskId>);
 
@@ -280,6 +298,27 @@ impl Gpio {
         )
     }
 
+    /// Configures a pin as described by `config`.
+    ///
+    /// If the pin has an initial level, it is set before the pin is
+    /// configured, so that an output doesn't glitch to some other level on its
+    /// way there.
+    pub fn configure_pin(&self, config: &PinConfig) -> Result<(), GpioError> {
+        if let Some(level) = config.initial {
+            self.set_to(config.pin, level)?;
+        }
+
+        self.configure(
+            config.pin.port,
+            config.pin.pin_mask,
+            config.mode,
+            config.output_type,
+            config.speed,
+            config.pull,
+            config.af,
+        )
+    }
+
     /// Alters some subset of pins in a GPIO port.
     pub fn set_reset(
         &self,
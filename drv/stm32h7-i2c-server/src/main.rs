@@ -99,7 +99,10 @@ fn configure_mux(
         // not the specfied segment; we will now call upon our
         // driver to enable this segment.
         mux.driver.enable_segment(mux, controller, segment, ctrl)?;
-        map.insert(id, segment);
+
+        // If there's no room to remember the segment, it's simply enabled
+        // again next time.
+        let _ = map.try_insert(id, segment);
 
         Ok(())
     })
@@ -166,7 +169,7 @@ fn main() -> ! {
 
     let ctrl = I2cControl {
         enable: |notification| {
-            sys_irq_control(notification, true);
+            enable_interrupts(notification);
         },
         wfi: |notification| {
             let _ = sys_recv_closed(&mut [], notification, TaskId::KERNEL);
@@ -192,7 +195,7 @@ fn main() -> ! {
                 let controller = lookup_controller(&controllers, controller)?;
                 validate_port(&pins, controller.controller, port)?;
 
-                configure_port(&mut portmap, controller, port, &pins);
+                configure_port(&mut portmap, controller, port, &pins)?;
 
                 match configure_mux(
                     &mut muxmap,
@@ -278,7 +281,7 @@ fn turn_on_i2c(controllers: &[I2cController]) {
 fn configure_controllers(controllers: &[I2cController]) {
     for controller in controllers {
         controller.configure();
-        sys_irq_control(controller.notification, true);
+        enable_interrupts(controller.notification);
     }
 }
 
@@ -287,11 +290,14 @@ fn configure_port(
     controller: &I2cController,
     port: PortIndex,
     pins: &[I2cPin],
-) {
-    let current = map.get(controller.controller).unwrap();
+) -> Result<(), ResponseCode> {
+    // A controller that isn't in the map has no pins configured at all.
+    let current = map
+        .get_mut(controller.controller)
+        .ok_or(ResponseCode::BadController)?;
 
-    if current == port {
-        return;
+    if *current == port {
+        return Ok(());
     }
 
     let gpio = GPIO.get_task_id();
@@ -305,7 +311,7 @@ fn configure_port(
         .iter()
         .filter(|p| p.controller == controller.controller)
     {
-        if pin.port == current {
+        if pin.port == *current {
             //
             // We de-configure our current port by setting the pins to
             // `Mode::input`, which will assure that we don't leave SCL and
@@ -325,7 +331,8 @@ fn configure_port(
         }
     }
 
-    map.insert(controller.controller, port);
+    *current = port;
+    Ok(())
 }
 
 fn configure_pins(
@@ -351,6 +358,14 @@ fn configure_pins(
             _ => {}
         }
 
+        if map.try_insert(controller.controller, pin.port).is_err() {
+            //
+            // There's no room to remember which port this controller is on,
+            // so we leave it unconfigured; requests for it will fail.
+            //
+            continue;
+        }
+
         gpio.configure_alternate(
             pin.gpio_pins,
             OutputType::OpenDrain,
@@ -359,8 +374,6 @@ fn configure_pins(
             pin.function,
         )
         .unwrap();
-
-        map.insert(controller.controller, pin.port);
     }
 }
 
@@ -377,7 +390,10 @@ fn configure_muxes(
     for mux in muxes {
         let controller =
             lookup_controller(controllers, mux.controller).unwrap();
-        configure_port(map, controller, mux.port, pins);
+        if configure_port(map, controller, mux.port, pins).is_err() {
+            // The mux's controller has no pins, so we can't reach it.
+            continue;
+        }
 
         loop {
             match mux.driver.configure(&mux, controller, &gpio, ctrl) {
@@ -38,6 +38,8 @@ pub struct I2cPin {
 pub struct I2cController<'a> {
     pub controller: drv_i2c_api::Controller,
     pub peripheral: drv_stm32h7_rcc_api::Peripheral,
+    /// Notification bits for the event and error interrupts, which each
+    /// have their own bit.
     pub notification: u32,
     pub registers: &'a RegisterBlock,
 }
@@ -53,6 +55,22 @@ pub struct I2cControl {
     pub wfi: fn(u32),
 }
 
+///
+/// Unmasks each of the interrupts in `notification`.  The kernel only knows
+/// an interrupt by its own notification bit, so an [`I2cControl`] `enable`
+/// function that is handed a controller's notification mask -- which has
+/// a bit for each of its interrupts -- must enable them one at a time.
+///
+pub fn enable_interrupts(notification: u32) {
+    let mut bits = notification;
+
+    while bits != 0 {
+        let bit = bits & bits.wrapping_neg();
+        sys_irq_control(bit, true);
+        bits &= !bit;
+    }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum I2cKonamiCode {
     Read,
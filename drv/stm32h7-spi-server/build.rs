@@ -2,6 +2,35 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::env;
+
 fn main() {
     build_util::expose_target_board();
+
+    //
+    // Our controller is named by whichever `spiN` feature we were built with;
+    // the standalone build has none, and gets SPI1.
+    //
+    let controller = (1..=6)
+        .find(|n| env::var_os(format!("CARGO_FEATURE_SPI{}", n)).is_some());
+
+    #[cfg(feature = "standalone")]
+    let (artifact, controller) =
+        (build_spi::Artifact::Standalone, controller.unwrap_or(1));
+
+    #[cfg(not(feature = "standalone"))]
+    let (artifact, controller) = match controller {
+        Some(controller) => (build_spi::Artifact::Dist, controller),
+        None => {
+            println!("no spiN feature names the controller to serve");
+            std::process::exit(1);
+        }
+    };
+
+    let disposition = build_spi::Disposition::Server(controller);
+
+    if let Err(e) = build_spi::codegen(disposition, artifact) {
+        println!("code generation failed: {}", e);
+        std::process::exit(1);
+    }
 }
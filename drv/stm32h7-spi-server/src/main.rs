@@ -4,7 +4,9 @@
 
 //! Server task for the STM32H7 SPI peripheral.
 //!
-//! Currently this hardcodes the clock rate.
+//! The controller, its pin muxing, and the devices on it (with their clock
+//! rates and modes) come from `config.spi` in the app.toml, by way of
+//! `build-spi`.
 //!
 //! See the `spi-api` crate for the protocol being implemented here.
 
@@ -44,6 +46,9 @@ ringbuf!(Trace, 64, Trace::None);
 
 const IRQ_MASK: u32 = 1;
 
+include!(concat!(env!("OUT_DIR"), "/spi_config.rs"));
+use spi_config::CONFIG;
+
 #[derive(Copy, Clone, Debug)]
 struct LockState {
     task: TaskId,
@@ -62,17 +67,6 @@ fn main() -> ! {
     rcc_driver.leave_reset(CONFIG.peripheral);
     let mut spi = spi_core::Spi::from(registers);
 
-    // This should correspond to '0' in the standard SPI parlance
-    spi.initialize(
-        device::spi1::cfg1::MBR_A::DIV64,
-        8,
-        device::spi1::cfg2::COMM_A::FULLDUPLEX,
-        device::spi1::cfg2::LSBFRST_A::MSBFIRST,
-        device::spi1::cfg2::CPHA_A::FIRSTEDGE,
-        device::spi1::cfg2::CPOL_A::IDLELOW,
-        device::spi1::cfg2::SSOM_A::ASSERTED,
-    );
-
     let gpio_driver = gpio_api::Gpio::from(GPIO.get_task_id());
 
     // Configure all devices' CS pins to be deasserted (set).
@@ -94,19 +88,28 @@ fn main() -> ! {
             .unwrap();
     }
 
-    // Initially, configure mux 0. This keeps us from having to deal with a "no
-    // mux selected" state.
+    // Initially, select device 0, configuring the controller for it and
+    // activating its mux option. This keeps us from having to deal with a "no
+    // device selected" state.
     //
-    // Note that the config check routine above ensured that there _is_ a mux
-    // option 0.
+    // Note that the config check routine above ensured that there _is_ a
+    // device 0.
     //
     // We deactivate before activate to avoid pin clash if we previously crashed
     // with one of these activated.
-    let mut current_mux_index = 0;
-    for opt in &CONFIG.mux_options[1..] {
-        deactivate_mux_option(&opt, &gpio_driver);
+    let mut current_device_index = 0;
+    let first = &CONFIG.devices[current_device_index];
+    for (i, opt) in CONFIG.mux_options.iter().enumerate() {
+        if i != first.mux_index {
+            deactivate_mux_option(&opt, &gpio_driver);
+        }
     }
-    activate_mux_option(&CONFIG.mux_options[0], &gpio_driver, &spi);
+    configure_device(first, &mut spi);
+    activate_mux_option(
+        &CONFIG.mux_options[first.mux_index],
+        &gpio_driver,
+        &spi,
+    );
 
     // If we get a lock request, we'll update this with the task ID. We'll then
     // use it to decide between open and closed receive.
@@ -153,6 +156,16 @@ fn main() -> ! {
                         .get(devidx)
                         .ok_or(SpiError::BadDevice)?;
 
+                    // Get the controller ready for the device before we touch
+                    // its CS, so it doesn't see another device's settings
+                    // while selected.
+                    select_device(
+                        devidx,
+                        &mut current_device_index,
+                        &gpio_driver,
+                        &mut spi,
+                    );
+
                     // If we're asserting CS, we want to *reset* the pin. If
                     // we're not, we want to *set* it. Because CS is active low.
                     let pin_mask = device.cs.pin_mask;
@@ -201,7 +214,10 @@ fn main() -> ! {
                     let device_index = usize::from(device_index);
 
                     // If we are locked, check that the caller isn't mistakenly
-                    // addressing the wrong device.
+                    // addressing the wrong device. This has to happen before
+                    // `select_device` below: the lock holder may have CS
+                    // asserted on its device, and switching the mux or clock
+                    // settings under it would corrupt that transaction.
                     if let Some(lockstate) = &lock_holder {
                         if lockstate.device_index != device_index {
                             return Err(SpiError::BadDevice);
@@ -318,21 +334,14 @@ fn main() -> ! {
                     // reasonable-looking lease(s). This is our commit point.
                     ringbuf_entry!(Trace::Start(op, xfer_len));
 
-                    // Switch the mux to the requested port.
-                    if device.mux_index != current_mux_index {
-                        deactivate_mux_option(
-                            &CONFIG.mux_options[current_mux_index],
-                            &gpio_driver,
-                        );
-                        activate_mux_option(
-                            &CONFIG.mux_options[device.mux_index],
-                            &gpio_driver,
-                            &spi,
-                        );
-                        // Remember this for later to avoid unnecessary
-                        // switching.
-                        current_mux_index = device.mux_index;
-                    }
+                    // Switch the mux to the requested port, and the
+                    // controller to the device's settings.
+                    select_device(
+                        device_index,
+                        &mut current_device_index,
+                        &gpio_driver,
+                        &mut spi,
+                    );
 
                     // Make sure SPI is on.
                     spi.enable(xfer_len.0 as u16);
@@ -481,6 +490,56 @@ fn main() -> ! {
     }
 }
 
+/// Switches from the device at `*current` to the one at `index`, if they
+/// differ: moves the controller to the new device's mux option if need be,
+/// and applies its clock rate, mode and bit order.
+///
+/// The controller must not be enabled, and if the controller is locked,
+/// `index` must be the locked device: the caller checks this, and fails with
+/// `BadDevice` otherwise.
+fn select_device(
+    index: usize,
+    current: &mut usize,
+    gpio: &gpio_api::Gpio,
+    spi: &mut spi_core::Spi,
+) {
+    if index == *current {
+        return;
+    }
+
+    let from = &CONFIG.devices[*current];
+    let to = &CONFIG.devices[index];
+
+    if to.mux_index != from.mux_index {
+        deactivate_mux_option(&CONFIG.mux_options[from.mux_index], gpio);
+    }
+
+    // Configure before activating, so the pins come up at the new device's
+    // clock polarity.
+    configure_device(to, spi);
+
+    if to.mux_index != from.mux_index {
+        activate_mux_option(&CONFIG.mux_options[to.mux_index], gpio, spi);
+    } else {
+        // Configuring resets the data line swap; put it back.
+        spi.set_data_line_swap(CONFIG.mux_options[to.mux_index].swap_data);
+    }
+
+    *current = index;
+}
+
+fn configure_device(dev: &DeviceDescriptor, spi: &mut spi_core::Spi) {
+    spi.initialize(
+        dev.clock_divider,
+        8,
+        device::spi1::cfg2::COMM_A::FULLDUPLEX,
+        dev.lsbfrst,
+        dev.cpha,
+        dev.cpol,
+        device::spi1::cfg2::SSOM_A::ASSERTED,
+    );
+}
+
 fn deactivate_mux_option(opt: &SpiMuxOption, gpio: &gpio_api::Gpio) {
     // Drive all output pins low.
     for &(pins, _af) in opt.outputs {
@@ -547,8 +606,9 @@ fn activate_mux_option(
 // Board-peripheral-server configuration matrix
 //
 // The configurable bits for a given board and controller combination are in the
-// ServerConfig struct. We use conditional compilation below to select _one_
-// instance of this struct in a const called `CONFIG`.
+// ServerConfig struct. `build-spi` generates _one_ instance of this struct, in
+// a const called `CONFIG`, from the app's `config.spi` entry for the controller
+// named by our `spiN` feature.
 
 /// Rolls up all the configuration options for this server on a given board and
 /// controller.
@@ -578,8 +638,8 @@ struct SpiMuxOption {
     /// require different AF numbers to work.
     ///
     /// To disable the mux, we'll force these pins low. This is correct for SPI
-    /// mode 0/1 but not mode 2/3; fortunately we currently don't support mode
-    /// 2/3, so we can simplify.
+    /// mode 0/1 but not mode 2/3; `build-spi` only allows mode 2/3 devices on
+    /// controllers with a single mux option, which is never disabled.
     outputs: &'static [(PinSet, gpio_api::Alternate)],
     /// A list of config changes to apply to activate the input pins of this mux
     /// option. This is _not_ a list because there's only one such pin, CIPO.
@@ -606,6 +666,13 @@ struct DeviceDescriptor {
     /// Where the CS pin is. While this is a `PinSet`, it should only have one
     /// pin in it, and we check this at startup.
     cs: PinSet,
+    /// Divider of the controller's kernel clock for this device.
+    clock_divider: device::spi1::cfg1::MBR_A,
+    /// Clock polarity and phase, which together make the SPI mode.
+    cpol: device::spi1::cfg2::CPOL_A,
+    cpha: device::spi1::cfg2::CPHA_A,
+    /// Bit order.
+    lsbfrst: device::spi1::cfg2::LSBFRST_A,
 }
 
 /// Any impl of ServerConfig for Server has to pass these tests at startup.
@@ -652,349 +719,3 @@ fn check_server_config() {
         assert!(dev.cs.pin_mask.is_power_of_two());
     }
 }
-
-cfg_if::cfg_if! {
-    //
-    // Gemini Bringup Board controllers
-    //
-    if #[cfg(all(target_board = "gemini-bu-1", feature = "spi2"))] {
-        const CONFIG: ServerConfig = ServerConfig {
-            registers: device::SPI2::ptr(),
-            peripheral: rcc_api::Peripheral::Spi2,
-            mux_options: &[
-                SpiMuxOption {
-                    outputs: &[
-                        (
-                            PinSet {
-                                port: gpio_api::Port::I,
-                                pin_mask: (1 << 1) | (1 << 3),
-                            },
-                            gpio_api::Alternate::AF5,
-                        ),
-                    ],
-                    input: (
-                        PinSet {
-                            port: gpio_api::Port::I,
-                            pin_mask: 1 << 2,
-                        },
-                        gpio_api::Alternate::AF5,
-                    ),
-                    swap_data: false,
-                },
-            ],
-            devices: &[
-                // Gemini BU SPI2 goes to an unmarked set of pins on an unmarked
-                // header, and so does the CS.
-                DeviceDescriptor {
-                    mux_index: 0,
-                    cs: PinSet { port: gpio_api::Port::I, pin_mask: 1 << 0 },
-                },
-            ],
-        };
-    } else if #[cfg(all(target_board = "gemini-bu-1", feature = "spi4"))] {
-        const CONFIG: ServerConfig = ServerConfig {
-            registers: device::SPI4::ptr(),
-            peripheral: rcc_api::Peripheral::Spi4,
-            mux_options: &[
-                // SPI4 is only muxed to one position.
-                SpiMuxOption {
-                    outputs: &[
-                        (
-                            PinSet {
-                                port: gpio_api::Port::E,
-                                pin_mask: (1 << 2) | (1 << 6),
-                            },
-                            gpio_api::Alternate::AF5,
-                        ),
-                    ],
-                    input: (
-                        PinSet {
-                            port: gpio_api::Port::E,
-                            pin_mask: 1 << 5,
-                        },
-                        gpio_api::Alternate::AF5,
-                    ),
-                    swap_data: false,
-                },
-            ],
-            devices: &[
-                // The only device is the RoT.
-                DeviceDescriptor {
-                    mux_index: 0,
-                    cs: PinSet { port: gpio_api::Port::E, pin_mask: 1 << 4 },
-                },
-            ],
-        };
-    //
-    // Glorified Gimletlet controllers
-    //
-    } else if #[cfg(all(target_board = "gimletlet-2", feature = "spi3"))] {
-        const CONFIG: ServerConfig = ServerConfig {
-            registers: device::SPI3::ptr(),
-            peripheral: rcc_api::Peripheral::Spi3,
-            mux_options: &[
-                SpiMuxOption {
-                    outputs: &[
-                        (
-                            PinSet {
-                                port: gpio_api::Port::C,
-                                pin_mask: (1 << 10) | (1 << 12),
-                            },
-                            gpio_api::Alternate::AF6,
-                        ),
-                    ],
-                    input: (
-                        PinSet {
-                            port: gpio_api::Port::C,
-                            pin_mask: 1 << 11,
-                        },
-                        gpio_api::Alternate::AF6,
-                    ),
-                    swap_data: false,
-                },
-            ],
-            devices: &[
-                DeviceDescriptor {
-                    mux_index: 0,
-                    cs: PinSet { port: gpio_api::Port::A, pin_mask: 1 << 15 },
-                },
-            ],
-        };
-    } else if #[cfg(all(target_board = "gimletlet-2", feature = "spi4"))] {
-        const CONFIG: ServerConfig = ServerConfig {
-            registers: device::SPI4::ptr(),
-            peripheral: rcc_api::Peripheral::Spi4,
-            mux_options: &[
-                SpiMuxOption {
-                    outputs: &[
-                        (
-                            PinSet {
-                                port: gpio_api::Port::E,
-                                pin_mask: (1 << 12) | (1 << 13),
-                            },
-                            gpio_api::Alternate::AF5,
-                        ),
-                    ],
-                    input: (
-                        PinSet {
-                            port: gpio_api::Port::E,
-                            pin_mask: 1 << 14,
-                        },
-                        gpio_api::Alternate::AF5,
-                    ),
-                    swap_data: false,
-                },
-            ],
-            devices: &[
-                DeviceDescriptor {
-                    mux_index: 0,
-                    cs: PinSet { port: gpio_api::Port::E, pin_mask: 1 << 11 },
-                },
-            ],
-        };
-    } else if #[cfg(all(target_board = "gimletlet-2", feature = "spi6"))] {
-        const CONFIG: ServerConfig = ServerConfig {
-            registers: device::SPI6::ptr(),
-            peripheral: rcc_api::Peripheral::Spi6,
-            mux_options: &[
-                SpiMuxOption {
-                    outputs: &[
-                        (
-                            PinSet {
-                                port: gpio_api::Port::G,
-                                pin_mask: (1 << 13) | (1 << 14),
-                            },
-                            gpio_api::Alternate::AF5,
-                        ),
-                    ],
-                    input: (
-                        PinSet {
-                            port: gpio_api::Port::G,
-                            pin_mask: 1 << 12,
-                        },
-                        gpio_api::Alternate::AF5,
-                    ),
-                    swap_data: false,
-                },
-            ],
-            devices: &[
-                DeviceDescriptor {
-                    mux_index: 0,
-                    cs: PinSet { port: gpio_api::Port::G, pin_mask: 1 << 8 },
-                },
-            ],
-        };
-    //
-    // Gimlet controllers
-    //
-    } else if #[cfg(all(target_board = "gimlet-1", feature = "spi2"))] {
-        const CONFIG: ServerConfig = ServerConfig {
-            registers: device::SPI2::ptr(),
-            peripheral: rcc_api::Peripheral::Spi2,
-            mux_options: &[
-                // Mux option 0 is on port I3:0.
-                SpiMuxOption {
-                    outputs: &[
-                        (
-                            PinSet {
-                                port: gpio_api::Port::I,
-                                pin_mask: (1 << 1) | (1 << 3),
-                            },
-                            gpio_api::Alternate::AF5,
-                        ),
-                    ],
-                    input: (
-                        PinSet {
-                            port: gpio_api::Port::I,
-                            pin_mask: 1 << 2,
-                        },
-                        gpio_api::Alternate::AF5,
-                    ),
-                    swap_data: false,
-                },
-                // Mux option 1 is on port B15:13.
-                SpiMuxOption {
-                    outputs: &[
-                        (
-                            PinSet {
-                                port: gpio_api::Port::B,
-                                pin_mask: (1 << 13) | (1 << 14),
-                            },
-                            gpio_api::Alternate::AF5,
-                        ),
-                    ],
-                    input: (
-                        PinSet {
-                            port: gpio_api::Port::B,
-                            pin_mask: 1 << 15,
-                        },
-                        gpio_api::Alternate::AF5,
-                    ),
-                    swap_data: true,
-                },
-            ],
-            devices: &[
-                // Device 0 is the sequencer logic (design inside U476).
-                // Shares port B with the flash and its own programming
-                // interface.
-                // CS is SP_TO_SEQ_MISC_B.
-                DeviceDescriptor {
-                    mux_index: 1,
-                    cs: PinSet { port: gpio_api::Port::A, pin_mask: 1 << 0 },
-                },
-                // Device 1 is the U476's iCE40 programming interface.
-                // Shares port B with the the other version of U476 and the
-                // flash.
-                // CS is SP_TO_SEQ_SPI_CS2.
-                DeviceDescriptor {
-                    mux_index: 1,
-                    cs: PinSet { port: gpio_api::Port::A, pin_mask: 1 << 0 },
-                },
-                // Device 2 is the KSZ8463 switch (U401).
-                // Connected on port I.
-                // CS is SPI_SP_TO_MGMT_MUX_CSN.
-                DeviceDescriptor {
-                    mux_index: 0,
-                    cs: PinSet { port: gpio_api::Port::A, pin_mask: 1 << 0 },
-                },
-                // Device 3 is the local flash (U557).
-                // Shares port B with the sequencer.
-                // CS is SP_TO_FLASH_SPI_CS.
-                DeviceDescriptor {
-                    mux_index: 1,
-                    cs: PinSet { port: gpio_api::Port::B, pin_mask: 1 << 12 },
-                },
-            ],
-        };
-    } else if #[cfg(all(target_board = "gimlet-1", feature = "spi4"))] {
-        const CONFIG: ServerConfig = ServerConfig {
-            registers: device::SPI4::ptr(),
-            peripheral: rcc_api::Peripheral::Spi4,
-            mux_options: &[
-                SpiMuxOption {
-                    outputs: &[
-                        // SPI4 is only muxed to one position.
-                        (
-                            PinSet {
-                                port: gpio_api::Port::E,
-                                pin_mask: (1 << 2) | (1 << 6),
-                            },
-                            gpio_api::Alternate::AF5,
-                        ),
-                    ],
-                    input: (
-                        PinSet {
-                            port: gpio_api::Port::E,
-                            pin_mask: 1 << 5,
-                        },
-                        gpio_api::Alternate::AF5,
-                    ),
-                    swap_data: false,
-                },
-            ],
-            devices: &[
-                // The only device is the RoT.
-                // CS is SPI_SP_TO_ROT_CS_L.
-                DeviceDescriptor {
-                    mux_index: 0,
-                    cs: PinSet { port: gpio_api::Port::E, pin_mask: 1 << 4 },
-                },
-            ],
-        };
-    //
-    // NUCLEO 743 board
-    //
-    } else if #[cfg(all(any(target_board = "nucleo-h743zi2", target_board = "nucleo-h753zi"),feature = "spi3"))] {
-        const CONFIG: ServerConfig = ServerConfig {
-            registers: device::SPI3::ptr(),
-            peripheral: rcc_api::Peripheral::Spi3,
-            mux_options: &[
-                SpiMuxOption {
-                    outputs: &[
-                        (
-                            PinSet {
-                                port: gpio_api::Port::B,
-                                pin_mask: 1 << 3,
-                            },
-                            gpio_api::Alternate::AF6,
-                        ),
-                        (
-                            PinSet {
-                                port: gpio_api::Port::B,
-                                pin_mask: 1 << 5,
-                            },
-                            gpio_api::Alternate::AF7,
-                        ),
-                    ],
-                    input: (
-                        PinSet {
-                            port: gpio_api::Port::B,
-                            pin_mask: 1 << 4,
-                        },
-                        gpio_api::Alternate::AF6,
-                    ),
-                    swap_data: false,
-                },
-            ],
-            devices: &[
-                DeviceDescriptor {
-                    mux_index: 0,
-                    cs: PinSet { port: gpio_api::Port::A, pin_mask: 1 << 4 },
-                },
-            ],
-        };
-    //
-    // Standalone build
-    //
-    } else if #[cfg(feature = "standalone")] {
-        // whatever - nobody gonna run it
-        const CONFIG: ServerConfig = ServerConfig {
-            registers: device::SPI1::ptr(),
-            peripheral: rcc_api::Peripheral::Spi1,
-            mux_options: &[],
-            devices: &[],
-        };
-    } else {
-        compile_error!("unsupported board-controller combination");
-    }
-}
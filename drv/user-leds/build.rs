@@ -2,6 +2,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-fn main() {
+use std::env;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     build_util::expose_target_board();
+
+    //
+    // On the STM32F3/F4, we poke the GPIOs directly; everywhere else, the
+    // LEDs are our pins in config.gpio.
+    //
+    let family = if env::var("CARGO_FEATURE_STM32H7").is_ok() {
+        build_gpio::Family::Stm32h7
+    } else if env::var("CARGO_FEATURE_LPC55").is_ok() {
+        build_gpio::Family::Lpc55
+    } else {
+        return Ok(());
+    };
+
+    #[cfg(feature = "standalone")]
+    let artifact = build_gpio::Artifact::Standalone;
+
+    #[cfg(not(feature = "standalone"))]
+    let artifact = build_gpio::Artifact::Dist;
+
+    build_gpio::codegen(family, artifact, build_gpio::Pins::default())
+        .map_err(|e| format!("code generation failed: {}", e))?;
+
+    Ok(())
 }
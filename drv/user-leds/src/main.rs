@@ -4,8 +4,10 @@
 
 //! A driver for some basic dev board User LEDs.
 //!
-//! We assume that there are two user LEDs available, numbered 0 and 1. The
-//! precise assignment of these to a particular dev board varies.
+//! On the STM32F3/F4 discovery boards, we assume that there are two user LEDs
+//! available, numbered 0 and 1. Elsewhere, the LEDs are the pins this task
+//! owns in the application's `config.gpio`, numbered in the order in which
+//! they are declared; the level each pin starts out at is the LED's "off".
 //!
 //! # IPC protocol
 //!
@@ -40,36 +42,6 @@ enum Op {
     Toggle = 3,
 }
 
-cfg_if::cfg_if! {
-    // Target boards with 4 leds
-    if #[cfg(any(target_board = "gemini-bu-1", target_board = "gimletlet-2"))] {
-        #[derive(FromPrimitive)]
-        enum Led {
-            Zero = 0,
-            One = 1,
-            Two = 2,
-            Three = 3,
-        }
-    }
-    // Target boards with 3 leds
-    else if #[cfg(any(target_board = "nucleo-h753zi", target_board = "nucleo-h743zi2"))] {
-        #[derive(FromPrimitive)]
-        enum Led {
-            Zero = 0,
-            One = 1,
-            Two = 2,
-        }
-    }
-    // Target boards with 2 leds -> the rest
-    else {
-        #[derive(FromPrimitive)]
-        enum Led {
-            Zero = 0,
-            One = 1,
-        }
-    }
-}
-
 #[repr(u32)]
 enum ResponseCode {
     BadArg = 2,
@@ -99,7 +71,7 @@ fn main() -> ! {
                     msg.fixed::<u32, ()>().ok_or(ResponseCode::BadArg)?;
 
                 // Every incoming message has the same permitted range, as well.
-                let led = Led::from_u32(*msg).ok_or(ResponseCode::BadArg)?;
+                let led = led(*msg).ok_or(ResponseCode::BadArg)?;
 
                 match op {
                     Op::On => led_on(led),
@@ -123,9 +95,20 @@ fn main() -> ! {
 cfg_if::cfg_if! {
     if #[cfg(any(feature = "stm32f4", feature = "stm32f3"))] {
         task_slot!(RCC, rcc_driver);
+
+        #[derive(FromPrimitive)]
+        enum Led {
+            Zero = 0,
+            One = 1,
+        }
     }
 }
 
+#[cfg(any(feature = "stm32f3", feature = "stm32f4"))]
+fn led(index: u32) -> Option<Led> {
+    Led::from_u32(index)
+}
+
 // The types returned are different and so we just use a macro
 // here to avoid repeating the cfg block when used below
 #[cfg(feature = "stm32f3")]
@@ -257,43 +240,23 @@ cfg_if::cfg_if! {
     if #[cfg(feature = "stm32h7")] {
         task_slot!(GPIO, gpio_driver);
 
-        cfg_if::cfg_if! {
-            if #[cfg(target_board = "stm32h7b3i-dk")] {
-                // STM32H7B3 DISCOVERY kit: LEDs are on G2 and G11.
-                const LEDS: &[(drv_stm32h7_gpio_api::PinSet, bool)] = &[
-                    (drv_stm32h7_gpio_api::Port::G.pin(2), true),
-                    (drv_stm32h7_gpio_api::Port::G.pin(11), true),
-                ];
-            } else if #[cfg(any(target_board = "nucleo-h743zi2", target_board = "nucleo-h753zi"))] {
-                // Nucleo boards: LEDs are on PB0, PB14 and PE1.
-                const LEDS: &[(drv_stm32h7_gpio_api::PinSet, bool)] = &[
-                    (drv_stm32h7_gpio_api::Port::B.pin(0), false),
-                    (drv_stm32h7_gpio_api::Port::B.pin(14), false),
-                    (drv_stm32h7_gpio_api::Port::E.pin(1), false),
-                ];
-            } else if #[cfg(target_board = "gemini-bu-1")] {
-                // Gemini bringup SP: LEDs are on PI8, PI9, PI10 and PI11.
-                const LEDS: &[(drv_stm32h7_gpio_api::PinSet, bool)] = &[
-                    (drv_stm32h7_gpio_api::Port::I.pin(8), false),
-                    (drv_stm32h7_gpio_api::Port::I.pin(9), false),
-                    (drv_stm32h7_gpio_api::Port::I.pin(10), false),
-                    (drv_stm32h7_gpio_api::Port::I.pin(11), false),
-                ];
-            } else if #[cfg(target_board = "gimletlet-2")] {
-                // Glorified gimletlet SP: LEDs are on PG2-5
-                const LEDS: &[(drv_stm32h7_gpio_api::PinSet, bool)] = &[
-                    (drv_stm32h7_gpio_api::Port::G.pin(2), false),
-                    (drv_stm32h7_gpio_api::Port::G.pin(3), false),
-                    (drv_stm32h7_gpio_api::Port::G.pin(4), false),
-                    (drv_stm32h7_gpio_api::Port::G.pin(5), false),
-                ];
-            } else {
-                compile_error!("no LED mapping for unknown board");
-            }
-        }
+        include!(concat!(env!("OUT_DIR"), "/gpio_config.rs"));
+
+        type Led = &'static drv_stm32h7_gpio_api::PinConfig;
     }
 }
 
+#[cfg(feature = "stm32h7")]
+fn led(index: u32) -> Option<Led> {
+    gpio_config::PINS.get(index as usize)
+}
+
+/// Returns the level that turns `led` off, which is the level it starts at.
+#[cfg(feature = "stm32h7")]
+fn led_off_level(led: Led) -> bool {
+    led.initial.unwrap_or(false)
+}
+
 #[cfg(feature = "stm32h7")]
 fn enable_led_pins() {
     use drv_stm32h7_gpio_api::*;
@@ -301,31 +264,8 @@ fn enable_led_pins() {
     let gpio_driver = GPIO.get_task_id();
     let gpio_driver = Gpio::from(gpio_driver);
 
-    for &(pinset, active_low) in LEDS {
-        // Make sure LEDs are initially off.
-        gpio_driver.set_to(pinset, active_low).unwrap();
-        // Make them outputs.
-        gpio_driver
-            .configure_output(
-                pinset,
-                OutputType::PushPull,
-                Speed::High,
-                Pull::None,
-            )
-            .unwrap();
-    }
-}
-
-#[cfg(feature = "stm32h7")]
-fn led_info(led: Led) -> (drv_stm32h7_gpio_api::PinSet, bool) {
-    match led {
-        Led::Zero => LEDS[0],
-        Led::One => LEDS[1],
-        #[cfg(any(target_board = "gemini-bu-1", target_board = "gimletlet-2", target_board = "nucleo-h753zi", target_board = "nucleo-h743zi2"))]
-        Led::Two => LEDS[2],
-        #[cfg(any(target_board = "gemini-bu-1", target_board = "gimletlet-2"))]
-        Led::Three => LEDS[3],
-    }
+    // This makes the LEDs outputs, turning them off first.
+    gpio_config::configure(&gpio_driver).unwrap();
 }
 
 #[cfg(feature = "stm32h7")]
@@ -335,8 +275,7 @@ fn led_on(led: Led) {
     let gpio_driver = GPIO.get_task_id();
     let gpio_driver = Gpio::from(gpio_driver);
 
-    let (pinset, active_low) = led_info(led);
-    gpio_driver.set_to(pinset, !active_low).unwrap();
+    gpio_driver.set_to(led.pin, !led_off_level(led)).unwrap();
 }
 
 #[cfg(feature = "stm32h7")]
@@ -346,9 +285,7 @@ fn led_off(led: Led) {
     let gpio_driver = GPIO.get_task_id();
     let gpio_driver = Gpio::from(gpio_driver);
 
-    let (pinset, active_low) = led_info(led);
-
-    gpio_driver.set_to(pinset, active_low).unwrap();
+    gpio_driver.set_to(led.pin, led_off_level(led)).unwrap();
 }
 
 #[cfg(feature = "stm32h7")]
@@ -358,8 +295,7 @@ fn led_toggle(led: Led) {
     let gpio_driver = GPIO.get_task_id();
     let gpio_driver = Gpio::from(gpio_driver);
 
-    let pinset = led_info(led).0;
-    gpio_driver.toggle(pinset.port, pinset.pin_mask).unwrap();
+    gpio_driver.toggle(led.pin.port, led.pin.pin_mask).unwrap();
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -369,33 +305,27 @@ cfg_if::cfg_if! {
     if #[cfg(feature = "lpc55")] {
         task_slot!(GPIO, gpio_driver);
 
-        cfg_if::cfg_if! {
-            if #[cfg(target_board = "lpcxpresso55s69")] {
-                const LED_ZERO_PIN: drv_lpc55_gpio_api::Pin = drv_lpc55_gpio_api::Pin::PIO1_6;
-                const LED_ONE_PIN: drv_lpc55_gpio_api::Pin = drv_lpc55_gpio_api::Pin::PIO1_4;
-
-                // xpressoboard is active low LEDS
-                const LED_OFF_VAL: drv_lpc55_gpio_api::Value = drv_lpc55_gpio_api::Value::One;
-                const LED_ON_VAL: drv_lpc55_gpio_api::Value = drv_lpc55_gpio_api::Value::Zero;
-            } else if #[cfg(target_board = "gemini-bu-rot-1")] {
-                const LED_ZERO_PIN: drv_lpc55_gpio_api::Pin = drv_lpc55_gpio_api::Pin::PIO0_15;
-                const LED_ONE_PIN: drv_lpc55_gpio_api::Pin = drv_lpc55_gpio_api::Pin::PIO0_31;
-
-                // gemini bu board is standard values
-                const LED_OFF_VAL: drv_lpc55_gpio_api::Value = drv_lpc55_gpio_api::Value::Zero;
-                const LED_ON_VAL: drv_lpc55_gpio_api::Value = drv_lpc55_gpio_api::Value::One;
-            } else {
-                compile_error!("no LED mapping for unknown board");
-            }
-        }
+        include!(concat!(env!("OUT_DIR"), "/gpio_config.rs"));
+
+        type Led = &'static drv_lpc55_gpio_api::PinConfig;
     }
 }
 
 #[cfg(feature = "lpc55")]
-const fn led_gpio_num(led: Led) -> drv_lpc55_gpio_api::Pin {
-    match led {
-        Led::Zero => LED_ZERO_PIN,
-        Led::One => LED_ONE_PIN,
+fn led(index: u32) -> Option<Led> {
+    gpio_config::PINS.get(index as usize)
+}
+
+/// Returns the values that turn `led` off and on; it starts out off.
+#[cfg(feature = "lpc55")]
+fn led_values(
+    led: Led,
+) -> (drv_lpc55_gpio_api::Value, drv_lpc55_gpio_api::Value) {
+    use drv_lpc55_gpio_api::Value;
+
+    match led.initial {
+        Some(Value::One) => (Value::One, Value::Zero),
+        _ => (Value::Zero, Value::One),
     }
 }
 
@@ -406,39 +336,9 @@ fn enable_led_pins() {
     let gpio_driver = GPIO.get_task_id();
     let gpio_driver = Gpio::from(gpio_driver);
 
-    gpio_driver
-        .iocon_configure(
-            LED_ZERO_PIN,
-            AltFn::Alt0,
-            Mode::NoPull,
-            Slew::Standard,
-            Invert::Disable,
-            Digimode::Digital,
-            Opendrain::Normal,
-        )
-        .unwrap();
-
-    gpio_driver
-        .iocon_configure(
-            LED_ONE_PIN,
-            AltFn::Alt0,
-            Mode::NoPull,
-            Slew::Standard,
-            Invert::Disable,
-            Digimode::Digital,
-            Opendrain::Normal,
-        )
-        .unwrap();
-
-    // Both LEDs are active low -- so they will light when we set the
-    // direction of the pin if we don't explicitly turn them off first
-    led_off(Led::Zero);
-    led_off(Led::One);
-
-    gpio_driver
-        .set_dir(LED_ZERO_PIN, Direction::Output)
-        .unwrap();
-    gpio_driver.set_dir(LED_ONE_PIN, Direction::Output).unwrap();
+    // Active low LEDs would light when we set the direction of the pin if we
+    // didn't explicitly turn them off first -- which this does.
+    gpio_config::configure(&gpio_driver).unwrap();
 }
 
 #[cfg(feature = "lpc55")]
@@ -446,8 +346,7 @@ fn led_on(led: Led) {
     let gpio_driver = GPIO.get_task_id();
     let gpio_driver = drv_lpc55_gpio_api::Gpio::from(gpio_driver);
 
-    let pin = led_gpio_num(led);
-    gpio_driver.set_val(pin, LED_ON_VAL).unwrap();
+    gpio_driver.set_val(led.pin, led_values(led).1).unwrap();
 }
 
 #[cfg(feature = "lpc55")]
@@ -455,8 +354,7 @@ fn led_off(led: Led) {
     let gpio_driver = GPIO.get_task_id();
     let gpio_driver = drv_lpc55_gpio_api::Gpio::from(gpio_driver);
 
-    let pin = led_gpio_num(led);
-    gpio_driver.set_val(pin, LED_OFF_VAL).unwrap();
+    gpio_driver.set_val(led.pin, led_values(led).0).unwrap();
 }
 
 #[cfg(feature = "lpc55")]
@@ -464,6 +362,5 @@ fn led_toggle(led: Led) {
     let gpio_driver = GPIO.get_task_id();
     let gpio_driver = drv_lpc55_gpio_api::Gpio::from(gpio_driver);
 
-    let pin = led_gpio_num(led);
-    gpio_driver.toggle(pin).unwrap();
+    gpio_driver.toggle(led.pin).unwrap();
 }
@@ -0,0 +1,132 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! DICE-style identity derivation, shared between stage0 and the image it
+//! boots.
+//!
+//! Before booting an image, stage0 measures it, and combines that
+//! measurement with the device's unique secret (UDS) to get a compound device
+//! identifier (CDI):
+//!
+//! ```text
+//! measurement = SHA-256(image)
+//! CDI         = HMAC-SHA-256(key = UDS, measurement)
+//! ```
+//!
+//! The CDI is secret, and unique to both the device and the exact image, so
+//! an image can use it to derive keys that nothing else -- including a
+//! different image on the same device -- can. stage0 hands the CDI and the
+//! measurement to the image in a [`Handoff`], and then locks the UDS away
+//! until the next reset.
+//!
+//! The handoff is at the start of the bootloader's SRAM (`bootloader_sram` in
+//! the app's outputs), which `xtask dist` tells the image's linker as
+//! `__stage0_handoff`. A task reads it with `read_handoff`, and can only do
+//! so if the app gives it a region covering that address, e.g.:
+//!
+//! ```toml
+//! [peripherals.dice_handoff]
+//! address = 0x14000000
+//! size = 0x60
+//! ```
+//!
+//! Apart from `read_handoff`, everything here is pure computation; see
+//! stage0 for where the UDS comes from.
+
+#![no_std]
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+
+/// Length of the UDS, CDI, and of keys derived from the CDI.
+pub const SECRET_LEN: usize = 32;
+
+/// Length of a measurement, a SHA-256 digest.
+pub const MEASUREMENT_LEN: usize = 32;
+
+/// Measures an image: the whole of it, as described by the image length in
+/// its vector table, which includes its header and certificate block.
+pub fn measure(image: &[u8]) -> [u8; MEASUREMENT_LEN] {
+    Sha256::digest(image).into()
+}
+
+fn hmac(key: &[u8; SECRET_LEN], data: &[u8]) -> [u8; SECRET_LEN] {
+    // HMAC takes keys of any length, so this can't fail.
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Derives the CDI for an image with the given measurement.
+pub fn derive_cdi(
+    uds: &[u8; SECRET_LEN],
+    measurement: &[u8; MEASUREMENT_LEN],
+) -> [u8; SECRET_LEN] {
+    hmac(uds, measurement)
+}
+
+/// Derives a key for a particular purpose, named by `label`, from a CDI.
+/// Different labels give unrelated keys.
+pub fn derive_key(cdi: &[u8; SECRET_LEN], label: &[u8]) -> [u8; SECRET_LEN] {
+    hmac(cdi, label)
+}
+
+/// What stage0 leaves for the booted image. This sits in RAM that only
+/// stage0, and tasks explicitly given that region, can reach.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Handoff {
+    magic: u32,
+    pub measurement: [u8; MEASUREMENT_LEN],
+    pub cdi: [u8; SECRET_LEN],
+}
+
+/// Marks a `Handoff` as filled in by this boot's stage0. Anything else means
+/// stage0 had no UDS to derive from, or the memory holds garbage.
+pub const HANDOFF_MAGIC: u32 = 0x4443_4431;
+
+impl Handoff {
+    pub fn new(
+        measurement: [u8; MEASUREMENT_LEN],
+        cdi: [u8; SECRET_LEN],
+    ) -> Self {
+        Self {
+            magic: HANDOFF_MAGIC,
+            measurement,
+            cdi,
+        }
+    }
+
+    /// A handoff that carries nothing, for when there is no UDS.
+    pub fn empty() -> Self {
+        Self {
+            magic: 0,
+            measurement: [0; MEASUREMENT_LEN],
+            cdi: [0; SECRET_LEN],
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.magic == HANDOFF_MAGIC
+    }
+}
+
+/// Reads the handoff stage0 left for this boot, if it derived a CDI. This
+/// faults unless the task has been given the handoff's region; see the crate
+/// documentation.
+#[cfg(target_os = "none")]
+pub fn read_handoff() -> Option<Handoff> {
+    extern "C" {
+        static __stage0_handoff: Handoff;
+    }
+
+    // Any bit pattern is a valid `Handoff`; whether it's this boot's is down
+    // to the magic number.
+    let handoff = unsafe { core::ptr::read_volatile(&__stage0_handoff) };
+    if handoff.is_valid() {
+        Some(handoff)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,86 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Known-answer tests. The expected values were computed independently,
+//! with Python's `hashlib` and `hmac`.
+
+use dice::*;
+
+fn hex(s: &str) -> [u8; 32] {
+    let mut out = [0; 32];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    out
+}
+
+fn image() -> Vec<u8> {
+    (0..0x400u32).map(|i| (i * 7) as u8).collect()
+}
+
+fn uds() -> [u8; SECRET_LEN] {
+    let mut uds = [0; SECRET_LEN];
+    for (i, b) in uds.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    uds
+}
+
+#[test]
+fn measurement() {
+    assert_eq!(
+        measure(&image()),
+        hex("41a8df8d7a09deeda1ce604e394aca7e77f054f4937b3e51c882a84f67de6d1d")
+    );
+    assert_eq!(
+        measure(&[]),
+        hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+    );
+}
+
+#[test]
+fn cdi() {
+    let m = measure(&image());
+    assert_eq!(
+        derive_cdi(&uds(), &m),
+        hex("b3752c67c02c213a0334f71908a00485eba7e47a02dac24289ccad5855ec3721")
+    );
+    assert_eq!(
+        derive_cdi(&[0xff; SECRET_LEN], &m),
+        hex("3c14b1d77a55f146cadbae69b28467a63d80bb708aa10d25beae20cf73fc3606")
+    );
+    assert_eq!(
+        derive_cdi(&uds(), &measure(&[])),
+        hex("d43ab268ace84897c7c38db898ceba8460c30c061f52a2ea4c514367ed85f6fa")
+    );
+}
+
+#[test]
+fn derived_keys() {
+    let cdi = derive_cdi(&uds(), &measure(&image()));
+    assert_eq!(
+        derive_key(&cdi, b"alias"),
+        hex("527e9599bc23ec13c432af365a4be14610ff868e04a7729ef2fadd8cb7104334")
+    );
+    assert_ne!(derive_key(&cdi, b"alias"), derive_key(&cdi, b"alias2"));
+}
+
+#[test]
+fn any_change_to_the_image_changes_the_cdi() {
+    let base = derive_cdi(&uds(), &measure(&image()));
+    for offset in &[0, 0x20, 0x28, 0x3ff] {
+        let mut modified = image();
+        modified[*offset] ^= 1;
+        assert_ne!(derive_cdi(&uds(), &measure(&modified)), base);
+    }
+}
+
+#[test]
+fn handoff() {
+    assert!(!Handoff::empty().is_valid());
+    let h = Handoff::new([1; MEASUREMENT_LEN], [2; SECRET_LEN]);
+    assert!(h.is_valid());
+    assert_eq!(h.measurement, [1; MEASUREMENT_LEN]);
+    assert_eq!(h.cdi, [2; SECRET_LEN]);
+}
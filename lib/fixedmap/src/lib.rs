@@ -7,18 +7,44 @@
 //! This contains a very simple implementation of a fixed-sized map, with
 //! keys of type `K` and values of type `V`.  Keys and values are both stored
 //! by value: both must implement `Copy`, and keys must implement `PartialEq`.
-//! It is up to callers to assure that the map doesn't overflow; an attempt
-//! to [`FixedMap::insert`] when the map is full will result in a `panic!`.
+//!
+//! Entries are kept packed at the front of the map, and lookups are a linear
+//! scan -- which, for the handful of entries a task typically has, is as fast
+//! as anything.  For larger maps with keys that implement `Ord`, a map created
+//! with [`FixedMap::new_sorted`] keeps its keys in order and finds them by
+//! binary search instead.
+//!
+//! A map never grows: [`FixedMap::try_insert`] (and [`Entry::or_insert`])
+//! hand back the key and value in an [`Overflow`] if there is no room.  An
+//! attempt to [`FixedMap::insert`] when the map is full will result in a
+//! `panic!`, and is only appropriate when the caller knows the map can't
+//! overflow.
 
 #![no_std]
 
+use core::cmp::Ordering;
+use core::fmt;
+
 ///
 /// A fixed-size map of size `N`, mapping keys of type `K` to values of
 /// type `V`.
 ///
-#[derive(Debug)]
 pub struct FixedMap<K: Copy + PartialEq, V: Copy, const N: usize> {
+    /// Entries, of which the first `len` are always `Some`.
     contents: [Option<(K, V)>; N],
+    len: usize,
+    /// Key ordering, if the map is kept sorted.
+    order: Option<fn(&K, &K) -> Ordering>,
+}
+
+///
+/// Error returned when a key would be added to a map that is already full,
+/// handing back the key and value that didn't fit.
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Overflow<K, V> {
+    pub key: K,
+    pub value: V,
 }
 
 impl<K: Copy + PartialEq, V: Copy, const N: usize> FixedMap<K, V, { N }> {
@@ -28,28 +54,68 @@ impl<K: Copy + PartialEq, V: Copy, const N: usize> FixedMap<K, V, { N }> {
     pub fn new() -> Self {
         Self {
             contents: [None; N],
+            len: 0,
+            order: None,
         }
     }
 
+    ///
+    /// Create a new `FixedMap` that keeps its keys sorted, making lookups
+    /// logarithmic rather than linear at the cost of shifting entries on
+    /// insertion and removal.  Iteration is in key order.
+    ///
+    pub fn new_sorted() -> Self
+    where
+        K: Ord,
+    {
+        Self {
+            contents: [None; N],
+            len: 0,
+            order: Some(K::cmp),
+        }
+    }
+
+    ///
+    /// Returns the number of keys in the map.
+    ///
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    ///
+    /// Returns `true` if the map has no keys.
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    ///
+    /// Returns `true` if the map has no room for another key.
+    ///
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
     ///
     /// Gets the value that corresponds to `key`, returning `None` if no
     /// such key is in the map.
     ///
     pub fn get(&self, key: K) -> Option<V> {
-        for i in 0..self.contents.len() {
-            match self.contents[i] {
-                None => {
-                    break;
-                }
-                Some((k, v)) => {
-                    if k == key {
-                        return Some(v);
-                    }
-                }
-            }
+        match self.position(key) {
+            Ok(i) => self.contents[i].map(|(_, v)| v),
+            Err(_) => None,
         }
+    }
 
-        None
+    ///
+    /// Gets a mutable reference to the value that corresponds to `key`,
+    /// returning `None` if no such key is in the map.
+    ///
+    pub fn get_mut(&mut self, key: K) -> Option<&mut V> {
+        match self.position(key) {
+            Ok(i) => Some(self.value_mut(i)),
+            Err(_) => None,
+        }
     }
 
     ///
@@ -59,60 +125,372 @@ impl<K: Copy + PartialEq, V: Copy, const N: usize> FixedMap<K, V, { N }> {
     /// is room in the map; if the map is full, this code will panic.
     ///
     pub fn insert(&mut self, key: K, value: V) {
-        for i in 0..self.contents.len() {
-            match self.contents[i] {
-                None => {
-                    self.contents[i] = Some((key, value));
-                    return;
-                }
-
-                Some((k, _)) => {
-                    if k == key {
-                        self.contents[i] = Some((key, value));
-                        return;
-                    }
-                }
+        if self.try_insert(key, value).is_err() {
+            panic!("FixedMap overflow");
+        }
+    }
+
+    ///
+    /// Inserts the `value` into the map for the specified `key`, returning
+    /// the value it replaces, if any.  If the key isn't already in the map
+    /// and the map is full, the map is left unchanged and the key and value
+    /// are returned in the error.
+    ///
+    pub fn try_insert(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> Result<Option<V>, Overflow<K, V>> {
+        match self.position(key) {
+            Ok(i) => {
+                let old = core::mem::replace(self.value_mut(i), value);
+                Ok(Some(old))
+            }
+            Err(i) => {
+                self.insert_at(i, key, value)?;
+                Ok(None)
             }
         }
+    }
 
-        panic!("FixedMap overflow");
+    ///
+    /// Removes the specified key from the map, returning its value if it
+    /// was present.  The remaining entries are compacted, so the map has room
+    /// for another key afterwards.
+    ///
+    pub fn remove(&mut self, key: K) -> Option<V> {
+        let i = self.position(key).ok()?;
+        let removed = self.contents[i].map(|(_, v)| v);
+        let last = self.len - 1;
+
+        if self.order.is_some() {
+            // Shift everything after the removed entry down to keep order.
+            self.contents.copy_within(i + 1..self.len, i);
+        } else {
+            // Order doesn't matter; just move the last entry into the hole.
+            self.contents[i] = self.contents[last];
+        }
+
+        self.contents[last] = None;
+        self.len = last;
+        removed
     }
 
     ///
-    /// Removes the specified key from the map.
+    /// Returns an iterator over the keys and values in the map.
     ///
-    pub fn remove(&mut self, key: K) {
-        let mut found = None;
-        let mut swap = None;
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.contents[..self.len].iter().flatten().copied()
+    }
 
-        for i in 0..self.contents.len() {
-            match self.contents[i] {
-                None => {
-                    break;
-                }
-                Some((k, _)) => {
-                    if k == key {
-                        found = Some(i);
-                    } else {
-                        if found.is_some() {
-                            swap = Some(i);
-                        }
-                    }
-                }
-            }
+    ///
+    /// Returns an iterator over the keys in the map, along with mutable
+    /// references to their values.
+    ///
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (K, &mut V)> + '_ {
+        self.contents[..self.len]
+            .iter_mut()
+            .flatten()
+            .map(|(k, v)| (*k, v))
+    }
+
+    ///
+    /// Gets the entry for `key`, for in-place update or insertion.
+    ///
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, { N }> {
+        let position = self.position(key);
+        Entry {
+            map: self,
+            key,
+            position,
+        }
+    }
+
+    ///
+    /// Finds `key`, returning either its index, or the index at which it
+    /// should be inserted.
+    ///
+    fn position(&self, key: K) -> Result<usize, usize> {
+        let live = &self.contents[..self.len];
+
+        match self.order {
+            Some(cmp) => live.binary_search_by(|ent| match ent {
+                Some((k, _)) => cmp(k, &key),
+                None => Ordering::Greater,
+            }),
+            None => live
+                .iter()
+                .position(|ent| matches!(ent, Some((k, _)) if *k == key))
+                .ok_or(self.len),
+        }
+    }
+
+    fn insert_at(
+        &mut self,
+        i: usize,
+        key: K,
+        value: V,
+    ) -> Result<(), Overflow<K, V>> {
+        if self.is_full() {
+            return Err(Overflow { key, value });
+        }
+
+        self.contents.copy_within(i..self.len, i + 1);
+        self.contents[i] = Some((key, value));
+        self.len += 1;
+        Ok(())
+    }
+
+    fn value_mut(&mut self, i: usize) -> &mut V {
+        match &mut self.contents[i] {
+            Some((_, v)) => v,
+            None => unreachable!(),
+        }
+    }
+}
+
+// Written out because `Debug` isn't implemented for `order`'s type: it's
+// generic over the lifetimes of its arguments.
+impl<K, V, const N: usize> fmt::Debug for FixedMap<K, V, { N }>
+where
+    K: Copy + PartialEq + fmt::Debug,
+    V: Copy + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FixedMap")
+            .field("contents", &self.contents)
+            .field("len", &self.len)
+            .field("sorted", &self.order.is_some())
+            .finish()
+    }
+}
+
+impl<K: Copy + PartialEq, V: Copy, const N: usize> Default
+    for FixedMap<K, V, { N }>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///
+/// A single key's place in a [`FixedMap`], which may or may not have a value;
+/// see [`FixedMap::entry`].
+///
+pub struct Entry<'a, K: Copy + PartialEq, V: Copy, const N: usize> {
+    map: &'a mut FixedMap<K, V, { N }>,
+    key: K,
+    position: Result<usize, usize>,
+}
+
+impl<'a, K: Copy + PartialEq, V: Copy, const N: usize> Entry<'a, K, V, { N }> {
+    ///
+    /// Returns the key for this entry.
+    ///
+    pub fn key(&self) -> K {
+        self.key
+    }
+
+    ///
+    /// Returns the value for this entry, if there is one.
+    ///
+    pub fn get(&self) -> Option<V> {
+        match self.position {
+            Ok(i) => self.map.contents[i].map(|(_, v)| v),
+            Err(_) => None,
+        }
+    }
+
+    ///
+    /// Calls `f` on the value, if there is one.
+    ///
+    pub fn and_modify(self, f: impl FnOnce(&mut V)) -> Self {
+        if let Ok(i) = self.position {
+            f(self.map.value_mut(i));
         }
+        self
+    }
+
+    ///
+    /// Returns a mutable reference to the value, inserting `default` first
+    /// if there is none.  Fails only if the value must be inserted and the
+    /// map is full.
+    ///
+    pub fn or_insert(self, default: V) -> Result<&'a mut V, Overflow<K, V>> {
+        self.or_insert_with(|| default)
+    }
 
-        match (found, swap) {
-            (Some(found), Some(swap)) => {
-                self.contents[found] = self.contents[swap];
-                self.contents[swap] = None;
+    ///
+    /// Like [`Entry::or_insert`], but only calls `default` if the value must
+    /// be inserted.
+    ///
+    pub fn or_insert_with(
+        self,
+        default: impl FnOnce() -> V,
+    ) -> Result<&'a mut V, Overflow<K, V>> {
+        let i = match self.position {
+            Ok(i) => i,
+            Err(i) => {
+                self.map.insert_at(i, self.key, default())?;
+                i
             }
+        };
+        Ok(self.map.value_mut(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            (Some(found), None) => {
-                self.contents[found] = None;
+    fn keys<const N: usize>(map: &FixedMap<u32, u32, N>) -> [Option<u32>; N] {
+        let mut out = [None; N];
+        for (slot, (k, _)) in out.iter_mut().zip(map.iter()) {
+            *slot = Some(k);
+        }
+        out
+    }
+
+    #[test]
+    fn insert_get_replace() {
+        let mut map: FixedMap<u32, u32, 4> = FixedMap::new();
+        assert!(map.is_empty());
+
+        map.insert(3, 30);
+        map.insert(1, 10);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(3), Some(30));
+        assert_eq!(map.get(1), Some(10));
+        assert_eq!(map.get(2), None);
+
+        assert_eq!(map.try_insert(3, 33), Ok(Some(30)));
+        assert_eq!(map.get(3), Some(33));
+        assert_eq!(map.len(), 2);
+
+        *map.get_mut(1).unwrap() += 1;
+        assert_eq!(map.get(1), Some(11));
+        assert_eq!(map.get_mut(2), None);
+    }
+
+    #[test]
+    fn overflow() {
+        for mut map in [FixedMap::<u32, u32, 2>::new(), FixedMap::new_sorted()]
+        {
+            assert_eq!(map.try_insert(2, 20), Ok(None));
+            assert_eq!(map.try_insert(1, 10), Ok(None));
+            assert!(map.is_full());
+
+            assert_eq!(
+                map.try_insert(3, 30),
+                Err(Overflow { key: 3, value: 30 })
+            );
+            assert_eq!(map.len(), 2);
+            assert_eq!(map.get(3), None);
+
+            // Replacing an existing key needs no room.
+            assert_eq!(map.try_insert(1, 11), Ok(Some(10)));
+
+            // Nor does reinserting once something is removed.
+            assert_eq!(map.remove(2), Some(20));
+            assert_eq!(map.try_insert(3, 30), Ok(None));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "FixedMap overflow")]
+    fn insert_panics_when_full() {
+        let mut map: FixedMap<u32, u32, 1> = FixedMap::new();
+        map.insert(1, 10);
+        map.insert(2, 20);
+    }
+
+    #[test]
+    fn unsorted_order() {
+        let mut map: FixedMap<u32, u32, 4> = FixedMap::new();
+        for k in [3, 1, 4, 2] {
+            map.insert(k, k * 10);
+        }
+        // Insertion order...
+        assert_eq!(keys(&map), [Some(3), Some(1), Some(4), Some(2)]);
+
+        // ...until something is removed, which moves the last entry into
+        // its place.
+        assert_eq!(map.remove(3), Some(30));
+        assert_eq!(keys(&map), [Some(2), Some(1), Some(4), None]);
+        assert_eq!(map.len(), 3);
+
+        // Removing the last entry just shortens the map.
+        assert_eq!(map.remove(4), Some(40));
+        assert_eq!(keys(&map), [Some(2), Some(1), None, None]);
+
+        assert_eq!(map.remove(4), None);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(2), Some(20));
+        assert_eq!(map.get(1), Some(10));
+    }
+
+    #[test]
+    fn sorted_order() {
+        let mut map: FixedMap<u32, u32, 4> = FixedMap::new_sorted();
+        for k in [3, 1, 4, 2] {
+            map.insert(k, k * 10);
+        }
+        assert_eq!(keys(&map), [Some(1), Some(2), Some(3), Some(4)]);
+
+        // Removal shifts the rest down, keeping them in order.
+        assert_eq!(map.remove(2), Some(20));
+        assert_eq!(keys(&map), [Some(1), Some(3), Some(4), None]);
+        assert_eq!(map.remove(2), None);
+
+        map.insert(0, 0);
+        assert_eq!(keys(&map), [Some(0), Some(1), Some(3), Some(4)]);
+        for k in [0, 1, 3, 4] {
+            assert_eq!(map.get(k), Some(k * 10));
+        }
+    }
+
+    #[test]
+    fn iter_mut() {
+        for mut map in [FixedMap::<u32, u32, 4>::new(), FixedMap::new_sorted()]
+        {
+            map.insert(2, 20);
+            map.insert(1, 10);
+            for (k, v) in map.iter_mut() {
+                *v += k;
             }
+            assert_eq!(map.get(1), Some(11));
+            assert_eq!(map.get(2), Some(22));
+        }
+    }
+
+    #[test]
+    fn entry() {
+        for mut map in [FixedMap::<u32, u32, 2>::new(), FixedMap::new_sorted()]
+        {
+            let e = map.entry(5);
+            assert_eq!(e.key(), 5);
+            assert_eq!(e.get(), None);
+            assert_eq!(e.and_modify(|v| *v += 1).or_insert(50), Ok(&mut 50));
+            assert_eq!(map.len(), 1);
+
+            let e = map.entry(5);
+            assert_eq!(e.get(), Some(50));
+            *e.and_modify(|v| *v += 1).or_insert(0).unwrap() += 1;
+            assert_eq!(map.get(5), Some(52));
+            assert_eq!(map.len(), 1);
+
+            // `or_insert_with` only makes a value when it needs one.
+            map.entry(5)
+                .or_insert_with(|| panic!("value made for a present key"))
+                .unwrap();
+            assert_eq!(map.entry(4).or_insert_with(|| 40), Ok(&mut 40));
+            assert_eq!(map.len(), 2);
 
-            (_, _) => {}
+            assert_eq!(
+                map.entry(3).or_insert(30),
+                Err(Overflow { key: 3, value: 30 })
+            );
+            assert_eq!(map.len(), 2);
         }
     }
 }
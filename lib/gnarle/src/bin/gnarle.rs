@@ -0,0 +1,114 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Host tool for compressing assets with gnarle, e.g. from a build script or
+//! by hand to check how well something compresses:
+//!
+//! ```text
+//! gnarle compress --lz fpga.bin fpga.bin.lz
+//! gnarle decompress --lz fpga.bin.lz fpga.bin
+//! ```
+
+use std::convert::Infallible;
+use std::process::exit;
+
+#[derive(Copy, Clone)]
+enum Method {
+    Rle,
+    Lz,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: gnarle (compress|decompress) [--rle|--lz] INPUT OUTPUT");
+    exit(2);
+}
+
+fn compress(method: Method, input: &[u8]) -> Vec<u8> {
+    let mut output = vec![];
+    let out = |chunk: &[u8]| {
+        output.extend_from_slice(chunk);
+        Ok::<_, Infallible>(())
+    };
+    match method {
+        Method::Rle => gnarle::compress(input, out),
+        Method::Lz => gnarle::lz::compress(input, out),
+    }
+    .ok();
+    output
+}
+
+fn decompress(method: Method, mut input: &[u8]) -> Vec<u8> {
+    let mut output = vec![];
+    let mut chunk = [0; 256];
+    match method {
+        Method::Rle => {
+            let mut state = gnarle::Decompressor::default();
+            while !input.is_empty() || !state.is_idle() {
+                let out =
+                    gnarle::decompress(&mut state, &mut input, &mut chunk);
+                if out.is_empty() {
+                    break;
+                }
+                output.extend_from_slice(out);
+            }
+        }
+        Method::Lz => {
+            let mut state = gnarle::lz::Decompressor::default();
+            while !input.is_empty() || !state.is_idle() {
+                let out =
+                    gnarle::lz::decompress(&mut state, &mut input, &mut chunk);
+                if out.is_empty() {
+                    break;
+                }
+                output.extend_from_slice(out);
+            }
+        }
+    }
+    output
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut method = Method::Lz;
+    let mut positional = vec![];
+
+    let command = args.next().unwrap_or_else(|| usage());
+    for arg in args {
+        match arg.as_str() {
+            "--rle" => method = Method::Rle,
+            "--lz" => method = Method::Lz,
+            _ if arg.starts_with('-') => usage(),
+            _ => positional.push(arg),
+        }
+    }
+    let (input_path, output_path) = match positional.as_slice() {
+        [i, o] => (i, o),
+        _ => usage(),
+    };
+
+    let input = std::fs::read(input_path).unwrap_or_else(|e| {
+        eprintln!("gnarle: can't read {}: {}", input_path, e);
+        exit(1);
+    });
+
+    let output = match command.as_str() {
+        "compress" => compress(method, &input),
+        "decompress" => decompress(method, &input),
+        _ => usage(),
+    };
+
+    std::fs::write(output_path, &output).unwrap_or_else(|e| {
+        eprintln!("gnarle: can't write {}: {}", output_path, e);
+        exit(1);
+    });
+
+    if command == "compress" && !input.is_empty() {
+        eprintln!(
+            "{} -> {} bytes ({:.1}%)",
+            input.len(),
+            output.len(),
+            100.0 * output.len() as f64 / input.len() as f64
+        );
+    }
+}
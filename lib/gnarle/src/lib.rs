@@ -8,11 +8,20 @@
 //! entropy, such as FPGA bitstreams. It generally performs worse than lz4, but
 //! there don't appear to be any `no_std` lz4 crates out there, no matter what
 //! their READMEs claim.
+//!
+//! For data that repeats in longer patterns than single bytes, the [`lz`]
+//! module has a codec with the same interface that also encodes back-references
+//! into a small window of recent output.
+//!
+//! With the `cli` feature, this also builds a `gnarle` binary for compressing
+//! and decompressing files with either method on the host.
 
 #![no_std]
 
 use core::convert::TryFrom;
 
+pub mod lz;
+
 /// Internal definition of how long the run count is. Tuning this might improve
 /// performance, though its current value seems optimal in practice.
 type RunType = u8;
@@ -0,0 +1,261 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A small-window LZ77-style compression method.
+//!
+//! This catches repeated sequences, not just repeated bytes, at the cost of a
+//! [`WINDOW`]-byte history buffer in the [`Decompressor`]. The compressed form
+//! is a sequence of tokens, each starting with a control byte:
+//!
+//! - `0LLLLLLL` is followed by `L + 1` literal bytes.
+//! - `1LLLLLLL` is followed by a byte `D`, and copies `L + MIN_MATCH` bytes
+//!   starting `D + 1` bytes back in the output. If `L` is all ones, the length
+//!   continues in the following bytes, each of which is added to it, up to and
+//!   including the first that isn't `0xFF` -- as in lz4.
+//!
+//! Copies may overlap the bytes they produce, so a long run of a single byte
+//! costs a literal and a copy with `D = 0`.
+
+use core::convert::TryFrom;
+
+/// Size of the history the decompressor keeps, which is as far back as a copy
+/// can reach.
+pub const WINDOW: usize = 256;
+
+/// Shortest copy we'll emit. Anything shorter costs at least as much as the
+/// literals it replaces.
+const MIN_MATCH: usize = 3;
+
+/// Value of the control byte's length field indicating that extension bytes
+/// follow.
+const LEN_EXTENDED: u8 = 0x7F;
+
+/// Most literal bytes in a single token.
+const MAX_LITERALS: usize = 0x80;
+
+/// Longest copy we'll emit. The format allows for more, but limiting it keeps
+/// the decompressor's arithmetic well clear of overflow on 32-bit targets.
+const MAX_MATCH: usize = 0xFFFF;
+
+/// Compresses data from `input`, handing the results to `out` as small slices.
+/// `out` has the opportunity to abort compression by returning `Err`.
+///
+/// This works exactly like [`crate::compress`] -- including being callable
+/// more than once to process input in chunks -- except that copies never refer
+/// to data from a previous chunk, so chunks should be as large as is
+/// convenient.
+///
+/// The search for repeats is exhaustive within the window, which is slow
+/// compared to decompression; this is meant to be done at build time.
+pub fn compress<E>(
+    input: &[u8],
+    mut out: impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    while i < input.len() {
+        let (offset, len) = longest_match(input, i);
+        if len < MIN_MATCH {
+            i += 1;
+            continue;
+        }
+
+        emit_literals(&input[literal_start..i], &mut out)?;
+        emit_copy(offset, len, &mut out)?;
+        i += len;
+        literal_start = i;
+    }
+
+    emit_literals(&input[literal_start..], &mut out)
+}
+
+/// Finds the longest match for the data at `input[pos..]` in the window before
+/// it, returning its distance back and length.
+fn longest_match(input: &[u8], pos: usize) -> (usize, usize) {
+    let limit = usize::min(input.len() - pos, MAX_MATCH);
+    let mut best = (0, 0);
+
+    for offset in 1..=usize::min(pos, WINDOW) {
+        let start = pos - offset;
+        let len = (0..limit)
+            .take_while(|&k| input[start + k] == input[pos + k])
+            .count();
+        if len > best.1 {
+            best = (offset, len);
+            if len == limit {
+                break;
+            }
+        }
+    }
+
+    best
+}
+
+fn emit_literals<E>(
+    mut literals: &[u8],
+    out: &mut impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    while !literals.is_empty() {
+        let (chunk, rest) =
+            literals.split_at(usize::min(literals.len(), MAX_LITERALS));
+        out(&[u8::try_from(chunk.len() - 1).unwrap()])?;
+        out(chunk)?;
+        literals = rest;
+    }
+    Ok(())
+}
+
+fn emit_copy<E>(
+    offset: usize,
+    len: usize,
+    out: &mut impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    let distance = u8::try_from(offset - 1).unwrap();
+    let mut extra = len - MIN_MATCH;
+
+    if extra < usize::from(LEN_EXTENDED) {
+        return out(&[0x80 | extra as u8, distance]);
+    }
+
+    out(&[0x80 | LEN_EXTENDED, distance])?;
+    extra -= usize::from(LEN_EXTENDED);
+    while extra >= 0xFF {
+        out(&[0xFF])?;
+        extra -= 0xFF;
+    }
+    out(&[extra as u8])
+}
+
+/// State that you're expected to hang on to while decompressing something,
+/// including the window of recent output.
+pub struct Decompressor {
+    state: DState,
+    history: [u8; WINDOW],
+    /// Index in `history` where the next output byte goes.
+    next: usize,
+}
+
+impl Decompressor {
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, DState::Idle)
+    }
+
+    fn record(&mut self, byte: u8) {
+        self.history[self.next] = byte;
+        self.next = (self.next + 1) % WINDOW;
+    }
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Self {
+            state: DState::Idle,
+            history: [0; WINDOW],
+            next: 0,
+        }
+    }
+}
+
+enum DState {
+    /// We're between tokens, waiting for a control byte.
+    Idle,
+    /// We're copying the given number of literal bytes from the input.
+    Literals(usize),
+    /// We've got a copy's control byte, and need its distance.
+    Distance { len: usize, extended: bool },
+    /// We're reading a copy's length extension bytes.
+    Extending { distance: usize, len: usize },
+    /// We're producing `len` bytes, starting `distance` bytes back.
+    Copying { distance: usize, len: usize },
+}
+
+/// Decompresses a chunk of data `input`, writing results to the start of
+/// `output`. Returns the prefix of `output` that was written.
+///
+/// This works exactly like [`crate::decompress`]: `input` is updated to lop
+/// off the bytes consumed, and decompression stops at the end of either
+/// `input` or `output`. A token may be split across calls at any byte.
+pub fn decompress<'a>(
+    state: &mut Decompressor,
+    input: &mut &[u8],
+    output: &'a mut [u8],
+) -> &'a [u8] {
+    fn take_byte(input: &mut &[u8]) -> Option<u8> {
+        let (first, rest) = input.split_first()?;
+        *input = rest;
+        Some(*first)
+    }
+
+    let mut n = 0;
+    while n < output.len() {
+        match state.state {
+            DState::Copying { distance, len } => {
+                let byte =
+                    state.history[(state.next + WINDOW - distance) % WINDOW];
+                state.record(byte);
+                output[n] = byte;
+                n += 1;
+                state.state = if len > 1 {
+                    DState::Copying {
+                        distance,
+                        len: len - 1,
+                    }
+                } else {
+                    DState::Idle
+                };
+            }
+            DState::Literals(count) => match take_byte(input) {
+                Some(byte) => {
+                    state.record(byte);
+                    output[n] = byte;
+                    n += 1;
+                    state.state = if count > 1 {
+                        DState::Literals(count - 1)
+                    } else {
+                        DState::Idle
+                    };
+                }
+                None => break,
+            },
+            DState::Idle => match take_byte(input) {
+                Some(control) if control & 0x80 == 0 => {
+                    state.state = DState::Literals(usize::from(control) + 1);
+                }
+                Some(control) => {
+                    let field = control & 0x7F;
+                    state.state = DState::Distance {
+                        len: usize::from(field) + MIN_MATCH,
+                        extended: field == LEN_EXTENDED,
+                    };
+                }
+                None => break,
+            },
+            DState::Distance { len, extended } => match take_byte(input) {
+                Some(d) => {
+                    let distance = usize::from(d) + 1;
+                    state.state = if extended {
+                        DState::Extending { distance, len }
+                    } else {
+                        DState::Copying { distance, len }
+                    };
+                }
+                None => break,
+            },
+            DState::Extending { distance, len } => match take_byte(input) {
+                Some(b) => {
+                    let len = len.saturating_add(usize::from(b));
+                    state.state = if b == 0xFF {
+                        DState::Extending { distance, len }
+                    } else {
+                        DState::Copying { distance, len }
+                    };
+                }
+                None => break,
+            },
+        }
+    }
+
+    &output[..n]
+}
@@ -0,0 +1,205 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Roundtrip tests for both codecs: whatever we compress, decompressing it in
+//! arbitrarily sized input and output chunks must give back the original.
+
+use std::convert::Infallible;
+
+/// Small deterministic PRNG (xorshift32), so failures are reproducible
+/// without pulling in a dependency.
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.next() as usize % n
+    }
+}
+
+/// Generates input resembling a bitstream: runs of a single byte, random
+/// noise, and repeats of earlier data, with the mix set by `rng`.
+fn generate(rng: &mut Rng, len: usize) -> Vec<u8> {
+    let alphabet = 1 + rng.below(256);
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let n = usize::min(1 + rng.below(600), len - data.len());
+        match rng.below(3) {
+            0 => {
+                let b = rng.below(alphabet) as u8;
+                data.resize(data.len() + n, b);
+            }
+            1 => {
+                for _ in 0..n {
+                    data.push(rng.below(alphabet) as u8);
+                }
+            }
+            _ if !data.is_empty() => {
+                let back = 1 + rng.below(usize::min(data.len(), 400));
+                let start = data.len() - back;
+                for k in 0..n {
+                    data.push(data[start + k]);
+                }
+            }
+            _ => (),
+        }
+    }
+    data
+}
+
+fn collect(
+    compress: impl FnOnce(&mut dyn FnMut(&[u8]) -> Result<(), Infallible>),
+) -> Vec<u8> {
+    let mut output = vec![];
+    compress(&mut |chunk| {
+        output.extend_from_slice(chunk);
+        Ok(())
+    });
+    output
+}
+
+/// Splits `compressed` into chunks of random size, decompressing each into
+/// randomly sized output buffers with `step`, which takes the remaining
+/// input of the current chunk and an output buffer.
+fn chunked(
+    rng: &mut Rng,
+    compressed: &[u8],
+    mut step: impl FnMut(&mut &[u8], &mut [u8]) -> Vec<u8>,
+) -> Vec<u8> {
+    let mut output = vec![];
+    let mut rest = compressed;
+    while !rest.is_empty() {
+        let (mut chunk, tail) = rest.split_at(1 + rng.below(rest.len()));
+        rest = tail;
+        while !chunk.is_empty() {
+            let mut buf = vec![0; 1 + rng.below(300)];
+            output.extend(step(&mut chunk, &mut buf));
+        }
+    }
+    // Drain anything still pending once all input is consumed.
+    loop {
+        let mut buf = vec![0; 1 + rng.below(300)];
+        let out = step(&mut &[][..], &mut buf);
+        if out.is_empty() {
+            break;
+        }
+        output.extend(out);
+    }
+    output
+}
+
+#[test]
+fn lz_roundtrip() {
+    let mut rng = Rng(0x1234_5678);
+    for case in 0..100 {
+        let len = if case == 0 { 0 } else { rng.below(8_000) };
+        let data = generate(&mut rng, len);
+        let compressed = collect(|out| {
+            gnarle::lz::compress(&data, out).unwrap();
+        });
+
+        let mut state = gnarle::lz::Decompressor::default();
+        let output = chunked(&mut rng, &compressed, |input, buf| {
+            gnarle::lz::decompress(&mut state, input, buf).to_vec()
+        });
+        assert!(state.is_idle(), "case {}: decompressor not idle", case);
+        assert_eq!(output, data, "case {}: roundtrip mismatch", case);
+    }
+}
+
+#[test]
+fn lz_concatenated_chunks() {
+    let mut rng = Rng(0xdead_beef);
+    let data = generate(&mut rng, 50_000);
+    let mut compressed = vec![];
+    for piece in data.chunks(4096) {
+        gnarle::lz::compress(piece, |c| {
+            compressed.extend_from_slice(c);
+            Ok::<_, Infallible>(())
+        })
+        .unwrap();
+    }
+
+    let mut state = gnarle::lz::Decompressor::default();
+    let mut input = &compressed[..];
+    let mut output = vec![];
+    let mut buf = [0; 256];
+    while !input.is_empty() || !state.is_idle() {
+        output.extend_from_slice(gnarle::lz::decompress(
+            &mut state, &mut input, &mut buf,
+        ));
+    }
+    assert_eq!(output, data);
+}
+
+#[test]
+fn lz_long_runs() {
+    for len in [1, 3, 129, 130, 131, 385, 386, 387, 70_000] {
+        let data = vec![0u8; len];
+        let compressed = collect(|out| {
+            gnarle::lz::compress(&data, out).unwrap();
+        });
+        let mut state = gnarle::lz::Decompressor::default();
+        let mut input = &compressed[..];
+        let mut output = vec![];
+        let mut buf = [0; 1000];
+        while !input.is_empty() || !state.is_idle() {
+            output.extend_from_slice(gnarle::lz::decompress(
+                &mut state, &mut input, &mut buf,
+            ));
+        }
+        assert_eq!(output, data, "run of {}", len);
+    }
+}
+
+#[test]
+fn rle_roundtrip() {
+    let mut rng = Rng(0x0bad_cafe);
+    for case in 0..100 {
+        let len = rng.below(8_000);
+        let data = generate(&mut rng, len);
+        let compressed = collect(|out| {
+            gnarle::compress(&data, out).unwrap();
+        });
+
+        // The RLE decompressor needs a whole escape sequence in one call, so
+        // feed it all of the input and only vary the output size.
+        let mut state = gnarle::Decompressor::default();
+        let mut input = &compressed[..];
+        let mut output = vec![];
+        while !input.is_empty() || !state.is_idle() {
+            let mut buf = vec![0; 1 + rng.below(300)];
+            output.extend_from_slice(gnarle::decompress(
+                &mut state, &mut input, &mut buf,
+            ));
+        }
+        assert_eq!(output, data, "case {}: roundtrip mismatch", case);
+    }
+}
+
+#[test]
+fn lz_beats_rle_on_repeats() {
+    let mut rng = Rng(42);
+    let pattern: Vec<u8> = (0..64).map(|_| rng.next() as u8).collect();
+    let data: Vec<u8> = pattern.iter().copied().cycle().take(16384).collect();
+
+    let lz = collect(|out| {
+        gnarle::lz::compress(&data, out).unwrap();
+    });
+    let rle = collect(|out| {
+        gnarle::compress(&data, out).unwrap();
+    });
+    assert!(
+        lz.len() * 10 < rle.len(),
+        "lz {} vs rle {}",
+        lz.len(),
+        rle.len()
+    );
+}
@@ -8,8 +8,11 @@ use std::io::Write;
 use std::path::PathBuf;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Do an architecture check.
-    if env::var("CARGO_CFG_TARGET_OS").unwrap() != "none" {
+    // Do an architecture check. The standalone versions of the calls don't
+    // need stage0, and can be built natively for testing.
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() != "none"
+        && env::var_os("CARGO_FEATURE_STANDALONE").is_none()
+    {
         eprintln!("***********************************************");
         eprintln!("Hi!");
         eprintln!("You appear to be building this natively,");
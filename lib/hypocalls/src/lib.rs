@@ -2,12 +2,117 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! Calls from Hubris tasks into the stage0 bootloader.
+//!
+//! stage0 exports a table of secure gateway entry points, one per symbol
+//! listed in `sharedsyms` in the app's `[bootloader]` config. `xtask dist`
+//! copies that table into each task as `__bootloader_fn_table`, so a task
+//! calls the entry points of the stage0 it was built with. Every call added
+//! after `write_to_flash` first checks the ABI version stage0 reports through
+//! `hypo_abi_version`, and fails with [`HypoError::Unsupported`] if it is too
+//! old. That check goes through the table too, so it can't detect a stage0
+//! from before the ABI was versioned, which has no `hypo_abi_version` entry:
+//! an image must be booted by a stage0 that implements at least version 1.
+//!
+//! How stage0 chooses between image slots, which those calls feed into, is
+//! defined in [`slots`].
+//!
+//! With the `standalone` feature, the calls are simulated in-process instead,
+//! which lets this be built and tested on the host; see [`standalone`].
+
 #![no_std]
 #![feature(asm)]
 #![feature(naked_functions)]
 
 pub use lpc55_romapi::FlashStatus;
 
+use num_derive::FromPrimitive;
+use zerocopy::{AsBytes, FromBytes};
+
+pub mod slots;
+
+/// Version of the hypocall ABI described here. This is bumped whenever calls
+/// are added; existing calls never change.
+///
+/// - 0: `write_to_flash` only, from before the ABI was versioned.
+/// - 1: adds `hypo_abi_version`, `get_boot_info`, `read_measurement`,
+///   `request_slot_switch`, and `confirm_image`.
+pub const HYPO_ABI_VERSION: u32 = 1;
+
+/// Length of the boot measurement, a SHA-256 digest of the booted image.
+pub const MEASUREMENT_LEN: usize = 32;
+
+/// Image slots stage0 can boot from.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+pub enum SlotId {
+    A = 0,
+    B = 1,
+}
+
+/// Status codes returned by the hypocalls added in ABI version 1.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+pub enum HypoError {
+    /// An argument was out of range, or a pointer was null or misaligned.
+    InvalidArg = 1,
+    /// The requested slot doesn't exist, or holds no valid image.
+    NoSuchSlot = 2,
+    /// stage0 doesn't implement this call.
+    Unsupported = 3,
+    /// stage0 couldn't save its records of the slots to flash, so the
+    /// request hasn't taken effect.
+    FlashFailed = 4,
+    /// stage0 returned something we don't recognize.
+    Unknown = 255,
+}
+
+/// Status code for success, shared with `FlashStatus`.
+pub const HYPO_SUCCESS: u32 = 0;
+
+impl HypoError {
+    /// Converts a raw status code from stage0.
+    pub fn check(code: u32) -> Result<(), HypoError> {
+        use num_traits::cast::FromPrimitive;
+
+        if code == HYPO_SUCCESS {
+            Ok(())
+        } else {
+            Err(HypoError::from_u32(code).unwrap_or(HypoError::Unknown))
+        }
+    }
+}
+
+/// Information about the image that stage0 booted, filled in by
+/// `get_boot_info`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromBytes, PartialEq, Eq)]
+pub struct BootInfo {
+    /// Slot the running image was booted from, as a `SlotId`.
+    pub slot: u32,
+    /// Build number from the image's certificate header.
+    pub version: u32,
+    /// Nonzero if the image has been confirmed healthy with `confirm_image`.
+    pub confirmed: u32,
+    /// Slot stage0 will boot on next reset, as a `SlotId`, if a switch has
+    /// been requested; otherwise `u32::MAX`.
+    pub pending_slot: u32,
+}
+
+impl BootInfo {
+    pub fn slot(&self) -> Option<SlotId> {
+        num_traits::cast::FromPrimitive::from_u32(self.slot)
+    }
+
+    pub fn pending_slot(&self) -> Option<SlotId> {
+        num_traits::cast::FromPrimitive::from_u32(self.pending_slot)
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed != 0
+    }
+}
+
 /// Write the buffer to the specified region number.
 ///
 /// Once we've established our regions this should be changed to an enum
@@ -36,8 +141,247 @@ pub fn hypo_write_to_flash(region: u32, buf: &[u8]) -> FlashStatus {
     return result;
 }
 
+/// Returns the hypocall ABI version implemented by stage0.
+#[cfg(not(feature = "standalone"))]
+#[inline(never)]
+pub fn hypo_abi_version() -> u32 {
+    unsafe {
+        core::mem::transmute::<_, unsafe extern "C" fn() -> u32>(
+            __bootloader_fn_table.hypo_abi_version,
+        )()
+    }
+}
+
+/// Fails with `Unsupported` if stage0 reports an ABI version older than
+/// `version`. (This needs stage0 to have `hypo_abi_version` at all; see the
+/// crate documentation.)
+fn require_version(version: u32) -> Result<(), HypoError> {
+    if hypo_abi_version() >= version {
+        Ok(())
+    } else {
+        Err(HypoError::Unsupported)
+    }
+}
+
+/// Returns which slot booted, the running image's version, and the state of
+/// any pending slot switch.
+#[cfg(not(feature = "standalone"))]
+#[inline(never)]
+pub fn hypo_get_boot_info() -> Result<BootInfo, HypoError> {
+    require_version(1)?;
+
+    let mut info = BootInfo::default();
+    let result = unsafe {
+        core::mem::transmute::<_, unsafe extern "C" fn(*mut BootInfo) -> u32>(
+            __bootloader_fn_table.get_boot_info,
+        )(&mut info)
+    };
+    HypoError::check(result)?;
+    Ok(info)
+}
+
+/// Copies the boot measurement of the running image into `buf`.
+#[cfg(not(feature = "standalone"))]
+#[inline(never)]
+pub fn hypo_read_measurement(
+    buf: &mut [u8; MEASUREMENT_LEN],
+) -> Result<(), HypoError> {
+    require_version(1)?;
+
+    let result = unsafe {
+        core::mem::transmute::<_, unsafe extern "C" fn(*mut u8, u32) -> u32>(
+            __bootloader_fn_table.read_measurement,
+        )(buf.as_mut_ptr(), buf.len() as u32)
+    };
+    HypoError::check(result)
+}
+
+/// Asks stage0 to boot from `slot` on the next reset, which must hold a newer
+/// image than the running one. The new image must then call
+/// `hypo_confirm_image` within [`slots::MAX_BOOT_ATTEMPTS`] boots, or stage0
+/// will fall back to the previous slot. Asking for the running slot cancels a
+/// pending switch.
+#[cfg(not(feature = "standalone"))]
+#[inline(never)]
+pub fn hypo_request_slot_switch(slot: SlotId) -> Result<(), HypoError> {
+    require_version(1)?;
+
+    let result = unsafe {
+        core::mem::transmute::<_, unsafe extern "C" fn(u32) -> u32>(
+            __bootloader_fn_table.request_slot_switch,
+        )(slot as u32)
+    };
+    HypoError::check(result)
+}
+
+/// Tells stage0 that the running image is healthy, and should keep being
+/// booted.
+#[cfg(not(feature = "standalone"))]
+#[inline(never)]
+pub fn hypo_confirm_image() -> Result<(), HypoError> {
+    require_version(1)?;
+
+    let result = unsafe {
+        core::mem::transmute::<_, unsafe extern "C" fn() -> u32>(
+            __bootloader_fn_table.confirm_image,
+        )()
+    };
+    HypoError::check(result)
+}
+
+#[cfg(feature = "standalone")]
+pub use standalone::{
+    hypo_abi_version, hypo_confirm_image, hypo_get_boot_info,
+    hypo_read_measurement, hypo_request_slot_switch, hypo_write_to_flash,
+};
+
+/// In-process stand-ins for stage0, used with the `standalone` feature.
+///
+/// These keep the same state stage0 would, and follow the same rules, so that
+/// an update task's logic can be exercised on the host. [`reset`] simulates a
+/// reset of the part, including stage0 acting on any pending slot switch.
 #[cfg(feature = "standalone")]
-pub fn hypo_write_to_flash(_addr: u32, _buf: &[u8], _size: u32) -> FlashStatus {
-    return FlashStatus::Success;
+pub mod standalone {
+    use super::*;
+    use crate::slots::{self, SlotRecord, SLOT_COUNT};
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    const NONE: u32 = u32::MAX;
+
+    static ABI_VERSION: AtomicU32 = AtomicU32::new(HYPO_ABI_VERSION);
+    static SLOT: AtomicU32 = AtomicU32::new(SlotId::A as u32);
+    static VERSIONS: [AtomicU32; SLOT_COUNT] =
+        [AtomicU32::new(1), AtomicU32::new(0)];
+
+    /// The records stage0 keeps for each slot, as version, state and
+    /// attempts.
+    static RECORDS: [[AtomicU32; 3]; SLOT_COUNT] = [
+        [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)],
+        [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)],
+    ];
+
+    fn records() -> [SlotRecord; SLOT_COUNT] {
+        let mut records = [SlotRecord::default(); SLOT_COUNT];
+        for (r, stored) in records.iter_mut().zip(RECORDS.iter()) {
+            r.version = stored[0].load(Ordering::SeqCst);
+            r.state = stored[1].load(Ordering::SeqCst);
+            r.attempts = stored[2].load(Ordering::SeqCst);
+        }
+        records
+    }
+
+    fn set_records(records: &[SlotRecord; SLOT_COUNT]) {
+        for (r, stored) in records.iter().zip(RECORDS.iter()) {
+            stored[0].store(r.version, Ordering::SeqCst);
+            stored[1].store(r.state, Ordering::SeqCst);
+            stored[2].store(r.attempts, Ordering::SeqCst);
+        }
+    }
+
+    /// Version of the image in `slot`, if there is one.
+    fn image(slot: SlotId) -> Option<u32> {
+        match VERSIONS[slot as usize].load(Ordering::SeqCst) {
+            0 => None,
+            v => Some(v),
+        }
+    }
+
+    fn running() -> SlotId {
+        if SLOT.load(Ordering::SeqCst) == SlotId::A as u32 {
+            SlotId::A
+        } else {
+            SlotId::B
+        }
+    }
+
+    /// Sets the version of the image in `slot`. A version of 0 means the slot
+    /// is empty.
+    pub fn set_image_version(slot: SlotId, version: u32) {
+        VERSIONS[slot as usize].store(version, Ordering::SeqCst);
+    }
+
+    /// Makes the simulated stage0 report `version` as its ABI version.
+    pub fn set_abi_version(version: u32) {
+        ABI_VERSION.store(version, Ordering::SeqCst);
+    }
+
+    /// Returns the simulation to its initial state, with a version 1 image in
+    /// slot A and slot B empty, and boots it. As on a real first power-on,
+    /// the image is pending until it confirms itself.
+    pub fn power_on() {
+        ABI_VERSION.store(HYPO_ABI_VERSION, Ordering::SeqCst);
+        VERSIONS[0].store(1, Ordering::SeqCst);
+        VERSIONS[1].store(0, Ordering::SeqCst);
+        set_records(&[SlotRecord::default(); SLOT_COUNT]);
+        reset();
+    }
+
+    /// Simulates a reset, with stage0 picking a slot to boot as described in
+    /// [`slots`]. Panics if there is nothing to boot, where stage0 would hang.
+    pub fn reset() {
+        let selection =
+            slots::select_slot([image(SlotId::A), image(SlotId::B)], records());
+        set_records(&selection.records);
+        let slot = selection.slot.expect("no bootable image");
+        SLOT.store(slot as u32, Ordering::SeqCst);
+    }
+
+    /// The measurement reported for an image: its version, repeated. Real
+    /// measurements are a digest of the whole image.
+    fn measurement(version: u32) -> [u8; MEASUREMENT_LEN] {
+        let mut m = [0; MEASUREMENT_LEN];
+        for chunk in m.chunks_mut(4) {
+            chunk.copy_from_slice(&version.to_le_bytes());
+        }
+        m
+    }
+
+    pub fn hypo_write_to_flash(_region: u32, _buf: &[u8]) -> FlashStatus {
+        FlashStatus::Success
+    }
+
+    pub fn hypo_abi_version() -> u32 {
+        ABI_VERSION.load(Ordering::SeqCst)
+    }
+
+    pub fn hypo_get_boot_info() -> Result<BootInfo, HypoError> {
+        require_version(1)?;
+        let slot = running();
+        let records = records();
+        Ok(BootInfo {
+            slot: slot as u32,
+            version: VERSIONS[slot as usize].load(Ordering::SeqCst),
+            confirmed: (records[slot as usize].state()
+                == slots::ImageState::Confirmed) as u32,
+            pending_slot: slots::pending_slot(&records, slot)
+                .map_or(NONE, |s| s as u32),
+        })
+    }
+
+    pub fn hypo_read_measurement(
+        buf: &mut [u8; MEASUREMENT_LEN],
+    ) -> Result<(), HypoError> {
+        require_version(1)?;
+        let slot = running() as usize;
+        *buf = measurement(VERSIONS[slot].load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    pub fn hypo_request_slot_switch(slot: SlotId) -> Result<(), HypoError> {
+        require_version(1)?;
+        let mut records = records();
+        slots::request_switch(&mut records, running(), slot, image(slot))?;
+        set_records(&records);
+        Ok(())
+    }
+
+    pub fn hypo_confirm_image() -> Result<(), HypoError> {
+        require_version(1)?;
+        let mut records = records();
+        slots::confirm(&mut records, running());
+        set_records(&records);
+        Ok(())
+    }
 }
+
 include!(concat!(env!("OUT_DIR"), "/hypo.rs"));
@@ -0,0 +1,260 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Image slot selection.
+//!
+//! stage0 keeps a [`SlotRecord`] for each slot, noting the version of the
+//! image it last saw there, what it knows about that image's health, and how
+//! many times it has tried to boot it unconfirmed. On every boot it checks
+//! which slots hold a valid image and hands both to [`select_slot`], which
+//! decides what to boot:
+//!
+//! - The newest image that is either [`ImageState::Confirmed`], or
+//!   [`ImageState::Pending`] with boot attempts left, wins. At equal versions
+//!   a confirmed image beats a pending one, and slot A beats slot B.
+//! - A pending image that has used up [`MAX_BOOT_ATTEMPTS`] without being
+//!   confirmed is marked [`ImageState::Bad`], and never picked again.
+//! - If neither slot can be picked that way, the newest image stage0 knows
+//!   nothing about is tried as if a switch to it had been requested: it
+//!   becomes pending, and has to be confirmed within its attempts like any
+//!   other. This is how the images present at first power-on get booted, and
+//!   what happens if stage0's records are lost; an image is never treated as
+//!   confirmed until it has said so itself.
+//!
+//! A record whose version doesn't match the image in its slot describes an
+//! image that has since been overwritten, and is forgotten.
+//!
+//! stage0 keeps the records in flash, laid out by [`encode`], so that they
+//! survive power cycles.
+//!
+//! This lives here, rather than in stage0, so that it can be tested on the
+//! host, and so that the `standalone` simulation follows the same rules.
+
+use crate::{HypoError, SlotId};
+use num_derive::FromPrimitive;
+
+/// Number of times a pending image is booted before stage0 gives up on it
+/// being confirmed.
+pub const MAX_BOOT_ATTEMPTS: u32 = 3;
+
+/// Number of image slots.
+pub const SLOT_COUNT: usize = 2;
+
+/// What stage0 knows about the image in a slot.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq, Eq)]
+pub enum ImageState {
+    /// Nothing: the slot is empty, or the image hasn't been booted or
+    /// requested since it was written.
+    Unknown = 0,
+    /// Requested with `request_slot_switch`, and being tried.
+    Pending = 1,
+    /// Confirmed healthy with `confirm_image`.
+    Confirmed = 2,
+    /// Failed to confirm within its boot attempts.
+    Bad = 3,
+}
+
+/// stage0's record of a single slot. Fields are plain `u32`s so that it can
+/// be read back from flash that may hold garbage.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SlotRecord {
+    /// Version of the image this record describes.
+    pub version: u32,
+    /// An `ImageState`.
+    pub state: u32,
+    /// Boots started while `Pending`.
+    pub attempts: u32,
+}
+
+impl SlotRecord {
+    pub fn new(version: u32, state: ImageState) -> Self {
+        Self {
+            version,
+            state: state as u32,
+            attempts: 0,
+        }
+    }
+
+    pub fn state(&self) -> ImageState {
+        num_traits::cast::FromPrimitive::from_u32(self.state)
+            .unwrap_or(ImageState::Unknown)
+    }
+
+    fn set_state(&mut self, state: ImageState) {
+        self.state = state as u32;
+        self.attempts = 0;
+    }
+}
+
+/// Outcome of [`select_slot`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Selection {
+    /// Slot to boot, or `None` if no slot holds a bootable image.
+    pub slot: Option<SlotId>,
+    /// Records to keep for the next boot.
+    pub records: [SlotRecord; SLOT_COUNT],
+}
+
+fn slot_id(index: usize) -> SlotId {
+    if index == 0 {
+        SlotId::A
+    } else {
+        SlotId::B
+    }
+}
+
+/// Picks the slot to boot, given the version of the valid image in each slot
+/// (`None` if the slot is empty or fails validation) and the records kept
+/// from the previous boot. If the picked image is pending, this counts as one
+/// of its boot attempts.
+pub fn select_slot(
+    images: [Option<u32>; SLOT_COUNT],
+    mut records: [SlotRecord; SLOT_COUNT],
+) -> Selection {
+    for (image, record) in images.iter().zip(records.iter_mut()) {
+        match *image {
+            None => *record = SlotRecord::default(),
+            Some(version) if version != record.version => {
+                *record = SlotRecord::new(version, ImageState::Unknown);
+            }
+            Some(_) => (),
+        }
+
+        if record.state() == ImageState::Pending
+            && record.attempts >= MAX_BOOT_ATTEMPTS
+        {
+            record.set_state(ImageState::Bad);
+        }
+    }
+
+    // Newest first, then confirmed over pending, then slot A over slot B.
+    let rank = |i: usize| {
+        let confirmed = records[i].state() == ImageState::Confirmed;
+        (records[i].version, confirmed, core::cmp::Reverse(i))
+    };
+    let best = |eligible: &dyn Fn(ImageState) -> bool| {
+        (0..SLOT_COUNT)
+            .filter(|&i| images[i].is_some() && eligible(records[i].state()))
+            .max_by_key(|&i| rank(i))
+    };
+
+    let bootable =
+        best(&|s| matches!(s, ImageState::Confirmed | ImageState::Pending));
+    let unknown = best(&|s| s == ImageState::Unknown);
+
+    let choice = match (bootable, unknown) {
+        (Some(i), _) => {
+            if records[i].state() == ImageState::Pending {
+                records[i].attempts += 1;
+            }
+            Some(i)
+        }
+        (None, Some(i)) => {
+            records[i].set_state(ImageState::Pending);
+            records[i].attempts = 1;
+            Some(i)
+        }
+        (None, None) => None,
+    };
+
+    Selection {
+        slot: choice.map(slot_id),
+        records,
+    }
+}
+
+/// Applies a request from the image running in `running` to boot `target`
+/// next; `target_version` is the version of the valid image in `target`, if
+/// any.
+///
+/// Since stage0 always boots the newest image it can, the target must be
+/// newer than the running image. Asking for the running slot instead cancels
+/// any pending switch.
+pub fn request_switch(
+    records: &mut [SlotRecord; SLOT_COUNT],
+    running: SlotId,
+    target: SlotId,
+    target_version: Option<u32>,
+) -> Result<(), HypoError> {
+    let version = target_version.ok_or(HypoError::NoSuchSlot)?;
+
+    if target == running {
+        if let Some(pending) = pending_slot(records, running) {
+            records[pending as usize].set_state(ImageState::Unknown);
+        }
+        return Ok(());
+    }
+
+    if version <= records[running as usize].version {
+        return Err(HypoError::InvalidArg);
+    }
+
+    records[target as usize] = SlotRecord::new(version, ImageState::Pending);
+    Ok(())
+}
+
+/// Marks the image running in `running` as healthy.
+pub fn confirm(records: &mut [SlotRecord; SLOT_COUNT], running: SlotId) {
+    records[running as usize].set_state(ImageState::Confirmed);
+}
+
+/// Returns the slot that will be tried on the next boot if it isn't the one
+/// running, i.e. a pending switch.
+pub fn pending_slot(
+    records: &[SlotRecord; SLOT_COUNT],
+    running: SlotId,
+) -> Option<SlotId> {
+    (0..SLOT_COUNT)
+        .filter(|&i| i != running as usize)
+        .find(|&i| records[i].state() == ImageState::Pending)
+        .map(slot_id)
+}
+
+/// Number of words that [`encode`] lays the records out in.
+pub const RECORD_WORDS: usize = 2 + 3 * SLOT_COUNT;
+
+const RECORD_MAGIC: u32 = 0x4859_5032;
+
+/// Checksum over the words before it. This catches a page that was only
+/// partly written, e.g. because power was lost; it isn't meant to resist
+/// anything deliberate.
+fn checksum(words: &[u32]) -> u32 {
+    words
+        .iter()
+        .fold(!0, |sum: u32, &w| sum.rotate_left(7).wrapping_add(w))
+}
+
+/// Lays out `records` for stage0 to keep in flash: a magic number, each
+/// record's fields in order, and a checksum.
+pub fn encode(records: &[SlotRecord; SLOT_COUNT]) -> [u32; RECORD_WORDS] {
+    let mut words = [0; RECORD_WORDS];
+    words[0] = RECORD_MAGIC;
+    for (fields, r) in words[1..RECORD_WORDS - 1].chunks_mut(3).zip(records) {
+        fields.copy_from_slice(&[r.version, r.state, r.attempts]);
+    }
+    words[RECORD_WORDS - 1] = checksum(&words[..RECORD_WORDS - 1]);
+    words
+}
+
+/// Reads back records laid out by [`encode`], or returns `None` if `words`
+/// don't hold any, in which case stage0 has lost its records.
+pub fn decode(words: &[u32; RECORD_WORDS]) -> Option<[SlotRecord; SLOT_COUNT]> {
+    if words[0] != RECORD_MAGIC
+        || words[RECORD_WORDS - 1] != checksum(&words[..RECORD_WORDS - 1])
+    {
+        return None;
+    }
+
+    let mut records = [SlotRecord::default(); SLOT_COUNT];
+    for (r, fields) in records.iter_mut().zip(words[1..].chunks(3)) {
+        *r = SlotRecord {
+            version: fields[0],
+            state: fields[1],
+            attempts: fields[2],
+        };
+    }
+    Some(records)
+}
@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tests for stage0's slot selection policy, which is a pure function of
+//! what's in flash and what stage0 recorded on the previous boot.
+
+use hypocalls::slots::*;
+use hypocalls::{HypoError, SlotId};
+
+fn record(version: u32, state: ImageState, attempts: u32) -> SlotRecord {
+    SlotRecord {
+        attempts,
+        ..SlotRecord::new(version, state)
+    }
+}
+
+const NONE: SlotRecord = SlotRecord {
+    version: 0,
+    state: 0,
+    attempts: 0,
+};
+
+#[test]
+fn first_boot_tries_newest_image() {
+    let s = select_slot([Some(3), Some(5)], [NONE, NONE]);
+    assert_eq!(s.slot, Some(SlotId::B));
+    assert_eq!(s.records[1], record(5, ImageState::Pending, 1));
+    assert_eq!(s.records[0], record(3, ImageState::Unknown, 0));
+
+    // It's kept once it confirms itself.
+    let mut records = s.records;
+    confirm(&mut records, SlotId::B);
+    let s = select_slot([Some(3), Some(5)], records);
+    assert_eq!(s.slot, Some(SlotId::B));
+    assert_eq!(s.records[1], record(5, ImageState::Confirmed, 0));
+
+    let s = select_slot([Some(3), None], [NONE, NONE]);
+    assert_eq!(s.slot, Some(SlotId::A));
+}
+
+#[test]
+fn nothing_to_boot() {
+    assert_eq!(select_slot([None, None], [NONE, NONE]).slot, None);
+
+    let bad = record(1, ImageState::Bad, 0);
+    assert_eq!(select_slot([Some(1), None], [bad, NONE]).slot, None);
+}
+
+#[test]
+fn newest_confirmed_or_pending_wins() {
+    let a = record(1, ImageState::Confirmed, 0);
+    let b = record(2, ImageState::Pending, 0);
+    let s = select_slot([Some(1), Some(2)], [a, b]);
+    assert_eq!(s.slot, Some(SlotId::B));
+    assert_eq!(s.records[1].attempts, 1);
+
+    let a = record(3, ImageState::Confirmed, 0);
+    let b = record(2, ImageState::Confirmed, 0);
+    assert_eq!(
+        select_slot([Some(3), Some(2)], [a, b]).slot,
+        Some(SlotId::A)
+    );
+}
+
+#[test]
+fn ties_prefer_confirmed_then_slot_a() {
+    let confirmed = record(2, ImageState::Confirmed, 0);
+    let pending = record(2, ImageState::Pending, 0);
+    let s = select_slot([Some(2), Some(2)], [pending, confirmed]);
+    assert_eq!(s.slot, Some(SlotId::B));
+
+    let s = select_slot([Some(2), Some(2)], [confirmed, confirmed]);
+    assert_eq!(s.slot, Some(SlotId::A));
+}
+
+#[test]
+fn unknown_image_is_not_booted_over_confirmed() {
+    let a = record(1, ImageState::Confirmed, 0);
+    let b = record(9, ImageState::Unknown, 0);
+    let s = select_slot([Some(1), Some(9)], [a, b]);
+    assert_eq!(s.slot, Some(SlotId::A));
+    assert_eq!(s.records[1], b);
+}
+
+#[test]
+fn pending_image_runs_out_of_attempts() {
+    let mut records = [
+        record(1, ImageState::Confirmed, 0),
+        record(2, ImageState::Pending, 0),
+    ];
+    for attempt in 1..=MAX_BOOT_ATTEMPTS {
+        let s = select_slot([Some(1), Some(2)], records);
+        assert_eq!(s.slot, Some(SlotId::B));
+        assert_eq!(s.records[1].attempts, attempt);
+        records = s.records;
+    }
+
+    let s = select_slot([Some(1), Some(2)], records);
+    assert_eq!(s.slot, Some(SlotId::A));
+    assert_eq!(s.records[1].state(), ImageState::Bad);
+
+    // and stays that way.
+    let s = select_slot([Some(1), Some(2)], s.records);
+    assert_eq!(s.slot, Some(SlotId::A));
+}
+
+#[test]
+fn invalid_image_falls_back() {
+    let a = record(1, ImageState::Confirmed, 0);
+    let b = record(2, ImageState::Confirmed, 0);
+    let s = select_slot([Some(1), None], [a, b]);
+    assert_eq!(s.slot, Some(SlotId::A));
+    assert_eq!(s.records[1], NONE);
+
+    let s = select_slot([None, Some(2)], [a, b]);
+    assert_eq!(s.slot, Some(SlotId::B));
+}
+
+#[test]
+fn overwritten_image_is_forgotten() {
+    let a = record(1, ImageState::Confirmed, 0);
+    let b = record(2, ImageState::Bad, 0);
+    let s = select_slot([Some(1), Some(3)], [a, b]);
+    assert_eq!(s.slot, Some(SlotId::A));
+    assert_eq!(s.records[1], record(3, ImageState::Unknown, 0));
+}
+
+#[test]
+fn garbage_state_is_unknown() {
+    let a = SlotRecord {
+        version: 1,
+        state: 0xdead_beef,
+        attempts: 0,
+    };
+    assert_eq!(a.state(), ImageState::Unknown);
+    let s = select_slot([Some(1), None], [a, NONE]);
+    assert_eq!(s.slot, Some(SlotId::A));
+    assert_eq!(s.records[0], record(1, ImageState::Pending, 1));
+}
+
+#[test]
+fn switching() {
+    let mut records = [record(1, ImageState::Confirmed, 0), NONE];
+
+    assert_eq!(
+        request_switch(&mut records, SlotId::A, SlotId::B, None),
+        Err(HypoError::NoSuchSlot)
+    );
+    assert_eq!(
+        request_switch(&mut records, SlotId::A, SlotId::B, Some(1)),
+        Err(HypoError::InvalidArg)
+    );
+
+    request_switch(&mut records, SlotId::A, SlotId::B, Some(2)).unwrap();
+    assert_eq!(pending_slot(&records, SlotId::A), Some(SlotId::B));
+    request_switch(&mut records, SlotId::A, SlotId::A, Some(1)).unwrap();
+    assert_eq!(pending_slot(&records, SlotId::A), None);
+
+    request_switch(&mut records, SlotId::A, SlotId::B, Some(2)).unwrap();
+    let s = select_slot([Some(1), Some(2)], records);
+    assert_eq!(s.slot, Some(SlotId::B));
+    records = s.records;
+    assert_eq!(pending_slot(&records, SlotId::B), None);
+
+    confirm(&mut records, SlotId::B);
+    assert_eq!(records[1], record(2, ImageState::Confirmed, 0));
+    let s = select_slot([Some(1), Some(2)], records);
+    assert_eq!(s.slot, Some(SlotId::B));
+    assert_eq!(s.records, records);
+}
+
+#[test]
+fn records_lost_with_newer_image_unconfirmed() {
+    // B was pending, and never confirmed, when stage0's records were lost.
+    // Neither image is trusted: B, the newer, gets only the attempts any
+    // pending image would, and then A is tried in its place.
+    let mut records = [NONE, NONE];
+    for attempt in 1..=MAX_BOOT_ATTEMPTS {
+        let s = select_slot([Some(1), Some(2)], records);
+        assert_eq!(s.slot, Some(SlotId::B));
+        assert_eq!(s.records[1], record(2, ImageState::Pending, attempt));
+        records = s.records;
+    }
+
+    let s = select_slot([Some(1), Some(2)], records);
+    assert_eq!(s.slot, Some(SlotId::A));
+    assert_eq!(s.records[0], record(1, ImageState::Pending, 1));
+    assert_eq!(s.records[1].state(), ImageState::Bad);
+
+    let mut records = s.records;
+    confirm(&mut records, SlotId::A);
+    let s = select_slot([Some(1), Some(2)], records);
+    assert_eq!(s.slot, Some(SlotId::A));
+    assert_eq!(s.records[0], record(1, ImageState::Confirmed, 0));
+
+    // Had A never confirmed either, there would be nothing left to boot.
+    let mut records = [NONE, NONE];
+    for _ in 0..2 * MAX_BOOT_ATTEMPTS {
+        records = select_slot([Some(1), Some(2)], records).records;
+    }
+    assert_eq!(select_slot([Some(1), Some(2)], records).slot, None);
+}
+
+#[test]
+fn records_round_trip() {
+    let records = [
+        record(7, ImageState::Confirmed, 0),
+        record(8, ImageState::Pending, 2),
+    ];
+    assert_eq!(decode(&encode(&records)), Some(records));
+    assert_eq!(decode(&encode(&[NONE, NONE])), Some([NONE, NONE]));
+}
+
+#[test]
+fn garbled_records_are_lost() {
+    let records = [
+        record(7, ImageState::Confirmed, 0),
+        record(8, ImageState::Pending, 2),
+    ];
+    let words = encode(&records);
+    for i in 0..RECORD_WORDS {
+        let mut garbled = words;
+        garbled[i] ^= 1 << i;
+        assert_eq!(decode(&garbled), None, "word {} garbled", i);
+    }
+
+    assert_eq!(decode(&[0; RECORD_WORDS]), None);
+    assert_eq!(decode(&[!0; RECORD_WORDS]), None);
+}
@@ -0,0 +1,139 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Exercises the hypocall ABI against the standalone simulation of stage0,
+//! e.g. with `cargo test -p hypocalls --target x86_64-unknown-linux-gnu`.
+
+use hypocalls::standalone::{self, power_on, reset, set_image_version};
+use hypocalls::*;
+
+/// The simulated stage0 is global, and tests run in parallel, so the
+/// scenarios that use it run in turn from a single test. Apart from the
+/// first, each starts from power-on with the image in slot A confirmed, as
+/// it would be once it had come up healthy.
+#[test]
+fn scenarios() {
+    power_on();
+    first_boot_is_pending();
+
+    for scenario in &[
+        boot_info,
+        switch_and_confirm,
+        requesting_current_slot_cancels,
+        unconfirmed_image_rolls_back,
+        old_stage0_is_unsupported,
+    ] {
+        power_on();
+        hypo_confirm_image().unwrap();
+        scenario();
+    }
+}
+
+fn first_boot_is_pending() {
+    let info = hypo_get_boot_info().unwrap();
+    assert_eq!(info.slot(), Some(SlotId::A));
+    assert!(!info.is_confirmed());
+
+    hypo_confirm_image().unwrap();
+    reset();
+    assert!(hypo_get_boot_info().unwrap().is_confirmed());
+}
+
+fn boot_info() {
+    let info = hypo_get_boot_info().unwrap();
+    assert_eq!(info.slot(), Some(SlotId::A));
+    assert_eq!(info.version, 1);
+    assert!(info.is_confirmed());
+    assert_eq!(info.pending_slot(), None);
+
+    let mut m = [0; MEASUREMENT_LEN];
+    hypo_read_measurement(&mut m).unwrap();
+    assert_eq!(&m[..4], &1u32.to_le_bytes());
+}
+
+fn switch_and_confirm() {
+    // Slot B is empty until something is written to it.
+    assert_eq!(
+        hypo_request_slot_switch(SlotId::B),
+        Err(HypoError::NoSuchSlot)
+    );
+    set_image_version(SlotId::B, 2);
+    hypo_request_slot_switch(SlotId::B).unwrap();
+    assert_eq!(
+        hypo_get_boot_info().unwrap().pending_slot(),
+        Some(SlotId::B)
+    );
+
+    reset();
+    let info = hypo_get_boot_info().unwrap();
+    assert_eq!(info.slot(), Some(SlotId::B));
+    assert_eq!(info.version, 2);
+    assert!(!info.is_confirmed());
+    assert_eq!(info.pending_slot(), None);
+
+    hypo_confirm_image().unwrap();
+    reset();
+    let info = hypo_get_boot_info().unwrap();
+    assert_eq!(info.slot(), Some(SlotId::B));
+    assert!(info.is_confirmed());
+}
+
+fn requesting_current_slot_cancels() {
+    set_image_version(SlotId::B, 2);
+    hypo_request_slot_switch(SlotId::B).unwrap();
+    hypo_request_slot_switch(SlotId::A).unwrap();
+    reset();
+    assert_eq!(hypo_get_boot_info().unwrap().slot(), Some(SlotId::A));
+}
+
+fn unconfirmed_image_rolls_back() {
+    set_image_version(SlotId::B, 2);
+    hypo_request_slot_switch(SlotId::B).unwrap();
+    for _ in 0..slots::MAX_BOOT_ATTEMPTS {
+        reset();
+        let info = hypo_get_boot_info().unwrap();
+        assert_eq!(info.slot(), Some(SlotId::B));
+        assert!(!info.is_confirmed());
+    }
+
+    reset();
+    let info = hypo_get_boot_info().unwrap();
+    assert_eq!(info.slot(), Some(SlotId::A));
+    assert!(info.is_confirmed());
+    assert_eq!(info.pending_slot(), None);
+
+    // B can be requested again, but overwriting it with something that isn't
+    // newer than A cancels that.
+    hypo_request_slot_switch(SlotId::B).unwrap();
+    set_image_version(SlotId::B, 1);
+    reset();
+    let info = hypo_get_boot_info().unwrap();
+    assert_eq!(info.slot(), Some(SlotId::A));
+    assert_eq!(info.pending_slot(), None);
+    assert_eq!(
+        hypo_request_slot_switch(SlotId::B),
+        Err(HypoError::InvalidArg)
+    );
+}
+
+fn old_stage0_is_unsupported() {
+    standalone::set_abi_version(0);
+    assert_eq!(hypo_get_boot_info(), Err(HypoError::Unsupported));
+    assert_eq!(hypo_confirm_image(), Err(HypoError::Unsupported));
+    assert_eq!(
+        hypo_read_measurement(&mut [0; MEASUREMENT_LEN]),
+        Err(HypoError::Unsupported)
+    );
+    assert_eq!(
+        hypo_request_slot_switch(SlotId::A),
+        Err(HypoError::Unsupported)
+    );
+}
+
+#[test]
+fn status_codes() {
+    assert_eq!(HypoError::check(HYPO_SUCCESS), Ok(()));
+    assert_eq!(HypoError::check(2), Err(HypoError::NoSuchSlot));
+    assert_eq!(HypoError::check(77), Err(HypoError::Unknown));
+}
@@ -0,0 +1,221 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signature verification for Hubris images.
+//!
+//! Images are signed at build time by `cargo xtask dist`, using the `ecc`
+//! signing method. A signed image is an ARMv8-M image with extra words in
+//! its vector table, the offset of a certificate block among them:
+//!
+//! ```text
+//! 0x00        initial SP, reset vector, ...
+//! 0x20        image length, including the signature
+//! 0x28        offset of the certificate block
+//! ...
+//! cert block  "cert", header version, header length, flags, build number,
+//!             signed length, certificate count, certificate table length
+//!             (8 words), then the certificate table: for each certificate,
+//!             its length as a word followed by its bytes
+//! signed len  signature length as a word, then the signature
+//! ```
+//!
+//! The signature is ECDSA over NIST P-256 and SHA-256, covering everything
+//! before it -- including the certificate block, and so the build number.
+//!
+//! The certificates form a chain, from the root to the key that signed the
+//! image:
+//!
+//! - The first is the root: a SEC1-encoded public key, which must hash to the
+//!   key hash the part was provisioned with.
+//! - Each of the rest is an intermediate: a SEC1-encoded public key followed
+//!   by a signature over that key (in the same forms as the image's) by the
+//!   certificate before it.
+//! - The last certificate's key signs the image.
+//!
+//! The signing tool embeds only the root key, which makes for a chain of one;
+//! the root key then signs the image itself. Chains are at most
+//! [`MAX_CHAIN`] long.
+
+#![no_std]
+
+use core::convert::TryFrom;
+
+use ecdsa::signature::DigestVerifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+/// Length of the key hash an image's key is checked against.
+pub const KEY_HASH_LEN: usize = 32;
+
+/// Offset in the vector table of the certificate block's offset.
+const HEADER_OFFSET_OFFSET: usize = 0x28;
+
+/// The certificate block's signature: "cert".
+const CERT_MAGIC: [u8; 4] = *b"cert";
+
+/// Size of the fixed part of the certificate block.
+const CERT_HEADER_LEN: usize = 8 * 4;
+
+/// Most certificates we'll walk, root included.
+pub const MAX_CHAIN: u32 = 4;
+
+/// Ways an image can fail verification.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// A structure in the image runs past its end.
+    OutOfBounds,
+    /// The certificate block doesn't start with `cert`.
+    BadCertHeader,
+    /// The image has no certificates, or more than we can check.
+    UnsupportedChain,
+    /// The root key doesn't match the provisioned key hash.
+    UntrustedKey,
+    /// An intermediate isn't signed by the certificate before it.
+    BadCertificate,
+    /// The key or signature can't be decoded.
+    Malformed,
+    /// The signature doesn't verify.
+    BadSignature,
+}
+
+/// What we learned from an image that verified.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Verified {
+    /// Build number from the certificate block.
+    pub build_number: u32,
+    /// SHA-256 of the signed part of the image.
+    pub digest: [u8; 32],
+}
+
+fn word(image: &[u8], offset: usize) -> Result<u32, VerifyError> {
+    let end = offset.checked_add(4).ok_or(VerifyError::OutOfBounds)?;
+    let bytes = image.get(offset..end).ok_or(VerifyError::OutOfBounds)?;
+    let mut word = [0; 4];
+    word.copy_from_slice(bytes);
+    Ok(u32::from_le_bytes(word))
+}
+
+/// Returns the `len` bytes at `offset`, along with the offset after them.
+fn bytes(
+    image: &[u8],
+    offset: usize,
+    len: u32,
+) -> Result<(&[u8], usize), VerifyError> {
+    let end = offset
+        .checked_add(len as usize)
+        .ok_or(VerifyError::OutOfBounds)?;
+    let b = image.get(offset..end).ok_or(VerifyError::OutOfBounds)?;
+    Ok((b, end))
+}
+
+/// Splits an intermediate into its key, whose length its SEC1 tag gives, and
+/// the signature after it.
+fn split_key(cert: &[u8]) -> Result<(&[u8], &[u8]), VerifyError> {
+    let len = match cert.first() {
+        Some(0x04) => 65,
+        Some(0x02) | Some(0x03) => 33,
+        _ => return Err(VerifyError::Malformed),
+    };
+    if cert.len() < len {
+        return Err(VerifyError::Malformed);
+    }
+    Ok(cert.split_at(len))
+}
+
+/// Decodes a signature, which may be ASN.1 DER, or the raw 64-byte form.
+fn signature(sig: &[u8]) -> Result<Signature, VerifyError> {
+    Signature::from_der(sig)
+        .or_else(|_| Signature::try_from(sig))
+        .map_err(|_| VerifyError::Malformed)
+}
+
+/// Walks the `count` certificates in `certs` from the root, which must hash
+/// to `trusted`, and returns the key at the end of the chain.
+fn walk_chain(
+    certs: &[u8],
+    count: u32,
+    trusted: &[u8; KEY_HASH_LEN],
+) -> Result<VerifyingKey, VerifyError> {
+    if count == 0 || count > MAX_CHAIN {
+        return Err(VerifyError::UnsupportedChain);
+    }
+
+    let mut offset = 0;
+    let mut issuer: Option<VerifyingKey> = None;
+    for _ in 0..count {
+        let len = word(certs, offset)?;
+        let (cert, next) = bytes(certs, offset + 4, len)?;
+        offset = next;
+
+        let key = match issuer {
+            None => {
+                if key_hash(cert) != *trusted {
+                    return Err(VerifyError::UntrustedKey);
+                }
+                cert
+            }
+            Some(issuer) => {
+                let (key, sig) = split_key(cert)?;
+                issuer
+                    .verify_digest(Sha256::new().chain(key), &signature(sig)?)
+                    .map_err(|_| VerifyError::BadCertificate)?;
+                key
+            }
+        };
+        issuer = Some(
+            VerifyingKey::from_sec1_bytes(key)
+                .map_err(|_| VerifyError::Malformed)?,
+        );
+    }
+
+    // We went round at least once.
+    issuer.ok_or(VerifyError::UnsupportedChain)
+}
+
+/// Checks the signature on `image`, which holds the whole image as described
+/// in its vector table, against a chain of certificates rooted in a key
+/// hashing to `trusted`.
+pub fn verify_image(
+    image: &[u8],
+    trusted: &[u8; KEY_HASH_LEN],
+) -> Result<Verified, VerifyError> {
+    let cert = word(image, HEADER_OFFSET_OFFSET)? as usize;
+    let (header, table) = bytes(image, cert, CERT_HEADER_LEN as u32)?;
+    if header[..4] != CERT_MAGIC {
+        return Err(VerifyError::BadCertHeader);
+    }
+
+    let build_number = word(header, 0x10)?;
+    let signed_len = word(header, 0x14)?;
+    let certificate_count = word(header, 0x18)?;
+    let table_len = word(header, 0x1c)?;
+
+    let (signed, sig_offset) = bytes(image, 0, signed_len)?;
+    let (certs, certs_end) = bytes(image, table, table_len)?;
+    if certs_end > sig_offset {
+        // Everything we rely on has to be covered by the signature.
+        return Err(VerifyError::OutOfBounds);
+    }
+
+    let key = walk_chain(certs, certificate_count, trusted)?;
+
+    let sig_len = word(image, sig_offset)?;
+    let (sig, _) = bytes(image, sig_offset + 4, sig_len)?;
+    let sig = signature(sig)?;
+
+    let digest = Sha256::new().chain(signed);
+    key.verify_digest(digest.clone(), &sig)
+        .map_err(|_| VerifyError::BadSignature)?;
+
+    Ok(Verified {
+        build_number,
+        digest: digest.finalize().into(),
+    })
+}
+
+/// Hashes an encoded root key the way `verify_image` does, giving the value
+/// to provision for it.
+pub fn key_hash(key: &[u8]) -> [u8; KEY_HASH_LEN] {
+    Sha256::digest(key).into()
+}
@@ -0,0 +1,312 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks images laid out the way the `ecc` signing method lays them out,
+//! signed with the fake keys in `support/fake_certs`.
+
+use image_verify::{key_hash, verify_image, VerifyError, MAX_CHAIN};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+const P256_KEY: &[u8] =
+    include_bytes!("../../../support/fake_certs/p256-private-key.der");
+const RSA_CERT: &[u8] =
+    include_bytes!("../../../support/fake_certs/fake_certificate.der.crt");
+
+/// Pulls the private scalar out of the PKCS#8 key: it's the octet string
+/// following the ECPrivateKey version.
+fn signing_key() -> SigningKey {
+    let marker = [0x02, 0x01, 0x01, 0x04, 0x20];
+    let at = P256_KEY
+        .windows(marker.len())
+        .position(|w| w == marker)
+        .unwrap()
+        + marker.len();
+    SigningKey::from_bytes(&P256_KEY[at..at + 32]).unwrap()
+}
+
+fn public_key(key: &SigningKey) -> Vec<u8> {
+    VerifyingKey::from(key)
+        .to_encoded_point(false)
+        .as_bytes()
+        .to_vec()
+}
+
+fn put(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// A fake image: a vector table and some code.
+fn unsigned_image() -> Vec<u8> {
+    let mut image: Vec<u8> = (0..0x400u32).map(|i| (i * 7) as u8).collect();
+    put(&mut image, 0, 0x2000_8000);
+    put(&mut image, 4, 0x0000_8101);
+    image
+}
+
+/// Appends a certificate block holding `certs` to `image`, and signs it with
+/// `key`, in raw or DER form.
+fn sign(
+    mut image: Vec<u8>,
+    build: u32,
+    certs: &[&[u8]],
+    key: &SigningKey,
+    der: bool,
+) -> Vec<u8> {
+    let cert_offset = image.len();
+    let mut table = vec![];
+    for c in certs {
+        table.extend_from_slice(&(c.len() as u32).to_le_bytes());
+        table.extend_from_slice(c);
+    }
+    while table.len() % 4 != 0 {
+        table.push(0);
+    }
+    let signed_len = cert_offset + 32 + table.len();
+
+    for word in &[
+        u32::from_le_bytes(*b"cert"),
+        1,
+        32,
+        0,
+        build,
+        signed_len as u32,
+        certs.len() as u32,
+        table.len() as u32,
+    ] {
+        image.extend_from_slice(&word.to_le_bytes());
+    }
+    image.extend_from_slice(&table);
+    put(&mut image, 0x28, cert_offset as u32);
+
+    // The image length is signed, but covers the signature, whose DER form
+    // varies in length; leave room for the longest.
+    let image_len = signed_len + 4 + MAX_DER_SIG;
+    put(&mut image, 0x20, image_len as u32);
+
+    let sig: Signature = key.sign(&image);
+    let sig = if der {
+        sig.to_der().as_bytes().to_vec()
+    } else {
+        sig.as_ref().to_vec()
+    };
+    image.extend_from_slice(&(sig.len() as u32).to_le_bytes());
+    image.extend_from_slice(&sig);
+    image.resize(image_len, 0);
+    image
+}
+
+const MAX_DER_SIG: usize = 72;
+
+fn word(image: &[u8], offset: usize) -> usize {
+    let mut b = [0; 4];
+    b.copy_from_slice(&image[offset..offset + 4]);
+    u32::from_le_bytes(b) as usize
+}
+
+/// Returns the offset just past the signature.
+fn sig_end(image: &[u8]) -> usize {
+    let signed_len = word(image, word(image, 0x28) + 0x14);
+    signed_len + 4 + word(image, signed_len)
+}
+
+fn signed_image() -> (Vec<u8>, [u8; 32]) {
+    let key = signing_key();
+    let public = public_key(&key);
+    let image = sign(unsigned_image(), 7, &[&public], &key, true);
+    (image, key_hash(&public))
+}
+
+#[test]
+fn good_image() {
+    let (image, hash) = signed_image();
+    let verified = verify_image(&image, &hash).unwrap();
+    assert_eq!(verified.build_number, 7);
+
+    let signed_len = word(&image, 0x414);
+    assert_eq!(
+        verified.digest[..],
+        Sha256::digest(&image[..signed_len])[..]
+    );
+}
+
+#[test]
+fn raw_signature() {
+    let key = signing_key();
+    let public = public_key(&key);
+    let image = sign(unsigned_image(), 1, &[&public], &key, false);
+    assert!(verify_image(&image, &key_hash(&public)).is_ok());
+}
+
+#[test]
+fn untrusted_key() {
+    let (image, mut hash) = signed_image();
+    hash[0] ^= 1;
+    assert_eq!(verify_image(&image, &hash), Err(VerifyError::UntrustedKey));
+
+    // An unprovisioned part reads back a key hash of zeroes, and trusts
+    // nothing.
+    assert_eq!(
+        verify_image(&image, &[0; 32]),
+        Err(VerifyError::UntrustedKey)
+    );
+}
+
+#[test]
+fn tampering() {
+    let (image, hash) = signed_image();
+
+    // Code, build number, and embedded key are all covered.
+    for &offset in &[0x4, 0x123, 0x410, 0x424] {
+        let mut bad = image.clone();
+        bad[offset] ^= 0x80;
+        assert!(
+            verify_image(&bad, &hash).is_err(),
+            "tampering at {:#x} went unnoticed",
+            offset
+        );
+    }
+
+    let mut bad = image;
+    let last = sig_end(&bad) - 1;
+    bad[last] ^= 1;
+    assert!(verify_image(&bad, &hash).is_err());
+}
+
+#[test]
+fn truncated() {
+    let (image, hash) = signed_image();
+    for len in &[0, 0x2c, 0x410, 0x420, sig_end(&image) - 1] {
+        assert_eq!(
+            verify_image(&image[..*len], &hash),
+            Err(VerifyError::OutOfBounds),
+            "truncated to {:#x}",
+            len
+        );
+    }
+}
+
+#[test]
+fn bad_header() {
+    let (mut image, hash) = signed_image();
+    image[0x400] = b'C';
+    assert_eq!(verify_image(&image, &hash), Err(VerifyError::BadCertHeader));
+
+    let (mut image, hash) = signed_image();
+    put(&mut image, 0x28, 0xffff_fff0);
+    assert_eq!(verify_image(&image, &hash), Err(VerifyError::OutOfBounds));
+}
+
+/// A key other than the root, for intermediates.
+fn other_key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32]).unwrap()
+}
+
+/// An intermediate for `key`, signed by `issuer`.
+fn endorse(issuer: &SigningKey, key: &SigningKey, der: bool) -> Vec<u8> {
+    let mut cert = public_key(key);
+    let sig: Signature = issuer.sign(&cert);
+    if der {
+        cert.extend_from_slice(sig.to_der().as_bytes());
+    } else {
+        cert.extend_from_slice(sig.as_ref());
+    }
+    cert
+}
+
+#[test]
+fn chain_lengths() {
+    let key = signing_key();
+    let public = public_key(&key);
+    let hash = key_hash(&public);
+
+    let image = sign(unsigned_image(), 1, &[], &key, true);
+    assert_eq!(
+        verify_image(&image, &hash),
+        Err(VerifyError::UnsupportedChain)
+    );
+
+    // The root endorsing itself makes a chain that is long, but valid, up to
+    // the limit.
+    let again = endorse(&key, &key, true);
+    let mut chain: Vec<&[u8]> = vec![&public];
+    while chain.len() < MAX_CHAIN as usize {
+        chain.push(&again);
+        let image = sign(unsigned_image(), 1, &chain, &key, true);
+        assert!(verify_image(&image, &hash).is_ok(), "{} long", chain.len());
+    }
+    chain.push(&again);
+    let image = sign(unsigned_image(), 1, &chain, &key, true);
+    assert_eq!(
+        verify_image(&image, &hash),
+        Err(VerifyError::UnsupportedChain)
+    );
+}
+
+#[test]
+fn intermediates() {
+    let root = signing_key();
+    let public = public_key(&root);
+    let hash = key_hash(&public);
+    let a = other_key(0x11);
+    let b = other_key(0x22);
+
+    let root_a = endorse(&root, &a, true);
+    let image = sign(unsigned_image(), 3, &[&public, &root_a], &a, false);
+    assert_eq!(verify_image(&image, &hash).unwrap().build_number, 3);
+
+    let a_b = endorse(&a, &b, false);
+    let image = sign(unsigned_image(), 3, &[&public, &root_a, &a_b], &b, true);
+    assert!(verify_image(&image, &hash).is_ok());
+
+    // Only the last key may sign the image.
+    let image = sign(unsigned_image(), 3, &[&public, &root_a], &root, true);
+    assert_eq!(verify_image(&image, &hash), Err(VerifyError::BadSignature));
+}
+
+#[test]
+fn bad_intermediates() {
+    let root = signing_key();
+    let public = public_key(&root);
+    let hash = key_hash(&public);
+    let a = other_key(0x11);
+    let b = other_key(0x22);
+
+    // Endorsed by someone other than the certificate before it.
+    let b_a = endorse(&b, &a, true);
+    let image = sign(unsigned_image(), 1, &[&public, &b_a], &a, true);
+    assert_eq!(
+        verify_image(&image, &hash),
+        Err(VerifyError::BadCertificate)
+    );
+
+    // Endorsed properly, but then altered.
+    let mut root_a = endorse(&root, &a, true);
+    root_a[10] ^= 1;
+    let image = sign(unsigned_image(), 1, &[&public, &root_a], &a, true);
+    assert_eq!(
+        verify_image(&image, &hash),
+        Err(VerifyError::BadCertificate)
+    );
+
+    // Not a key at all, or a key without a signature.
+    for cert in &[&b"nonsense"[..], &public_key(&a)[..], &public_key(&a)[..20]]
+    {
+        let image = sign(unsigned_image(), 1, &[&public, cert], &a, true);
+        assert_eq!(verify_image(&image, &hash), Err(VerifyError::Malformed));
+    }
+}
+
+#[test]
+fn rsa_certificate_is_not_a_key() {
+    // The certificate used to sign stage0 for the ROM is no good here, even
+    // if its hash is trusted.
+    let key = signing_key();
+    let image = sign(unsigned_image(), 1, &[RSA_CERT], &key, true);
+    assert_eq!(
+        verify_image(&image, &key_hash(RSA_CERT)),
+        Err(VerifyError::Malformed)
+    );
+}
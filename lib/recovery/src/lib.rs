@@ -0,0 +1,529 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Serial recovery protocol.
+//!
+//! When stage0 has no bootable image, or is asked to by a strap pin, it
+//! enters recovery mode and waits for a new image over a serial port. Both
+//! ends speak in frames:
+//!
+//! ```text
+//! 0xa5  kind  seq  len (u16 LE)  payload (len bytes)  CRC (u16 LE)
+//! ```
+//!
+//! The CRC is CRC-16/CCITT-FALSE over everything between the sync byte and
+//! the CRC itself. A receiver drops any frame that fails it, and looks for
+//! the next sync byte.
+//!
+//! The host sends one frame at a time and waits for the device to answer it
+//! with an `Ack` or a `Nak` carrying the same sequence number; if no answer
+//! comes, it sends the frame again. The device remembers its last answer, so
+//! that a repeated frame -- because that answer was lost -- is answered again
+//! rather than acted on twice. A transfer is:
+//!
+//! - `Start`, with the image length as a `u32`;
+//! - `Data` for each [`PAGE_SIZE`] page of the image in order, with the
+//!   page's offset as a `u32` followed by its bytes. Only the last page may
+//!   be short, and it is padded with `0xff` before being written;
+//! - `Finish`, which the device acknowledges only once the image it has
+//!   written validates.
+//!
+//! [`Receiver`] is the device end, and [`Sender`] the host end. Both run
+//! over a [`Transport`], and the receiver writes to a [`Flash`], so that the
+//! whole exchange can be tested on the host.
+
+#![no_std]
+
+/// Size of the pages the image is sent and written in.
+pub const PAGE_SIZE: usize = 512;
+
+/// Largest payload a frame can carry: a page and its offset.
+pub const MAX_PAYLOAD: usize = 4 + PAGE_SIZE;
+
+/// Marks the start of a frame.
+pub const SYNC: u8 = 0xa5;
+
+/// Bytes in a frame besides its payload: sync, kind, sequence number,
+/// length, and CRC.
+const OVERHEAD: usize = 7;
+
+/// Times the sender tries a frame before giving up.
+pub const MAX_RETRIES: usize = 8;
+
+/// Kinds of frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Start = 1,
+    Data = 2,
+    Finish = 3,
+    Ack = 0x80,
+    Nak = 0x81,
+}
+
+impl Kind {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Kind::Start),
+            2 => Some(Kind::Data),
+            3 => Some(Kind::Finish),
+            0x80 => Some(Kind::Ack),
+            0x81 => Some(Kind::Nak),
+            _ => None,
+        }
+    }
+}
+
+/// Why the device refused a frame, sent as the payload of a `Nak`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NakReason {
+    /// The frame's kind or payload makes no sense, e.g. a `Data` frame with
+    /// no data.
+    BadFrame = 1,
+    /// `Data` or `Finish` arrived before `Start`.
+    NotStarted = 2,
+    /// The image doesn't fit.
+    TooLarge = 3,
+    /// A `Data` frame isn't the next page, is short before the end, or comes
+    /// after the end.
+    OutOfOrder = 4,
+    /// Writing to flash failed.
+    FlashFailed = 5,
+    /// The image was written, but doesn't validate.
+    InvalidImage = 6,
+    /// Some reason the sender doesn't know about.
+    Unknown = 0xff,
+}
+
+impl NakReason {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => NakReason::BadFrame,
+            2 => NakReason::NotStarted,
+            3 => NakReason::TooLarge,
+            4 => NakReason::OutOfOrder,
+            5 => NakReason::FlashFailed,
+            6 => NakReason::InvalidImage,
+            _ => NakReason::Unknown,
+        }
+    }
+}
+
+/// A byte stream between the host and the device.
+pub trait Transport {
+    /// Sends `bytes`.
+    fn write(&mut self, bytes: &[u8]);
+
+    /// Receives a byte, or returns `None` if none arrived in time. On the
+    /// device, this may just wait.
+    fn read(&mut self) -> Option<u8>;
+}
+
+/// Failure to write to flash.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FlashError;
+
+/// Where the device puts the image it receives.
+pub trait Flash {
+    /// Largest image that fits.
+    fn capacity(&self) -> u32;
+
+    /// Erases and programs the page at `offset` into the image.
+    fn write_page(
+        &mut self,
+        offset: u32,
+        page: &[u8; PAGE_SIZE],
+    ) -> Result<(), FlashError>;
+
+    /// Checks the `len`-byte image that has been written.
+    fn validate(&mut self, len: u32) -> bool;
+}
+
+/// Updates a CRC-16/CCITT-FALSE with `data`. Start from `0xffff`.
+pub fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Sends a frame.
+pub fn send_frame<T: Transport>(
+    t: &mut T,
+    kind: Kind,
+    seq: u8,
+    payload: &[u8],
+) {
+    assert!(payload.len() <= MAX_PAYLOAD);
+
+    let len = payload.len() as u16;
+    let header = [kind as u8, seq, len as u8, (len >> 8) as u8];
+    let crc = crc16(crc16(0xffff, &header), payload);
+
+    t.write(&[SYNC]);
+    t.write(&header);
+    t.write(payload);
+    t.write(&crc.to_le_bytes());
+}
+
+/// A frame, as received.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub kind: Kind,
+    pub seq: u8,
+    pub payload: &'a [u8],
+}
+
+/// Picks frames out of a byte stream.
+pub struct Decoder {
+    buf: [u8; OVERHEAD + MAX_PAYLOAD],
+    len: usize,
+    /// Length of the frame last returned, which is still at the start of
+    /// `buf`.
+    consumed: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; OVERHEAD + MAX_PAYLOAD],
+            len: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Forgets any partial frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.consumed = 0;
+    }
+
+    /// Drops the first `n` bytes of `buf`.
+    fn discard(&mut self, n: usize) {
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+    }
+
+    /// Takes the next byte of the stream, returning a frame if it completes
+    /// one. Bytes outside of a frame, and frames that are malformed or fail
+    /// their CRC, are dropped.
+    ///
+    /// A frame that turns out to be bad may have swallowed the start of a
+    /// good one -- e.g. if its length was corrupted -- so rather than
+    /// dropping everything, we look for the next sync byte after its own.
+    pub fn push(&mut self, byte: u8) -> Option<Frame<'_>> {
+        if self.consumed != 0 {
+            self.discard(self.consumed);
+            self.consumed = 0;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        loop {
+            match self.buf[..self.len].iter().position(|&b| b == SYNC) {
+                Some(start) => self.discard(start),
+                None => {
+                    self.len = 0;
+                    return None;
+                }
+            }
+
+            // Header: sync, kind, seq, and two bytes of length.
+            if self.len < 5 {
+                return None;
+            }
+            let payload_len =
+                u16::from_le_bytes([self.buf[3], self.buf[4]]) as usize;
+            if payload_len > MAX_PAYLOAD {
+                self.discard(1);
+                continue;
+            }
+            let total = OVERHEAD + payload_len;
+            if self.len < total {
+                return None;
+            }
+
+            let body = &self.buf[1..5 + payload_len];
+            let crc =
+                u16::from_le_bytes([self.buf[total - 2], self.buf[total - 1]]);
+            let kind = Kind::from_u8(self.buf[1]);
+            match kind {
+                Some(kind) if crc16(0xffff, body) == crc => {
+                    self.consumed = total;
+                    return Some(Frame {
+                        kind,
+                        seq: self.buf[2],
+                        payload: &self.buf[5..total - 2],
+                    });
+                }
+                _ => self.discard(1),
+            }
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> Option<u32> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Where the device is in a transfer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    Receiving { len: u32, written: u32 },
+    Done,
+}
+
+/// The device end of a transfer.
+pub struct Receiver<F: Flash> {
+    flash: F,
+    decoder: Decoder,
+    state: State,
+    /// Sequence number and answer (`None` for an `Ack`) of the last frame
+    /// acted on.
+    last: Option<(u8, Option<NakReason>)>,
+}
+
+impl<F: Flash> Receiver<F> {
+    pub fn new(flash: F) -> Self {
+        Self {
+            flash,
+            decoder: Decoder::new(),
+            state: State::Idle,
+            last: None,
+        }
+    }
+
+    /// Gives back the flash, e.g. to inspect it after a test.
+    pub fn into_flash(self) -> F {
+        self.flash
+    }
+
+    /// Returns true once a complete image has been written and validated,
+    /// and the host told so.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Handles a byte from the host, answering on `t` if it completes a
+    /// frame. Returns true once the transfer is done.
+    pub fn receive<T: Transport>(&mut self, byte: u8, t: &mut T) -> bool {
+        if let Some(frame) = self.decoder.push(byte) {
+            let seq = frame.seq;
+            let answer = match self.last {
+                // A repeat of the frame we last acted on, because our answer
+                // was lost: answer it again. `Start` is always acted on,
+                // since doing so twice is harmless, and it may come from a
+                // host that has started over with the same sequence number.
+                Some((last_seq, answer))
+                    if last_seq == seq && frame.kind != Kind::Start =>
+                {
+                    answer
+                }
+                _ => {
+                    let answer = handle(
+                        &mut self.flash,
+                        &mut self.state,
+                        frame.kind,
+                        frame.payload,
+                    )
+                    .err();
+                    self.last = Some((seq, answer));
+                    answer
+                }
+            };
+
+            match answer {
+                None => send_frame(t, Kind::Ack, seq, &[]),
+                Some(reason) => send_frame(t, Kind::Nak, seq, &[reason as u8]),
+            }
+        }
+        self.is_done()
+    }
+
+    /// Receives until the transfer is done.
+    pub fn run<T: Transport>(&mut self, t: &mut T) {
+        while !self.is_done() {
+            if let Some(byte) = t.read() {
+                self.receive(byte, t);
+            }
+        }
+    }
+}
+
+fn handle<F: Flash>(
+    flash: &mut F,
+    state: &mut State,
+    kind: Kind,
+    payload: &[u8],
+) -> Result<(), NakReason> {
+    match kind {
+        Kind::Start => {
+            let len = read_u32(payload).ok_or(NakReason::BadFrame)?;
+            if len == 0 {
+                return Err(NakReason::BadFrame);
+            }
+            if len > flash.capacity() {
+                return Err(NakReason::TooLarge);
+            }
+            // A new start abandons any transfer in progress.
+            *state = State::Receiving { len, written: 0 };
+            Ok(())
+        }
+        Kind::Data => {
+            let (len, written) = match *state {
+                State::Receiving { len, written } => (len, written),
+                _ => return Err(NakReason::NotStarted),
+            };
+            let offset = read_u32(payload).ok_or(NakReason::BadFrame)?;
+            let data = &payload[4..];
+            if data.is_empty() {
+                return Err(NakReason::BadFrame);
+            }
+
+            // Once the whole image is written, there's no next page: a page
+            // at `len` would land past the end of the image, and perhaps of
+            // the flash.
+            let remaining = (len - written) as usize;
+            let expected = remaining.min(PAGE_SIZE);
+            if written == len || offset != written || data.len() != expected {
+                return Err(NakReason::OutOfOrder);
+            }
+
+            let mut page = [0xff; PAGE_SIZE];
+            page[..data.len()].copy_from_slice(data);
+            flash
+                .write_page(offset, &page)
+                .map_err(|_| NakReason::FlashFailed)?;
+
+            *state = State::Receiving {
+                len,
+                written: written + data.len() as u32,
+            };
+            Ok(())
+        }
+        Kind::Finish => {
+            let len = match *state {
+                State::Receiving { len, written } if written == len => len,
+                State::Receiving { .. } => return Err(NakReason::OutOfOrder),
+                _ => return Err(NakReason::NotStarted),
+            };
+            if !flash.validate(len) {
+                // Whatever was written is no good; the host has to start
+                // over.
+                *state = State::Idle;
+                return Err(NakReason::InvalidImage);
+            }
+            *state = State::Done;
+            Ok(())
+        }
+        Kind::Ack | Kind::Nak => Err(NakReason::BadFrame),
+    }
+}
+
+/// Ways sending an image can fail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// The image is empty, or too big to describe.
+    BadImage,
+    /// The device didn't answer a frame after `MAX_RETRIES` tries.
+    NoResponse,
+    /// The device refused a frame.
+    Refused(NakReason),
+}
+
+/// The host end of a transfer.
+pub struct Sender<T: Transport> {
+    transport: T,
+    decoder: Decoder,
+    seq: u8,
+}
+
+impl<T: Transport> Sender<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            decoder: Decoder::new(),
+            seq: 0,
+        }
+    }
+
+    /// Gives back the transport.
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// Sends `image`, returning once the device has written and validated
+    /// it.
+    pub fn send_image(&mut self, image: &[u8]) -> Result<(), SendError> {
+        if image.is_empty() || image.len() > u32::MAX as usize {
+            return Err(SendError::BadImage);
+        }
+
+        self.exchange(Kind::Start, &(image.len() as u32).to_le_bytes())?;
+
+        let mut payload = [0; MAX_PAYLOAD];
+        for (i, chunk) in image.chunks(PAGE_SIZE).enumerate() {
+            let offset = (i * PAGE_SIZE) as u32;
+            payload[..4].copy_from_slice(&offset.to_le_bytes());
+            payload[4..4 + chunk.len()].copy_from_slice(chunk);
+            self.exchange(Kind::Data, &payload[..4 + chunk.len()])?;
+        }
+
+        self.exchange(Kind::Finish, &[])
+    }
+
+    /// Sends a frame until the device answers it.
+    fn exchange(
+        &mut self,
+        kind: Kind,
+        payload: &[u8],
+    ) -> Result<(), SendError> {
+        self.seq = self.seq.wrapping_add(1);
+
+        for _ in 0..MAX_RETRIES {
+            send_frame(&mut self.transport, kind, self.seq, payload);
+            self.decoder.reset();
+
+            // Read until the device goes quiet, skipping any stale answers
+            // to earlier tries of earlier frames.
+            while let Some(byte) = self.transport.read() {
+                let frame = match self.decoder.push(byte) {
+                    Some(frame) if frame.seq == self.seq => frame,
+                    _ => continue,
+                };
+                match frame.kind {
+                    Kind::Ack => return Ok(()),
+                    Kind::Nak => {
+                        let reason = frame
+                            .payload
+                            .first()
+                            .map_or(NakReason::Unknown, |&r| {
+                                NakReason::from_u8(r)
+                            });
+                        return Err(SendError::Refused(reason));
+                    }
+                    _ => continue,
+                }
+            }
+        }
+
+        Err(SendError::NoResponse)
+    }
+}
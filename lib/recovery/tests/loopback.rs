@@ -0,0 +1,355 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs the sender against the receiver over a loopback transport, which
+//! can corrupt or lose bytes on the way.
+
+use recovery::*;
+use std::collections::VecDeque;
+
+/// Flash that's just memory. Images validate if they start with `MAGIC`.
+struct MemFlash {
+    data: Vec<u8>,
+    writes: usize,
+}
+
+const MAGIC: &[u8] = b"HUBRIS";
+
+impl MemFlash {
+    fn new(capacity: usize) -> Self {
+        Self {
+            data: vec![0; capacity],
+            writes: 0,
+        }
+    }
+}
+
+impl Flash for MemFlash {
+    fn capacity(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    fn write_page(
+        &mut self,
+        offset: u32,
+        page: &[u8; PAGE_SIZE],
+    ) -> Result<(), FlashError> {
+        let offset = offset as usize;
+        let end = (offset + PAGE_SIZE).min(self.data.len());
+        self.data[offset..end].copy_from_slice(&page[..end - offset]);
+        self.writes += 1;
+        Ok(())
+    }
+
+    fn validate(&mut self, len: u32) -> bool {
+        self.data[..len as usize].starts_with(MAGIC)
+    }
+}
+
+/// Mangles every `nth` byte that passes through, if `nth` isn't 0.
+#[derive(Default)]
+struct Fault {
+    nth: usize,
+    count: usize,
+    drop: bool,
+}
+
+impl Fault {
+    fn every(nth: usize, drop: bool) -> Self {
+        Self {
+            nth,
+            count: 0,
+            drop,
+        }
+    }
+
+    fn apply(&mut self, byte: u8) -> Option<u8> {
+        self.count += 1;
+        if self.nth == 0 || self.count % self.nth != 0 {
+            Some(byte)
+        } else if self.drop {
+            None
+        } else {
+            Some(byte ^ 0x10)
+        }
+    }
+}
+
+/// Answers from the device, waiting for the host to read them.
+#[derive(Default)]
+struct Replies(VecDeque<u8>);
+
+impl Transport for Replies {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend(bytes);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.0.pop_front()
+    }
+}
+
+/// The host's view of the line: what it writes goes straight to the
+/// receiver, and the receiver's answers queue up for it to read. With
+/// nothing queued, a read times out.
+struct Loopback {
+    receiver: Receiver<MemFlash>,
+    replies: Replies,
+    to_device: Fault,
+    to_host: Fault,
+}
+
+impl Loopback {
+    fn new(flash: MemFlash) -> Self {
+        Self {
+            receiver: Receiver::new(flash),
+            replies: Replies::default(),
+            to_device: Fault::default(),
+            to_host: Fault::default(),
+        }
+    }
+}
+
+impl Transport for Loopback {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if let Some(b) = self.to_device.apply(b) {
+                self.receiver.receive(b, &mut self.replies);
+            }
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        loop {
+            let b = self.replies.read()?;
+            if let Some(b) = self.to_host.apply(b) {
+                return Some(b);
+            }
+        }
+    }
+}
+
+fn image(len: usize) -> Vec<u8> {
+    let mut image: Vec<u8> = (0..len).map(|i| (i * 7 + 3) as u8).collect();
+    image[..MAGIC.len()].copy_from_slice(MAGIC);
+    image
+}
+
+fn send(line: Loopback, image: &[u8]) -> (Result<(), SendError>, Loopback) {
+    let mut sender = Sender::new(line);
+    let result = sender.send_image(image);
+    (result, sender.into_transport())
+}
+
+fn check_written(line: Loopback, image: &[u8]) {
+    assert!(line.receiver.is_done());
+    let pages = (image.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+    let flash = line.receiver.into_flash();
+    assert_eq!(flash.writes, pages);
+    assert_eq!(&flash.data[..image.len()], image);
+    assert!(flash.data[image.len()..pages * PAGE_SIZE]
+        .iter()
+        .all(|&b| b == 0xff));
+}
+
+#[test]
+fn crc_check_value() {
+    assert_eq!(crc16(0xffff, b"123456789"), 0x29b1);
+}
+
+#[test]
+fn decoder_skips_garbage() {
+    let mut frame = Replies::default();
+    send_frame(&mut frame, Kind::Data, 7, &[1, 2, 3, 4, 5]);
+
+    let mut decoder = Decoder::new();
+    for &b in &[0x00, SYNC, 0x01, 0xff, 0xff, 0x42] {
+        assert_eq!(decoder.push(b), None);
+    }
+    decoder.reset();
+
+    let bytes: Vec<u8> = frame.0.into_iter().collect();
+    let (last, rest) = bytes.split_last().unwrap();
+    for &b in rest {
+        assert_eq!(decoder.push(b), None);
+    }
+    assert_eq!(
+        decoder.push(*last),
+        Some(Frame {
+            kind: Kind::Data,
+            seq: 7,
+            payload: &[1, 2, 3, 4, 5],
+        })
+    );
+}
+
+#[test]
+fn clean_transfer() {
+    let image = image(3 * PAGE_SIZE + 100);
+    let (result, line) = send(Loopback::new(MemFlash::new(8192)), &image);
+    assert_eq!(result, Ok(()));
+    check_written(line, &image);
+}
+
+#[test]
+fn corrupted_bytes_are_retried() {
+    let image = image(5 * PAGE_SIZE);
+    let mut line = Loopback::new(MemFlash::new(8192));
+    line.to_device = Fault::every(1499, false);
+    line.to_host = Fault::every(11, false);
+
+    let (result, line) = send(line, &image);
+    assert_eq!(result, Ok(()));
+    check_written(line, &image);
+}
+
+#[test]
+fn lost_acks_do_not_repeat_writes() {
+    let image = image(4 * PAGE_SIZE + 1);
+    let mut line = Loopback::new(MemFlash::new(8192));
+    line.to_device = Fault::every(997, true);
+    line.to_host = Fault::every(20, true);
+
+    let (result, line) = send(line, &image);
+    assert_eq!(result, Ok(()));
+    check_written(line, &image);
+}
+
+#[test]
+fn image_too_large() {
+    let (result, line) = send(Loopback::new(MemFlash::new(1024)), &image(1025));
+    assert_eq!(result, Err(SendError::Refused(NakReason::TooLarge)));
+    assert_eq!(line.receiver.into_flash().writes, 0);
+}
+
+#[test]
+fn invalid_image_is_refused() {
+    let mut bad = image(PAGE_SIZE);
+    bad[0] = 0;
+    let (result, line) = send(Loopback::new(MemFlash::new(8192)), &bad);
+    assert_eq!(result, Err(SendError::Refused(NakReason::InvalidImage)));
+    assert!(!line.receiver.is_done());
+
+    // The device takes another go.
+    let good = image(PAGE_SIZE);
+    let (result, line) = send(line, &good);
+    assert_eq!(result, Ok(()));
+    assert!(line.receiver.is_done());
+}
+
+#[test]
+fn dead_line() {
+    let mut line = Loopback::new(MemFlash::new(8192));
+    line.to_host = Fault::every(1, true);
+    let (result, _) = send(line, &image(100));
+    assert_eq!(result, Err(SendError::NoResponse));
+}
+
+#[test]
+fn data_before_start() {
+    let mut receiver = Receiver::new(MemFlash::new(8192));
+    let mut frame = Replies::default();
+    send_frame(&mut frame, Kind::Data, 1, &[0; 4 + PAGE_SIZE]);
+
+    let mut replies = Replies::default();
+    for b in frame.0 {
+        receiver.receive(b, &mut replies);
+    }
+
+    let mut decoder = Decoder::new();
+    let (last, rest) = replies.0.make_contiguous().split_last().unwrap();
+    for &b in rest {
+        assert_eq!(decoder.push(b), None);
+    }
+    assert_eq!(
+        decoder.push(*last),
+        Some(Frame {
+            kind: Kind::Nak,
+            seq: 1,
+            payload: &[NakReason::NotStarted as u8],
+        })
+    );
+}
+
+/// Sends `receiver` a single frame, returning the kind and payload of its
+/// answer.
+fn answer(
+    receiver: &mut Receiver<MemFlash>,
+    kind: Kind,
+    seq: u8,
+    payload: &[u8],
+) -> (Kind, Vec<u8>) {
+    let mut frame = Replies::default();
+    send_frame(&mut frame, kind, seq, payload);
+
+    let mut replies = Replies::default();
+    for b in frame.0 {
+        receiver.receive(b, &mut replies);
+    }
+
+    let mut decoder = Decoder::new();
+    let mut answer = None;
+    for b in replies.0 {
+        if let Some(frame) = decoder.push(b) {
+            assert_eq!(frame.seq, seq);
+            answer = Some((frame.kind, frame.payload.to_vec()));
+        }
+    }
+    answer.expect("no answer")
+}
+
+fn data(offset: usize, bytes: &[u8]) -> Vec<u8> {
+    let mut payload = (offset as u32).to_le_bytes().to_vec();
+    payload.extend_from_slice(bytes);
+    payload
+}
+
+#[test]
+fn data_after_last_page() {
+    let image = image(2 * PAGE_SIZE);
+    let mut receiver = Receiver::new(MemFlash::new(8192));
+    let ack = (Kind::Ack, vec![]);
+
+    let len = (image.len() as u32).to_le_bytes();
+    assert_eq!(answer(&mut receiver, Kind::Start, 1, &len), ack);
+    for (i, page) in image.chunks(PAGE_SIZE).enumerate() {
+        let payload = data(i * PAGE_SIZE, page);
+        assert_eq!(
+            answer(&mut receiver, Kind::Data, 2 + i as u8, &payload),
+            ack
+        );
+    }
+
+    // Nothing more fits in the image, whether it's an empty page...
+    assert_eq!(
+        answer(&mut receiver, Kind::Data, 4, &data(image.len(), &[])),
+        (Kind::Nak, vec![NakReason::BadFrame as u8])
+    );
+    // ...or a real one.
+    assert_eq!(
+        answer(&mut receiver, Kind::Data, 5, &data(image.len(), &[0; 16])),
+        (Kind::Nak, vec![NakReason::OutOfOrder as u8])
+    );
+
+    assert_eq!(answer(&mut receiver, Kind::Finish, 6, &[]), ack);
+    let flash = receiver.into_flash();
+    assert_eq!(flash.writes, 2);
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+    assert!(flash.data[image.len()..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn empty_data_is_refused() {
+    let mut receiver = Receiver::new(MemFlash::new(8192));
+    let len = (PAGE_SIZE as u32).to_le_bytes();
+    assert_eq!(
+        answer(&mut receiver, Kind::Start, 1, &len),
+        (Kind::Ack, vec![])
+    );
+    assert_eq!(
+        answer(&mut receiver, Kind::Data, 2, &data(0, &[])),
+        (Kind::Nak, vec![NakReason::BadFrame as u8])
+    );
+    assert_eq!(receiver.into_flash().writes, 0);
+}
@@ -62,6 +62,32 @@
 //! ringbuf_entry!((temp, Some(Register::TempMSB)));
 //! ```
 //!
+//! ## Timestamped ring buffers
+//!
+//! To correlate entries across tasks, a ring buffer can also record when each
+//! entry happened, using the [`timestamped_ringbuf!`] macro in place of
+//! [`ringbuf!`]. It takes the same arguments, and entries are added with the
+//! same [`ringbuf_entry!`] macro:
+//!
+//! ```
+//! timestamped_ringbuf!(u32, 16, 0);
+//!
+//! // ...
+//!
+//! ringbuf_entry!(isr.bits());
+//! ```
+//!
+//! Each [`TimestampedRingbufEntry`] carries the time of the `first` and `last`
+//! occurrence of its payload: when an entry is coalesced by bumping `count`,
+//! only `last` is updated. By default, time is the kernel's tick count as
+//! returned by `sys_get_timer`, which is the same across all tasks. A named
+//! ring buffer can instead be given any `fn() -> u64` as a clock -- say, a
+//! wrapper around the DWT cycle counter -- for finer resolution:
+//!
+//! ```
+//! timestamped_ringbuf!(SPI_RINGBUF, Trace, 64, Trace::None, cycles);
+//! ```
+//!
 //! ## Inspecting a ring buffer via Humility
 //!
 //! Humility has built-in support for dumping a ring buffer, and will (by
@@ -142,6 +168,12 @@
 /// macros is guaranteed to be able to find them.
 pub use userlib::util::StaticCell;
 
+/// The default clock for [`timestamped_ringbuf!`]: the current kernel time,
+/// in ticks since boot.
+pub fn kernel_ticks() -> u64 {
+    userlib::sys_get_timer().now
+}
+
 /// Declares a ringbuffer in the current module or context.
 ///
 /// `ringbuf!(NAME, Type, N, expr)` makes a ringbuffer named `NAME`,
@@ -184,8 +216,58 @@ macro_rules! ringbuf {
     ($t:ty, $n:expr, $init:expr) => {};
 }
 
+/// Declares a timestamped ringbuffer in the current module or context.
+///
+/// This is exactly like [`ringbuf!`], except that the actual type of `name`
+/// will be `StaticCell<TimestampedRingbuf<T, N>>`, and each entry records the
+/// time of the first and last occurrence of its payload.
+///
+/// `timestamped_ringbuf!(NAME, Type, N, expr, clock)` additionally takes a
+/// `fn() -> u64` to use as the clock, in place of the default
+/// [`kernel_ticks`].
+#[cfg(not(feature = "disabled"))]
+#[macro_export]
+macro_rules! timestamped_ringbuf {
+    ($name:ident, $t:ty, $n:expr, $init:expr, $clock:expr) => {
+        #[used]
+        static $name: $crate::StaticCell<$crate::TimestampedRingbuf<$t, $n>> =
+            $crate::StaticCell::new($crate::TimestampedRingbuf {
+                last: None,
+                clock: $clock,
+                buffer: [$crate::TimestampedRingbufEntry {
+                    line: 0,
+                    generation: 0,
+                    count: 0,
+                    first: 0,
+                    last: 0,
+                    payload: $init,
+                }; $n],
+            });
+    };
+    ($name:ident, $t:ty, $n:expr, $init:expr) => {
+        $crate::timestamped_ringbuf!(
+            $name,
+            $t,
+            $n,
+            $init,
+            $crate::kernel_ticks
+        );
+    };
+    ($t:ty, $n:expr, $init:expr) => {
+        $crate::timestamped_ringbuf!(__RINGBUF, $t, $n, $init);
+    };
+}
+
+#[cfg(feature = "disabled")]
+#[macro_export]
+macro_rules! timestamped_ringbuf {
+    ($name:ident, $t:ty, $n:expr, $init:expr, $clock:expr) => {};
+    ($name:ident, $t:ty, $n:expr, $init:expr) => {};
+    ($t:ty, $n:expr, $init:expr) => {};
+}
+
 /// Inserts data into a named ringbuffer (which should have been declared with
-/// the `ringbuf!` macro).
+/// the `ringbuf!` or `timestamped_ringbuf!` macro).
 ///
 /// `ringbuf_entry!(NAME, expr)` will insert `expr` into the ringbuffer called
 /// `NAME`.
@@ -202,8 +284,8 @@ macro_rules! ringbuf_entry {
         let (p, buf) = ($payload, &$buf);
         // Invoke these functions using slightly weird syntax to avoid
         // accidentally calling a _different_ routine called borrow_mut or
-        // entry.
-        $crate::Ringbuf::entry(
+        // record_entry.
+        $crate::RecordEntry::record_entry(
             &mut *$crate::StaticCell::borrow_mut(buf),
             line!() as u16,
             p,
@@ -245,6 +327,20 @@ pub struct Ringbuf<T: Copy + PartialEq, const N: usize> {
     pub buffer: [RingbufEntry<T>; N],
 }
 
+///
+/// The operation common to all kinds of ring buffer: adding an entry, as
+/// [`ringbuf_entry!`] does.
+///
+pub trait RecordEntry<T> {
+    fn record_entry(&mut self, line: u16, payload: T);
+}
+
+impl<T: Copy + PartialEq, const N: usize> RecordEntry<T> for Ringbuf<T, { N }> {
+    fn record_entry(&mut self, line: u16, payload: T) {
+        self.entry(line, payload);
+    }
+}
+
 impl<T: Copy + PartialEq, const N: usize> Ringbuf<T, { N }> {
     pub fn entry(&mut self, line: u16, payload: T) {
         let ndx = match self.last {
@@ -278,3 +374,76 @@ impl<T: Copy + PartialEq, const N: usize> Ringbuf<T, { N }> {
         self.last = Some(ndx);
     }
 }
+
+///
+/// The structure of a single [`TimestampedRingbuf`] entry. This is like a
+/// [`RingbufEntry`], but also records the time of the `first` occurrence of
+/// the payload, and the `last` time `count` was incremented for it.
+///
+#[derive(Debug, Copy, Clone)]
+pub struct TimestampedRingbufEntry<T: Copy + PartialEq> {
+    pub line: u16,
+    pub generation: u16,
+    pub count: u32,
+    pub first: u64,
+    pub last: u64,
+    pub payload: T,
+}
+
+///
+/// A ring buffer of parametrized type and size, with timestamped entries.  As
+/// with [`Ringbuf`], this should be instantiated with a macro -- see
+/// [`timestamped_ringbuf!`].
+///
+#[derive(Debug)]
+pub struct TimestampedRingbuf<T: Copy + PartialEq, const N: usize> {
+    pub last: Option<usize>,
+    pub clock: fn() -> u64,
+    pub buffer: [TimestampedRingbufEntry<T>; N],
+}
+
+impl<T: Copy + PartialEq, const N: usize> RecordEntry<T>
+    for TimestampedRingbuf<T, { N }>
+{
+    fn record_entry(&mut self, line: u16, payload: T) {
+        let now = (self.clock)();
+        self.entry(line, payload, now);
+    }
+}
+
+impl<T: Copy + PartialEq, const N: usize> TimestampedRingbuf<T, { N }> {
+    pub fn entry(&mut self, line: u16, payload: T, now: u64) {
+        let ndx = match self.last {
+            None => 0,
+            Some(last) => {
+                let ent = &mut self.buffer[last];
+
+                if ent.line == line && ent.payload == payload {
+                    // Only reuse this entry if we don't overflow the
+                    // count.
+                    if let Some(new_count) = ent.count.checked_add(1) {
+                        ent.count = new_count;
+                        ent.last = now;
+                        return;
+                    }
+                }
+
+                if last + 1 >= self.buffer.len() {
+                    0
+                } else {
+                    last + 1
+                }
+            }
+        };
+
+        let ent = &mut self.buffer[ndx];
+        ent.line = line;
+        ent.payload = payload;
+        ent.count = 1;
+        ent.first = now;
+        ent.last = now;
+        ent.generation = ent.generation.wrapping_add(1);
+
+        self.last = Some(ndx);
+    }
+}
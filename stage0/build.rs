@@ -0,0 +1,162 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Generates stage0's board configuration for recovery mode from
+//! `config.recovery`, e.g.:
+//!
+//! ```toml
+//! [config.recovery.strap]
+//! port = "1"
+//! pin = 9
+//!
+//! [config.recovery.usart]
+//! flexcomm = 0
+//! rx = { port = "0", pin = 29, function = 1 }
+//! tx = { port = "0", pin = 30, function = 1 }
+//! ```
+//!
+//! The strap is optional. A board with no `config.recovery` at all has no
+//! recovery mode, and stage0 hangs if it has nothing to boot.
+//!
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::env;
+use std::fmt::Write;
+use std::fs::File;
+use std::path::Path;
+
+//
+// We share the `Config` type with all other build-specific types; we must
+// not set `deny_unknown_fields` here.
+//
+#[derive(Clone, Debug, Deserialize)]
+struct Config {
+    recovery: Option<Recovery>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Recovery {
+    /// pin that, held low at reset, asks for recovery mode
+    strap: Option<Pin>,
+
+    /// USART that images are received on
+    usart: Usart,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pin {
+    /// port: `0` or `1`
+    port: String,
+
+    /// pin within the port
+    pin: u8,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Usart {
+    /// Flexcomm interface: `0` through `7`
+    flexcomm: u8,
+    rx: UsartPin,
+    tx: UsartPin,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UsartPin {
+    /// port: `0` or `1`
+    port: String,
+
+    /// pin within the port
+    pin: u8,
+
+    /// IOCON function that connects the pin to the Flexcomm
+    function: u8,
+}
+
+fn pin(what: &str, port: &str, pin: u8) -> Result<String> {
+    let port: u8 = match port {
+        "0" => 0,
+        "1" => 1,
+        _ => bail!("{}: port must be 0 or 1, not {:?}", what, port),
+    };
+    if pin > 31 {
+        bail!("{}: pin must be less than 32, not {}", what, pin);
+    }
+    Ok(format!("Pin {{ port: {}, pin: {} }}", port, pin))
+}
+
+fn usart_pin(what: &str, p: &UsartPin) -> Result<String> {
+    if p.function > 15 {
+        bail!(
+            "{}: function must be less than 16, not {}",
+            what,
+            p.function
+        );
+    }
+    Ok(format!(
+        "UsartPin {{ pin: {}, function: {} }}",
+        pin(what, &p.port, p.pin)?,
+        p.function
+    ))
+}
+
+fn generate(recovery: Option<&Recovery>) -> Result<String> {
+    let mut s = String::new();
+
+    let strap = match recovery.and_then(|r| r.strap.as_ref()) {
+        Some(p) => format!("Some({})", pin("strap", &p.port, p.pin)?),
+        None => "None".to_string(),
+    };
+    writeln!(&mut s, "pub(crate) const STRAP: Option<Pin> = {};", strap)?;
+
+    let usart = match recovery.map(|r| &r.usart) {
+        Some(u) => {
+            if u.flexcomm > 7 {
+                bail!(
+                    "usart: flexcomm must be less than 8, not {}",
+                    u.flexcomm
+                );
+            }
+            format!(
+                "Some(UsartConfig {{ flexcomm: {}, rx: {}, tx: {} }})",
+                u.flexcomm,
+                usart_pin("usart rx", &u.rx)?,
+                usart_pin("usart tx", &u.tx)?
+            )
+        }
+        None => "None".to_string(),
+    };
+    writeln!(
+        &mut s,
+        "pub(crate) const USART: Option<UsartConfig> = {};",
+        usart
+    )?;
+
+    Ok(s)
+}
+
+fn main() -> Result<()> {
+    // Standalone builds (e.g. `cargo xtask check`) have no application, and
+    // so no recovery mode.
+    println!("cargo:rerun-if-env-changed=HUBRIS_APP_CONFIG");
+    let config = if env::var_os("HUBRIS_APP_CONFIG").is_some() {
+        build_util::config::<Config>()?
+    } else {
+        Config { recovery: None }
+    };
+
+    let out_dir = env::var("OUT_DIR")?;
+    let dest_path = Path::new(&out_dir).join("recovery_config.rs");
+    let mut file = File::create(&dest_path)?;
+
+    use std::io::Write;
+    file.write_all(generate(config.recovery.as_ref())?.as_bytes())?;
+
+    Ok(())
+}
@@ -0,0 +1,36 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Measured boot: deriving the booted image's identity, and handing it over.
+//!
+//! The handoff lives in `.attestation`, which the linker script places at the
+//! start of the bootloader's SRAM; the image is told that address as
+//! `__stage0_handoff`, and reads it with `dice::read_handoff`. No task can
+//! read it unless the app gives it that region.
+
+use core::mem::MaybeUninit;
+use dice::{Handoff, MEASUREMENT_LEN};
+
+#[link_section = ".attestation"]
+static mut HANDOFF: MaybeUninit<Handoff> = MaybeUninit::uninit();
+
+/// Derives the CDI for the image with `measurement`, and leaves it for the
+/// image. If we can't (or, without the `dice-cdi` feature, don't), the handoff is
+/// left empty, so nothing stale from a previous boot survives.
+pub fn hand_off(measurement: &[u8; MEASUREMENT_LEN]) {
+    let handoff = derive(measurement).unwrap_or_else(Handoff::empty);
+    unsafe { core::ptr::write_volatile(HANDOFF.as_mut_ptr(), handoff) }
+}
+
+#[cfg(feature = "dice-cdi")]
+fn derive(measurement: &[u8; MEASUREMENT_LEN]) -> Option<Handoff> {
+    crate::puf::with_uds(|uds| {
+        Handoff::new(*measurement, dice::derive_cdi(uds, measurement))
+    })
+}
+
+#[cfg(not(feature = "dice-cdi"))]
+fn derive(_measurement: &[u8; MEASUREMENT_LEN]) -> Option<Handoff> {
+    None
+}
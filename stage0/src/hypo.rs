@@ -3,8 +3,201 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Hypovisor calls
+//!
+//! Each call is a secure gateway veneer in `.flash_hypo` that branches to the
+//! implementation. The veneers' addresses are handed to tasks through the
+//! `sharedsyms` table; the ABI itself is defined in `lib/hypocalls`.
 
+use crate::image_header;
+use hypocalls::slots::{
+    self, ImageState, SlotRecord, RECORD_WORDS, SLOT_COUNT,
+};
+use hypocalls::{
+    BootInfo, HypoError, SlotId, HYPO_ABI_VERSION, HYPO_SUCCESS,
+    MEASUREMENT_LEN,
+};
 use lpc55_romapi::FlashStatus;
+use num_traits::cast::FromPrimitive;
+
+/// What we recorded about the image we booted. This is written once, by
+/// `main`, before branching to the image, and only read after that.
+pub struct BootState {
+    pub slot: SlotId,
+    pub version: u32,
+    pub measurement: [u8; MEASUREMENT_LEN],
+}
+
+static mut BOOT_STATE: Option<BootState> = None;
+
+pub fn record_boot(state: BootState) {
+    unsafe {
+        BOOT_STATE = Some(state);
+    }
+}
+
+/// Size of the flash page our records are kept in.
+const RECORDS_PAGE_SIZE: usize = 512;
+
+extern "C" {
+    // The start of a page of flash of its own, set aside by the linker
+    // script.
+    static SLOT_RECORDS: [u32; RECORD_WORDS];
+}
+
+/// Buffer for programming the records page. It's here rather than on the
+/// stack because hypocalls run on the calling task's stack, which may not
+/// have room for it. Only the first `RECORD_WORDS` are ever set.
+static mut RECORDS_BUFFER: [u32; RECORDS_PAGE_SIZE / 4] =
+    [0; RECORDS_PAGE_SIZE / 4];
+
+fn records_addr() -> u32 {
+    unsafe { SLOT_RECORDS.as_ptr() as u32 }
+}
+
+/// Reads our records of each slot, which the running image updates through
+/// hypocalls and we act on at the next boot. They're kept in flash so that
+/// they survive a power cycle: a pending image is still pending, with no more
+/// attempts than it had, when power comes back. A page that's erased, or
+/// garbled (e.g. by losing power while it was written), reads as no records
+/// at all, which `select_slot` copes with without trusting either image.
+pub fn records() -> [SlotRecord; SLOT_COUNT] {
+    // Reading erased flash faults, so check first.
+    if !lpc55_romapi::validate_programmed(
+        records_addr(),
+        RECORDS_PAGE_SIZE as u32,
+    ) {
+        return [SlotRecord::default(); SLOT_COUNT];
+    }
+
+    slots::decode(unsafe { &SLOT_RECORDS })
+        .unwrap_or([SlotRecord::default(); SLOT_COUNT])
+}
+
+/// Saves our records of each slot. To spare the flash, the page is only
+/// written if they've changed.
+pub fn set_records(
+    records: &[SlotRecord; SLOT_COUNT],
+) -> Result<(), FlashStatus> {
+    if self::records() == *records {
+        return Ok(());
+    }
+
+    unsafe {
+        RECORDS_BUFFER[..RECORD_WORDS].copy_from_slice(&slots::encode(records));
+        program_flash(
+            records_addr(),
+            RECORDS_BUFFER.as_mut_ptr(),
+            RECORDS_PAGE_SIZE as u32,
+        )
+    }
+}
+
+/// Forgets everything we know about the slots, so that the next boot picks
+/// an image as if it were the first power-on.
+pub fn forget_slots() -> Result<(), FlashStatus> {
+    set_records(&[SlotRecord::default(); SLOT_COUNT])
+}
+
+/// Checks that `len` bytes at `ptr`, aligned to `align`, make a plausible
+/// buffer. As with `write_to_flash`, the tt instructions don't tell us much
+/// about a non-secure, unprivileged caller's buffer, so a bad but plausible
+/// pointer will fault rather than be rejected here.
+fn check_buffer(ptr: usize, len: usize, align: usize) -> bool {
+    ptr != 0 && ptr % align == 0 && ptr.checked_add(len).is_some()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __hypo_abi_version() -> u32 {
+    HYPO_ABI_VERSION
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __get_boot_info(out: *mut BootInfo) -> u32 {
+    if !check_buffer(
+        out as usize,
+        core::mem::size_of::<BootInfo>(),
+        core::mem::align_of::<BootInfo>(),
+    ) {
+        return HypoError::InvalidArg as u32;
+    }
+
+    let boot = match &BOOT_STATE {
+        Some(boot) => boot,
+        None => return HypoError::Unsupported as u32,
+    };
+    let records = records();
+    let confirmed =
+        records[boot.slot as usize].state() == ImageState::Confirmed;
+
+    core::ptr::write_volatile(
+        out,
+        BootInfo {
+            slot: boot.slot as u32,
+            version: boot.version,
+            confirmed: confirmed as u32,
+            pending_slot: slots::pending_slot(&records, boot.slot)
+                .map_or(u32::MAX, |s| s as u32),
+        },
+    );
+    HYPO_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __read_measurement(buffer: *mut u8, len: u32) -> u32 {
+    let len = len as usize;
+    if len != MEASUREMENT_LEN || !check_buffer(buffer as usize, len, 1) {
+        return HypoError::InvalidArg as u32;
+    }
+
+    match &BOOT_STATE {
+        Some(boot) => {
+            core::ptr::copy_nonoverlapping(
+                boot.measurement.as_ptr(),
+                buffer,
+                len,
+            );
+            HYPO_SUCCESS
+        }
+        None => HypoError::Unsupported as u32,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __request_slot_switch(slot: u32) -> u32 {
+    let boot = match &BOOT_STATE {
+        Some(boot) => boot,
+        None => return HypoError::Unsupported as u32,
+    };
+    let target = match SlotId::from_u32(slot) {
+        Some(target) => target,
+        None => return HypoError::NoSuchSlot as u32,
+    };
+    let version = image_header::get_image(target).map(|i| i.get_version());
+
+    let mut records = records();
+    match slots::request_switch(&mut records, boot.slot, target, version) {
+        Ok(()) => match set_records(&records) {
+            Ok(()) => HYPO_SUCCESS,
+            Err(_) => HypoError::FlashFailed as u32,
+        },
+        Err(e) => e as u32,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __confirm_image() -> u32 {
+    let boot = match &BOOT_STATE {
+        Some(boot) => boot,
+        None => return HypoError::Unsupported as u32,
+    };
+
+    let mut records = records();
+    slots::confirm(&mut records, boot.slot);
+    match set_records(&records) {
+        Ok(()) => HYPO_SUCCESS,
+        Err(_) => HypoError::FlashFailed as u32,
+    }
+}
 
 // FlashStatus is represented as a u32 so it's safe to return directly.
 // We convert on the receiving end for safety
@@ -31,12 +224,13 @@ pub unsafe extern "C" fn __write_to_flash(
     if which == 0 {
         let flash_addr = address_of_test_region as *const u32 as u32;
 
-        if let Err(result) = lpc55_romapi::flash_erase(flash_addr, len) {
-            return result;
+        // Our slot records follow the test region; they're not for tasks
+        // to write.
+        if len > records_addr() - flash_addr {
+            return FlashStatus::InvalidArg;
         }
 
-        if let Err(result) = lpc55_romapi::flash_write(flash_addr, buffer, len)
-        {
+        if let Err(result) = program_flash(flash_addr, buffer, len) {
             return result;
         }
 
@@ -46,6 +240,18 @@ pub unsafe extern "C" fn __write_to_flash(
     return FlashStatus::InvalidArg;
 }
 
+/// Erases and programs `len` bytes of flash at `addr` from `buffer`. Both
+/// `addr` and `len` must be multiples of the 512-byte flash page. This is how
+/// stage0 writes flash, whether for a task or in recovery mode.
+pub unsafe fn program_flash(
+    addr: u32,
+    buffer: *mut u32,
+    len: u32,
+) -> Result<(), FlashStatus> {
+    lpc55_romapi::flash_erase(addr, len)?;
+    lpc55_romapi::flash_write(addr, buffer, len)
+}
+
 #[link_section = ".flash_hypo"]
 #[naked]
 #[no_mangle]
@@ -72,3 +278,33 @@ pub unsafe extern "C" fn write_to_flash(
         options(noreturn)
     );
 }
+
+/// Defines a secure gateway veneer, like `write_to_flash` above, that
+/// branches to `$target`. Arguments and return value pass through untouched
+/// in registers, so the veneer can be declared without any.
+macro_rules! secure_gateway {
+    ($veneer:ident => $target:ident) => {
+        #[link_section = ".flash_hypo"]
+        #[naked]
+        #[no_mangle]
+        pub unsafe extern "C" fn $veneer() -> u32 {
+            asm!(
+                "
+                sg
+                push {{lr}}
+                bl {target}
+                pop {{lr}}
+                bx lr
+                ",
+                target = sym $target,
+                options(noreturn)
+            );
+        }
+    };
+}
+
+secure_gateway!(hypo_abi_version => __hypo_abi_version);
+secure_gateway!(get_boot_info => __get_boot_info);
+secure_gateway!(read_measurement => __read_measurement);
+secure_gateway!(request_slot_switch => __request_slot_switch);
+secure_gateway!(confirm_image => __confirm_image);
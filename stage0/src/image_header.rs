@@ -2,8 +2,15 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use hypocalls::SlotId;
+use image_verify::KEY_HASH_LEN;
+
 extern "C" {
     static IMAGEA: ImageHeader;
+    // Only a separate slot if `image_slot_count` is 2.
+    static IMAGEB: ImageHeader;
+    // Number of image slots on the board (1 or 2), from the linker script.
+    static image_slot_count: u32;
 }
 
 // TODO grab this from lpc55_support or another crate eventually
@@ -24,28 +31,34 @@ struct CertHeader {
     key: u32,
 }
 
-pub fn get_image_a() -> Option<&'static ImageHeader> {
-    // Taking the reference to our supposed imagea
-    let imagea = unsafe { &IMAGEA };
+pub fn get_image(slot: SlotId) -> Option<&'static ImageHeader> {
+    // Taking the reference to our supposed image
+    let image = unsafe {
+        match slot {
+            SlotId::A => &IMAGEA,
+            SlotId::B if image_slot_count > 1 => &IMAGEB,
+            SlotId::B => return None,
+        }
+    };
 
     // Step 1: check if the flash for this image is actually programmed
-    if !imagea.validate() {
+    if !image.validate() {
         return None;
     }
 
     // We've validated that the image range should be safe
-    let img_start = imagea.get_img_start();
+    let img_start = image.get_img_start();
 
-    let table_start = imagea.get_table_start();
+    let table_start = image.get_table_start();
 
     // Step 2: Check that the table pointed to by this image is actually
     // within our image range that we checked before
-    if !imagea.check_bounds(table_start) {
+    if !image.check_bounds(table_start) {
         return None;
     }
 
     // The table is within bounds so accessing it will not cause a fault
-    let table = unsafe { &*imagea.get_cert_table() };
+    let table = unsafe { &*image.get_cert_table() };
 
     // our 'signature' is the letters cert. If this isn't valid the rest
     // of the data is probably not valid either.
@@ -56,13 +69,13 @@ pub fn get_image_a() -> Option<&'static ImageHeader> {
     let key_start = &table.key as *const u32 as u32;
 
     // validate that our key is fully programmed
-    if !imagea.check_bounds(key_start + table.key_size) {
+    if !image.check_bounds(key_start + table.key_size) {
         return None;
     }
 
     let sig_addr = img_start + table.total_image_len;
 
-    if !imagea.check_bounds(sig_addr) {
+    if !image.check_bounds(sig_addr) {
         return None;
     }
 
@@ -70,16 +83,16 @@ pub fn get_image_a() -> Option<&'static ImageHeader> {
     let sig_size = unsafe { core::ptr::read_volatile(sig_addr as *const u32) };
 
     // Check the signature
-    if !imagea.check_bounds(sig_addr + 4) {
+    if !image.check_bounds(sig_addr + 4) {
         return None;
     }
 
-    if !imagea.check_bounds(sig_addr + 4 + sig_size) {
+    if !image.check_bounds(sig_addr + 4 + sig_size) {
         return None;
     }
 
     // Check what is supposed to be the full image length
-    if !imagea.check_bounds(img_start + table.total_image_len) {
+    if !image.check_bounds(img_start + table.total_image_len) {
         return None;
     }
 
@@ -87,7 +100,37 @@ pub fn get_image_a() -> Option<&'static ImageHeader> {
     // - Accessing the full image range
     // - Accessing the key range
     // - Accessing the signature range
-    Some(imagea)
+    //
+    // Step 3: check the signature, with a chain of keys rooted in one we've
+    // been told to trust.
+    let trusted = trusted_key_hash()?;
+    if image_verify::verify_image(image.get_image(), &trusted).is_err() {
+        return None;
+    }
+
+    Some(image)
+}
+
+/// Offset, in the CMPA's customer data, of the hash of the root key that
+/// images' certificate chains must start from. A part that hasn't been
+/// provisioned with one reads back zeroes, which no key hashes to, and so
+/// boots nothing.
+const KEY_HASH_CMPA_OFFSET: u32 = 0;
+
+fn trusted_key_hash() -> Option<[u8; KEY_HASH_LEN]> {
+    let mut words = [0u32; KEY_HASH_LEN / 4];
+    lpc55_romapi::get_cmpa_data(
+        &mut words,
+        KEY_HASH_CMPA_OFFSET,
+        KEY_HASH_LEN as u32,
+    )
+    .ok()?;
+
+    let mut hash = [0; KEY_HASH_LEN];
+    for (bytes, word) in hash.chunks_mut(4).zip(words.iter()) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    Some(hash)
 }
 
 // The careful observer will note that yes this is just the
@@ -146,6 +189,23 @@ impl ImageHeader {
         self.get_table_start() as *const CertHeader
     }
 
+    /// Returns the build number from the certificate table. Only call this on
+    /// an image returned by `get_image`, which has checked the table.
+    pub fn get_version(&self) -> u32 {
+        unsafe { (*self.get_cert_table()).build_number }
+    }
+
+    /// Returns the whole image, for measurement. As with `get_version`, the
+    /// image must have been validated.
+    pub fn get_image(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self.get_img_start() as *const u8,
+                self.image_length as usize,
+            )
+        }
+    }
+
     pub fn get_pc(&self) -> u32 {
         self.pc
     }
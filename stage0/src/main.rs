@@ -12,9 +12,15 @@
 extern crate panic_halt;
 use cortex_m::peripheral::Peripherals;
 use cortex_m_rt::entry;
+use hypocalls::slots::{self, ImageState};
+use hypocalls::SlotId;
 
+mod attest;
 mod hypo;
 mod image_header;
+#[cfg(feature = "dice-cdi")]
+mod puf;
+mod recovery;
 
 /// Initial entry point for handling a memory management fault.
 #[allow(non_snake_case)]
@@ -60,10 +66,41 @@ fn main() -> ! {
         loop {}
     }
 
-    let imagea = image_header::get_image_a().unwrap();
+    // Find out which slots hold a valid image, and pick one to boot. The
+    // records are saved before booting, so that a pending image that hangs or
+    // crashes still uses up one of its attempts. If they can't be saved, a
+    // pending image could be tried forever, so we don't boot one.
+    let images = [SlotId::A, SlotId::B].map(image_header::get_image);
+    let versions = images.map(|image| image.map(|i| i.get_version()));
+
+    let mut selection = slots::select_slot(versions, hypo::records());
+    if hypo::set_records(&selection.records).is_err() {
+        selection.slot = selection.slot.filter(|&slot| {
+            selection.records[slot as usize].state() != ImageState::Pending
+        });
+    }
 
-    let entry_pt = imagea.get_pc();
-    let stack = imagea.get_sp();
+    // With nothing to boot, or if asked to, wait for a new image instead.
+    let (slot, image) = match selection.slot {
+        Some(slot) if !recovery::strapped() => {
+            (slot, images[slot as usize].unwrap())
+        }
+        _ => recovery::run(),
+    };
+
+    // Measure what we're booting, and record it for the image to query
+    // through hypocalls. Derive its identity from the measurement, after
+    // which the device secret is locked away until the next reset.
+    let measurement = dice::measure(image.get_image());
+    hypo::record_boot(hypo::BootState {
+        slot,
+        version: image.get_version(),
+        measurement,
+    });
+    attest::hand_off(&measurement);
+
+    let entry_pt = image.get_pc();
+    let stack = image.get_sp();
 
     let mut peripherals = Peripherals::take().unwrap();
 
@@ -78,7 +115,7 @@ fn main() -> ! {
         // Write the VTOR
         core::ptr::write_volatile(
             0xE000ED08 as *mut u32,
-            imagea.get_img_start(),
+            image.get_img_start(),
         );
 
         // and branch
@@ -0,0 +1,119 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Just enough of the PUF to reconstruct the UDS.
+//!
+//! The UDS is stored wrapped, as a key code in the FFR key store, which only
+//! this part's PUF can turn back into the key. Once we have used it, we
+//! zeroize the PUF, which disables it until the next reset; the booted image
+//! can't get at the UDS, only at what we derived from it.
+
+use dice::SECRET_LEN;
+use lpc55_romapi::{FFRKeyType, ACTIVATION_CODE_SIZE};
+
+const PUF_BASE: usize = 0x4003_b000;
+
+const CTRL: usize = 0x00;
+const STAT: usize = 0x20;
+const CODEINPUT: usize = 0x44;
+const KEYOUTINDEX: usize = 0x60;
+const KEYOUTPUT: usize = 0x64;
+
+const CTRL_ZEROIZE: u32 = 1 << 0;
+const CTRL_START: u32 = 1 << 2;
+const CTRL_GETKEY: u32 = 1 << 6;
+
+const STAT_BUSY: u32 = 1 << 0;
+const STAT_SUCCESS: u32 = 1 << 1;
+const STAT_ERROR: u32 = 1 << 2;
+const STAT_KEYOUTAVAIL: u32 = 1 << 5;
+const STAT_CODEINREQ: u32 = 1 << 6;
+
+/// AHBCLKCTRLSET2, and the PUF's bit in it.
+const AHBCLKCTRLSET2: usize = 0x5000_0228;
+const AHBCLKCTRL2_PUF: u32 = 1 << 7;
+
+fn read(reg: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((PUF_BASE + reg) as *const u32) }
+}
+
+fn write(reg: usize, val: u32) {
+    unsafe { core::ptr::write_volatile((PUF_BASE + reg) as *mut u32, val) }
+}
+
+/// Runs a PUF command, feeding it `input` as it asks for code words and
+/// collecting key words into `output`. Returns `false` if the command fails,
+/// or the key goes anywhere but the key output register.
+fn command(cmd: u32, input: &[u32], output: &mut [u32]) -> bool {
+    write(CTRL, cmd);
+    while read(STAT) & (STAT_BUSY | STAT_ERROR) == 0 {}
+
+    let mut input = input.iter();
+    let mut output = output.iter_mut();
+    let mut index = 0;
+    loop {
+        let stat = read(STAT);
+        if stat & STAT_BUSY == 0 {
+            return stat & STAT_SUCCESS != 0 && index == 0;
+        }
+        if stat & STAT_CODEINREQ != 0 {
+            write(CODEINPUT, input.next().copied().unwrap_or(0));
+        }
+        if stat & STAT_KEYOUTAVAIL != 0 {
+            index = read(KEYOUTINDEX);
+            let word = read(KEYOUTPUT);
+            if let Some(out) = output.next() {
+                *out = word;
+            }
+        }
+    }
+}
+
+/// Reconstructs the UDS and hands it to `f`, then wipes it and disables the
+/// PUF. Returns `None` if the UDS isn't there, e.g. because the part hasn't
+/// been provisioned with one.
+pub fn with_uds<R>(f: impl FnOnce(&[u8; SECRET_LEN]) -> R) -> Option<R> {
+    unsafe {
+        core::ptr::write_volatile(AHBCLKCTRLSET2 as *mut u32, AHBCLKCTRL2_PUF);
+    }
+
+    let mut words = [0u32; SECRET_LEN / 4];
+    let ok = (|| {
+        let mut ac = [0u32; ACTIVATION_CODE_SIZE / 4];
+        lpc55_romapi::get_activation_code(&mut ac).ok()?;
+        if !command(CTRL_START, &ac, &mut []) {
+            return None;
+        }
+
+        let mut key_code = [0u32; 13];
+        lpc55_romapi::get_key_code(FFRKeyType::UDS, &mut key_code).ok()?;
+        if !command(CTRL_GETKEY, &key_code, &mut words) {
+            return None;
+        }
+        Some(())
+    })();
+
+    let result = ok.map(|()| {
+        let mut uds = [0; SECRET_LEN];
+        for (bytes, word) in uds.chunks_mut(4).zip(words.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        let result = f(&uds);
+        wipe(&mut uds);
+        result
+    });
+
+    wipe(&mut words);
+    write(CTRL, CTRL_ZEROIZE);
+    while read(STAT) & STAT_BUSY != 0 {}
+    result
+}
+
+/// Clears `secret` in a way the compiler won't optimize out.
+fn wipe<T: Copy + Default>(secret: &mut [T]) {
+    for x in secret.iter_mut() {
+        unsafe { core::ptr::write_volatile(x, T::default()) }
+    }
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
@@ -0,0 +1,268 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Serial recovery mode.
+//!
+//! We end up here if no slot holds a bootable image, or if the board's strap
+//! pin is held low at reset. A new image for slot A is received over the
+//! board's USART at 115200 8N1, using the protocol in `lib/recovery`, and once
+//! it has been written and validated, we reset to boot it.
+//!
+//! Which pins and Flexcomm these are comes from the application's
+//! `config.recovery` (see `build.rs`). A board without one has no recovery
+//! mode.
+//!
+//! Nothing else has set up the hardware at this point, so, as with the PUF,
+//! we poke the few registers we need directly.
+
+use crate::hypo;
+use crate::image_header;
+use hypocalls::SlotId;
+use recovery::{Flash, FlashError, Receiver, Transport, PAGE_SIZE};
+use zerocopy::AsBytes;
+
+/// A GPIO pin.
+#[derive(Copy, Clone)]
+pub(crate) struct Pin {
+    port: usize,
+    pin: usize,
+}
+
+/// A pin for a USART, and the IOCON function that connects it.
+#[derive(Copy, Clone)]
+pub(crate) struct UsartPin {
+    pin: Pin,
+    function: u32,
+}
+
+/// The Flexcomm to run as a USART, and its pins.
+#[derive(Copy, Clone)]
+pub(crate) struct UsartConfig {
+    flexcomm: usize,
+    rx: UsartPin,
+    tx: UsartPin,
+}
+
+include!(concat!(env!("OUT_DIR"), "/recovery_config.rs"));
+
+const SYSCON_BASE: usize = 0x5000_0000;
+const PRESETCTRLCLR1: usize = 0x144;
+const AHBCLKCTRLSET0: usize = 0x220;
+const AHBCLKCTRLSET1: usize = 0x224;
+const FCCLKSEL0: usize = 0x2b0;
+
+const AHBCLKCTRL0_IOCON: u32 = 1 << 13;
+const AHBCLKCTRL0_GPIO0: u32 = 1 << 14;
+const AHBCLKCTRL1_FC0: u32 = 1 << 11;
+const PRESETCTRL1_FC0: u32 = 1 << 11;
+const FCCLKSEL_FRO_12M: u32 = 2;
+
+const IOCON_BASE: usize = 0x4000_1000;
+
+const IOCON_MODE_PULLUP: u32 = 2 << 4;
+const IOCON_DIGIMODE: u32 = 1 << 8;
+
+const GPIO_BASE: usize = 0x5008_c000;
+const GPIO_PIN0: usize = 0x2100;
+
+const FC_BASES: [usize; 8] = [
+    0x4008_6000,
+    0x4008_7000,
+    0x4008_8000,
+    0x4008_9000,
+    0x4008_a000,
+    0x4009_6000,
+    0x4009_7000,
+    0x4009_8000,
+];
+const USART_CFG: usize = 0x000;
+const USART_STAT: usize = 0x008;
+const USART_BRG: usize = 0x020;
+const USART_OSR: usize = 0x028;
+const FIFOCFG: usize = 0xe00;
+const FIFOSTAT: usize = 0xe04;
+const FIFOWR: usize = 0xe20;
+const FIFORD: usize = 0xe30;
+const PSELID: usize = 0xff8;
+
+const PSELID_USART: u32 = 1;
+const CFG_ENABLE: u32 = 1 << 0;
+const CFG_DATALEN_8: u32 = 1 << 2;
+const STAT_TXIDLE: u32 = 1 << 3;
+const FIFOCFG_ENABLETX: u32 = 1 << 0;
+const FIFOCFG_ENABLERX: u32 = 1 << 1;
+const FIFOSTAT_TXEMPTY: u32 = 1 << 3;
+const FIFOSTAT_TXNOTFULL: u32 = 1 << 5;
+const FIFOSTAT_RXNOTEMPTY: u32 = 1 << 6;
+const FIFORD_ERRORS: u32 = 0b111 << 13;
+
+// 12 MHz / 13 / 8 is 115384 baud, close enough to 115200.
+const OSR: u32 = 12;
+const BRG: u32 = 7;
+
+fn read(base: usize, reg: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((base + reg) as *const u32) }
+}
+
+fn write(base: usize, reg: usize, val: u32) {
+    unsafe { core::ptr::write_volatile((base + reg) as *mut u32, val) }
+}
+
+/// Offset of a pin's register in IOCON.
+fn iocon(pin: Pin) -> usize {
+    pin.port * 0x80 + pin.pin * 4
+}
+
+/// Returns true if the strap pin asks for recovery mode.
+pub fn strapped() -> bool {
+    let strap = match STRAP {
+        Some(strap) => strap,
+        None => return false,
+    };
+
+    write(
+        SYSCON_BASE,
+        AHBCLKCTRLSET0,
+        AHBCLKCTRL0_IOCON | AHBCLKCTRL0_GPIO0 << strap.port,
+    );
+    write(IOCON_BASE, iocon(strap), IOCON_MODE_PULLUP | IOCON_DIGIMODE);
+
+    // Give the pull-up a moment.
+    cortex_m::asm::delay(1000);
+
+    read(GPIO_BASE, GPIO_PIN0 + strap.port * 4) & 1 << strap.pin == 0
+}
+
+/// Receives an image into slot A, then resets.
+pub fn run() -> ! {
+    // Without a USART, there's no way to receive an image; all we can do is
+    // wait for someone with a debugger.
+    let config = match USART {
+        Some(config) => config,
+        None => loop {},
+    };
+
+    let mut usart = Usart::new(config);
+    let mut receiver = Receiver::new(SlotA::new());
+    receiver.run(&mut usart);
+
+    // Let the last acknowledgement go out.
+    while read(usart.base, FIFOSTAT) & FIFOSTAT_TXEMPTY == 0 {}
+    while read(usart.base, USART_STAT) & STAT_TXIDLE == 0 {}
+
+    // Whatever we knew about slot A described an image that is now gone; it
+    // may even have been marked bad at the same version. If forgetting fails,
+    // the old records stand, but only for as long as the version matches.
+    let _ = hypo::forget_slots();
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+struct Usart {
+    base: usize,
+}
+
+impl Usart {
+    fn new(config: UsartConfig) -> Self {
+        let n = config.flexcomm;
+        write(SYSCON_BASE, AHBCLKCTRLSET0, AHBCLKCTRL0_IOCON);
+        write(SYSCON_BASE, AHBCLKCTRLSET1, AHBCLKCTRL1_FC0 << n);
+        write(SYSCON_BASE, PRESETCTRLCLR1, PRESETCTRL1_FC0 << n);
+        write(SYSCON_BASE, FCCLKSEL0 + n * 4, FCCLKSEL_FRO_12M);
+
+        for p in &[config.rx, config.tx] {
+            write(IOCON_BASE, iocon(p.pin), p.function | IOCON_DIGIMODE);
+        }
+
+        let base = FC_BASES[n];
+        write(base, PSELID, PSELID_USART);
+        write(base, FIFOCFG, FIFOCFG_ENABLETX | FIFOCFG_ENABLERX);
+        write(base, USART_OSR, OSR);
+        write(base, USART_BRG, BRG);
+        write(base, USART_CFG, CFG_ENABLE | CFG_DATALEN_8);
+
+        Usart { base }
+    }
+}
+
+impl Transport for Usart {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            while read(self.base, FIFOSTAT) & FIFOSTAT_TXNOTFULL == 0 {}
+            write(self.base, FIFOWR, b as u32);
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        // We have nothing better to do than wait. Bytes received with errors
+        // are dropped, leaving the frame they were in to fail its CRC.
+        loop {
+            if read(self.base, FIFOSTAT) & FIFOSTAT_RXNOTEMPTY != 0 {
+                let v = read(self.base, FIFORD);
+                if v & FIFORD_ERRORS == 0 {
+                    return Some(v as u8);
+                }
+            }
+        }
+    }
+}
+
+/// Slot A's flash, as described by the linker script.
+struct SlotA {
+    start: u32,
+    end: u32,
+}
+
+impl SlotA {
+    fn new() -> Self {
+        extern "C" {
+            static address_of_imagea_flash: u32;
+            // The test region starts where image A's flash ends.
+            static address_of_test_region: u32;
+        }
+
+        unsafe {
+            SlotA {
+                start: address_of_imagea_flash,
+                end: address_of_test_region,
+            }
+        }
+    }
+}
+
+impl Flash for SlotA {
+    fn capacity(&self) -> u32 {
+        self.end - self.start
+    }
+
+    fn write_page(
+        &mut self,
+        offset: u32,
+        page: &[u8; PAGE_SIZE],
+    ) -> Result<(), FlashError> {
+        // Whatever the receiver thinks it's doing, never program past the
+        // end of the slot, into image B or the test region.
+        match offset.checked_add(PAGE_SIZE as u32) {
+            Some(end) if end <= self.capacity() => (),
+            _ => return Err(FlashError),
+        }
+
+        // The ROM wants words.
+        let mut words = [0u32; PAGE_SIZE / 4];
+        words.as_bytes_mut().copy_from_slice(page);
+
+        unsafe {
+            hypo::program_flash(
+                self.start + offset,
+                words.as_mut_ptr(),
+                PAGE_SIZE as u32,
+            )
+        }
+        .map_err(|_| FlashError)
+    }
+
+    fn validate(&mut self, _len: u32) -> bool {
+        // This does the same checks as at boot, signature included.
+        image_header::get_image(SlotId::A).is_some()
+    }
+}
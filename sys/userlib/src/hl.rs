@@ -433,6 +433,24 @@ impl Borrow<'_> {
             Some(())
         }
     }
+
+    /// Converts this borrow into a `BorrowReader`, which reads sequentially
+    /// from the start of the borrow.
+    ///
+    /// This returns `None` if the borrow doesn't exist or doesn't allow
+    /// reading.
+    pub fn into_reader(self) -> Option<BorrowReader<Self>> {
+        BorrowReader::new(self)
+    }
+
+    /// Converts this borrow into a `BorrowWriter`, which writes sequentially
+    /// from the start of the borrow.
+    ///
+    /// This returns `None` if the borrow doesn't exist or doesn't allow
+    /// writing.
+    pub fn into_writer(self) -> Option<BorrowWriter<Self>> {
+        BorrowWriter::new(self)
+    }
 }
 
 /// Information record returned by `Borrow::info`.
@@ -443,6 +461,309 @@ pub struct BorrowInfo {
     pub len: usize,
 }
 
+/// Trait implemented by things that can be read from and written to at an
+/// offset, like a `Borrow`.
+///
+/// `BorrowReader` and `BorrowWriter` are generic over this trait so that the
+/// position bookkeeping isn't tied to the borrow syscalls.
+pub trait LeaseIo {
+    /// Gets the attributes and length of this lease, or `None` if it doesn't
+    /// exist.
+    fn info(&self) -> Option<BorrowInfo>;
+
+    /// Reads exactly `dest.len()` bytes starting at `offset`.
+    fn read_fully_at(&self, offset: usize, dest: &mut [u8]) -> Option<()>;
+
+    /// Writes exactly `src.len()` bytes starting at `offset`.
+    fn write_fully_at(&self, offset: usize, src: &[u8]) -> Option<()>;
+}
+
+impl LeaseIo for Borrow<'_> {
+    fn info(&self) -> Option<BorrowInfo> {
+        Borrow::info(self)
+    }
+
+    fn read_fully_at(&self, offset: usize, dest: &mut [u8]) -> Option<()> {
+        Borrow::read_fully_at(self, offset, dest)
+    }
+
+    fn write_fully_at(&self, offset: usize, src: &[u8]) -> Option<()> {
+        Borrow::write_fully_at(self, offset, src)
+    }
+}
+
+/// A cursor that reads sequentially from a lease, keeping track of the
+/// position for you.
+///
+/// The length of the lease is captured when the reader is created, so reads
+/// never run off the end: they get shortened instead. As with `Borrow`, any
+/// failure to read from the lease is reported as `None`, because the only
+/// sensible thing to do at that point is to reject the client.
+pub struct BorrowReader<L> {
+    lease: L,
+    pos: usize,
+    len: usize,
+}
+
+impl<L: LeaseIo> BorrowReader<L> {
+    /// Creates a reader positioned at the start of `lease`.
+    ///
+    /// This returns `None` if the lease doesn't exist or doesn't allow
+    /// reading.
+    pub fn new(lease: L) -> Option<Self> {
+        let info = lease.info()?;
+        if !info.attributes.contains(abi::LeaseAttributes::READ) {
+            return None;
+        }
+        Some(Self {
+            lease,
+            pos: 0,
+            len: info.len,
+        })
+    }
+
+    /// Returns the current offset into the lease.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns the total length of the lease, in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks whether the lease is zero-length.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of bytes left to be read.
+    pub fn remaining(&self) -> usize {
+        self.len - self.pos
+    }
+
+    /// Checks whether the entire lease has been read.
+    pub fn is_done(&self) -> bool {
+        self.pos == self.len
+    }
+
+    /// Advances the position by up to `n` bytes without reading them.
+    /// Returns the number of bytes actually skipped.
+    pub fn skip(&mut self, n: usize) -> usize {
+        let n = n.min(self.remaining());
+        self.pos += n;
+        n
+    }
+
+    /// Reads as many bytes as will fit in `dest`, or as remain in the lease,
+    /// whichever is smaller. Returns the prefix of `dest` that was filled;
+    /// this is empty once the lease has been completely read.
+    pub fn read<'b>(&mut self, dest: &'b mut [u8]) -> Option<&'b [u8]> {
+        let n = dest.len().min(self.remaining());
+        let dest = &mut dest[..n];
+        self.lease.read_fully_at(self.pos, dest)?;
+        self.pos += n;
+        Some(dest)
+    }
+
+    /// Reads exactly `dest.len()` bytes, failing if that would run off the
+    /// end of the lease. On failure, the position is unchanged.
+    pub fn read_exact(&mut self, dest: &mut [u8]) -> Option<()> {
+        if dest.len() > self.remaining() {
+            return None;
+        }
+        self.lease.read_fully_at(self.pos, dest)?;
+        self.pos += dest.len();
+        Some(())
+    }
+
+    /// Reads one item of type `T`, failing if that would run off the end of
+    /// the lease.
+    ///
+    /// As with `Borrow::read_at`, no alignment requirement is placed on the
+    /// client side.
+    pub fn read_item<T>(&mut self) -> Option<T>
+    where
+        T: Default + FromBytes + AsBytes,
+    {
+        let mut item = T::default();
+        self.read_exact(item.as_bytes_mut())?;
+        Some(item)
+    }
+
+    /// Reads the rest of the lease in `scratch`-sized chunks (shortened for
+    /// the final one), handing each to `sink` along with the offset of the
+    /// chunk within the lease.
+    ///
+    /// If `sink` fails, its error is returned and nothing more is read; if
+    /// the lease fails, this returns `Ok(None)`.
+    ///
+    /// # Panics
+    ///
+    /// If `scratch` is empty.
+    pub fn for_each_chunk<E>(
+        &mut self,
+        scratch: &mut [u8],
+        mut sink: impl FnMut(usize, &[u8]) -> Result<(), E>,
+    ) -> Result<Option<()>, E> {
+        assert!(!scratch.is_empty());
+        while !self.is_done() {
+            let offset = self.pos;
+            let chunk = match self.read(scratch) {
+                Some(chunk) => chunk,
+                None => return Ok(None),
+            };
+            sink(offset, chunk)?;
+        }
+        Ok(Some(()))
+    }
+
+    /// Copies the rest of the lease into `writer`, one `scratch`-sized chunk
+    /// at a time, stopping early if `writer` fills up. Returns the number of
+    /// bytes copied.
+    ///
+    /// This is the moral equivalent of `std::io::copy` between two leases,
+    /// with the caller providing the bounce buffer.
+    pub fn copy_to<W: LeaseIo>(
+        &mut self,
+        writer: &mut BorrowWriter<W>,
+        scratch: &mut [u8],
+    ) -> Option<usize> {
+        let mut copied = 0;
+        loop {
+            let n = scratch.len().min(writer.remaining());
+            let chunk = self.read(&mut scratch[..n])?;
+            if chunk.is_empty() {
+                break;
+            }
+            writer.write_all(chunk)?;
+            copied += chunk.len();
+        }
+        Some(copied)
+    }
+
+    /// Returns the underlying lease.
+    pub fn into_inner(self) -> L {
+        self.lease
+    }
+}
+
+/// A cursor that writes sequentially into a lease, keeping track of the
+/// position for you.
+///
+/// This is the writing counterpart to `BorrowReader`, and has the same
+/// approach to lengths and errors.
+pub struct BorrowWriter<L> {
+    lease: L,
+    pos: usize,
+    len: usize,
+}
+
+impl<L: LeaseIo> BorrowWriter<L> {
+    /// Creates a writer positioned at the start of `lease`.
+    ///
+    /// This returns `None` if the lease doesn't exist or doesn't allow
+    /// writing.
+    pub fn new(lease: L) -> Option<Self> {
+        let info = lease.info()?;
+        if !info.attributes.contains(abi::LeaseAttributes::WRITE) {
+            return None;
+        }
+        Some(Self {
+            lease,
+            pos: 0,
+            len: info.len,
+        })
+    }
+
+    /// Returns the current offset into the lease.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns the total length of the lease, in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks whether the lease is zero-length.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of bytes that can still be written.
+    pub fn remaining(&self) -> usize {
+        self.len - self.pos
+    }
+
+    /// Checks whether the entire lease has been written.
+    pub fn is_done(&self) -> bool {
+        self.pos == self.len
+    }
+
+    /// Writes as much of `src` as will fit in the lease. Returns the number
+    /// of bytes written, which is 0 once the lease is full.
+    pub fn write(&mut self, src: &[u8]) -> Option<usize> {
+        let n = src.len().min(self.remaining());
+        self.lease.write_fully_at(self.pos, &src[..n])?;
+        self.pos += n;
+        Some(n)
+    }
+
+    /// Writes all of `src`, failing if that would run off the end of the
+    /// lease. On failure, the position is unchanged.
+    pub fn write_all(&mut self, src: &[u8]) -> Option<()> {
+        if src.len() > self.remaining() {
+            return None;
+        }
+        self.lease.write_fully_at(self.pos, src)?;
+        self.pos += src.len();
+        Some(())
+    }
+
+    /// Writes one item of type `T`, failing if that would run off the end of
+    /// the lease.
+    pub fn write_item<T>(&mut self, value: T) -> Option<()>
+    where
+        T: AsBytes,
+    {
+        self.write_all(value.as_bytes())
+    }
+
+    /// Fills the rest of the lease by calling `source` with successive
+    /// `scratch`-sized chunks (shortened for the final one), writing each
+    /// chunk out once `source` has filled it in. `source` receives the offset
+    /// of the chunk within the lease.
+    ///
+    /// If `source` fails, its error is returned and nothing more is written;
+    /// if the lease fails, this returns `Ok(None)`.
+    ///
+    /// # Panics
+    ///
+    /// If `scratch` is empty.
+    pub fn fill_from<E>(
+        &mut self,
+        scratch: &mut [u8],
+        mut source: impl FnMut(usize, &mut [u8]) -> Result<(), E>,
+    ) -> Result<Option<()>, E> {
+        assert!(!scratch.is_empty());
+        while !self.is_done() {
+            let n = scratch.len().min(self.remaining());
+            let chunk = &mut scratch[..n];
+            source(self.pos, chunk)?;
+            if self.write_all(chunk).is_none() {
+                return Ok(None);
+            }
+        }
+        Ok(Some(()))
+    }
+
+    /// Returns the underlying lease.
+    pub fn into_inner(self) -> L {
+        self.lease
+    }
+}
+
 /// Trait implemented by types that represent a message sent to another task.
 ///
 /// A `Call` type `C` has four parts: the contents of a value of type `C`, which
@@ -0,0 +1,694 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! In-process IPC harness for running task code on a host.
+//!
+//! With the `host-ipc` feature enabled, the syscall stubs in this crate are
+//! replaced by the implementations in this module, which act on a simulated
+//! kernel kept in a thread-local. This lets ordinary `#[test]`s drive a
+//! server's message handling without a board:
+//!
+//! ```ignore
+//! use userlib::host::{self, HostLease};
+//!
+//! let client = TaskId::for_index_and_gen(3, Generation::default());
+//! host::reset();
+//! host::send(client, Op::PageProgram as u16, &addr.to_le_bytes(), 0,
+//!     vec![HostLease::read(&data)]);
+//!
+//! // Run one iteration of the server's loop, which calls `hl::recv`.
+//! server.step();
+//!
+//! let reply = host::take_reply(client).unwrap();
+//! assert_eq!(reply.code, 0);
+//! ```
+//!
+//! The simulation is deliberately simple. The task under test is the only
+//! "real" task: messages to it are queued by the test, and any SEND it makes
+//! is handed to a handler installed with `set_send_handler`. Time only moves
+//! when the test calls `advance_time`. If the task does something that would
+//! fault it on real hardware (e.g. naming a lease that doesn't exist), or
+//! would block with nothing left to receive, the harness panics with a
+//! description of what happened.
+//!
+//! Because this is thread-local, each test thread gets its own kernel; tests
+//! don't need to serialize against each other, but should call `reset` if
+//! they reuse a thread.
+//!
+//! With the `host-sim` feature as well, the same stubs talk instead to the
+//! kernel run by `cargo xtask sim`, and the kernel here goes unused; see the
+//! `sim` module.
+
+// Under `host-sim`, nothing reaches the kernel here.
+#![cfg_attr(feature = "host-sim", allow(dead_code))]
+
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::vec::Vec;
+
+use abi::{LeaseAttributes, TaskId};
+
+use crate::{
+    BorrowReadArgs, BorrowWriteArgs, RawBorrowInfo, RawRecvMessage,
+    RawTimerState, RcLen, SendArgs,
+};
+
+/// A lease attached to a message injected with `send`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HostLease {
+    /// Access the receiving task is granted to the lease.
+    pub attributes: LeaseAttributes,
+    /// Contents of the leased memory.
+    pub data: Vec<u8>,
+}
+
+impl HostLease {
+    /// Creates a read-only lease containing a copy of `data`.
+    pub fn read(data: &[u8]) -> Self {
+        Self {
+            attributes: LeaseAttributes::READ,
+            data: data.to_vec(),
+        }
+    }
+
+    /// Creates a write-only lease of `len` zero bytes.
+    pub fn write(len: usize) -> Self {
+        Self {
+            attributes: LeaseAttributes::WRITE,
+            data: std::vec![0; len],
+        }
+    }
+
+    /// Creates a read-write lease containing a copy of `data`.
+    pub fn read_write(data: &[u8]) -> Self {
+        Self {
+            attributes: LeaseAttributes::READ | LeaseAttributes::WRITE,
+            data: data.to_vec(),
+        }
+    }
+}
+
+/// A reply sent by the task under test, as collected by `take_reply`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Reply {
+    /// Response code; 0 conventionally means success.
+    pub code: u32,
+    /// Reply message contents.
+    pub message: Vec<u8>,
+    /// The leases that were sent with the original message, including any
+    /// changes the task made to them.
+    pub leases: Vec<HostLease>,
+}
+
+/// A lease passed by the task under test on an outgoing SEND, as seen by the
+/// send handler.
+pub enum LeaseBuf<'a> {
+    /// The lease can only be read.
+    Read(&'a [u8]),
+    /// The lease can be written (and, in practice, read).
+    Write(&'a mut [u8]),
+}
+
+/// Handler for SENDs made by the task under test. It receives the target,
+/// operation, outgoing message, a buffer for the response, and the leases,
+/// and returns the response code and response length.
+pub type SendHandler = dyn FnMut(
+    TaskId,
+    u16,
+    &[u8],
+    &mut [u8],
+    &mut [LeaseBuf<'_>],
+) -> (u32, usize);
+
+struct Pending {
+    sender: TaskId,
+    operation: u16,
+    message: Vec<u8>,
+    response_capacity: usize,
+    leases: Vec<HostLease>,
+}
+
+#[derive(Default)]
+struct Kernel {
+    /// Messages queued for the task under test, in arrival order.
+    queue: VecDeque<Pending>,
+    /// Messages that have been received but not yet replied to, by sender.
+    in_reply: BTreeMap<u16, Pending>,
+    /// Replies that haven't been collected by the test yet, by sender.
+    replies: BTreeMap<u16, VecDeque<Reply>>,
+    /// Pending notification bits.
+    notifications: u32,
+    /// Current time, in ticks.
+    now: u64,
+    /// Timer deadline and the notification bits it will post.
+    timer: Option<(u64, u32)>,
+    /// Notification bits currently enabled with `sys_irq_control`.
+    irqs_enabled: u32,
+    /// Notifications posted to other tasks, by task index.
+    posts: BTreeMap<usize, u32>,
+}
+
+std::thread_local! {
+    static KERNEL: RefCell<Kernel> = RefCell::new(Kernel::default());
+    static SEND_HANDLER: RefCell<Option<Box<SendHandler>>> = RefCell::new(None);
+}
+
+fn with_kernel<R>(f: impl FnOnce(&mut Kernel) -> R) -> R {
+    KERNEL.with(|k| f(&mut k.borrow_mut()))
+}
+
+/// Resets the simulated kernel, discarding all queued messages, replies,
+/// notifications and timer state, and removing any send handler.
+pub fn reset() {
+    with_kernel(|k| *k = Kernel::default());
+    SEND_HANDLER.with(|h| *h.borrow_mut() = None);
+}
+
+/// Queues a message from `sender` to the task under test.
+///
+/// `response_capacity` is the size of the response buffer the sender is
+/// pretending to have provided.
+pub fn send(
+    sender: TaskId,
+    operation: u16,
+    message: &[u8],
+    response_capacity: usize,
+    leases: Vec<HostLease>,
+) {
+    with_kernel(|k| {
+        k.queue.push_back(Pending {
+            sender,
+            operation,
+            message: message.to_vec(),
+            response_capacity,
+            leases,
+        })
+    });
+}
+
+/// Posts notification `bits` to the task under test, as an interrupt or
+/// another task's `sys_post` would.
+pub fn post(bits: u32) {
+    with_kernel(|k| k.notifications |= bits);
+}
+
+/// Advances the simulated clock by `ticks`, firing the task's timer if its
+/// deadline has been reached.
+pub fn advance_time(ticks: u64) {
+    with_kernel(|k| {
+        k.now += ticks;
+        k.fire_timer();
+    });
+}
+
+/// Returns the current simulated time, in ticks.
+pub fn now() -> u64 {
+    with_kernel(|k| k.now)
+}
+
+/// Collects the oldest uncollected reply sent to `sender`, if any.
+pub fn take_reply(sender: TaskId) -> Option<Reply> {
+    with_kernel(|k| k.replies.get_mut(&sender.0)?.pop_front())
+}
+
+/// Checks whether a message from `sender` has been received but not yet
+/// replied to.
+pub fn is_awaiting_reply(sender: TaskId) -> bool {
+    with_kernel(|k| k.in_reply.contains_key(&sender.0))
+}
+
+/// Returns the number of queued messages that the task under test hasn't
+/// received yet.
+pub fn queued() -> usize {
+    with_kernel(|k| k.queue.len())
+}
+
+/// Returns the notification bits the task currently has enabled with
+/// `sys_irq_control`.
+pub fn irqs_enabled() -> u32 {
+    with_kernel(|k| k.irqs_enabled)
+}
+
+/// Returns, and clears, the notification bits the task under test has posted
+/// to the task with index `task`.
+pub fn take_posts(task: usize) -> u32 {
+    with_kernel(|k| k.posts.remove(&task).unwrap_or(0))
+}
+
+/// Installs the handler for SENDs made by the task under test, replacing any
+/// previous one. With no handler installed, any SEND panics.
+pub fn set_send_handler(
+    handler: impl FnMut(
+            TaskId,
+            u16,
+            &[u8],
+            &mut [u8],
+            &mut [LeaseBuf<'_>],
+        ) -> (u32, usize)
+        + 'static,
+) {
+    SEND_HANDLER.with(|h| *h.borrow_mut() = Some(Box::new(handler)));
+}
+
+impl Kernel {
+    fn fire_timer(&mut self) {
+        if let Some((deadline, bits)) = self.timer {
+            if deadline <= self.now {
+                self.notifications |= bits;
+                self.timer = None;
+            }
+        }
+    }
+
+    /// Finds the lease `index` of the message from `lender` that we're
+    /// holding, mirroring the kernel's checks. A lender that isn't waiting on
+    /// us is treated as defecting; a bad lease index or offset would fault
+    /// the borrower, which we turn into a panic.
+    fn lease(
+        &mut self,
+        lender: u32,
+        index: usize,
+        offset: usize,
+    ) -> Option<&mut HostLease> {
+        let pending = self.in_reply.get_mut(&(lender as u16))?;
+        let nleases = pending.leases.len();
+        let lease = match pending.leases.get_mut(index) {
+            Some(lease) => lease,
+            None => panic!(
+                "task would fault: lease {} out of range ({} leases)",
+                index, nleases
+            ),
+        };
+        if offset > lease.data.len() {
+            panic!(
+                "task would fault: offset {} past end of {}-byte lease",
+                offset,
+                lease.data.len()
+            );
+        }
+        Some(lease)
+    }
+}
+
+/// Where the syscalls of a task running on the host go. The stubs at the
+/// bottom of this module turn the raw syscall arguments into slices, and
+/// hand them to `Active`: the kernel in this module or, with the `host-sim`
+/// feature, the one run by `cargo xtask sim`.
+pub(crate) trait Backend {
+    fn send(
+        target: TaskId,
+        operation: u16,
+        outgoing: &[u8],
+        incoming: &mut [u8],
+        leases: Vec<(LeaseAttributes, LeaseBuf<'_>)>,
+    ) -> (u32, usize);
+    fn recv(
+        buffer: &mut [u8],
+        notification_mask: u32,
+        specific_sender: Option<TaskId>,
+    ) -> (u32, RawRecvMessage);
+    fn reply(peer: u32, code: u32, message: &[u8]);
+    fn set_timer(deadline: Option<u64>, notification: u32);
+    fn borrow_read(
+        lender: u32,
+        index: usize,
+        offset: usize,
+        dest: &mut [u8],
+    ) -> (u32, usize);
+    fn borrow_write(
+        lender: u32,
+        index: usize,
+        offset: usize,
+        src: &[u8],
+    ) -> (u32, usize);
+    fn borrow_info(lender: u32, index: usize) -> RawBorrowInfo;
+    fn irq_control(mask: u32, enable: bool);
+    fn panic(message: &[u8]) -> !;
+    fn get_timer() -> RawTimerState;
+    fn refresh_task_id(tid: u32) -> u32;
+    fn post(tid: u32, mask: u32) -> u32;
+}
+
+#[cfg(not(feature = "host-sim"))]
+type Active = Local;
+
+#[cfg(feature = "host-sim")]
+type Active = crate::sim::Remote;
+
+/// The kernel in this module.
+pub(crate) struct Local;
+
+impl Backend for Local {
+    fn send(
+        target: TaskId,
+        operation: u16,
+        outgoing: &[u8],
+        incoming: &mut [u8],
+        leases: Vec<(LeaseAttributes, LeaseBuf<'_>)>,
+    ) -> (u32, usize) {
+        let mut bufs =
+            leases.into_iter().map(|(_, buf)| buf).collect::<Vec<_>>();
+
+        // Take the handler out while it runs, so that it's free to use the
+        // rest of this module.
+        let mut handler = match SEND_HANDLER.with(|h| h.borrow_mut().take()) {
+            Some(handler) => handler,
+            None => panic!(
+                "task sent operation {} to {:?} with no send handler \
+                installed",
+                operation, target
+            ),
+        };
+        let (rc, len) =
+            handler(target, operation, outgoing, incoming, &mut bufs);
+        SEND_HANDLER.with(|h| {
+            let mut h = h.borrow_mut();
+            if h.is_none() {
+                *h = Some(handler);
+            }
+        });
+
+        if len > incoming.len() {
+            panic!(
+                "send handler returned {} bytes for a {}-byte response buffer",
+                len,
+                incoming.len()
+            );
+        }
+        (rc, len)
+    }
+
+    fn recv(
+        buffer: &mut [u8],
+        notification_mask: u32,
+        specific_sender: Option<TaskId>,
+    ) -> (u32, RawRecvMessage) {
+        with_kernel(|k| {
+            // As in the kernel, notifications take precedence over messages.
+            let wants_kernel =
+                specific_sender.map_or(true, |s| s == TaskId::KERNEL);
+            let fired = k.notifications & notification_mask;
+            if wants_kernel && fired != 0 {
+                k.notifications &= !fired;
+                return (
+                    0,
+                    RawRecvMessage {
+                        sender: u32::from(TaskId::KERNEL.0),
+                        operation: fired,
+                        message_len: 0,
+                        response_capacity: 0,
+                        lease_count: 0,
+                    },
+                );
+            }
+
+            let position = match specific_sender {
+                Some(TaskId::KERNEL) => None,
+                Some(sender) => k.queue.iter().position(|p| p.sender == sender),
+                None => {
+                    if k.queue.is_empty() {
+                        None
+                    } else {
+                        Some(0)
+                    }
+                }
+            };
+            let pending = match position.and_then(|i| k.queue.remove(i)) {
+                Some(pending) => pending,
+                None => panic!(
+                    "task would block forever: nothing queued for recv \
+                    (mask {:#x}, sender {:?})",
+                    notification_mask, specific_sender
+                ),
+            };
+
+            if pending.message.len() > buffer.len() {
+                panic!(
+                    "message from {:?} is {} bytes, but the receive buffer \
+                    is only {}",
+                    pending.sender,
+                    pending.message.len(),
+                    buffer.len()
+                );
+            }
+            buffer[..pending.message.len()].copy_from_slice(&pending.message);
+
+            let message = RawRecvMessage {
+                sender: u32::from(pending.sender.0),
+                operation: u32::from(pending.operation),
+                message_len: pending.message.len(),
+                response_capacity: pending.response_capacity,
+                lease_count: pending.leases.len(),
+            };
+            k.in_reply.insert(pending.sender.0, pending);
+            (0, message)
+        })
+    }
+
+    fn reply(peer: u32, code: u32, message: &[u8]) {
+        with_kernel(|k| {
+            // Like the kernel, we silently ignore replies to tasks that
+            // aren't waiting for one.
+            if let Some(pending) = k.in_reply.remove(&(peer as u16)) {
+                if message.len() > pending.response_capacity {
+                    panic!(
+                        "task would fault: {}-byte reply to {:?}, which has \
+                        room for {}",
+                        message.len(),
+                        pending.sender,
+                        pending.response_capacity
+                    );
+                }
+                k.replies.entry(peer as u16).or_default().push_back(Reply {
+                    code,
+                    message: message.to_vec(),
+                    leases: pending.leases,
+                });
+            }
+        });
+    }
+
+    fn set_timer(deadline: Option<u64>, notification: u32) {
+        with_kernel(|k| {
+            k.timer = deadline.map(|deadline| (deadline, notification));
+            k.fire_timer();
+        });
+    }
+
+    fn borrow_read(
+        lender: u32,
+        index: usize,
+        offset: usize,
+        dest: &mut [u8],
+    ) -> (u32, usize) {
+        with_kernel(|k| match k.lease(lender, index, offset) {
+            Some(lease) if lease.attributes.contains(LeaseAttributes::READ) => {
+                let src = &lease.data[offset..];
+                let n = src.len().min(dest.len());
+                dest[..n].copy_from_slice(&src[..n]);
+                (0, n)
+            }
+            _ => (abi::DEFECT, 0),
+        })
+    }
+
+    fn borrow_write(
+        lender: u32,
+        index: usize,
+        offset: usize,
+        src: &[u8],
+    ) -> (u32, usize) {
+        with_kernel(|k| match k.lease(lender, index, offset) {
+            Some(lease)
+                if lease.attributes.contains(LeaseAttributes::WRITE) =>
+            {
+                let dest = &mut lease.data[offset..];
+                let n = src.len().min(dest.len());
+                dest[..n].copy_from_slice(&src[..n]);
+                (0, n)
+            }
+            _ => (abi::DEFECT, 0),
+        })
+    }
+
+    fn borrow_info(lender: u32, index: usize) -> RawBorrowInfo {
+        with_kernel(|k| match k.lease(lender, index, 0) {
+            Some(lease) => RawBorrowInfo {
+                rc: 0,
+                atts: lease.attributes.bits(),
+                length: lease.data.len(),
+            },
+            None => RawBorrowInfo {
+                rc: abi::DEFECT,
+                atts: 0,
+                length: 0,
+            },
+        })
+    }
+
+    fn irq_control(mask: u32, enable: bool) {
+        with_kernel(|k| {
+            if enable {
+                k.irqs_enabled |= mask;
+            } else {
+                k.irqs_enabled &= !mask;
+            }
+        });
+    }
+
+    fn panic(message: &[u8]) -> ! {
+        panic!(
+            "task panicked: {}",
+            std::string::String::from_utf8_lossy(message)
+        );
+    }
+
+    fn get_timer() -> RawTimerState {
+        with_kernel(|k| {
+            let (set, deadline, on_dl) = match k.timer {
+                Some((deadline, bits)) => (1, deadline, bits),
+                None => (0, 0, 0),
+            };
+            RawTimerState {
+                now_lo: k.now as u32,
+                now_hi: (k.now >> 32) as u32,
+                set,
+                dl_lo: deadline as u32,
+                dl_hi: (deadline >> 32) as u32,
+                on_dl,
+            }
+        })
+    }
+
+    fn refresh_task_id(tid: u32) -> u32 {
+        // Every simulated task is on its first generation.
+        tid & u32::from(TaskId::INDEX_MASK)
+    }
+
+    fn post(tid: u32, mask: u32) -> u32 {
+        let index = TaskId(tid as u16).index();
+        with_kernel(|k| *k.posts.entry(index).or_default() |= mask);
+        0
+    }
+}
+
+pub(crate) unsafe fn sys_send_stub(args: &mut SendArgs<'_>) -> RcLen {
+    let target = TaskId((args.packed_target_operation >> 16) as u16);
+    let operation = args.packed_target_operation as u16;
+    let outgoing =
+        core::slice::from_raw_parts(args.outgoing_ptr, args.outgoing_len);
+    let incoming =
+        core::slice::from_raw_parts_mut(args.incoming_ptr, args.incoming_len);
+    let leases = core::slice::from_raw_parts(args.lease_ptr, args.lease_len)
+        .iter()
+        .map(|l| {
+            let buf = if l.attributes.contains(LeaseAttributes::WRITE) {
+                LeaseBuf::Write(core::slice::from_raw_parts_mut(
+                    l.base_address,
+                    l.length,
+                ))
+            } else {
+                LeaseBuf::Read(core::slice::from_raw_parts(
+                    l.base_address,
+                    l.length,
+                ))
+            };
+            (l.attributes, buf)
+        })
+        .collect();
+
+    let (rc, len) = Active::send(target, operation, outgoing, incoming, leases);
+    RcLen(u64::from(rc) | (len as u64) << 32)
+}
+
+pub(crate) unsafe fn sys_recv_stub(
+    buffer_ptr: *mut u8,
+    buffer_len: usize,
+    notification_mask: u32,
+    specific_sender: u32,
+    out: *mut RawRecvMessage,
+) -> u32 {
+    let buffer = core::slice::from_raw_parts_mut(buffer_ptr, buffer_len);
+    let specific_sender = if specific_sender & (1 << 31) != 0 {
+        Some(TaskId(specific_sender as u16))
+    } else {
+        None
+    };
+
+    let (rc, message) =
+        Active::recv(buffer, notification_mask, specific_sender);
+    out.write(message);
+    rc
+}
+
+pub(crate) unsafe fn sys_reply_stub(
+    peer: u32,
+    code: u32,
+    message_ptr: *const u8,
+    message_len: usize,
+) {
+    let message = core::slice::from_raw_parts(message_ptr, message_len);
+    Active::reply(peer, code, message);
+}
+
+pub(crate) unsafe fn sys_set_timer_stub(
+    set_timer: u32,
+    deadline_lo: u32,
+    deadline_hi: u32,
+    notification: u32,
+) {
+    let deadline = if set_timer != 0 {
+        Some(u64::from(deadline_lo) | u64::from(deadline_hi) << 32)
+    } else {
+        None
+    };
+    Active::set_timer(deadline, notification);
+}
+
+pub(crate) unsafe fn sys_borrow_read_stub(args: *mut BorrowReadArgs) -> RcLen {
+    let args = &*args;
+    let dest = core::slice::from_raw_parts_mut(args.dest, args.dest_len);
+    let (rc, n) =
+        Active::borrow_read(args.lender, args.index, args.offset, dest);
+    RcLen(u64::from(rc) | (n as u64) << 32)
+}
+
+pub(crate) unsafe fn sys_borrow_write_stub(
+    args: *mut BorrowWriteArgs,
+) -> RcLen {
+    let args = &*args;
+    let src = core::slice::from_raw_parts(args.src, args.src_len);
+    let (rc, n) =
+        Active::borrow_write(args.lender, args.index, args.offset, src);
+    RcLen(u64::from(rc) | (n as u64) << 32)
+}
+
+pub(crate) unsafe fn sys_borrow_info_stub(
+    lender: u32,
+    index: usize,
+    out: *mut RawBorrowInfo,
+) {
+    out.write(Active::borrow_info(lender, index));
+}
+
+pub(crate) unsafe fn sys_irq_control_stub(mask: u32, enable: u32) {
+    Active::irq_control(mask, enable != 0);
+}
+
+pub(crate) unsafe fn sys_panic_stub(msg: *const u8, len: usize) -> ! {
+    Active::panic(core::slice::from_raw_parts(msg, len))
+}
+
+pub(crate) unsafe fn sys_get_timer_stub(out: *mut RawTimerState) {
+    out.write(Active::get_timer());
+}
+
+pub(crate) unsafe fn sys_refresh_task_id_stub(tid: u32) -> u32 {
+    Active::refresh_task_id(tid)
+}
+
+pub(crate) unsafe fn sys_post_stub(tid: u32, mask: u32) -> u32 {
+    Active::post(tid, mask)
+}
@@ -28,6 +28,9 @@
 #![feature(asm)]
 #![feature(naked_functions)]
 
+#[cfg(feature = "host-ipc")]
+extern crate std;
+
 #[macro_use]
 pub mod macros;
 
@@ -37,12 +40,25 @@ pub use num_traits::{FromPrimitive, ToPrimitive};
 
 use core::marker::PhantomData;
 
+#[cfg(feature = "host-ipc")]
+use host::{
+    sys_borrow_info_stub, sys_borrow_read_stub, sys_borrow_write_stub,
+    sys_get_timer_stub, sys_irq_control_stub, sys_panic_stub, sys_post_stub,
+    sys_recv_stub, sys_refresh_task_id_stub, sys_reply_stub, sys_send_stub,
+    sys_set_timer_stub,
+};
+
 pub mod hl;
+#[cfg(feature = "host-ipc")]
+pub mod host;
 pub mod kipc;
+#[cfg(feature = "host-sim")]
+pub mod sim;
 pub mod task_slot;
 pub mod units;
 pub mod util;
 
+#[cfg(not(feature = "host-ipc"))]
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct Lease<'a> {
@@ -50,6 +66,7 @@ pub struct Lease<'a> {
     _marker: PhantomData<&'a mut ()>,
 }
 
+#[cfg(not(feature = "host-ipc"))]
 impl<'a> From<&'a [u8]> for Lease<'a> {
     fn from(x: &'a [u8]) -> Self {
         Self {
@@ -63,6 +80,7 @@ impl<'a> From<&'a [u8]> for Lease<'a> {
     }
 }
 
+#[cfg(not(feature = "host-ipc"))]
 impl<'a> From<&'a mut [u8]> for Lease<'a> {
     fn from(x: &'a mut [u8]) -> Self {
         Self {
@@ -76,6 +94,41 @@ impl<'a> From<&'a mut [u8]> for Lease<'a> {
     }
 }
 
+/// On the host, addresses don't fit in the kernel's 32-bit lease
+/// representation, so we keep a full pointer for the harness to use instead.
+#[cfg(feature = "host-ipc")]
+#[derive(Debug)]
+pub struct Lease<'a> {
+    attributes: LeaseAttributes,
+    base_address: *mut u8,
+    length: usize,
+    _marker: PhantomData<&'a mut ()>,
+}
+
+#[cfg(feature = "host-ipc")]
+impl<'a> From<&'a [u8]> for Lease<'a> {
+    fn from(x: &'a [u8]) -> Self {
+        Self {
+            attributes: LeaseAttributes::READ,
+            base_address: x.as_ptr() as *mut u8,
+            length: x.len(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "host-ipc")]
+impl<'a> From<&'a mut [u8]> for Lease<'a> {
+    fn from(x: &'a mut [u8]) -> Self {
+        Self {
+            attributes: LeaseAttributes::READ | LeaseAttributes::WRITE,
+            base_address: x.as_mut_ptr(),
+            length: x.len(),
+            _marker: PhantomData,
+        }
+    }
+}
+
 /// Return type for stubs that return an `(rc, len)` tuple, because the layout
 /// of tuples is not specified in the C ABI, and we're using the C ABI to
 /// interface to assembler.
@@ -128,6 +181,7 @@ struct SendArgs<'a> {
 /// Core implementation of the SEND syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_send_stub(_args: &mut SendArgs<'_>) -> RcLen {
     asm!("
@@ -262,6 +316,7 @@ pub struct RecvMessage {
 /// Core implementation of the RECV syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 #[must_use]
 unsafe extern "C" fn sys_recv_stub(
@@ -327,6 +382,7 @@ pub fn sys_reply(peer: TaskId, code: u32, message: &[u8]) {
 /// Core implementation of the REPLY syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_reply_stub(
     _peer: u32,
@@ -390,6 +446,7 @@ pub fn sys_set_timer(deadline: Option<u64>, notifications: u32) {
 /// Core implementation of the SET_TIMER syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_set_timer_stub(
     _set_timer: u32,
@@ -446,6 +503,7 @@ pub fn sys_borrow_read(
 /// Core implementation of the BORROW_READ syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_borrow_read_stub(_args: *mut BorrowReadArgs) -> RcLen {
     asm!("
@@ -504,6 +562,7 @@ pub fn sys_borrow_write(
 /// Core implementation of the BORROW_WRITE syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_borrow_write_stub(
     _args: *mut BorrowWriteArgs,
@@ -568,6 +627,7 @@ struct RawBorrowInfo {
 /// Core implementation of the BORROW_INFO syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_borrow_info_stub(
     _lender: u32,
@@ -611,6 +671,7 @@ pub fn sys_irq_control(mask: u32, enable: bool) {
 /// Core implementation of the IRQ_CONTROL syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_irq_control_stub(_mask: u32, _enable: u32) {
     asm!("
@@ -646,6 +707,7 @@ pub fn sys_panic(msg: &[u8]) -> ! {
 /// Core implementation of the PANIC syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_panic_stub(_msg: *const u8, _len: usize) -> ! {
     asm!("
@@ -728,6 +790,7 @@ struct RawTimerState {
 /// Core implementation of the GET_TIMER syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_get_timer_stub(_out: *mut RawTimerState) {
     asm!("
@@ -753,6 +816,7 @@ unsafe extern "C" fn sys_get_timer_stub(_out: *mut RawTimerState) {
 
 /// This is the entry point for the kernel. Its job is to set up our memory
 /// before jumping to user-defined `main`.
+#[cfg(not(feature = "host-ipc"))]
 #[doc(hidden)]
 #[no_mangle]
 #[link_section = ".text.start"]
@@ -822,7 +886,7 @@ pub unsafe extern "C" fn _start() -> ! {
     )
 }
 
-#[cfg(feature = "panic-messages")]
+#[cfg(all(feature = "panic-messages", not(feature = "host-ipc")))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     use core::fmt::Write;
@@ -853,7 +917,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     });
 }
 
-#[cfg(not(feature = "panic-messages"))]
+#[cfg(not(any(feature = "panic-messages", feature = "host-ipc")))]
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo) -> ! {
     sys_panic(b"PANIC")
@@ -868,6 +932,7 @@ pub fn sys_refresh_task_id(task_id: TaskId) -> TaskId {
 /// Core implementation of the REFRESH_TASK_ID syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_refresh_task_id_stub(_tid: u32) -> u32 {
     asm!("
@@ -904,6 +969,7 @@ pub fn sys_post(task_id: TaskId, bits: u32) -> u32 {
 /// Core implementation of the POST syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(not(feature = "host-ipc"))]
 #[naked]
 unsafe extern "C" fn sys_post_stub(_tid: u32, _mask: u32) -> u32 {
     asm!("
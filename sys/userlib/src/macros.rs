@@ -5,7 +5,7 @@
 pub use bstringify;
 pub use paste;
 
-#[cfg(feature = "log-itm")]
+#[cfg(all(feature = "log-itm", not(feature = "host-sim")))]
 #[macro_export]
 macro_rules! sys_log {
     ($s:expr) => {
@@ -22,7 +22,7 @@ macro_rules! sys_log {
     };
 }
 
-#[cfg(feature = "log-semihosting")]
+#[cfg(all(feature = "log-semihosting", not(feature = "host-sim")))]
 #[macro_export]
 macro_rules! sys_log {
     ($s:expr) => {
@@ -33,7 +33,22 @@ macro_rules! sys_log {
     };
 }
 
-#[cfg(not(any(feature = "log-semihosting", feature = "log-itm")))]
+#[cfg(feature = "host-sim")]
+#[macro_export]
+macro_rules! sys_log {
+    ($s:expr) => {
+        $crate::sim::log(format_args!($s))
+    };
+    ($s:expr, $($tt:tt)*) => {
+        $crate::sim::log(format_args!($s, $($tt)*))
+    };
+}
+
+#[cfg(not(any(
+    feature = "log-semihosting",
+    feature = "log-itm",
+    feature = "host-sim"
+)))]
 #[macro_export]
 macro_rules! sys_log {
     ($s:expr) => {
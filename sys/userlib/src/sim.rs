@@ -0,0 +1,409 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Syscalls for tasks running under `cargo xtask sim`.
+//!
+//! With the `host-sim` feature enabled, a task is built as an ordinary host
+//! program, and `xtask sim` runs each task in the application as its own
+//! process. This builds on `host-ipc`: its syscall stubs hand every syscall
+//! to `Remote`, which forwards it over a Unix socket to the simulated kernel
+//! inside `xtask`, and then waits for its answer. The kernel only answers
+//! one task at a time, so a task runs only when the kernel has scheduled it,
+//! and the kernel's priority rules decide who goes next just as they would
+//! on hardware.
+//!
+//! The kernel starts each task with the path to its socket in
+//! `HUBRIS_SIM_SOCKET`, the task's index in `HUBRIS_SIM_TASK`, and its name in
+//! `HUBRIS_SIM_NAME`. The task connects the first time it makes a syscall.
+//!
+//! # Wire format
+//!
+//! Every message in either direction is a frame: a little-endian `u32`
+//! length followed by that many bytes of payload. A payload is a sequence of
+//! fields, each of which is either a little-endian `u32` word or a byte
+//! string (a word giving its length, then the bytes).
+//!
+//! The first frame a task sends holds a single word, its index. Each frame
+//! after that is a syscall, starting with its `Sysnum` and followed by its
+//! arguments:
+//!
+//! | Syscall         | Arguments                                          |
+//! |-----------------|----------------------------------------------------|
+//! | `Send`          | target and operation (packed as in the real ABI),  |
+//! |                 | message, response capacity, lease count, and then  |
+//! |                 | attributes and contents of each lease              |
+//! | `Recv`          | buffer size, notification mask, specific sender    |
+//! | `Reply`         | peer, code, message                                |
+//! | `SetTimer`      | set flag, deadline (low and high words), bits      |
+//! | `BorrowRead`    | lender, lease index, offset, buffer size           |
+//! | `BorrowWrite`   | lender, lease index, offset, data                  |
+//! | `BorrowInfo`    | lender, lease index                                |
+//! | `IrqControl`    | mask, enable flag                                  |
+//! | `Panic`         | message                                            |
+//! | `GetTimer`      | none                                               |
+//! | `RefreshTaskId` | task ID                                            |
+//! | `Post`          | task ID, bits                                      |
+//!
+//! The kernel's answer to each syscall is its result, laid out the same way:
+//!
+//! | Syscall         | Results                                            |
+//! |-----------------|----------------------------------------------------|
+//! | `Send`          | response code, reply message, lease count, and the |
+//! |                 | contents of each lease after the call              |
+//! | `Recv`          | response code, sender, operation, message,         |
+//! |                 | response capacity, lease count                     |
+//! | `BorrowRead`    | response code, data                                |
+//! | `BorrowWrite`   | response code, bytes written                       |
+//! | `BorrowInfo`    | response code, attributes, length                  |
+//! | `GetTimer`      | the six words of `RawTimerState`, in order         |
+//! | `RefreshTaskId` | task ID                                            |
+//! | `Post`          | response code                                      |
+//! | anything else   | nothing                                            |
+//!
+//! `Panic` gets no answer: the kernel marks the task as faulted and stops it.
+//! Since leases are copied into the kernel with the message, the sender's
+//! writable leases are updated from the `Send` results when the reply comes
+//! back, rather than as the receiver writes to them.
+
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::vec::Vec;
+
+use abi::{LeaseAttributes, Sysnum, TaskId};
+
+use crate::host::{Backend, LeaseBuf};
+use crate::{RawBorrowInfo, RawRecvMessage, RawTimerState};
+
+/// Environment variable holding the path to the simulated kernel's socket.
+pub const SOCKET_ENV: &str = "HUBRIS_SIM_SOCKET";
+
+/// Environment variable holding the index of the task being run.
+pub const TASK_ENV: &str = "HUBRIS_SIM_TASK";
+
+/// Environment variable holding the name of the task being run, which is
+/// used to label its log output.
+pub const NAME_ENV: &str = "HUBRIS_SIM_NAME";
+
+std::thread_local! {
+    static KERNEL: RefCell<Option<UnixStream>> = RefCell::new(None);
+}
+
+/// Writes a line of log output, labeled with the task's name. This is what
+/// `sys_log!` expands to under the simulator.
+pub fn log(args: core::fmt::Arguments<'_>) {
+    let name = std::env::var(NAME_ENV).unwrap_or_else(|_| "task".into());
+    std::println!("{}: {}", name, args);
+}
+
+/// A syscall on its way to the kernel.
+struct Request(Vec<u8>);
+
+impl Request {
+    fn new(sysnum: Sysnum) -> Self {
+        Self(Vec::new()).word(sysnum as u32)
+    }
+
+    fn word(mut self, word: u32) -> Self {
+        self.0.extend_from_slice(&word.to_le_bytes());
+        self
+    }
+
+    fn bytes(self, bytes: &[u8]) -> Self {
+        let mut this = self.word(bytes.len() as u32);
+        this.0.extend_from_slice(bytes);
+        this
+    }
+}
+
+/// The kernel's answer to a syscall.
+struct Response {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Response {
+    fn word(&mut self) -> u32 {
+        let mut word = [0; 4];
+        word.copy_from_slice(self.take(4));
+        u32::from_le_bytes(word)
+    }
+
+    fn bytes(&mut self) -> &[u8] {
+        let len = self.word() as usize;
+        self.take(len)
+    }
+
+    fn take(&mut self, len: usize) -> &[u8] {
+        if self.data.len() - self.pos < len {
+            kernel_lost("truncated response");
+        }
+        self.pos += len;
+        &self.data[self.pos - len..self.pos]
+    }
+}
+
+/// Gives up on the simulation. There's nobody left to report a fault to, so
+/// we just say why and leave.
+fn kernel_lost(why: &str) -> ! {
+    std::eprintln!("lost the simulated kernel: {}", why);
+    std::process::exit(1);
+}
+
+fn write_frame(stream: &mut UnixStream, payload: &[u8]) {
+    let len = (payload.len() as u32).to_le_bytes();
+    if let Err(e) = stream
+        .write_all(&len)
+        .and_then(|_| stream.write_all(payload))
+    {
+        kernel_lost(&std::format!("{}", e));
+    }
+}
+
+fn read_frame(stream: &mut UnixStream) -> Vec<u8> {
+    let mut len = [0; 4];
+    if let Err(e) = stream.read_exact(&mut len) {
+        kernel_lost(&std::format!("{}", e));
+    }
+    let mut payload = std::vec![0; u32::from_le_bytes(len) as usize];
+    if let Err(e) = stream.read_exact(&mut payload) {
+        kernel_lost(&std::format!("{}", e));
+    }
+    payload
+}
+
+/// Connects to the kernel and introduces ourselves. This also routes panics
+/// through `sys_panic`, so that the kernel hears about them as it would on
+/// hardware.
+fn connect() -> UnixStream {
+    let path = match std::env::var(SOCKET_ENV) {
+        Ok(path) => path,
+        Err(_) => kernel_lost(&std::format!(
+            "{} is not set; tasks built for the simulator must be run by \
+             `cargo xtask sim`",
+            SOCKET_ENV
+        )),
+    };
+    let index = std::env::var(TASK_ENV)
+        .ok()
+        .and_then(|index| index.parse::<u32>().ok())
+        .unwrap_or_else(|| kernel_lost(&std::format!("bad {}", TASK_ENV)));
+
+    let mut stream = match UnixStream::connect(&path) {
+        Ok(stream) => stream,
+        Err(e) => kernel_lost(&std::format!("{}: {}", path, e)),
+    };
+    write_frame(&mut stream, &index.to_le_bytes());
+
+    std::panic::set_hook(Box::new(|info| {
+        let msg = std::format!("{}", info);
+        crate::sys_panic(msg.as_bytes());
+    }));
+
+    stream
+}
+
+/// Sends `request` to the kernel, and waits for it to answer.
+fn call(request: Request) -> Response {
+    KERNEL.with(|kernel| {
+        let mut kernel = kernel.borrow_mut();
+        let stream = kernel.get_or_insert_with(connect);
+        write_frame(stream, &request.0);
+        Response {
+            data: read_frame(stream),
+            pos: 0,
+        }
+    })
+}
+
+/// The kernel run by `xtask sim`.
+pub(crate) struct Remote;
+
+impl Backend for Remote {
+    fn send(
+        target: TaskId,
+        operation: u16,
+        outgoing: &[u8],
+        incoming: &mut [u8],
+        mut leases: Vec<(LeaseAttributes, LeaseBuf<'_>)>,
+    ) -> (u32, usize) {
+        let mut request = Request::new(Sysnum::Send)
+            .word(u32::from(target.0) << 16 | u32::from(operation))
+            .bytes(outgoing)
+            .word(incoming.len() as u32)
+            .word(leases.len() as u32);
+        for (attributes, buf) in &leases {
+            let data = match buf {
+                LeaseBuf::Read(data) => &data[..],
+                LeaseBuf::Write(data) => &data[..],
+            };
+            request = request.word(attributes.bits()).bytes(data);
+        }
+
+        let mut response = call(request);
+        let rc = response.word();
+        let reply = response.bytes();
+        let len = reply.len().min(incoming.len());
+        incoming[..len].copy_from_slice(&reply[..len]);
+
+        let count = response.word() as usize;
+        for (_, buf) in leases.iter_mut().take(count) {
+            let data = response.bytes();
+            if let LeaseBuf::Write(dest) = buf {
+                let n = data.len().min(dest.len());
+                dest[..n].copy_from_slice(&data[..n]);
+            }
+        }
+
+        (rc, len)
+    }
+
+    fn recv(
+        buffer: &mut [u8],
+        notification_mask: u32,
+        specific_sender: Option<TaskId>,
+    ) -> (u32, RawRecvMessage) {
+        let mut response = call(
+            Request::new(Sysnum::Recv)
+                .word(buffer.len() as u32)
+                .word(notification_mask)
+                .word(specific_sender.map_or(0, |s| 1 << 31 | u32::from(s.0))),
+        );
+
+        let rc = response.word();
+        let sender = response.word();
+        let operation = response.word();
+        let message = response.bytes();
+        let message_len = message.len().min(buffer.len());
+        buffer[..message_len].copy_from_slice(&message[..message_len]);
+
+        let message = RawRecvMessage {
+            sender,
+            operation,
+            message_len,
+            response_capacity: response.word() as usize,
+            lease_count: response.word() as usize,
+        };
+        (rc, message)
+    }
+
+    fn reply(peer: u32, code: u32, message: &[u8]) {
+        call(
+            Request::new(Sysnum::Reply)
+                .word(peer)
+                .word(code)
+                .bytes(message),
+        );
+    }
+
+    fn set_timer(deadline: Option<u64>, notification: u32) {
+        let (set, deadline) = match deadline {
+            Some(deadline) => (1, deadline),
+            None => (0, 0),
+        };
+        call(
+            Request::new(Sysnum::SetTimer)
+                .word(set)
+                .word(deadline as u32)
+                .word((deadline >> 32) as u32)
+                .word(notification),
+        );
+    }
+
+    fn borrow_read(
+        lender: u32,
+        index: usize,
+        offset: usize,
+        dest: &mut [u8],
+    ) -> (u32, usize) {
+        let mut response = call(
+            Request::new(Sysnum::BorrowRead)
+                .word(lender)
+                .word(index as u32)
+                .word(offset as u32)
+                .word(dest.len() as u32),
+        );
+
+        let rc = response.word();
+        let data = response.bytes();
+        let n = data.len().min(dest.len());
+        dest[..n].copy_from_slice(&data[..n]);
+        (rc, n)
+    }
+
+    fn borrow_write(
+        lender: u32,
+        index: usize,
+        offset: usize,
+        src: &[u8],
+    ) -> (u32, usize) {
+        let mut response = call(
+            Request::new(Sysnum::BorrowWrite)
+                .word(lender)
+                .word(index as u32)
+                .word(offset as u32)
+                .bytes(src),
+        );
+
+        let rc = response.word();
+        (rc, response.word() as usize)
+    }
+
+    fn borrow_info(lender: u32, index: usize) -> RawBorrowInfo {
+        let mut response = call(
+            Request::new(Sysnum::BorrowInfo)
+                .word(lender)
+                .word(index as u32),
+        );
+
+        RawBorrowInfo {
+            rc: response.word(),
+            atts: response.word(),
+            length: response.word() as usize,
+        }
+    }
+
+    fn irq_control(mask: u32, enable: bool) {
+        call(
+            Request::new(Sysnum::IrqControl)
+                .word(mask)
+                .word(enable as u32),
+        );
+    }
+
+    fn panic(message: &[u8]) -> ! {
+        let request = Request::new(Sysnum::Panic).bytes(message);
+
+        // The kernel doesn't answer a panic; it just stops us. Should we
+        // panic before ever making a syscall, we connect only to say so.
+        KERNEL.with(|kernel| {
+            let mut kernel = kernel.borrow_mut();
+            let stream = kernel.get_or_insert_with(connect);
+            write_frame(stream, &request.0);
+        });
+        std::process::exit(101);
+    }
+
+    fn get_timer() -> RawTimerState {
+        let mut response = call(Request::new(Sysnum::GetTimer));
+
+        RawTimerState {
+            now_lo: response.word(),
+            now_hi: response.word(),
+            set: response.word(),
+            dl_lo: response.word(),
+            dl_hi: response.word(),
+            on_dl: response.word(),
+        }
+    }
+
+    fn refresh_task_id(tid: u32) -> u32 {
+        call(Request::new(Sysnum::RefreshTaskId).word(tid)).word()
+    }
+
+    fn post(tid: u32, mask: u32) -> u32 {
+        call(Request::new(Sysnum::Post).word(tid).word(mask)).word()
+    }
+}
@@ -0,0 +1,231 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Exercises `hl::BorrowReader` and `hl::BorrowWriter` against leases lent
+//! through the in-process IPC harness, e.g. with `cargo test -p userlib
+//! --features host-ipc --target x86_64-unknown-linux-gnu`.
+
+use userlib::hl::{BorrowInfo, BorrowReader, BorrowWriter, Caller, LeaseIo};
+use userlib::host::{self, HostLease, Reply};
+use userlib::*;
+
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+enum Op {
+    Go = 1,
+}
+
+/// Sends a message lending `leases` to a server that runs `body` on the
+/// caller, then replies, returning what the client gets back.
+fn serve(leases: Vec<HostLease>, body: impl FnOnce(&Caller<()>)) -> Reply {
+    host::reset();
+    let client = TaskId::for_index_and_gen(1, Generation::default());
+    let count = leases.len();
+    host::send(client, Op::Go as u16, &[], 0, leases);
+
+    let mut buffer = [0; 4];
+    hl::recv_without_notification(
+        &mut buffer,
+        |Op::Go, msg| -> Result<(), u32> {
+            let (_, caller) =
+                msg.fixed_with_leases::<[u8; 0], ()>(count).unwrap();
+            body(&caller);
+            caller.reply(());
+            Ok(())
+        },
+    );
+    host::take_reply(client).unwrap()
+}
+
+#[test]
+fn reader() {
+    serve(vec![HostLease::read(b"\x01\x02\x03\x04hello")], |caller| {
+        let mut reader = caller.borrow(0).into_reader().unwrap();
+        assert_eq!(reader.len(), 9);
+        assert_eq!(reader.read_item::<u32>(), Some(0x0403_0201));
+        assert_eq!(reader.position(), 4);
+
+        let mut buf = [0; 3];
+        assert_eq!(reader.read(&mut buf), Some(&b"hel"[..]));
+        assert_eq!(reader.remaining(), 2);
+
+        // Reads get shortened at the end of the lease, rather than failing.
+        assert_eq!(reader.read(&mut buf), Some(&b"lo"[..]));
+        assert!(reader.is_done());
+        assert_eq!(reader.read(&mut buf), Some(&b""[..]));
+    });
+}
+
+#[test]
+fn reader_exact() {
+    serve(vec![HostLease::read(b"hello")], |caller| {
+        let mut reader = caller.borrow(0).into_reader().unwrap();
+        assert_eq!(reader.skip(1), 1);
+
+        // Reading past the end fails without moving.
+        let mut buf = [0; 5];
+        assert_eq!(reader.read_exact(&mut buf), None);
+        assert_eq!(reader.read_item::<[u8; 5]>(), None);
+        assert_eq!(reader.position(), 1);
+
+        assert_eq!(reader.read_exact(&mut buf[..4]), Some(()));
+        assert_eq!(&buf[..4], b"ello");
+        assert_eq!(reader.skip(10), 0);
+    });
+}
+
+#[test]
+fn attributes() {
+    serve(
+        vec![HostLease::read(b"ro"), HostLease::write(2)],
+        |caller| {
+            assert!(caller.borrow(0).into_writer().is_none());
+            assert!(caller.borrow(1).into_reader().is_none());
+        },
+    );
+
+    serve(vec![HostLease::read_write(b"rw")], |caller| {
+        let mut writer = caller.borrow(0).into_writer().unwrap();
+        assert_eq!(writer.write_all(b"RW"), Some(()));
+        let mut reader = writer.into_inner().into_reader().unwrap();
+        assert_eq!(reader.read_item::<[u8; 2]>(), Some(*b"RW"));
+    });
+}
+
+#[test]
+fn chunks() {
+    serve(vec![HostLease::read(b"0123456789")], |caller| {
+        let mut reader = caller.borrow(0).into_reader().unwrap();
+        let mut seen = vec![];
+        let mut scratch = [0; 4];
+        let result = reader.for_each_chunk(&mut scratch, |offset, chunk| {
+            seen.push((offset, chunk.to_vec()));
+            Ok::<(), ()>(())
+        });
+        assert_eq!(result, Ok(Some(())));
+        assert_eq!(
+            seen,
+            [
+                (0, b"0123".to_vec()),
+                (4, b"4567".to_vec()),
+                (8, b"89".to_vec()),
+            ]
+        );
+    });
+}
+
+#[test]
+fn chunks_stop() {
+    serve(vec![HostLease::read(b"0123456789")], |caller| {
+        let mut reader = caller.borrow(0).into_reader().unwrap();
+        let mut scratch = [0; 4];
+        let result = reader.for_each_chunk(&mut scratch, |offset, _| {
+            if offset == 4 {
+                Err(offset)
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(result, Err(4));
+        assert_eq!(reader.position(), 8);
+    });
+}
+
+#[test]
+fn writer() {
+    let reply = serve(vec![HostLease::write(8)], |caller| {
+        let mut writer = caller.borrow(0).into_writer().unwrap();
+        assert_eq!(writer.write_item(0x0403_0201u32), Some(()));
+
+        // Writes get shortened at the end of the lease, and then do nothing.
+        assert_eq!(writer.write(b"abcdef"), Some(4));
+        assert!(writer.is_done());
+        assert_eq!(writer.write(b"g"), Some(0));
+        assert_eq!(writer.write_all(b"g"), None);
+        assert_eq!(writer.position(), 8);
+    });
+    assert_eq!(reply.leases[0].data, b"\x01\x02\x03\x04abcd");
+}
+
+#[test]
+fn fill() {
+    let reply = serve(vec![HostLease::write(7)], |caller| {
+        let mut writer = caller.borrow(0).into_writer().unwrap();
+        let mut scratch = [0; 3];
+        let result = writer.fill_from(&mut scratch, |offset, chunk| {
+            for (i, b) in chunk.iter_mut().enumerate() {
+                *b = b'a' + (offset + i) as u8;
+            }
+            Ok::<(), ()>(())
+        });
+        assert_eq!(result, Ok(Some(())));
+    });
+    assert_eq!(reply.leases[0].data, b"abcdefg");
+}
+
+#[test]
+fn copy() {
+    let reply = serve(
+        vec![HostLease::read(b"0123456789"), HostLease::write(6)],
+        |caller| {
+            let mut reader = caller.borrow(0).into_reader().unwrap();
+            let mut writer = caller.borrow(1).into_writer().unwrap();
+            let mut scratch = [0; 4];
+
+            // The copy stops when the writer fills up, leaving the rest of
+            // the reader for later.
+            assert_eq!(reader.copy_to(&mut writer, &mut scratch), Some(6));
+            assert!(writer.is_done());
+            assert_eq!(reader.position(), 6);
+        },
+    );
+    assert_eq!(reply.leases[1].data, b"012345");
+}
+
+/// A lease that claims to be readable, but fails every access past `good`
+/// bytes, as a borrow does when its lender goes away.
+struct Flaky {
+    good: usize,
+}
+
+impl LeaseIo for Flaky {
+    fn info(&self) -> Option<BorrowInfo> {
+        Some(BorrowInfo {
+            attributes: LeaseAttributes::READ | LeaseAttributes::WRITE,
+            len: 8,
+        })
+    }
+
+    fn read_fully_at(&self, offset: usize, dest: &mut [u8]) -> Option<()> {
+        if offset + dest.len() > self.good {
+            return None;
+        }
+        dest.iter_mut().for_each(|b| *b = 0xaa);
+        Some(())
+    }
+
+    fn write_fully_at(&self, offset: usize, src: &[u8]) -> Option<()> {
+        if offset + src.len() > self.good {
+            return None;
+        }
+        Some(())
+    }
+}
+
+#[test]
+fn failing_lease() {
+    let mut reader = BorrowReader::new(Flaky { good: 4 }).unwrap();
+    let mut scratch = [0; 4];
+    let mut chunks = 0;
+    let result = reader.for_each_chunk(&mut scratch, |_, _| {
+        chunks += 1;
+        Ok::<(), ()>(())
+    });
+    assert_eq!((result, chunks), (Ok(None), 1));
+    assert_eq!(reader.position(), 4);
+
+    let mut writer = BorrowWriter::new(Flaky { good: 4 }).unwrap();
+    let result = writer.fill_from(&mut scratch[..3], |_, _| Ok::<(), ()>(()));
+    assert_eq!(result, Ok(None));
+    assert_eq!(writer.position(), 3);
+}
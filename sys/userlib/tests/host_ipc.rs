@@ -0,0 +1,155 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Exercises the in-process IPC harness, e.g. with `cargo test -p userlib
+//! --features host-ipc --target x86_64-unknown-linux-gnu`.
+
+use userlib::host::{self, HostLease};
+use userlib::*;
+
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+enum Op {
+    Sum = 1,
+    Copy = 2,
+}
+
+/// Runs one iteration of a small server: `Sum` replies with the sum of the
+/// bytes in its message, and `Copy` copies its read lease into its write
+/// lease, replying with the number of bytes copied.
+fn step() {
+    let mut buffer = [0; 4];
+    hl::recv_without_notification(&mut buffer, |op, msg| -> Result<(), u32> {
+        match op {
+            Op::Sum => {
+                let (bytes, caller) =
+                    msg.fixed::<[u8; 4], u32>().ok_or(2u32)?;
+                caller.reply(bytes.iter().map(|&b| u32::from(b)).sum());
+            }
+            Op::Copy => {
+                let (_, caller) =
+                    msg.fixed_with_leases::<[u8; 0], u32>(2).ok_or(2u32)?;
+                let src = caller.borrow(0);
+                let dest = caller.borrow(1);
+                let src_info = src.info().ok_or(3u32)?;
+                let dest_info = dest.info().ok_or(3u32)?;
+                if !src_info.attributes.contains(LeaseAttributes::READ)
+                    || !dest_info.attributes.contains(LeaseAttributes::WRITE)
+                {
+                    return Err(4);
+                }
+                let len = src_info.len.min(dest_info.len);
+                let mut data = [0; 16];
+                src.read_fully_at(0, &mut data[..len]).ok_or(3u32)?;
+                dest.write_fully_at(0, &data[..len]).ok_or(3u32)?;
+                caller.reply(len as u32);
+            }
+        }
+        Ok(())
+    });
+}
+
+fn client(index: usize) -> TaskId {
+    TaskId::for_index_and_gen(index, Generation::default())
+}
+
+#[test]
+fn round_trip() {
+    host::reset();
+    let a = client(1);
+    let b = client(2);
+    host::send(a, Op::Sum as u16, &[1, 2, 3, 4], 4, vec![]);
+    host::send(b, Op::Sum as u16, &[10, 20, 30, 40], 4, vec![]);
+    assert_eq!(host::queued(), 2);
+
+    step();
+    assert_eq!(host::queued(), 1);
+    assert!(!host::is_awaiting_reply(a));
+    let reply = host::take_reply(a).unwrap();
+    assert_eq!(reply.code, 0);
+    assert_eq!(reply.message, 10u32.to_ne_bytes());
+    assert!(reply.leases.is_empty());
+    assert_eq!(host::take_reply(a), None);
+
+    step();
+    assert_eq!(host::queued(), 0);
+    assert_eq!(host::take_reply(b).unwrap().message, 100u32.to_ne_bytes());
+}
+
+#[test]
+fn round_trip_failures() {
+    host::reset();
+    let a = client(1);
+
+    // An operation the server doesn't know gets the stock error.
+    host::send(a, 99, &[], 4, vec![]);
+    step();
+    let reply = host::take_reply(a).unwrap();
+    assert_eq!(reply.code, 1);
+    assert!(reply.message.is_empty());
+
+    // So does a malformed message, with the server's own code.
+    host::send(a, Op::Sum as u16, &[1, 2], 4, vec![]);
+    step();
+    assert_eq!(host::take_reply(a).unwrap().code, 2);
+}
+
+#[test]
+#[should_panic(expected = "task would block forever")]
+fn recv_with_nothing_queued() {
+    host::reset();
+    step();
+}
+
+#[test]
+fn borrows() {
+    host::reset();
+    let a = client(1);
+    host::send(
+        a,
+        Op::Copy as u16,
+        &[],
+        4,
+        vec![HostLease::read(b"hello"), HostLease::write(4)],
+    );
+    step();
+
+    let reply = host::take_reply(a).unwrap();
+    assert_eq!(reply.code, 0);
+    assert_eq!(reply.message, 4u32.to_ne_bytes());
+    assert_eq!(reply.leases[0], HostLease::read(b"hello"));
+    assert_eq!(reply.leases[1].data, b"hell");
+}
+
+#[test]
+fn borrow_attributes() {
+    host::reset();
+    let a = client(1);
+
+    // Leases the wrong way round are turned away by the server's own check.
+    host::send(
+        a,
+        Op::Copy as u16,
+        &[],
+        4,
+        vec![HostLease::write(4), HostLease::read(b"hello")],
+    );
+    step();
+    assert_eq!(host::take_reply(a).unwrap().code, 4);
+
+    // And the harness itself refuses accesses the lease doesn't allow.
+    host::send(a, Op::Sum as u16, &[0; 4], 4, vec![HostLease::read(b"ro")]);
+    let mut buffer = [0; 4];
+    hl::recv_without_notification(
+        &mut buffer,
+        |_: Op, msg| -> Result<(), u32> {
+            let (_, caller) = msg.fixed_with_leases::<[u8; 4], ()>(1).unwrap();
+            let lease = caller.borrow(0);
+            assert_eq!(lease.read_at::<u8>(1), Some(b'o'));
+            assert_eq!(lease.write_at(0, 0u8), None);
+            caller.reply(());
+            Ok(())
+        },
+    );
+    assert_eq!(host::take_reply(a).unwrap().leases[0].data, b"ro");
+}
@@ -8,6 +8,8 @@
 //!
 //! - Maintaining the system console output (currently via semihosting).
 //! - Monitoring tasks for failures and restarting them.
+//! - With the `confirm-image` feature, telling stage0 that the running image
+//!   is healthy once it has been up for a while.
 //!
 //! It will probably become responsible for:
 //!
@@ -86,6 +88,26 @@ fn log_fault(t: usize, fault: &abi::FaultInfo) {
     }
 }
 
+/// How long the image must have been running, in ticks, before we tell stage0
+/// that it's healthy. If it resets before then, e.g. because of a kernel
+/// panic, stage0 counts that against the image's boot attempts, and
+/// eventually falls back to another.
+#[cfg(feature = "confirm-image")]
+const CONFIRM_AFTER: u64 = 10_000;
+
+#[cfg(feature = "confirm-image")]
+fn confirm_image(now: u64, confirmed: &mut bool) {
+    if *confirmed || now < CONFIRM_AFTER {
+        return;
+    }
+
+    // Whether or not this works, trying again won't help.
+    if let Err(e) = hypocalls::hypo_confirm_image() {
+        sys_log!("Image confirmation failed: {:?}", e);
+    }
+    *confirmed = true;
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Disposition {
     Restart,
@@ -117,6 +139,9 @@ fn main() -> ! {
 
     sys_set_timer(Some(deadline), TIMER_MASK);
 
+    #[cfg(feature = "confirm-image")]
+    let mut confirmed = false;
+
     external::set_ready();
 
     loop {
@@ -130,6 +155,9 @@ fn main() -> ! {
             if msginfo.operation & TIMER_MASK != 0 {
                 deadline += TIMER_INTERVAL;
                 sys_set_timer(Some(deadline), TIMER_MASK);
+
+                #[cfg(feature = "confirm-image")]
+                confirm_image(deadline, &mut confirmed);
             }
 
             // If our disposition has changed or if we have been notified of
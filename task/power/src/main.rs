@@ -52,22 +52,28 @@ fn main() -> ! {
     let task = I2C.get_task_id();
 
     cfg_if::cfg_if! {
-        if #[cfg(target_board = "gemini-bu-1")] {
-            let (device, rail) = i2c_config::pmbus::isl_evl_vout0(task);
-            let mut isl0 = Isl68224::new(&device, rail);
-
-            let (device, rail) = i2c_config::pmbus::isl_evl_vout1(task);
-            let mut isl1 = Isl68224::new(&device, rail);
+        if #[cfg(feature = "standalone")] {
+            let device = &i2c_config::devices::mock(task);
+            let mut isl0 = Isl68224::new(&device, 0);
+            let mut isl1 = Isl68224::new(&device, 0);
         } else {
-            cfg_if::cfg_if! {
-                if #[cfg(feature = "standalone")] {
-                    let device = &i2c_config::devices::mock(task);
-                    let mut isl0 = Isl68224::new(&device, 0);
-                    let mut isl1 = Isl68224::new(&device, 0);
-                } else {
-                    compile_error!("unknown board");
-                }
-            }
+            //
+            // We toggle between the first two rails of the board's ISL68224s
+            // (on Gemini, those of the evaluation board).
+            //
+            let mut rails = i2c_config::inventory::by_device("isl68224")
+                .flat_map(|info| {
+                    let device = info.device(task);
+                    info.rails
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, name)| !name.is_empty())
+                        .map(move |(rail, _)| {
+                            Isl68224::new(&device, rail as u8)
+                        })
+                });
+            let mut isl0 = rails.next().unwrap();
+            let mut isl1 = rails.next().unwrap();
         }
     }
 
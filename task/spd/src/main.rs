@@ -378,7 +378,7 @@ fn main() -> ! {
 
     let ctrl = I2cControl {
         enable: |notification| {
-            sys_irq_control(notification, true);
+            enable_interrupts(notification);
         },
         wfi: |notification| {
             let _ = sys_recv_closed(&mut [], notification, TaskId::KERNEL);
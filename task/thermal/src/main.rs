@@ -75,32 +75,13 @@ fn temp_read<E: core::fmt::Debug, T: TempSensor<E> + core::fmt::Display>(
 #[export_name = "main"]
 fn main() -> ! {
     let task = I2C.get_task_id();
-    use i2c_config::devices;
+    use i2c_config::{devices, inventory};
 
     cfg_if::cfg_if! {
-        if #[cfg(target_board = "gemini-bu-1")] {
-            let fctrl = Max31790::new(&devices::max31790(task)[0]);
-            let tmp116: [Tmp116; 0] = [];
-        } else if #[cfg(target_board = "gimlet-1")] {
-            let tmp116 = [
-                Tmp116::new(&devices::tmp117_front_zone1(task)),
-                Tmp116::new(&devices::tmp117_front_zone2(task)),
-                Tmp116::new(&devices::tmp117_front_zone3(task)),
-                Tmp116::new(&devices::tmp117_rear_zone1(task)),
-                Tmp116::new(&devices::tmp117_rear_zone2(task)),
-                Tmp116::new(&devices::tmp117_rear_zone3(task)),
-            ];
-
-            let fctrl = Max31790::new(&devices::max31790(task)[0]);
+        if #[cfg(feature = "standalone")] {
+            let fctrl = Max31790::new(&devices::mock(task));
         } else {
-            cfg_if::cfg_if! {
-                if #[cfg(feature = "standalone")] {
-                    let fctrl = Max31790::new(&devices::mock(task));
-                    let tmp116: [Tmp116; 0] = [];
-                } else {
-                    compile_error!("unknown board");
-                }
-            }
+            let fctrl = Max31790::new(&devices::max31790(task)[0]);
         }
     }
 
@@ -120,8 +101,14 @@ fn main() -> ! {
     loop {
         read_fans(&fctrl);
 
-        for device in &tmp116 {
-            temp_read(device);
+        //
+        // Read every temperature sensor the board has; the TMP116 and TMP117
+        // share a driver.
+        //
+        for info in
+            inventory::by_device("tmp116").chain(inventory::by_device("tmp117"))
+        {
+            temp_read(&Tmp116::new(&info.device(task)));
         }
 
         hl::sleep_for(1000);
@@ -229,6 +229,11 @@ fn main() -> ! {
         test_run();
         TEST_RUNS.fetch_add(1, Ordering::SeqCst);
 
+        // Whatever the results, the suite ran to the end, which is all stage0
+        // needs to know to keep booting this image.
+        #[cfg(feature = "confirm-image")]
+        let _ = hypocalls::hypo_confirm_image();
+
         while TEST_KICK.load(Ordering::SeqCst) == 0 {
             continue;
         }